@@ -0,0 +1,58 @@
+use std::time::Instant;
+use usync::constants::CHUNK_SIZE;
+use usync::protocol::coding::{
+    FrameReceiver, FrameSender,
+    raptorq_code::{RaptorqPreset, RaptorqReceiver, RaptorqSender},
+};
+use usync::util::generate_random;
+
+// Frames lost on the way, one in LOSS_PERIOD.
+const LOSS_PERIOD: u32 = 20;
+
+// Encodes and decodes a chunk with every preset, for choosing `--raptorq`.
+pub fn main() {
+    debug_assert!(
+        false,
+        "Please run in release mod, since raptorq will be far more faster."
+    );
+
+    let data = generate_random(CHUNK_SIZE);
+    for preset in [
+        RaptorqPreset::Default,
+        RaptorqPreset::LowMemory,
+        RaptorqPreset::SmallBlocks,
+    ] {
+        let start = Instant::now();
+        let mut encoder = RaptorqSender::with_params(&data, 0, preset.params());
+        let encoded = start.elapsed();
+
+        let info = encoder.get_trasmission_info();
+        let source_frames = encoder.source_frames();
+        let mut decoder = RaptorqReceiver::try_init(&info).unwrap();
+        let (mut received, mut decoding) = (0u32, std::time::Duration::ZERO);
+        let restored = loop {
+            let (frame_id, frame) = encoder.next_frame();
+            if frame_id % LOSS_PERIOD == 0 {
+                continue;
+            }
+            received += 1;
+            let start = Instant::now();
+            let restored = decoder.update(frame_id, &frame);
+            decoding += start.elapsed();
+            if let Some(restored) = restored {
+                break restored;
+            }
+        };
+        assert_eq!(data, restored);
+
+        let config = raptorq::ObjectTransmissionInformation::deserialize(&info);
+        println!(
+            "{preset:?}: {} blocks of {} sub-blocks, init {:?}, decode {:?}, {received} frames for {source_frames} ({:.2}%)",
+            config.source_blocks(),
+            config.sub_blocks(),
+            encoded,
+            decoding,
+            (received as f64 / source_frames as f64 - 1.0) * 100.0,
+        );
+    }
+}
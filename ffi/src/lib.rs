@@ -0,0 +1,465 @@
+// C ABI over `usync::engine::transfer`, for programs embedding transfers.
+// cbindgen turns the `///` comments below into those of usync.h.
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char, c_void};
+use std::net::SocketAddr;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+
+use usync::prelude::{
+    CancelHandle, ContentKey, DownloadProgress, Downloader, FileConfig, Seeder, TransferError, init,
+};
+
+#[cfg(feature = "python")]
+mod python;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Outcome of a call.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsyncStatus {
+    Ok,
+    /// A null or malformed argument, or a transfer already run.
+    InvalidArgument,
+    Io,
+    /// The server stayed silent or busy for too long.
+    Unreachable,
+    Cancelled,
+    /// Some chunks failed on every attempt, the others were written.
+    Incomplete,
+}
+
+impl From<TransferError> for UsyncStatus {
+    fn from(err: TransferError) -> Self {
+        set_last_error(err.to_string());
+        match err {
+            TransferError::Io(_) | TransferError::Socket(_) => Self::Io,
+            TransferError::Unreachable(_) => Self::Unreachable,
+            TransferError::Cancelled => Self::Cancelled,
+        }
+    }
+}
+
+fn invalid(message: impl Into<String>) -> UsyncStatus {
+    set_last_error(message.into());
+    UsyncStatus::InvalidArgument
+}
+
+/// Progress of a download, counted in chunks of the plan.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct UsyncProgress {
+    pub total: usize,
+    pub written: usize,
+    pub failed: usize,
+    pub bytes_written: u64,
+}
+
+impl From<&DownloadProgress> for UsyncProgress {
+    fn from(progress: &DownloadProgress) -> Self {
+        Self {
+            total: progress.total,
+            written: progress.written,
+            failed: progress.failed,
+            bytes_written: progress.bytes_written,
+        }
+    }
+}
+
+/// Called on the thread running the download each time a chunk is written
+/// or fails. `progress` is only valid during the call.
+pub type UsyncProgressCallback =
+    Option<unsafe extern "C" fn(progress: *const UsyncProgress, user_data: *mut c_void)>;
+
+/// A download, run once.
+pub struct UsyncDownload {
+    downloader: Mutex<Option<Downloader>>,
+    cancel: CancelHandle,
+}
+
+/// A seeder of one file, run once. A process seeds one file.
+pub struct UsyncSeed {
+    seeder: Mutex<Option<Seeder>>,
+    cancel: CancelHandle,
+}
+
+unsafe fn read_str<'a>(string: *const c_char, name: &str) -> Result<&'a str, UsyncStatus> {
+    if string.is_null() {
+        return Err(invalid(format!("{name} is null")));
+    }
+    unsafe { CStr::from_ptr(string) }
+        .to_str()
+        .map_err(|_| invalid(format!("{name} is not valid UTF-8")))
+}
+
+unsafe fn read_strs(
+    strings: *const *const c_char,
+    count: usize,
+    name: &str,
+) -> Result<Vec<String>, UsyncStatus> {
+    if count == 0 {
+        return Ok(vec![]);
+    }
+    if strings.is_null() {
+        return Err(invalid(format!("{name} is null")));
+    }
+    unsafe { std::slice::from_raw_parts(strings, count) }
+        .iter()
+        .map(|string| unsafe { read_str(*string, name) }.map(str::to_string))
+        .collect()
+}
+
+fn load_plan(plan_path: &str) -> Result<FileConfig, UsyncStatus> {
+    let plan = std::fs::read_to_string(plan_path).map_err(|err| {
+        set_last_error(format!("{plan_path}: {err}"));
+        UsyncStatus::Io
+    })?;
+    toml::from_str(&plan).map_err(|err| invalid(format!("{plan_path}: {err}")))
+}
+
+fn parse_addr(addr: &str) -> Result<SocketAddr, UsyncStatus> {
+    addr.parse()
+        .map_err(|_| invalid(format!("{addr} is not an address and port")))
+}
+
+fn block_on<F: Future>(future: F) -> Result<F::Output, UsyncStatus> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(|err| {
+            set_last_error(format!("Failed to start a runtime: {err}"));
+            UsyncStatus::Io
+        })?;
+    Ok(runtime.block_on(future))
+}
+
+/// Message of the last failed call on this thread, null if none. Valid until
+/// the next call on this thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn usync_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Sets the hex server public keys accepted and the hex private keys tickets
+/// are signed with, once per process before any transfer.
+///
+/// # Safety
+/// `public_keys` and `private_keys` point to that many NUL-terminated strings,
+/// or are null with a count of 0.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn usync_init_keys(
+    public_keys: *const *const c_char,
+    public_count: usize,
+    private_keys: *const *const c_char,
+    private_count: usize,
+) -> UsyncStatus {
+    let keys = unsafe {
+        read_strs(public_keys, public_count, "public_keys").and_then(|public_keys| {
+            Ok((
+                public_keys,
+                read_strs(private_keys, private_count, "private_keys")?,
+            ))
+        })
+    };
+    let (public_keys, private_keys) = match keys {
+        Ok(keys) => keys,
+        Err(status) => return status,
+    };
+    // The key ring panics on malformed keys.
+    match catch_unwind(|| init(public_keys, private_keys)) {
+        Ok(()) => UsyncStatus::Ok,
+        Err(_) => invalid("Malformed key"),
+    }
+}
+
+/// A download of the file planned at `plan_path` from `server`, an address
+/// and port, to `destination`. Null if an argument is malformed.
+///
+/// # Safety
+/// The arguments are NUL-terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn usync_download_new(
+    plan_path: *const c_char,
+    server: *const c_char,
+    destination: *const c_char,
+) -> *mut UsyncDownload {
+    let download = || -> Result<UsyncDownload, UsyncStatus> {
+        let config = load_plan(unsafe { read_str(plan_path, "plan_path") }?)?;
+        let server = parse_addr(unsafe { read_str(server, "server") }?)?;
+        let destination = PathBuf::from(unsafe { read_str(destination, "destination") }?);
+        let downloader = Downloader::new(config, server, &destination);
+        Ok(UsyncDownload {
+            cancel: downloader.cancel_handle(),
+            downloader: Mutex::new(Some(downloader)),
+        })
+    };
+    match download() {
+        Ok(download) => Box::into_raw(Box::new(download)),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+unsafe fn update_download(
+    download: *const UsyncDownload,
+    update: impl FnOnce(Downloader) -> Downloader,
+) -> UsyncStatus {
+    let Some(download) = (unsafe { download.as_ref() }) else {
+        return invalid("download is null");
+    };
+    let mut downloader = download.downloader.lock().unwrap();
+    let Some(current) = downloader.take() else {
+        return invalid("The download has already run");
+    };
+    *downloader = Some(update(current));
+    UsyncStatus::Ok
+}
+
+/// Asks the server for this rate, in kbps.
+///
+/// # Safety
+/// `download` comes from `usync_download_new` and is not freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn usync_download_set_rate(
+    download: *const UsyncDownload,
+    rate_kbps: u32,
+) -> UsyncStatus {
+    unsafe {
+        update_download(download, |downloader| {
+            downloader.with_rate_kbps(rate_kbps.into())
+        })
+    }
+}
+
+/// The hex content key of a sealed file.
+///
+/// # Safety
+/// `download` comes from `usync_download_new` and is not freed, `key` is a
+/// NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn usync_download_set_content_key(
+    download: *const UsyncDownload,
+    key: *const c_char,
+) -> UsyncStatus {
+    let key = match unsafe { read_str(key, "key") } {
+        Ok(key) => key,
+        Err(status) => return status,
+    };
+    let Some(key) = ContentKey::from_hex(key) else {
+        return invalid("Not a content key");
+    };
+    unsafe {
+        update_download(download, |downloader| {
+            downloader.with_content_key(Arc::new(key))
+        })
+    }
+}
+
+/// Runs the download to its end, blocking the calling thread. `callback` may
+/// be null, `user_data` is passed to it as is.
+///
+/// # Safety
+/// `download` comes from `usync_download_new` and is not freed before this
+/// returns.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn usync_download_run(
+    download: *const UsyncDownload,
+    callback: UsyncProgressCallback,
+    user_data: *mut c_void,
+) -> UsyncStatus {
+    let Some(download) = (unsafe { download.as_ref() }) else {
+        return invalid("download is null");
+    };
+    let Some(downloader) = download.downloader.lock().unwrap().take() else {
+        return invalid("The download has already run");
+    };
+    let on_progress = |progress: &DownloadProgress| {
+        if let Some(callback) = callback {
+            let progress = UsyncProgress::from(progress);
+            unsafe { callback(&progress, user_data) };
+        }
+    };
+    let run = catch_unwind(AssertUnwindSafe(|| block_on(downloader.run(on_progress))));
+    match run {
+        Ok(Ok(Ok(report))) if report.failed.is_empty() => UsyncStatus::Ok,
+        Ok(Ok(Ok(report))) => {
+            set_last_error(format!("{} chunks failed", report.failed.len()));
+            UsyncStatus::Incomplete
+        }
+        Ok(Ok(Err(err))) => err.into(),
+        Ok(Err(status)) => status,
+        Err(_) => {
+            set_last_error("The download panicked".to_string());
+            UsyncStatus::Io
+        }
+    }
+}
+
+/// Stops the download, from any thread. `usync_download_run` then returns
+/// `USYNC_STATUS_CANCELLED`, at once if called later.
+///
+/// # Safety
+/// `download` comes from `usync_download_new` and is not freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn usync_download_cancel(download: *const UsyncDownload) {
+    if let Some(download) = unsafe { download.as_ref() } {
+        download.cancel.cancel();
+    }
+}
+
+/// # Safety
+/// `download` comes from `usync_download_new`, or is null. It is not running.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn usync_download_free(download: *mut UsyncDownload) {
+    if !download.is_null() {
+        drop(unsafe { Box::from_raw(download) });
+    }
+}
+
+/// A seeder of `file`, planned at `plan_path`, listening on `listening`, an
+/// address and port. Null if an argument is malformed.
+///
+/// # Safety
+/// The arguments are NUL-terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn usync_seed_new(
+    plan_path: *const c_char,
+    file: *const c_char,
+    listening: *const c_char,
+) -> *mut UsyncSeed {
+    let seed = || -> Result<UsyncSeed, UsyncStatus> {
+        let config = load_plan(unsafe { read_str(plan_path, "plan_path") }?)?;
+        let file = unsafe { read_str(file, "file") }?;
+        let listening = parse_addr(unsafe { read_str(listening, "listening") }?)?;
+        let seeder = Seeder::new(config, Path::new(file), listening);
+        Ok(UsyncSeed {
+            cancel: seeder.cancel_handle(),
+            seeder: Mutex::new(Some(seeder)),
+        })
+    };
+    match seed() {
+        Ok(seed) => Box::into_raw(Box::new(seed)),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Serves the file until cancelled, blocking the calling thread.
+///
+/// # Safety
+/// `seed` comes from `usync_seed_new` and is not freed before this returns.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn usync_seed_run(seed: *const UsyncSeed) -> UsyncStatus {
+    let Some(seed) = (unsafe { seed.as_ref() }) else {
+        return invalid("seed is null");
+    };
+    let Some(seeder) = seed.seeder.lock().unwrap().take() else {
+        return invalid("The seeder has already run");
+    };
+    match catch_unwind(AssertUnwindSafe(|| block_on(seeder.run()))) {
+        Ok(Ok(Ok(()))) => UsyncStatus::Ok,
+        Ok(Ok(Err(err))) => err.into(),
+        Ok(Err(status)) => status,
+        Err(_) => {
+            set_last_error("The seeder panicked".to_string());
+            UsyncStatus::Io
+        }
+    }
+}
+
+/// Stops the seeder, from any thread.
+///
+/// # Safety
+/// `seed` comes from `usync_seed_new` and is not freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn usync_seed_cancel(seed: *const UsyncSeed) {
+    if let Some(seed) = unsafe { seed.as_ref() } {
+        seed.cancel.cancel();
+    }
+}
+
+/// # Safety
+/// `seed` comes from `usync_seed_new`, or is null. It is not running.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn usync_seed_free(seed: *mut UsyncSeed) {
+    if !seed.is_null() {
+        drop(unsafe { Box::from_raw(seed) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_arguments_are_refused() {
+        let server = c"127.0.0.1:7234";
+        let destination = c"/tmp/usync-ffi-test";
+        let download = unsafe {
+            usync_download_new(
+                c"/nonexistent/plan.toml".as_ptr(),
+                server.as_ptr(),
+                destination.as_ptr(),
+            )
+        };
+        assert!(download.is_null());
+        assert!(!usync_last_error().is_null());
+
+        let plan = tempfile_plan();
+        let download = unsafe {
+            usync_download_new(
+                plan.as_ptr(),
+                c"not an address".as_ptr(),
+                destination.as_ptr(),
+            )
+        };
+        assert!(download.is_null());
+
+        let download =
+            unsafe { usync_download_new(plan.as_ptr(), server.as_ptr(), destination.as_ptr()) };
+        assert!(!download.is_null());
+        unsafe {
+            assert_eq!(
+                usync_download_set_content_key(download, c"beef".as_ptr()),
+                UsyncStatus::InvalidArgument
+            );
+            assert_eq!(usync_download_set_rate(download, 1024), UsyncStatus::Ok);
+            usync_download_cancel(download);
+            assert_eq!(
+                usync_download_run(download, None, ptr::null_mut()),
+                UsyncStatus::Cancelled
+            );
+            assert_eq!(
+                usync_download_run(download, None, ptr::null_mut()),
+                UsyncStatus::InvalidArgument
+            );
+            usync_download_free(download);
+            assert_eq!(
+                usync_download_run(ptr::null(), None, ptr::null_mut()),
+                UsyncStatus::InvalidArgument
+            );
+        }
+    }
+
+    fn tempfile_plan() -> CString {
+        let path = std::env::temp_dir().join(format!("usync-ffi-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "file_name = \"empty\"\ntotal_length = 0\ntotal_hash = \"\"\nchunks = []\n",
+        )
+        .unwrap();
+        CString::new(path.to_str().unwrap()).unwrap()
+    }
+}
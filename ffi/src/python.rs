@@ -0,0 +1,183 @@
+// The `usync` Python module, built with `maturin build --features python`.
+// Transfers run on the tokio runtime of pyo3-async-runtimes and are awaited
+// from asyncio, cancelling the task cancels the transfer.
+use pyo3::exceptions::asyncio::CancelledError;
+use pyo3::exceptions::{PyConnectionError, PyOSError, PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use usync::prelude::{
+    CancelHandle, ContentKey, DownloadProgress, Downloader, FileConfig, PlanBuilder, Seeder,
+    TransferError, init, plan_file,
+};
+
+fn transfer_error(err: TransferError) -> PyErr {
+    match err {
+        TransferError::Io(err) => PyOSError::new_err(err.to_string()),
+        TransferError::Socket(err) => PyOSError::new_err(err.to_string()),
+        TransferError::Unreachable(err) => PyConnectionError::new_err(err.to_string()),
+        TransferError::Cancelled => CancelledError::new_err("cancelled"),
+    }
+}
+
+fn parse_plan(plan: &str) -> PyResult<FileConfig> {
+    toml::from_str(plan).map_err(|err| PyValueError::new_err(err.to_string()))
+}
+
+fn parse_addr(addr: &str) -> PyResult<SocketAddr> {
+    addr.parse()
+        .map_err(|_| PyValueError::new_err(format!("{addr} is not an address and port")))
+}
+
+// Taken out by the first `run`.
+fn take<T>(slot: &Mutex<Option<T>>) -> PyResult<T> {
+    slot.lock()
+        .unwrap()
+        .take()
+        .ok_or_else(|| PyRuntimeError::new_err("already run"))
+}
+
+/// Plans the file at `path` in chunks of about `chunk_size` bytes, returned as
+/// the TOML the server and client read.
+#[pyfunction]
+#[pyo3(signature = (path, chunk_size = 32 * 1024 * 1024))]
+fn plan(py: Python<'_>, path: &str, chunk_size: usize) -> PyResult<String> {
+    let builder = PlanBuilder::default()
+        .with_chunk_size(chunk_size)
+        .with_min_tail(chunk_size);
+    let config = py
+        .allow_threads(|| plan_file(Path::new(path), &builder))
+        .map_err(|err| PyOSError::new_err(err.to_string()))?;
+    toml::to_string_pretty(&config).map_err(|err| PyValueError::new_err(err.to_string()))
+}
+
+/// Sets the hex server public keys accepted and the hex private keys tickets
+/// are signed with, once per process before any transfer.
+#[pyfunction]
+#[pyo3(signature = (public_keys = vec![], private_keys = vec![]))]
+fn init_keys(public_keys: Vec<String>, private_keys: Vec<String>) -> PyResult<()> {
+    // The key ring panics on malformed keys.
+    std::panic::catch_unwind(|| init(public_keys, private_keys))
+        .map_err(|_| PyValueError::new_err("malformed key"))
+}
+
+/// A download of the file of `plan`, given as TOML, from `server` to
+/// `destination`. Chunks already there with their planned hash are kept.
+#[pyclass(module = "usync")]
+struct Download {
+    downloader: Mutex<Option<Downloader>>,
+    cancel: CancelHandle,
+}
+
+#[pymethods]
+impl Download {
+    #[new]
+    #[pyo3(signature = (plan, server, destination, rate_kbps = None, content_key = None))]
+    fn new(
+        plan: &str,
+        server: &str,
+        destination: &str,
+        rate_kbps: Option<u64>,
+        content_key: Option<&str>,
+    ) -> PyResult<Self> {
+        let mut downloader = Downloader::new(
+            parse_plan(plan)?,
+            parse_addr(server)?,
+            Path::new(destination),
+        );
+        if let Some(rate_kbps) = rate_kbps {
+            downloader = downloader.with_rate_kbps(rate_kbps);
+        }
+        if let Some(key) = content_key {
+            let key = ContentKey::from_hex(key)
+                .ok_or_else(|| PyValueError::new_err("not a content key"))?;
+            downloader = downloader.with_content_key(Arc::new(key));
+        }
+        Ok(Self {
+            cancel: downloader.cancel_handle(),
+            downloader: Mutex::new(Some(downloader)),
+        })
+    }
+
+    /// Awaits the end of the download, with the ids of the chunks written.
+    /// `progress(written, failed, total, bytes_written)` is called each time a
+    /// chunk is written or fails. Raises if any chunk failed.
+    #[pyo3(signature = (progress = None))]
+    fn run<'py>(&self, py: Python<'py>, progress: Option<PyObject>) -> PyResult<Bound<'py, PyAny>> {
+        let downloader = take(&self.downloader)?;
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let on_progress = |update: &DownloadProgress| {
+                if let Some(progress) = progress.as_ref() {
+                    Python::with_gil(|py| {
+                        let args = (
+                            update.written,
+                            update.failed,
+                            update.total,
+                            update.bytes_written,
+                        );
+                        if let Err(err) = progress.call1(py, args) {
+                            err.print(py);
+                        }
+                    });
+                }
+            };
+            let report = downloader.run(on_progress).await.map_err(transfer_error)?;
+            match report.failed.is_empty() {
+                true => Ok(report.written),
+                false => Err(PyRuntimeError::new_err(format!(
+                    "{} chunks failed",
+                    report.failed.len()
+                ))),
+            }
+        })
+    }
+
+    /// Stops the download, from any thread.
+    fn cancel(&self) {
+        self.cancel.cancel();
+    }
+}
+
+/// A seeder of `file`, planned by `plan` given as TOML, listening on
+/// `listening`. A process seeds one file.
+#[pyclass(module = "usync")]
+struct Seed {
+    seeder: Mutex<Option<Seeder>>,
+    cancel: CancelHandle,
+}
+
+#[pymethods]
+impl Seed {
+    #[new]
+    fn new(plan: &str, file: &str, listening: &str) -> PyResult<Self> {
+        let seeder = Seeder::new(parse_plan(plan)?, Path::new(file), parse_addr(listening)?);
+        Ok(Self {
+            cancel: seeder.cancel_handle(),
+            seeder: Mutex::new(Some(seeder)),
+        })
+    }
+
+    /// Serves the file until cancelled.
+    fn run<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let seeder = take(&self.seeder)?;
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            seeder.run().await.map_err(transfer_error)
+        })
+    }
+
+    /// Stops the seeder, from any thread.
+    fn cancel(&self) {
+        self.cancel.cancel();
+    }
+}
+
+#[pymodule]
+fn usync(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(plan, module)?)?;
+    module.add_function(wrap_pyfunction!(init_keys, module)?)?;
+    module.add_class::<Download>()?;
+    module.add_class::<Seed>()?;
+    Ok(())
+}
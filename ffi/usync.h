@@ -0,0 +1,125 @@
+#ifndef USYNC_H
+#define USYNC_H
+
+/* Generated by cbindgen from ffi/src/lib.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Outcome of a call.
+typedef enum UsyncStatus {
+  USYNC_STATUS_OK,
+  // A null or malformed argument, or a transfer already run.
+  USYNC_STATUS_INVALID_ARGUMENT,
+  USYNC_STATUS_IO,
+  // The server stayed silent or busy for too long.
+  USYNC_STATUS_UNREACHABLE,
+  USYNC_STATUS_CANCELLED,
+  // Some chunks failed on every attempt, the others were written.
+  USYNC_STATUS_INCOMPLETE,
+} UsyncStatus;
+
+// A download, run once.
+typedef struct UsyncDownload UsyncDownload;
+
+// A seeder of one file, run once. A process seeds one file.
+typedef struct UsyncSeed UsyncSeed;
+
+// Progress of a download, counted in chunks of the plan.
+typedef struct UsyncProgress {
+  size_t total;
+  size_t written;
+  size_t failed;
+  uint64_t bytes_written;
+} UsyncProgress;
+
+// Called on the thread running the download each time a chunk is written
+// or fails. `progress` is only valid during the call.
+typedef void (*UsyncProgressCallback)(const struct UsyncProgress *progress, void *user_data);
+
+// Message of the last failed call on this thread, null if none. Valid until
+// the next call on this thread.
+const char *usync_last_error(void);
+
+// Sets the hex server public keys accepted and the hex private keys tickets
+// are signed with, once per process before any transfer.
+//
+// # Safety
+// `public_keys` and `private_keys` point to that many NUL-terminated strings,
+// or are null with a count of 0.
+enum UsyncStatus usync_init_keys(const char *const *public_keys,
+                                 size_t public_count,
+                                 const char *const *private_keys,
+                                 size_t private_count);
+
+// A download of the file planned at `plan_path` from `server`, an address
+// and port, to `destination`. Null if an argument is malformed.
+//
+// # Safety
+// The arguments are NUL-terminated strings.
+struct UsyncDownload *usync_download_new(const char *plan_path,
+                                         const char *server,
+                                         const char *destination);
+
+// Asks the server for this rate, in kbps.
+//
+// # Safety
+// `download` comes from `usync_download_new` and is not freed.
+enum UsyncStatus usync_download_set_rate(const struct UsyncDownload *download, uint32_t rate_kbps);
+
+// The hex content key of a sealed file.
+//
+// # Safety
+// `download` comes from `usync_download_new` and is not freed, `key` is a
+// NUL-terminated string.
+enum UsyncStatus usync_download_set_content_key(const struct UsyncDownload *download,
+                                                const char *key);
+
+// Runs the download to its end, blocking the calling thread. `callback` may
+// be null, `user_data` is passed to it as is.
+//
+// # Safety
+// `download` comes from `usync_download_new` and is not freed before this
+// returns.
+enum UsyncStatus usync_download_run(const struct UsyncDownload *download,
+                                    UsyncProgressCallback callback,
+                                    void *user_data);
+
+// Stops the download, from any thread. `usync_download_run` then returns
+// `USYNC_STATUS_CANCELLED`, at once if called later.
+//
+// # Safety
+// `download` comes from `usync_download_new` and is not freed.
+void usync_download_cancel(const struct UsyncDownload *download);
+
+// # Safety
+// `download` comes from `usync_download_new`, or is null. It is not running.
+void usync_download_free(struct UsyncDownload *download);
+
+// A seeder of `file`, planned at `plan_path`, listening on `listening`, an
+// address and port. Null if an argument is malformed.
+//
+// # Safety
+// The arguments are NUL-terminated strings.
+struct UsyncSeed *usync_seed_new(const char *plan_path, const char *file, const char *listening);
+
+// Serves the file until cancelled, blocking the calling thread.
+//
+// # Safety
+// `seed` comes from `usync_seed_new` and is not freed before this returns.
+enum UsyncStatus usync_seed_run(const struct UsyncSeed *seed);
+
+// Stops the seeder, from any thread.
+//
+// # Safety
+// `seed` comes from `usync_seed_new` and is not freed.
+void usync_seed_cancel(const struct UsyncSeed *seed);
+
+// # Safety
+// `seed` comes from `usync_seed_new`, or is null. It is not running.
+void usync_seed_free(struct UsyncSeed *seed);
+
+#endif  /* USYNC_H */
@@ -1,116 +1,888 @@
-use anyhow::anyhow;
+use anyhow::{Context, anyhow};
 use clap::Parser;
 use directories::UserDirs;
 use humansize::{BINARY, format_size};
 use owo_colors::OwoColorize;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::io::Write;
+use std::ops::{Range, RangeInclusive};
 use std::str::FromStr;
-use std::sync::{Arc, atomic::AtomicUsize};
-use std::{fs, net::SocketAddr, path::PathBuf};
-use tokio::sync::Semaphore;
-use tokio::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::{
+    ffi::OsString,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::time::{Duration, Instant, interval};
 use usync::constants::TRANSMISSION_INFO_LENGTH;
-use usync::engine::{Bus, BusAddress, BusMessage, decoding, receiving};
-use usync::protocol::{coding::raptorq_code::RaptorqReceiver, init};
-use usync::transmission::real::RealUdpSocket;
+use usync::engine::{
+    Bus, BusAddress, BusMessage, PLAN_CHUNK_ID,
+    control::{
+        ControlSocket, ControlState, DEFAULT_CONTROL_SOCKET, handle_pause_signals, read_rate_file,
+    },
+    decoding::{self, DecodeStats},
+    download::{ChunkVerifier, DownloadManager, DownloadReport, PlanHashVerifier, open_chunk},
+    gateway::{FILE_PATH, PLAN_PATH, http_get},
+    receiving::{self, DEFAULT_KEEPALIVE, DEFAULT_RATE_KBPS},
+    sending::SendingSocket,
+    stats::SenderStats,
+};
+use usync::protocol::token::{DownloadToken, file_id};
+use usync::protocol::wire::encoding::{ParseMode, set_parse_mode};
+use usync::protocol::wire::frames::{GrantFrame, Priority};
+use usync::protocol::{
+    KEY_RING,
+    coding::raptorq_code::{RaptorqReceiver, RaptorqSender},
+    init,
+};
+use usync::transmission::multipath::PathManager;
+use usync::transmission::{
+    DynSocket, Transport,
+    real::{RealUdpSocket, parse_port_range, parse_traffic_class},
+    tcp::TcpDatagramSocket,
+};
 use usync::util::{
-    file::{check_file_exist_create, mmap_segment, write_at},
-    log::init as init_log,
-    plan::{FileChunk, FileConfig},
+    cpu,
+    exit::{Failure, exit_code, tag},
+    file::{
+        CHUNK_INDEX, ChunkIndex, apply_metadata, available_space, check_file_exist,
+        check_file_exist_create, chunk_hash, is_rotational, restore_symlink, write_at,
+    },
+    i18n,
+    log::{current_timestamp_ms, init as init_log},
+    plan::{FileChunk, FileConfig, parse_byte_range, parse_chunk_ids, parse_size},
+    seal::{self, ContentKey},
+    summary::{ChunkSummary, TransferSummary},
+    units::ChunkId,
+    uri::PlanUri,
+    verified::VerificationCache,
 };
-use zerocopy::IntoBytes;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about = "Client for receiving file", long_about = None)]
 struct Args {
     /// The path to the plan file (TOML format).
-    #[arg(short, long, value_name = "PLAN_FILE")]
-    plan_file: PathBuf,
+    #[arg(short, long, value_name = "PLAN_FILE", required_unless_present = "uri")]
+    plan_file: Option<PathBuf>,
 
-    /// Socket Addr of Server
+    /// A `usync://host:port/<plan hash>?key=...` URI to fetch the plan from the server instead.
+    /// Its key, a download token or a private key, is used unless one is given.
+    #[arg(long, value_name = "URI", conflicts_with_all = ["plan_file", "follow", "upload"])]
+    uri: Option<String>,
+
+    /// Socket Addr of Server, the first one the plan suggests if not given.
     #[arg(short, long, value_name = "SERVER")]
-    server: SocketAddr,
+    server: Option<SocketAddr>,
+
+    /// Private Key, or a directory of key files. May be given several times;
+    /// the first identity the server accepts is used.
+    #[arg(short, long, value_name = "PRI_KEY", required_unless_present_any = ["token", "uri"])]
+    private_key: Vec<String>,
 
-    /// Private Key
-    #[arg(short, long, value_name = "PRI_KEY")]
-    private_key: String,
+    /// A download token from the server's owner, used instead of a private key.
+    #[arg(long, value_name = "TOKEN", conflicts_with_all = ["private_key", "rotate_identity"])]
+    token: Option<String>,
+
+    /// Start from a random identity instead of the first, so transfers are not linkable by key.
+    #[arg(long)]
+    rotate_identity: bool,
+
+    /// Push the file at --downloading-file (or in your download folder) to the server instead,
+    /// which has to run with --accept-upload and asks for the chunks it lacks.
+    #[arg(long, requires = "server_key", conflicts_with_all = ["token", "follow", "multipath"])]
+    upload: bool,
+
+    /// Hex public key of the server, which signs the tickets asking for the chunks of an upload.
+    #[arg(long, value_name = "PUB_KEY", requires = "upload")]
+    server_key: Option<String>,
+
+    /// File holding the hex content key the planner sealed the file with, needed for plans
+    /// of sealed files.
+    #[arg(long, value_name = "KEY_FILE")]
+    content_key: Option<PathBuf>,
+
+    /// Base URL of the server's HTTP gateway, e.g. `https://files.example.com:8443`. The plan of
+    /// a --uri and the chunks left are fetched from it when the server cannot be reached over UDP.
+    #[arg(long, value_name = "URL", conflicts_with = "follow")]
+    http_fallback: Option<String>,
 
     /// The path to the downloading file (optional, in your download folder as default).
     #[arg(short, long, value_name = "DOWNLOADING_FILE")]
     downloading_file: Option<PathBuf>,
-}
 
-fn check_chunks<'b>(path: &PathBuf, config: &'b FileConfig) -> Vec<&'b FileChunk> {
-    let mut result = vec![];
-    for chunk in config.chunks.iter() {
-        result.push(chunk);
+    /// Stop downloading once free space at the destination drops below this many MiB.
+    #[arg(long, value_name = "MIB", default_value_t = 64)]
+    min_free: u64,
 
-        print!(
-            ">>> Checking chunk {:04}: ...",
-            chunk.chunk_id.bright_blue()
-        );
+    /// Local address to bind, with an optional port.
+    #[arg(short, long, value_name = "ADDR", default_value = "0.0.0.0", value_parser = parse_bind_addr)]
+    bind: SocketAddr,
 
-        let hash = match mmap_segment(path, chunk.offset, chunk.length) {
-            Ok(chunk_data) => hex::encode(blake3::hash(chunk_data.as_bytes()).as_bytes()),
-            Err(err) => {
-                println!("\x1b[3D {}: {err:#}", "Failed to read".yellow());
-                continue;
-            }
-        };
+    /// Network interface to send and receive through, e.g. eth1.
+    #[arg(long, value_name = "INTERFACE", conflicts_with = "multipath")]
+    interface: Option<String>,
 
-        if hash.as_str() != chunk.hash {
-            println!(
-                "\x1b[3D {}. Expected {}, actual {}",
-                "Hash check failed".red(),
-                chunk.hash.yellow(),
-                hash.yellow()
-            );
+    /// Fetch over one socket per interface, e.g. eth0,wlan0. Each chunk goes over one of them,
+    /// chunks move off an interface that goes quiet or loses far more than the others.
+    #[arg(long, value_name = "INTERFACES", value_delimiter = ',')]
+    multipath: Vec<String>,
+
+    /// Local ports to try in turn instead of an OS-picked one, e.g. 7000-7100.
+    #[arg(long, value_name = "START-END", value_parser = parse_port_range)]
+    port_range: Option<RangeInclusive<u16>>,
+
+    /// Receive on this many sockets sharing the local port, each read on a core of its own.
+    /// Packets of a chunk always arrive on the same one (Linux).
+    #[arg(long, value_name = "SOCKETS", default_value_t = 1)]
+    receive_shards: usize,
+
+    /// Carry packets over UDP, or over TCP where UDP is blocked. Has to match the server's.
+    /// UDP if neither given nor suggested by the plan.
+    #[arg(long, value_enum, conflicts_with_all = ["interface", "multipath"])]
+    transport: Option<Transport>,
+
+    /// `lenient` lets packets off the spec in harmless ways through, counting them in `status`
+    /// on the control socket. For debugging against other implementations.
+    #[arg(long, value_enum, default_value_t = ParseMode::Strict)]
+    parse_mode: ParseMode,
+
+    /// Account the CPU time of encoders, decoders and socket tasks, shown in `status` on the
+    /// control socket. Costs a syscall around every poll of those tasks.
+    #[arg(long)]
+    cpu_accounting: bool,
+
+    /// Traffic class (TOS byte) of outgoing packets, a number such as 0x28 or a DSCP name such
+    /// as ef, af41 or cs1. The ECN bits are left alone.
+    #[arg(long, value_name = "CLASS", value_parser = parse_traffic_class)]
+    traffic_class: Option<u8>,
+
+    /// Also write the transfer summary printed on exit to this file, as JSON.
+    #[arg(long, value_name = "JSON_FILE")]
+    summary: Option<PathBuf>,
+
+    /// Download only these chunks, e.g. `3,10-20`. Combines with --range.
+    #[arg(long, value_name = "IDS", value_delimiter = ',', value_parser = parse_chunk_ids)]
+    chunks: Vec<RangeInclusive<ChunkId>>,
+
+    /// Download only the chunks holding bytes START to END (exclusive) of the file, e.g. `1G-2G`
+    /// or `100M-`. May be given several times.
+    #[arg(long, value_name = "START-END", value_parser = parse_byte_range)]
+    range: Vec<Range<u64>>,
+
+    /// Hash every chunk already at the destination, even those the verification cache next to
+    /// it vouches for.
+    #[arg(long)]
+    force_verify: bool,
+
+    /// Hash chunks already at the destination on this many threads. One per core by default,
+    /// two if the destination is on a spinning disk.
+    #[arg(long, value_name = "THREADS")]
+    verify_threads: Option<usize>,
+
+    /// Download chunks failing their hash check again up to this many times.
+    #[arg(long, value_name = "RETRIES", default_value_t = 2)]
+    retries: usize,
+
+    /// Keep chunks that decode but fail their hash check in <DOWNLOADING_FILE>.bad, with
+    /// their transmission info and frame stats, to tell disk, decoder and server corruption apart.
+    #[arg(long)]
+    keep_damaged: bool,
+
+    /// Flag encoders and decoders idle for this many seconds in the debug output.
+    #[arg(long, value_name = "SECS", default_value_t = 120)]
+    leak_after: u64,
+
+    /// Also unregister the flagged encoders and decoders, ending their tasks.
+    #[arg(long)]
+    reap_leaks: bool,
+
+    /// Rate to ask the server for, in kbps. The plan's suggestion if not given, 40960 without one.
+    #[arg(long, value_name = "KBPS")]
+    rate: Option<u64>,
+
+    /// File holding a rate in kbps that overrides --rate, read at start and again on SIGUSR1.
+    /// Empty or 0 goes back to --rate.
+    #[arg(long, value_name = "RATE_FILE")]
+    rate_file: Option<PathBuf>,
+
+    /// Priority of the download. Servers sharing their link by priority give urgent transfers the
+    /// larger share, and background ones the smaller.
+    #[arg(long, value_enum, default_value_t = Priority::Normal)]
+    priority: Priority,
+
+    /// Keep the download in sync: after finishing, ask the server for its plan every SECS seconds
+    /// (2 if not given) and fetch the chunks changed in every epoch a watching server publishes.
+    #[arg(long, value_name = "SECS", num_args = 0..=1, default_missing_value = "2")]
+    follow: Option<u64>,
+
+    /// Leave the permission bits of the downloaded file alone instead of copying the source's.
+    #[arg(long)]
+    no_perms: bool,
+
+    /// Leave the modification time of the downloaded file alone instead of copying the source's.
+    #[arg(long)]
+    no_times: bool,
+
+    /// Give up once the server has been busy or silent for this many seconds.
+    #[arg(long, value_name = "SECS", default_value_t = 300)]
+    max_wait: u64,
+
+    /// Send the server a tiny keepalive packet after this many seconds without a ticket, so NAT
+    /// bindings survive pauses in the transfer. 0 sends none.
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_KEEPALIVE.as_secs())]
+    keepalive: u64,
+
+    /// Start no more chunks once about SIZE bytes (K, M, G or T suffixed) were received this
+    /// run. Chunks already on the way are finished, rerun to fetch the rest.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_bytes: Option<u64>,
+
+    /// Start no more chunks after running this many seconds. Chunks already on the way are
+    /// finished, rerun to fetch the rest.
+    #[arg(long, value_name = "SECS")]
+    max_duration: Option<u64>,
+
+    /// Serve `status`, `peers`, `chunks`, `timings`, `set-rate`, `pause` and `resume` on a Unix socket, /run/usync.sock if no path is given.
+    #[arg(long, value_name = "SOCKET", num_args = 0..=1, default_missing_value = DEFAULT_CONTROL_SOCKET)]
+    control: Option<PathBuf>,
+
+    /// Pause the transfer on SIGTSTP (Ctrl-Z) instead of stopping the process, and resume
+    /// it on SIGCONT.
+    #[arg(long)]
+    pause_on_suspend: bool,
+}
+
+// Every file of a key directory holds one hex private key.
+fn load_private_keys(keys: &[String]) -> anyhow::Result<Vec<String>> {
+    let mut private_keys = vec![];
+    for key in keys {
+        let path = PathBuf::from(key);
+        if !path.is_dir() {
+            private_keys.push(key.clone());
             continue;
         }
-        println!("\x1b[3D {}", "OK".green());
-        result.pop();
+        let mut files = fs::read_dir(&path)
+            .and_then(|entries| {
+                entries
+                    .map(|entry| entry.map(|entry| entry.path()))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .context(Failure::Disk)?;
+        files.sort();
+        for file in files.iter().filter(|file| file.is_file()) {
+            private_keys.push(
+                fs::read_to_string(file)
+                    .context(Failure::Disk)?
+                    .trim()
+                    .to_string(),
+            );
+        }
     }
-    result
+    if private_keys.is_empty() {
+        return Err(anyhow!("No private key found.")).context(Failure::Auth);
+    }
+    Ok(private_keys)
 }
 
+// Accepts a bare IP as well, binding an OS-picked port.
+fn parse_bind_addr(addr: &str) -> Result<SocketAddr, String> {
+    SocketAddr::from_str(addr)
+        .or_else(|_| IpAddr::from_str(addr).map(|ip| SocketAddr::new(ip, 0)))
+        .map_err(|err| format!("{err}"))
+}
+
+// Chunks the cache vouches for are not hashed again, those that pass are added
+// to it. The rest are hashed on `threads` threads, sealed first with
+// `content_key` if given.
+fn check_chunks<'b>(
+    path: &PathBuf,
+    chunks: &[&'b FileChunk],
+    content_key: Option<&ContentKey>,
+    verified: &mut VerificationCache,
+    threads: usize,
+) -> anyhow::Result<Vec<&'b FileChunk>> {
+    let (cached, unchecked): (Vec<&FileChunk>, Vec<&FileChunk>) =
+        chunks.iter().partition(|chunk| verified.is_verified(chunk));
+    if !cached.is_empty() {
+        println!("{}", i18n::chunks_unchanged(cached.len().green()));
+    }
+    if unchecked.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()?;
+    let checked = AtomicUsize::new(0);
+    // About a thousand progress updates however many chunks there are.
+    let step = (unchecked.len() / 1000).max(1);
+    let passed: Vec<bool> = pool.install(|| {
+        unchecked
+            .par_iter()
+            .map(|chunk| {
+                let hash = match content_key {
+                    Some(content_key) => content_key.sealed_hash(path, chunk),
+                    None => chunk_hash(path, chunk.offset, chunk.length),
+                };
+                let passed = match hash {
+                    Ok(hash) if hash == chunk.hash => true,
+                    Ok(hash) => {
+                        println!(
+                            "\r\x1b[K>>> Chunk {:04}: {}. Expected {}, actual {}",
+                            chunk.chunk_id.bright_blue(),
+                            "Hash check failed".red(),
+                            chunk.hash.yellow(),
+                            hash.yellow()
+                        );
+                        false
+                    }
+                    Err(err) => {
+                        println!(
+                            "\r\x1b[K>>> Chunk {:04}: {}: {err:#}",
+                            chunk.chunk_id.bright_blue(),
+                            "Failed to read".yellow()
+                        );
+                        false
+                    }
+                };
+                let done = checked.fetch_add(1, Ordering::Relaxed) + 1;
+                if done.is_multiple_of(step) || done == unchecked.len() {
+                    print!("{}\r", i18n::chunks_checked(done, unchecked.len()));
+                    std::io::stdout().flush().ok();
+                }
+                passed
+            })
+            .collect()
+    });
+    println!();
+
+    let mut result = vec![];
+    for (chunk, passed) in unchecked.into_iter().zip(passed) {
+        match passed {
+            true => verified.record(chunk),
+            false => result.push(chunk),
+        }
+    }
+    println!(
+        "{}",
+        i18n::chunks_passed((chunks.len() - cached.len() - result.len()).green())
+    );
+    Ok(result)
+}
+
+// One thread per core on solid state, few enough that a spinning disk does
+// not seek between them all the time otherwise.
+fn verify_threads(args: &Args, path: &PathBuf) -> usize {
+    if let Some(threads) = args.verify_threads {
+        return threads.max(1);
+    }
+    match is_rotational(path) {
+        Some(true) => 2,
+        _ => std::thread::available_parallelism().map_or(1, usize::from),
+    }
+}
+
+// Only the `selected` chunks are checked and downloaded, at their planned offsets.
 fn check_file<'a>(
     downloading_file: &PathBuf,
     config: &'a FileConfig,
+    selected: &[&'a FileChunk],
+    content_key: Option<&ContentKey>,
+    verified: &mut VerificationCache,
+    threads: usize,
 ) -> anyhow::Result<Vec<&'a FileChunk>> {
     println!(
-        "{} chunks in total for file {}.",
-        config.chunks.len(),
-        downloading_file.display()
+        "{}",
+        i18n::chunks_in_file(config.chunks.len(), downloading_file.display())
     );
+    let selected_size: u64 = selected.iter().map(|chunk| chunk.length as u64).sum();
+    if selected.len() < config.chunks.len() {
+        println!("{}", i18n::chunks_selected(selected.len().yellow()));
+    }
 
-    let need_to_download = check_chunks(downloading_file, config);
+    let need_to_download =
+        check_chunks(downloading_file, selected, content_key, verified, threads)?;
     let download_size: usize = need_to_download.iter().map(|chunk| chunk.length).sum();
 
     let print_config = BINARY.decimal_places(3).decimal_zeroes(3);
     println!(
-        "Need to download {} / {} chunks which sized {} / {}.",
-        need_to_download.len().yellow(),
-        config.chunks.len().blue(),
-        format_size(download_size, print_config).yellow(),
-        format_size(config.total_length, print_config).blue(),
+        "{}",
+        i18n::need_to_download(
+            need_to_download.len().yellow(),
+            selected.len().blue(),
+            format_size(download_size, print_config).yellow(),
+            format_size(selected_size, print_config).blue(),
+        )
     );
     Ok(need_to_download)
 }
+
+// Missing chunks are holes in the sparse file, each one still needs its full length.
+fn check_space(
+    downloading_file: &PathBuf,
+    need_to_download: &[&FileChunk],
+    min_free: u64,
+) -> anyhow::Result<()> {
+    let required = need_to_download
+        .iter()
+        .map(|chunk| chunk.length as u64)
+        .sum::<u64>()
+        + min_free;
+    let available = available_space(downloading_file)?;
+    if available < required {
+        let print_config = BINARY.decimal_places(3).decimal_zeroes(3);
+        return Err(anyhow!(
+            "Not enough space at {}: {} available, {} required.",
+            downloading_file.display(),
+            format_size(available, print_config),
+            format_size(required, print_config)
+        ));
+    }
+    Ok(())
+}
+
+// Returns the chunks still to be downloaded, and whether they are of the whole file.
+fn chunks_to_download(
+    args: &Args,
+    downloading_file: &PathBuf,
+    config: &FileConfig,
+    content_key: Option<&ContentKey>,
+    min_free: u64,
+    verified: &mut VerificationCache,
+) -> anyhow::Result<(Vec<FileChunk>, bool)> {
+    let selected = if args.chunks.is_empty() && args.range.is_empty() {
+        config.chunks.iter().collect()
+    } else {
+        config
+            .select(&args.chunks, &args.range)
+            .map_err(|err| anyhow!(err))?
+    };
+    let need_to_download = check_file(
+        downloading_file,
+        config,
+        &selected,
+        content_key,
+        verified,
+        verify_threads(args, downloading_file),
+    )?;
+    check_space(downloading_file, &need_to_download, min_free).context(Failure::Disk)?;
+    Ok((
+        need_to_download.into_iter().cloned().collect(),
+        selected.len() == config.chunks.len(),
+    ))
+}
+
+// The sockets of one path, several with --receive-shards. The first one sends.
+async fn bind_path(
+    args: &Args,
+    bind_addr: SocketAddr,
+    interface: Option<&str>,
+) -> anyhow::Result<Vec<DynSocket>> {
+    if args.transport == Some(Transport::Tcp) {
+        let socket = TcpDatagramSocket::bind(bind_addr).await?;
+        println!("{}", i18n::bound_over_tcp(socket.local_addr()?.green()));
+        return Ok(vec![Box::new(socket)]);
+    }
+    let port_range = args.port_range.clone();
+    let mut sockets = match args.receive_shards {
+        0 | 1 => vec![],
+        shards => RealUdpSocket::bind_sharded(bind_addr, interface, port_range.clone(), shards)
+            .await
+            .inspect_err(|err| {
+                eprintln!("Failed to receive on {shards} sockets, using one: {err}");
+            })
+            .unwrap_or_default(),
+    };
+    if sockets.is_empty() {
+        sockets.push(RealUdpSocket::bind_with_fallback(bind_addr, interface, port_range).await?);
+    }
+    let local_addr = sockets[0].local_addr()?;
+    match interface {
+        Some(interface) => println!("{}", i18n::bound_on(local_addr.green(), interface)),
+        None => println!("{}", i18n::bound(local_addr.green())),
+    }
+    if sockets.len() > 1 {
+        println!("{}", i18n::receiving_on_sockets(sockets.len()));
+    }
+    let socket = &mut sockets[0];
+    if let Some(class) = args.traffic_class
+        && let Err(err) = socket.set_traffic_class(class)
+    {
+        eprintln!("Failed to set the traffic class: {err}");
+    }
+    if local_addr.is_ipv6()
+        && let Err(err) = socket.enable_flow_labels()
+    {
+        eprintln!("Flow labels unavailable, leaving them to the kernel: {err}");
+    }
+    for socket in sockets.iter() {
+        if let Err(err) = socket.enable_ecn() {
+            eprintln!("ECN unavailable, congestion is only detected by loss: {err}");
+            break;
+        }
+    }
+    Ok(sockets
+        .into_iter()
+        .map(|socket| Box::new(socket) as DynSocket)
+        .collect())
+}
+
+// Asks the server for its plan every period, and reads the plan file in case
+// it is shared, until either is of a later epoch than `config`.
+async fn wait_for_epoch(
+    bus: &Arc<Bus<BusAddress, BusMessage<TRANSMISSION_INFO_LENGTH>>>,
+    plan_file: &PathBuf,
+    config: &FileConfig,
+    period: Duration,
+) -> FileConfig {
+    let mut ticker = interval(period);
+    loop {
+        ticker.tick().await;
+        if let Ok(toml_str) = fs::read_to_string(plan_file) {
+            match toml::from_str::<FileConfig>(&toml_str) {
+                Ok(new_config) if new_config.epoch > config.epoch => return new_config,
+                Ok(_) => {}
+                Err(err) => eprintln!("Failed to read {}: {err}", plan_file.display()),
+            }
+        }
+        match fetch_current_plan(bus, config).await {
+            Ok(new_config) if new_config.epoch > config.epoch => return new_config,
+            Ok(_) => {}
+            Err(err) => eprintln!("{err:#}"),
+        }
+    }
+}
+
+// The server's plan over the session already open, served in any epoch.
+async fn fetch_current_plan(
+    bus: &Arc<Bus<BusAddress, BusMessage<TRANSMISSION_INFO_LENGTH>>>,
+    config: &FileConfig,
+) -> anyhow::Result<FileConfig> {
+    let outcome =
+        decoding::spawn::<RaptorqReceiver, TRANSMISSION_INFO_LENGTH>(PLAN_CHUNK_ID, bus.clone())
+            .await;
+    let plan = outcome
+        .ok()
+        .flatten()
+        .and_then(|outcome| outcome.data)
+        .ok_or_else(|| anyhow!("The server did not send its plan."))?;
+    let new_config: FileConfig = toml::from_str(std::str::from_utf8(&plan)?)?;
+    if new_config.file_name != config.file_name {
+        return Err(anyhow!(
+            "The server now plans {}, not {}.",
+            new_config.file_name,
+            config.file_name
+        ));
+    }
+    new_config.check_chunks().map_err(|err| anyhow!("{err}."))?;
+    Ok(new_config)
+}
+
+// The rate file may be edited while downloading, SIGUSR1 makes it take effect.
+fn watch_rate_file(path: PathBuf, control: Arc<ControlState>) -> anyhow::Result<()> {
+    control.set_rate_kbps(read_rate_file(&path)?);
+    let mut reload = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        while reload.recv().await.is_some() {
+            match read_rate_file(&path) {
+                Ok(rate_kbps) => {
+                    control.set_rate_kbps(rate_kbps);
+                    eprintln!("Reread {}.", path.display());
+                }
+                Err(err) => eprintln!("Failed to read {}: {err}", path.display()),
+            }
+        }
+    });
+    Ok(())
+}
+
+// Asks the server for its plan file, served like a chunk, over sockets of its
+// own that are closed again before the download binds.
+fn keepalive(args: &Args) -> Option<Duration> {
+    (args.keepalive > 0).then(|| Duration::from_secs(args.keepalive))
+}
+
+async fn fetch_plan(
+    args: &Args,
+    server: SocketAddr,
+    uri: &PlanUri,
+    grant: Option<GrantFrame>,
+) -> anyhow::Result<FileConfig> {
+    let sockets = bind_path(args, args.bind, args.interface.as_deref()).await?;
+    let bus: Arc<Bus<BusAddress, BusMessage<TRANSMISSION_INFO_LENGTH>>> = Arc::new(Bus::default());
+    let receiver = receiving::ReceivingSocket::over_paths(
+        PathManager::sharded(vec![sockets]),
+        bus.clone().register(BusAddress::ReceiverSocket).unwrap(),
+    )
+    .with_upcoming(vec![PLAN_CHUNK_ID])
+    .with_max_wait(Duration::from_secs(args.max_wait))
+    .with_keepalive(keepalive(args));
+    let receiver = match grant {
+        Some(grant) => receiver.with_grant(grant),
+        None => receiver,
+    };
+    let mut receiver = tokio::spawn(receiver.run(server));
+    println!("{}", i18n::fetching_plan(server.green()));
+
+    let decoder = decoding::spawn::<RaptorqReceiver, TRANSMISSION_INFO_LENGTH>(PLAN_CHUNK_ID, bus);
+    let outcome = tokio::select! {
+        outcome = decoder => outcome.ok().flatten(),
+        Ok(Err(err)) = &mut receiver => {
+            return Err(tag(anyhow!("Failed to fetch the plan, {err}."), Failure::of_session(&err)));
+        }
+    };
+    receiver.abort();
+    let _ = receiver.await;
+
+    let plan = outcome
+        .and_then(|outcome| outcome.data)
+        .ok_or_else(|| anyhow!("The server did not send its plan."))?;
+    parse_fetched_plan(uri, &plan)
+}
+
+// Checked against the hash in the URI before it is trusted.
+fn parse_fetched_plan(uri: &PlanUri, plan: &[u8]) -> anyhow::Result<FileConfig> {
+    uri.check(plan)
+        .map_err(|err| anyhow!("{err}."))
+        .context(Failure::Verification)?;
+    let plan = std::str::from_utf8(plan).context(Failure::BadPlan)?;
+    toml::from_str(plan).context(Failure::BadPlan)
+}
+
+// Fetches chunks one at a time by byte range from the server's HTTP gateway,
+// checked and written as the download manager does.
+async fn download_over_http(
+    gateway: &str,
+    token: Option<&str>,
+    chunks: &[FileChunk],
+    file: &Path,
+    content_key: Option<&ContentKey>,
+) -> DownloadReport {
+    let started = Instant::now();
+    let (mut summaries, mut written, mut failed) = (vec![], vec![], vec![]);
+    for chunk in chunks {
+        let chunk_id = chunk.chunk_id;
+        let range = (chunk.offset, chunk.offset + chunk.length as u64);
+        let fetched_at = Instant::now();
+        let data = match http_get(gateway, FILE_PATH, token, Some(range)).await {
+            Ok(data) => data,
+            Err(err) => {
+                eprintln!(
+                    "Failed to fetch chunk {} over HTTP: {err}",
+                    chunk_id.on_red()
+                );
+                failed.push(chunk_id);
+                continue;
+            }
+        };
+        let stats = DecodeStats {
+            frames_received: 1,
+            bytes_received: data.len() as u64,
+            max_frame_length: data.len(),
+            elapsed: fetched_at.elapsed(),
+            ..Default::default()
+        };
+        let opened = PlanHashVerifier
+            .verify(chunk, &data)
+            .and_then(|()| open_chunk(content_key, chunk, data));
+        summaries.push(ChunkSummary::new(
+            chunk_id,
+            chunk.length,
+            &stats,
+            opened.is_ok(),
+        ));
+        let written_at = match opened {
+            Ok((offset, data)) => write_at(file, offset, &data).map_err(|err| format!("{err}")),
+            Err(reason) => Err(reason),
+        };
+        match written_at {
+            Ok(()) => {
+                eprintln!("Succeed in download chunk {} over HTTP.", chunk_id.green());
+                written.push(chunk_id);
+            }
+            Err(reason) => {
+                eprintln!(
+                    "Chunk {} fetched over HTTP rejected: {reason}",
+                    chunk_id.on_red()
+                );
+                failed.push(chunk_id);
+            }
+        }
+    }
+    DownloadReport {
+        summary: TransferSummary::new(summaries, started.elapsed()),
+        written,
+        failed,
+        out_of_space: false,
+        over_budget: vec![],
+        blacklisted: vec![],
+    }
+}
+
+// Offers the file to the server and serves the chunks its tickets ask for,
+// until they stop.
+async fn upload(
+    args: &Args,
+    server: SocketAddr,
+    config: &FileConfig,
+    file: &Path,
+) -> anyhow::Result<()> {
+    check_file_exist(file).context(Failure::Disk)?;
+    let index = ChunkIndex::new(
+        HashMap::from([(0usize, OsString::from(file))]),
+        config.chunk_map(),
+    )
+    .with_epoch(config.epoch);
+    for (chunk_id, err) in index.validate_lengths() {
+        eprintln!("Chunk {} {}: {err}", chunk_id, "is stale".red());
+    }
+    CHUNK_INDEX
+        .set(index)
+        .map_err(|_| anyhow!("Chunk index already set."))?;
+
+    let socket = bind_path(args, args.bind, args.interface.as_deref())
+        .await?
+        .remove(0);
+    init_log("upload.log".into());
+    let bus: Arc<Bus<BusAddress, BusMessage<TRANSMISSION_INFO_LENGTH>>> = Arc::new(Bus::default());
+    tokio::spawn(SenderStats::new(bus.clone().register(BusAddress::SenderStats).unwrap()).run());
+    println!("{}", i18n::offering(file.display(), server.green()));
+    SendingSocket::new(
+        socket,
+        bus.clone().register(BusAddress::SenderSocket).unwrap(),
+    )
+    .push::<RaptorqSender>(
+        server,
+        file_id(&config.file_name),
+        Duration::from_secs(args.max_wait),
+    )
+    .await
+    .map_err(|err| tag(anyhow!("Upload stopped, {err}."), Failure::of_session(&err)))?;
+    println!("{}", i18n::upload_finished().green());
+    Ok(())
+}
+
+fn report_summary(summary: &TransferSummary, json: Option<&PathBuf>) {
+    summary.print();
+    if let Some(path) = json
+        && let Err(err) = summary.write_json(path)
+    {
+        eprintln!("Failed to write summary to {}: {err}", path.display());
+    }
+}
+
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> ExitCode {
+    exit_code(run().await)
+}
+
+async fn run() -> anyhow::Result<()> {
     debug_assert!(
         false,
         "Run in release mode instead for raptorq is too slow in debug mode."
     );
 
-    let args = Args::parse();
+    let mut args = Args::parse();
+    set_parse_mode(args.parse_mode);
+    if args.cpu_accounting {
+        cpu::enable();
+    }
+    let uri = match args.uri.as_deref() {
+        Some(uri) => Some(PlanUri::parse(uri).map_err(|err| anyhow!("{err}."))?),
+        None => None,
+    };
+    if let Some(key) = uri.as_ref().and_then(|uri| uri.key.clone())
+        && args.token.is_none()
+        && args.private_key.is_empty()
+    {
+        match DownloadToken::decode(&key) {
+            Some(_) => args.token = Some(key),
+            None => args.private_key.push(key),
+        }
+    }
 
     // Init key ring.
-    init(vec![], Some(args.private_key));
+    let token = match args.token.as_deref() {
+        Some(token) => {
+            let token = DownloadToken::decode(token).ok_or(anyhow!("Malformed download token."));
+            Some(token.context(Failure::Auth)?)
+        }
+        None => None,
+    };
+    match token.as_ref() {
+        Some(token) => init(vec![], vec![hex::encode(token.key.to_bytes())]),
+        None => init(
+            args.server_key.iter().cloned().collect(),
+            load_private_keys(&args.private_key)?,
+        ),
+    }
+    let key_ring = KEY_RING.get().unwrap();
+    if args.rotate_identity {
+        key_ring.select_private_key(rand::random_range(0..key_ring.private_key_count()));
+    }
 
-    let toml_str = fs::read_to_string(&args.plan_file)?;
-    let config: FileConfig = toml::from_str(&toml_str)?;
+    let config: FileConfig = match (uri.as_ref(), args.plan_file.as_ref()) {
+        (Some(uri), _) => {
+            if args.server.is_none() {
+                args.server = Some(uri.server()?);
+            }
+            let server = args.server.unwrap();
+            let grant = token.as_ref().map(|token| token.grant);
+            match (
+                fetch_plan(&args, server, uri, grant).await,
+                args.http_fallback.as_deref(),
+            ) {
+                (Ok(config), _) => config,
+                (Err(err), Some(gateway)) => {
+                    eprintln!("{err:#} Fetching it over HTTP instead.");
+                    let plan = http_get(gateway, PLAN_PATH, None, None).await?;
+                    parse_fetched_plan(uri, &plan)?
+                }
+                (Err(err), None) => return Err(err),
+            }
+        }
+        (None, Some(plan_file)) => {
+            toml::from_str(&fs::read_to_string(plan_file).context(Failure::BadPlan)?)
+                .context(Failure::BadPlan)?
+        }
+        (None, None) => return Err(anyhow!("No plan file given.")),
+    };
+    config
+        .check_chunks()
+        .and_then(|()| config.check_file_name())
+        .map_err(|err| anyhow!("{err}."))
+        .context(Failure::BadPlan)?;
+    if let Some(hints) = config.hints.as_ref() {
+        hints
+            .check()
+            .map_err(|err| anyhow!("{err}."))
+            .context(Failure::BadPlan)?;
+        args.server = args.server.or(hints.servers.first().copied());
+        args.rate = args.rate.or(hints.rate_kbps.map(u64::from));
+        args.transport = args.transport.or(hints.transport);
+    }
+    let server = args
+        .server
+        .ok_or_else(|| anyhow!("No server given, and the plan suggests none."))?;
+    if let Some(token) = token.as_ref() {
+        if token.grant.file_id.get() != file_id(&config.file_name) {
+            return Err(anyhow!(
+                "The download token is not for {}.",
+                config.file_name
+            ))
+            .context(Failure::Auth);
+        }
+        if token.grant.expires_ms.get() <= current_timestamp_ms() {
+            return Err(anyhow!("The download token has expired.")).context(Failure::Auth);
+        }
+    }
 
-    let downloading_file = match args.downloading_file {
+    let downloading_file = match args.downloading_file.clone() {
         Some(path) => path,
         None => {
             let user_dir = UserDirs::new();
@@ -123,82 +895,257 @@ async fn main() -> anyhow::Result<()> {
         }
     };
 
-    println!("Downloading file: {}", downloading_file.display());
+    let content_key = match (config.sealed.as_ref(), args.content_key.as_ref()) {
+        (None, _) => None,
+        (Some(sealed), _) if sealed.cipher != seal::CIPHER => {
+            return Err(anyhow!("Unknown cipher {} in the plan.", sealed.cipher))
+                .context(Failure::BadPlan);
+        }
+        (Some(_), None) => {
+            return Err(anyhow!(
+                "{} is sealed, give its content key with --content-key.",
+                config.file_name
+            ));
+        }
+        (Some(_), Some(path)) => Some(Arc::new(
+            ContentKey::from_hex(&fs::read_to_string(path)?)
+                .ok_or_else(|| anyhow!("{} does not hold a content key.", path.display()))?,
+        )),
+    };
 
-    if check_file_exist_create(&downloading_file)? {
-        println!("{} already exists.", downloading_file.display(),);
-    } else {
-        println!(
-            "Created {} successfully as an empty file.",
-            downloading_file.display()
-        )
+    if args.upload {
+        if config.sealed.is_some() {
+            return Err(anyhow!("A sealed file cannot be uploaded."));
+        }
+        return upload(&args, server, &config, &downloading_file).await;
     }
 
-    let bus: Arc<Bus<BusAddress, BusMessage<TRANSMISSION_INFO_LENGTH>>> = Arc::new(Bus::default());
-    let socket = RealUdpSocket::bind(SocketAddr::from_str("0.0.0.0:0").unwrap())
-        .await
-        .unwrap();
-    let receiver =
-        receiving::ReceivingSocket::new(socket, bus.clone().register(BusAddress::ReceiverSocket));
-    tokio::spawn(receiver.run(args.server));
-
-    let need_to_download = check_file(&downloading_file, &config)?;
+    if let Some(target) = config.link_target.as_ref() {
+        if restore_symlink(&downloading_file, target).context(Failure::Disk)? {
+            println!(
+                "{}",
+                i18n::linked(downloading_file.display(), target.display())
+            );
+        } else {
+            println!(
+                "{}",
+                i18n::already_linked(downloading_file.display(), target.display())
+            );
+        }
+        return Ok(());
+    }
 
-    init_log("download.log".into());
+    println!("{}", i18n::downloading_file(downloading_file.display()));
 
-    let semaphore = Arc::new(Semaphore::new(8));
-    let finish = Arc::new(AtomicUsize::new(need_to_download.len()));
+    // Plans of a directory name files below it.
+    if let Some(parent) = downloading_file.parent() {
+        fs::create_dir_all(parent).context(Failure::Disk)?;
+    }
+    if check_file_exist_create(&downloading_file).context(Failure::Disk)? {
+        println!("{}", i18n::already_exists(downloading_file.display()));
+    } else {
+        println!("{}", i18n::created_empty(downloading_file.display()));
+    }
 
-    for to_download in need_to_download {
-        let to_download = to_download.clone();
-        let semaphore = semaphore.clone();
-        let bus = bus.clone();
-        let finish = finish.clone();
-        let downloading_file = downloading_file.clone();
+    let bus: Arc<Bus<BusAddress, BusMessage<TRANSMISSION_INFO_LENGTH>>> = Arc::new(Bus::default());
+    let mut bind_addr = args.bind;
+    if let Some(ports) = &args.port_range
+        && bind_addr.port() == 0
+    {
+        bind_addr.set_port(*ports.start());
+    }
+    let interfaces: Vec<Option<&str>> = match args.multipath.is_empty() {
+        true => vec![args.interface.as_deref()],
+        false => args
+            .multipath
+            .iter()
+            .map(|name| Some(name.as_str()))
+            .collect(),
+    };
+    let mut paths = vec![];
+    for interface in interfaces {
+        paths.push(bind_path(&args, bind_addr, interface).await?);
+    }
 
-        let chunk_id = to_download.chunk_id as u32;
+    let min_free = args.min_free * 1024 * 1024;
+    let mut verified = match args.force_verify {
+        true => VerificationCache::empty(&downloading_file),
+        false => VerificationCache::load(&downloading_file),
+    };
+    let (mut need_to_download, mut whole_file) = chunks_to_download(
+        &args,
+        &downloading_file,
+        &config,
+        content_key.as_deref(),
+        min_free,
+        &mut verified,
+    )?;
 
-        let waiting = |finish: Arc<AtomicUsize>| async move {
-            let permit = semaphore.acquire().await.unwrap();
-            let result =
-                decoding::spawn::<RaptorqReceiver, TRANSMISSION_INFO_LENGTH>(chunk_id, bus.clone())
-                    .await;
+    let control = ControlState::new("client");
+    if let Some(path) = args.control.as_ref() {
+        let socket = ControlSocket::bind(path).await?;
+        println!("{}", i18n::control_socket(path.display()));
+        tokio::spawn(socket.serve(control.clone(), bus.clone()));
+    }
+    if args.pause_on_suspend {
+        handle_pause_signals(control.clone())?;
+    }
+    if let Some(path) = args.rate_file.clone() {
+        watch_rate_file(path, control.clone())?;
+    }
+    let generation = Arc::new(AtomicU64::new(config.epoch));
+    let receiver = receiving::ReceivingSocket::over_paths(
+        PathManager::sharded(paths),
+        bus.clone().register(BusAddress::ReceiverSocket).unwrap(),
+    )
+    .with_upcoming(
+        need_to_download
+            .iter()
+            .map(|chunk| chunk.chunk_id)
+            .collect(),
+    )
+    .with_rate_kbps(args.rate.unwrap_or(DEFAULT_RATE_KBPS))
+    .with_priority(args.priority)
+    .with_max_wait(Duration::from_secs(args.max_wait))
+    .with_keepalive(keepalive(&args))
+    .with_generation(generation.clone())
+    .with_control(control.clone());
+    let receiver = match token.as_ref() {
+        Some(token) => receiver.with_grant(token.grant),
+        None => receiver,
+    };
+    let mut receiver = tokio::spawn(receiver.run(server));
 
-            drop(permit);
-            let Ok(Some(result)) = result else {
-                eprintln!(
-                    "Downloaded chunk {} currupted.",
-                    to_download.chunk_id.on_red(),
-                );
-                finish.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
-                return;
-            };
+    init_log("download.log".into());
 
-            let hash = hex::encode(blake3::hash(&result).as_bytes());
-            if hash == to_download.hash && result.len() == to_download.length {
-                write_at(downloading_file, to_download.offset, &result).ok();
-                eprintln!(
-                    "Succeed in download chunk {}, at [{},{})",
-                    to_download.chunk_id.green(),
-                    to_download.offset.magenta(),
-                    (to_download.offset + to_download.length as u64).magenta()
-                )
-            } else {
-                eprintln!(
-                    "Downloaded chunk {} currupted.",
-                    to_download.chunk_id.on_red(),
+    tokio::spawn({
+        let bus = bus.clone();
+        let (leak_after, reap_leaks) = (Duration::from_secs(args.leak_after), args.reap_leaks);
+        async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                bus.debug();
+                bus.detect_leaks(leak_after, BusAddress::is_coder, reap_leaks);
+            }
+        }
+    });
+    let deadline = args
+        .max_duration
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
+    let mut bytes_received = 0;
+    let mut config = config;
+    loop {
+        let mut download = DownloadManager::new(bus.clone(), &downloading_file)
+            .with_retries(args.retries)
+            .with_keep_damaged(args.keep_damaged)
+            .with_min_free(min_free)
+            .with_control(control.clone());
+        if let Some(max_bytes) = args.max_bytes {
+            download = download.with_max_bytes(max_bytes.saturating_sub(bytes_received));
+        }
+        if let Some(deadline) = deadline {
+            download = download.with_deadline(deadline);
+        }
+        if let Some(content_key) = content_key.as_ref() {
+            download = download.with_content_key(content_key.clone());
+        }
+        let planned: HashMap<ChunkId, FileChunk> = need_to_download
+            .iter()
+            .map(|chunk| (chunk.chunk_id, chunk.clone()))
+            .collect();
+        let download = download.run::<RaptorqReceiver>(need_to_download);
+        let report = tokio::select! {
+            report = download => report,
+            Ok(Err(err)) = &mut receiver => {
+                let Some(gateway) = args.http_fallback.as_deref() else {
+                    return Err(tag(anyhow!("Stopped, {err}. Rerun to resume."), Failure::of_session(&err)));
+                };
+                eprintln!("Stopped, {err}. Fetching the chunks left over HTTP.");
+                let (left, _) = chunks_to_download(
+                    &args,
+                    &downloading_file,
+                    &config,
+                    content_key.as_deref(),
+                    min_free,
+                    &mut verified,
+                )?;
+                download_over_http(
+                    gateway,
+                    args.token.as_deref(),
+                    &left,
+                    &downloading_file,
+                    content_key.as_deref(),
                 )
+                .await
             }
-
-            finish.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
         };
-        tokio::spawn(waiting(finish));
-    }
 
-    while finish.load(std::sync::atomic::Ordering::Relaxed) > 0 {
-        tokio::time::sleep(Duration::from_secs(5)).await;
-        bus.debug();
-    }
+        report_summary(&report.summary, args.summary.as_ref());
+        bytes_received += report.summary.bytes_received;
+        for chunk_id in &report.written {
+            verified.record(&planned[chunk_id]);
+        }
+        if let Err(err) = verified.save() {
+            eprintln!("Failed to save the verification cache: {err}");
+        }
+        if report.out_of_space {
+            return Err(anyhow!(
+                "Running out of space at {}, stopped. Free up space and rerun to resume.",
+                downloading_file.display()
+            ))
+            .context(Failure::Disk);
+        }
+        if !report.blacklisted.is_empty() {
+            eprintln!(
+                "{} chunks differ on the server from the plan, it may serve another version of the file.",
+                report.blacklisted.len().red()
+            );
+        }
+        // Written chunks pass their hash check on the next run and are not fetched again.
+        if !report.over_budget.is_empty() {
+            return Err(anyhow!(
+                "Budget of this run spent, {} chunks left. Rerun to resume.",
+                report.over_budget.len()
+            ));
+        }
+        // Only a complete copy takes on the source's metadata.
+        if let Some(metadata) = config.metadata.as_ref()
+            && whole_file
+            && report.failed.is_empty()
+            && !(args.no_perms && args.no_times)
+        {
+            apply_metadata(&downloading_file, metadata, !args.no_perms, !args.no_times)
+                .context(Failure::Disk)?;
+            // Stamped again with the metadata just applied.
+            if let Err(err) = verified.save() {
+                eprintln!("Failed to save the verification cache: {err}");
+            }
+        }
 
-    Ok(())
+        let (Some(period), Some(plan_file)) = (args.follow, args.plan_file.as_ref()) else {
+            // Followers retry failed chunks with the next epoch instead.
+            if !report.failed.is_empty() {
+                return Err(anyhow!(
+                    "{} chunks failed to download or verify. Rerun to resume.",
+                    report.failed.len()
+                ))
+                .context(Failure::Verification);
+            }
+            return Ok(());
+        };
+        config = wait_for_epoch(&bus, plan_file, &config, Duration::from_secs(period)).await;
+        generation.store(config.epoch, Ordering::Relaxed);
+        println!("{}", i18n::epoch_published(config.epoch.yellow()));
+        verified
+            .set_len(config.plain_length())
+            .context(Failure::Disk)?;
+        (need_to_download, whole_file) = chunks_to_download(
+            &args,
+            &downloading_file,
+            &config,
+            content_key.as_deref(),
+            min_free,
+            &mut verified,
+        )?;
+    }
 }
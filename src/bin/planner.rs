@@ -1,49 +1,290 @@
-use clap::Parser;
-use std::path::PathBuf;
+use anyhow::{Context, anyhow};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use zerocopy::IntoBytes;
 
-use usync::util::file::{mmap_segment, sanity_check};
-use usync::util::plan::{FileChunk, FileConfig, make_plan};
+use usync::transmission::Transport;
+use usync::util::cdc::Cdc;
+use usync::util::exit::{Failure, exit_code};
+use usync::util::file::{mmap_segment, read_metadata, sanity_check};
+use usync::util::filter::PathFilter;
+use usync::util::i18n;
+use usync::util::plan::{
+    DownloadHints, FileChunk, FileConfig, PlanBuilder, SealedFile, check_file_name, parse_size,
+};
+use usync::util::seal::{self, ContentKey};
+use usync::util::units::ChunkId;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about = "A simple CLI program to build transmission plan.", long_about = None)]
 struct Args {
-    /// The path to the file to read.
+    /// The path to the file to read, or a directory to plan every file of.
     #[arg(short, long, value_name = "FILE")]
     file: PathBuf,
+
+    /// Where to write the plans of a directory, one `<relative path>.toml` per file.
+    #[arg(short, long, value_name = "DIR")]
+    out_dir: Option<PathBuf>,
+
+    /// Skip files and directories matching this .gitignore-style pattern when planning a
+    /// directory. May be given several times.
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Only plan files matching the .gitignore-style patterns in this file when planning a
+    /// directory. --exclude still applies.
+    #[arg(long, value_name = "PATTERN_FILE")]
+    include_from: Option<PathBuf>,
+
+    /// Plan the file a symbolic link points to, rather than the link itself.
+    #[arg(long)]
+    follow_links: bool,
+
+    /// Cut chunks where the content says so rather than at fixed offsets, so they keep
+    /// their hashes when bytes are inserted or removed before them. --chunk-size is then
+    /// the average.
+    #[arg(long)]
+    cdc: bool,
+
+    /// Target chunk size, e.g. 32M.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "32M")]
+    chunk_size: u64,
+
+    /// Chunks start on multiples of this, e.g. 4K.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "4K")]
+    alignment: u64,
+
+    /// Grow chunks past --chunk-size rather than planning more than this many.
+    #[arg(long, value_name = "CHUNKS")]
+    max_chunks: Option<usize>,
+
+    /// Even out a last chunk shorter than this with the one before it, --chunk-size if not given.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    min_tail: Option<u64>,
+
+    /// Seal every chunk with the content key in this file, created if missing, so the
+    /// server only ever holds ciphertext. The plan is of the sealed file, written under
+    /// --sealed-dir. Clients need the key to download.
+    #[arg(long, value_name = "KEY_FILE", requires = "sealed_dir")]
+    encrypt: Option<PathBuf>,
+
+    /// Where to write the sealed files, by their file names, for the server to serve.
+    #[arg(long, value_name = "DIR", requires = "encrypt")]
+    sealed_dir: Option<PathBuf>,
+
+    /// Suggest this server to clients not given --server. `usync get` tries those of the plan
+    /// in turn, the client alone takes the first. May be given several times.
+    #[arg(long, value_name = "SERVER")]
+    hint_server: Vec<SocketAddr>,
+
+    /// Suggest asking the server for this rate, in kbps.
+    #[arg(long, value_name = "KBPS")]
+    hint_rate: Option<u32>,
+
+    /// Suggest the transport the server runs with.
+    #[arg(long, value_enum, value_name = "TRANSPORT")]
+    hint_transport: Option<Transport>,
 }
 
-fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+impl Args {
+    // Along with them, the plan records the codec and MTU of this build.
+    fn hints(&self) -> Option<DownloadHints> {
+        (!self.hint_server.is_empty() || self.hint_rate.is_some() || self.hint_transport.is_some())
+            .then(|| {
+                DownloadHints::new(
+                    self.hint_server.clone(),
+                    self.hint_rate,
+                    self.hint_transport,
+                )
+            })
+    }
+}
+
+fn load_content_key(path: &Path) -> anyhow::Result<ContentKey> {
+    if !path.exists() {
+        let key: [u8; seal::KEY_LENGTH] = rand::random();
+        fs::write(path, hex::encode(key)).context(Failure::Disk)?;
+        eprintln!("{}", i18n::new_content_key(path.display()));
+    }
+    ContentKey::from_hex(&fs::read_to_string(path).context(Failure::Disk)?)
+        .ok_or_else(|| anyhow!("{} does not hold a content key.", path.display()))
+        .context(Failure::Auth)
+}
+
+// `file_name` is where the client and server find the file, relative to their folders.
+fn plan_file(
+    args: &Args,
+    key: Option<&ContentKey>,
+    path: &Path,
+    file_name: String,
+) -> anyhow::Result<FileConfig> {
+    if !args.follow_links && fs::symlink_metadata(path)?.is_symlink() {
+        return Ok(FileConfig {
+            file_name,
+            total_length: 0,
+            total_hash: hex::encode(blake3::hash(&[]).as_bytes()),
+            epoch: 0,
+            chunks: vec![],
+            metadata: None,
+            link_target: Some(fs::read_link(path)?),
+            sealed: None,
+            hints: args.hints(),
+            cdc: None,
+        });
+    }
 
-    let (total_length, file_name) = sanity_check(&args.file)?;
+    let (total_length, _) = sanity_check(path)?;
+    let metadata = read_metadata(path)?;
+
+    let mut sealed_file = match (key, args.sealed_dir.as_ref()) {
+        (Some(key), Some(sealed_dir)) => {
+            let sealed_path = sealed_dir.join(&file_name);
+            if let Some(parent) = sealed_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            Some((key, File::create(sealed_path)?))
+        }
+        _ => None,
+    };
+    let mut sealed_length = 0u64;
+    let mut write_error = None;
 
     let mut total_hasher = blake3::Hasher::new();
     let mut chunks = vec![];
-
-    for (chunk_id, (offset, length)) in make_plan(total_length).enumerate() {
-        let chunk = mmap_segment(&args.file, offset, length)?;
-        let chunk_bytes = chunk.as_bytes();
-        assert_eq!(chunk_bytes.len(), length);
-        let hash = hex::encode(blake3::hash(chunk_bytes).as_bytes());
+    let mut add_chunk = |offset: u64, chunk_bytes: &[u8]| {
+        // Sealed chunks are planned where they land in the sealed file.
+        let sealed;
+        let (offset, chunk_bytes) = match sealed_file.as_mut() {
+            Some((key, file)) => {
+                sealed = key.seal(chunk_bytes);
+                if let Err(err) = file.write_all(&sealed) {
+                    write_error.get_or_insert(err);
+                }
+                sealed_length += sealed.len() as u64;
+                (sealed_length - sealed.len() as u64, sealed.as_slice())
+            }
+            None => (offset, chunk_bytes),
+        };
         total_hasher.update(chunk_bytes);
-
         chunks.push(FileChunk {
-            chunk_id,
-            hash,
+            chunk_id: ChunkId(chunks.len() as u32),
+            hash: hex::encode(blake3::hash(chunk_bytes).as_bytes()),
             offset,
-            length,
+            length: chunk_bytes.len(),
         })
+    };
+
+    if args.cdc {
+        Cdc::new(args.chunk_size as usize).split(File::open(path)?, add_chunk)?;
+    } else {
+        let mut builder = PlanBuilder::default()
+            .with_chunk_size(args.chunk_size as usize)
+            .with_alignment(args.alignment as usize)
+            .with_min_tail(args.min_tail.unwrap_or(args.chunk_size) as usize);
+        if let Some(max_chunks) = args.max_chunks {
+            builder = builder.with_max_chunks(max_chunks);
+        }
+        for (offset, length) in builder.plan(total_length) {
+            let chunk = mmap_segment(path, offset, length)?;
+            let chunk_bytes = chunk.as_bytes();
+            assert_eq!(chunk_bytes.len(), length);
+            add_chunk(offset, chunk_bytes);
+        }
     }
 
-    let total_hash = hex::encode(total_hasher.finalize().as_bytes());
+    if let Some(err) = write_error {
+        return Err(err.into());
+    }
+    let sealed = key.map(|_| SealedFile {
+        cipher: seal::CIPHER.to_string(),
+        plain_length: total_length,
+    });
 
-    let plan = FileConfig {
+    Ok(FileConfig {
         file_name,
-        total_hash,
-        total_length,
+        total_hash: hex::encode(total_hasher.finalize().as_bytes()),
+        total_length: match sealed {
+            Some(_) => sealed_length,
+            None => total_length,
+        },
+        epoch: 0,
         chunks,
+        metadata: Some(metadata),
+        link_target: None,
+        sealed,
+        hints: args.hints(),
+        cdc: args.cdc.then_some(args.chunk_size as usize),
+    })
+}
+
+fn plan_dir(args: &Args, key: Option<&ContentKey>, out_dir: &Path) -> anyhow::Result<()> {
+    let mut filter = PathFilter::default();
+    if let Some(path) = args.include_from.as_ref() {
+        filter = filter.include_from(path).context(Failure::Disk)?;
+    }
+    for pattern in args.exclude.iter() {
+        filter = filter.exclude(pattern);
+    }
+
+    let files = filter.walk(&args.file).context(Failure::Disk)?;
+    for relative in files.iter() {
+        let file_name = relative
+            .to_str()
+            .ok_or_else(|| anyhow!("File name is not valid UTF-8."))?
+            .to_string();
+        check_file_name(&file_name).map_err(|err| anyhow!("{err}."))?;
+        let plan = plan_file(args, key, &args.file.join(relative), file_name.clone())
+            .context(Failure::Disk)?;
+        let plan_path = out_dir.join(format!("{file_name}.toml"));
+        if let Some(parent) = plan_path.parent() {
+            fs::create_dir_all(parent).context(Failure::Disk)?;
+        }
+        fs::write(&plan_path, toml::to_string_pretty(&plan)?).context(Failure::Disk)?;
+        println!("{}", plan_path.display());
+    }
+    eprintln!("{}", i18n::planned_files(files.len()));
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    exit_code(run())
+}
+
+// Failures reading the file or writing what is planned of it are disk errors,
+// a content key that does not parse an authentication one. Arguments that do
+// not fit the file exit with clap's usage code.
+fn run() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let key = args.encrypt.as_deref().map(load_content_key).transpose()?;
+
+    let metadata = fs::metadata(&args.file).context(Failure::Disk)?;
+    let is_link = fs::symlink_metadata(&args.file)
+        .context(Failure::Disk)?
+        .is_symlink();
+    if metadata.is_dir() && !is_link {
+        let Some(out_dir) = args.out_dir.as_ref() else {
+            Args::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "Planning a directory takes --out-dir.",
+                )
+                .exit();
+        };
+        return plan_dir(&args, key.as_ref(), out_dir);
+    }
+
+    let Some(file_name) = args.file.file_name().and_then(|name| name.to_str()) else {
+        Args::command()
+            .error(ErrorKind::InvalidUtf8, "File name is not valid UTF-8.")
+            .exit();
     };
+    let file_name = file_name.to_string();
+    let plan = plan_file(&args, key.as_ref(), &args.file, file_name).context(Failure::Disk)?;
 
     println!("{}", toml::to_string_pretty(&plan).unwrap());
 
@@ -1,20 +1,59 @@
+use anyhow::Context;
 use clap::Parser;
+use owo_colors::OwoColorize;
+use rand::seq::IndexedRandom;
 use std::collections::HashMap;
 use std::ffi::OsString;
 use std::fs::File;
 use std::io::BufRead;
+use std::ops::RangeInclusive;
+use std::process::ExitCode;
 
 use std::sync::Arc;
-use std::{fs, net::SocketAddr, path::PathBuf};
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+use tokio::net::TcpListener;
 use tokio::time::Duration;
 use usync::constants::TRANSMISSION_INFO_LENGTH;
-use usync::engine::{Bus, BusAddress, BusMessage, sending};
-use usync::protocol::{coding::raptorq_code::RaptorqSender, init};
-use usync::transmission::real::RealUdpSocket;
+use usync::engine::{
+    Bus, BusAddress, BusMessage, PLAN_CHUNK_ID,
+    admission::AdmissionLimits,
+    control::{ControlSocket, ControlState, DEFAULT_CONTROL_SOCKET, handle_pause_signals},
+    download::DownloadManager,
+    encoding::{RepairStrategy, parse_repair},
+    gateway::HttpGateway,
+    journal::SessionJournal,
+    receiving::{self, await_offer},
+    scrubbing::Scrubber,
+    sending,
+    stats::SenderStats,
+};
+use usync::protocol::{
+    coding::raptorq_code::{RaptorqPreset, RaptorqReceiver, RaptorqSender, set_params},
+    init,
+    quota::{Quota, QuotaBook, parse_key_line},
+    token::{TokenChecker, file_id},
+    wire::encoding::{ParseMode, set_parse_mode},
+};
+use usync::transmission::{
+    DynSocket, Transport,
+    real::{RealUdpSocket, TxTimeClock, parse_port_range, parse_traffic_class},
+    tcp::TcpDatagramSocket,
+};
 use usync::util::{
-    file::{CHUNK_INDEX, ChunkIndex, check_file_exist},
+    audit::{init as init_audit, parse_signing_key},
+    cpu,
+    exit::{Failure, exit_code, tag},
+    file::{CHUNK_INDEX, ChunkIndex, check_file_exist, check_file_exist_create, chunk_hash},
+    i18n,
     log::init as init_log,
-    plan::FileConfig,
+    plan::{FileConfig, parse_size, replan},
+    store::ChunkStore,
+    telemetry::telemetry,
+    units::ChunkId,
 };
 
 #[derive(Parser, Debug)]
@@ -29,61 +68,666 @@ struct Args {
     listening: SocketAddr,
 
     /// The path to authorized public key, one per line.
+    /// A key may be followed by byte quotas, e.g. `daily=10G total=1T`.
     #[arg(short, long, value_name = "PUB_KEY")]
     public_key: PathBuf,
 
     /// The path to the folder that contains the  file to be downloaded.
     #[arg(short, long, value_name = "DOWNLOAD_FOLDER")]
     folder: PathBuf,
+
+    /// Number of randomly picked chunks to verify against the plan hashes at startup.
+    #[arg(long, value_name = "CHUNKS", default_value_t = 0)]
+    spot_check: usize,
+
+    /// Hash every chunk against the plan when its encoder is first initialized,
+    /// refusing to serve chunks that changed since planning. Costs a hash per chunk served.
+    #[arg(long)]
+    verify_chunks: bool,
+
+    /// Re-hash served chunks in the background at this rate (MiB/s). 0 disables scrubbing.
+    #[arg(long, value_name = "MIB_PER_SEC", default_value_t = 0)]
+    scrub_rate: u64,
+
+    /// Lock the pages of chunks in memory while their encoders are initialized.
+    #[arg(long)]
+    mlock: bool,
+
+    /// How chunks are read from disk. `direct` bypasses the page cache.
+    #[arg(long, value_enum, default_value_t = ChunkStore::Mmap)]
+    chunk_store: ChunkStore,
+
+    /// Ports to try in turn if the listening one is taken or not permitted, e.g. 7000-7100.
+    #[arg(long, value_name = "START-END", value_parser = parse_port_range)]
+    port_range: Option<RangeInclusive<u16>>,
+
+    /// Carry packets over UDP, or over TCP where UDP is blocked. Clients have to use the same.
+    #[arg(long, value_enum, default_value_t = Transport::Udp)]
+    transport: Transport,
+
+    /// Traffic class (TOS byte) of outgoing packets, a number such as 0x28 or a DSCP name such
+    /// as ef, af41 or cs1. The ECN bits are left alone.
+    #[arg(long, value_name = "CLASS", value_parser = parse_traffic_class)]
+    traffic_class: Option<u8>,
+
+    /// Send each chunk with this much repair in percent, then more only while the client
+    /// still asks for it, instead of streaming repair until the client has the chunk. Saves
+    /// bandwidth on links losing less than PERCENT. `auto` follows the loss towards each client.
+    #[arg(long, value_name = "PERCENT", value_parser = parse_repair)]
+    fec_overhead: Option<RepairStrategy>,
+
+    /// How chunks are cut into RaptorQ blocks, trading decoder memory and speed for frames
+    /// needed. Clients follow the server's choice.
+    #[arg(long, value_enum, value_name = "PRESET", default_value_t = RaptorqPreset::Default)]
+    raptorq: RaptorqPreset,
+
+    /// Let the kernel pace outgoing packets with SO_TXTIME on this clock (Linux, needs an ETF or fq qdisc).
+    #[arg(long, value_enum, value_name = "CLOCK")]
+    txtime: Option<TxTimeClock>,
+
+    /// `lenient` lets packets off the spec in harmless ways through, counting them in `status`
+    /// on the control socket. For debugging against other implementations.
+    #[arg(long, value_enum, default_value_t = ParseMode::Strict)]
+    parse_mode: ParseMode,
+
+    /// Account the CPU time of encoders, decoders and socket tasks, shown in `status` on the
+    /// control socket. Costs a syscall around every poll of those tasks.
+    #[arg(long)]
+    cpu_accounting: bool,
+
+    /// Append a signed record of every accepted ticket to this file.
+    #[arg(long, value_name = "AUDIT_LOG", requires = "audit_key")]
+    audit_log: Option<PathBuf>,
+
+    /// The path to the hex private key signing the audit log.
+    #[arg(long, value_name = "KEY_FILE", requires = "audit_log")]
+    audit_key: Option<PathBuf>,
+
+    /// Receive the plan's file into DOWNLOAD_FOLDER from an authorized client pushing it with
+    /// `--upload`, instead of serving it. Chunks already in place are kept.
+    #[arg(long, requires = "private_key", conflicts_with_all = ["watch", "token_key"])]
+    accept_upload: bool,
+
+    /// The path to the hex private key tickets are signed with when accepting an upload.
+    /// Uploading clients are given its public key with `--server-key`.
+    #[arg(long, value_name = "KEY_FILE")]
+    private_key: Option<PathBuf>,
+
+    /// The path to the hex private key download tokens are minted with (see `usync token`).
+    /// Clients presenting a valid token for this file are served without an authorized key.
+    #[arg(long, value_name = "KEY_FILE")]
+    token_key: Option<PathBuf>,
+
+    /// Where per-key quota usage is kept across restarts.
+    #[arg(long, value_name = "QUOTA_STATE")]
+    quota_state: Option<PathBuf>,
+
+    /// Journal the sessions being served to this file, and resume the ones found in it on start,
+    /// so clients carry on right away after a restart.
+    #[arg(long, value_name = "JOURNAL")]
+    journal: Option<PathBuf>,
+
+    /// Pad every data packet to the same size and send each client packets at this many kbps,
+    /// cover packets when there is nothing to send, so on-path observers learn little about file
+    /// sizes and progress. Clients get no more than this rate of data.
+    #[arg(long, value_name = "KBPS")]
+    cover_rate: Option<u32>,
+
+    /// Send the chunks streaming to a client frame by frame in turn, evenly spaced, so they
+    /// complete one after the other instead of in bursts. Smooths the client's decoding load.
+    #[arg(long, conflicts_with = "cover_rate")]
+    interleave: bool,
+
+    /// Most encoders kept at once, each holding a chunk in memory. The least recently ordered is closed first.
+    #[arg(long, value_name = "ENCODERS", default_value_t = 16)]
+    max_encoders: usize,
+
+    /// Flag encoders and decoders idle for this many seconds in the debug output.
+    #[arg(long, value_name = "SECS", default_value_t = 120)]
+    leak_after: u64,
+
+    /// Also unregister the flagged encoders and decoders, ending their tasks.
+    #[arg(long)]
+    reap_leaks: bool,
+
+    /// Sessions served at once. Further clients are told to wait their turn.
+    #[arg(long, value_name = "SESSIONS")]
+    max_sessions: Option<usize>,
+
+    /// Total rate the served clients may ask for, in kbps. Further clients are told to wait their turn.
+    #[arg(long, value_name = "KBPS")]
+    max_total_rate: Option<u64>,
+
+    /// Rate of the link the clients share, in kbps. Clients asking for more than it together get
+    /// shares of it by the priority of their transfer, so background jobs yield to urgent ones.
+    #[arg(long, value_name = "KBPS")]
+    link_rate: Option<u64>,
+
+    /// Take tickets stamped up to this many seconds off our clock, so clients whose clocks drift
+    /// are still served. The skew measured for every client is logged.
+    #[arg(long, value_name = "SECS", default_value_t = 900)]
+    max_clock_skew: u64,
+
+    /// Poll the served file every SECS seconds (2 if not given). Once it changed, plan it again
+    /// as the next epoch, rewrite the plan file and serve the new chunks.
+    #[arg(long, value_name = "SECS", num_args = 0..=1, default_missing_value = "2")]
+    watch: Option<u64>,
+
+    /// Also serve the plan file over HTTP on this address at /plan, for clients that cannot get
+    /// UDP through, and the file itself at /file to holders of a download token.
+    #[arg(long, value_name = "LISTEN", conflicts_with = "accept_upload")]
+    http: Option<SocketAddr>,
+
+    /// Largest file served at /file, e.g. 64M. Larger ones are only served over UDP.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "64M")]
+    http_max_size: u64,
+
+    /// Speak HTTPS on --http with the certificate chain in this PEM file.
+    #[arg(long, value_name = "PEM_FILE", requires_all = ["http", "http_key"])]
+    http_cert: Option<PathBuf>,
+
+    /// The private key of --http-cert, in a PEM file.
+    #[arg(long, value_name = "PEM_FILE", requires = "http_cert")]
+    http_key: Option<PathBuf>,
+
+    /// Serve `status`, `peers`, `chunks`, `timings`, `set-rate`, `pause` and `resume` on a Unix socket, /run/usync.sock if no path is given.
+    #[arg(long, value_name = "SOCKET", num_args = 0..=1, default_missing_value = DEFAULT_CONTROL_SOCKET)]
+    control: Option<PathBuf>,
+
+    /// Pause the transfer on SIGTSTP (Ctrl-Z) instead of stopping the process, and resume
+    /// it on SIGCONT.
+    #[arg(long)]
+    pause_on_suspend: bool,
+
+    /// Keep the timelines of the chunks sent, from order to finish, as JSON in this file,
+    /// rewritten every 5 seconds.
+    #[arg(long, value_name = "JSON_FILE")]
+    timings: Option<PathBuf>,
+}
+
+// Stale chunks are marked unavailable, so requests for them get an error frame
+// instead of bytes that do not match the plan.
+fn validate_chunks(index: &ChunkIndex, file: &PathBuf, config: &FileConfig, spot_check: usize) {
+    for (chunk_id, err) in index.validate_lengths() {
+        eprintln!("Chunk {} {}: {err}", chunk_id, "is stale".red());
+    }
+
+    for chunk in config.chunks.choose_multiple(&mut rand::rng(), spot_check) {
+        let chunk_id = chunk.chunk_id;
+        if !index.is_available(chunk_id) {
+            continue;
+        }
+        match chunk_hash(file, chunk.offset, chunk.length) {
+            Ok(hash) if hash == chunk.hash => {}
+            Ok(hash) => {
+                index.mark_unavailable(chunk_id);
+                eprintln!(
+                    "Chunk {} {}. Expected {}, actual {}",
+                    chunk_id,
+                    "hash check failed".red(),
+                    chunk.hash.yellow(),
+                    hash.yellow()
+                );
+            }
+            Err(err) => {
+                index.mark_unavailable(chunk_id);
+                eprintln!("Chunk {} {}: {err}", chunk_id, "failed to read".red());
+            }
+        }
+    }
+
+    let available = config
+        .chunks
+        .iter()
+        .filter(|chunk| index.is_available(chunk.chunk_id))
+        .count();
+    println!("{}", i18n::chunks_available(available, config.chunks.len()));
+}
+
+// Waits for a client to offer the file of the plan, then fetches the chunks
+// that are missing or differ from it, like a client downloading them would.
+async fn accept_upload(socket: DynSocket, config: &FileConfig, file: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = file.parent() {
+        fs::create_dir_all(parent).context(Failure::Disk)?;
+    }
+    check_file_exist_create(file).context(Failure::Disk)?;
+    let missing: Vec<_> = config
+        .chunks
+        .iter()
+        .filter(|chunk| {
+            chunk_hash(file, chunk.offset, chunk.length).ok().as_ref() != Some(&chunk.hash)
+        })
+        .cloned()
+        .collect();
+    println!(
+        "{}",
+        i18n::chunks_to_receive(missing.len(), config.chunks.len())
+    );
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    println!("{}", i18n::waiting_for_upload(config.file_name.blue()));
+    let client =
+        await_offer::<_, TRANSMISSION_INFO_LENGTH>(&socket, file_id(&config.file_name)).await?;
+    println!("{}", i18n::receiving(file.display(), client.green()));
+    init_log("download.log".into());
+    let bus: Arc<Bus<BusAddress, BusMessage<TRANSMISSION_INFO_LENGTH>>> = Arc::new(Bus::default());
+    let receiver = receiving::ReceivingSocket::new(
+        socket,
+        bus.clone().register(BusAddress::ReceiverSocket).unwrap(),
+    )
+    .with_upcoming(missing.iter().map(|chunk| chunk.chunk_id).collect());
+    let mut receiver = tokio::spawn(receiver.run(client));
+    let download = DownloadManager::new(bus.clone(), file).run::<RaptorqReceiver>(missing);
+    let report = tokio::select! {
+        report = download => report,
+        Ok(Err(err)) = &mut receiver => {
+            return Err(tag(anyhow::anyhow!("Stopped, {err}. Rerun to resume."), Failure::of_session(&err)));
+        }
+    };
+    report.summary.print();
+    if !report.failed.is_empty() {
+        return Err(anyhow::anyhow!(
+            "{} chunks failed their hash check. Rerun to resume.",
+            report.failed.len()
+        ))
+        .context(Failure::Verification);
+    }
+    Ok(())
+}
+
+// Written aside and renamed over the plan, so followers never read half of it.
+// Where the plan file is in the chunk index.
+const PLAN_FILE_INDEX: usize = 1;
+
+// The chunks of a plan, and the plan file itself for clients given a usync:// URI.
+fn chunks_with_plan(
+    config: &FileConfig,
+    plan_length: usize,
+) -> HashMap<ChunkId, (usize, u64, usize)> {
+    let mut chunks = config.chunk_map();
+    chunks.insert(PLAN_CHUNK_ID, (PLAN_FILE_INDEX, 0, plan_length));
+    chunks
+}
+
+fn publish_plan(plan_file: &Path, plan: &str) -> anyhow::Result<()> {
+    let staged = plan_file.with_extension("toml.tmp");
+    fs::write(&staged, plan)?;
+    fs::rename(&staged, plan_file)?;
+    Ok(())
+}
+
+// Plans `file` again once it changed and stayed unchanged for a period, then
+// publishes the plan as the next epoch and serves its chunks.
+async fn watch_file(plan_file: PathBuf, file: PathBuf, mut config: FileConfig, period: Duration) {
+    let stamp = |file: &PathBuf| {
+        fs::metadata(file)
+            .and_then(|metadata| Ok((metadata.len(), metadata.modified()?)))
+            .ok()
+    };
+    let mut last_stamp = stamp(&file);
+    let mut settling = false;
+    let mut ticker = tokio::time::interval(period);
+    loop {
+        ticker.tick().await;
+        let current = stamp(&file);
+        if current != last_stamp {
+            // Peers are refused until the file is planned again.
+            CHUNK_INDEX.get().unwrap().mark_changing();
+            (last_stamp, settling) = (current, true);
+            continue;
+        }
+        if !settling {
+            continue;
+        }
+        settling = false;
+
+        let replanned = tokio::task::spawn_blocking({
+            let file = file.clone();
+            let config = config.clone();
+            // Not knowing what changed, every chunk is hashed again.
+            move || replan(&file, &config, None)
+        })
+        .await;
+        let new_config = match replanned {
+            Ok(Ok(new_config)) => new_config,
+            Ok(Err(err)) => {
+                eprintln!("Failed to plan {} again: {err}", file.display());
+                continue;
+            }
+            Err(err) => {
+                eprintln!("Planning task failed: {err}");
+                continue;
+            }
+        };
+        let changed = new_config
+            .chunks
+            .iter()
+            .filter(|chunk| {
+                config.chunks.get(chunk.chunk_id.index()).is_none_or(|old| {
+                    (old.offset, old.length, &old.hash) != (chunk.offset, chunk.length, &chunk.hash)
+                })
+            })
+            .count();
+        let plan = match toml::to_string_pretty(&new_config) {
+            Ok(plan) => plan,
+            Err(err) => {
+                eprintln!("Failed to write the plan of {}: {err}", file.display());
+                continue;
+            }
+        };
+        // Served before published, so a follower never asks for an epoch not served yet.
+        CHUNK_INDEX.get().unwrap().replace_chunks(
+            new_config.epoch,
+            chunks_with_plan(&new_config, plan.len()),
+            new_config.chunk_hashes(),
+        );
+        if let Err(err) = publish_plan(&plan_file, &plan) {
+            eprintln!("Failed to publish {}: {err}", plan_file.display());
+            continue;
+        }
+        println!(
+            "{}",
+            i18n::published_epoch(
+                new_config.epoch.green(),
+                file.display(),
+                changed.yellow(),
+                new_config.chunks.len()
+            )
+        );
+        config = new_config;
+    }
+}
+
+// The socket packets go out and come in on. Marking and kernel pacing only apply to UDP.
+async fn bind_socket(
+    transport: Transport,
+    listening: SocketAddr,
+    port_range: Option<RangeInclusive<u16>>,
+    traffic_class: Option<u8>,
+    txtime: Option<TxTimeClock>,
+) -> anyhow::Result<(DynSocket, bool)> {
+    if transport == Transport::Tcp {
+        let socket = TcpDatagramSocket::bind(listening).await?;
+        let local_addr = socket.local_addr()?;
+        println!(
+            "{}",
+            i18n::listening(local_addr.green(), "TCP", local_addr.port())
+        );
+        return Ok((Box::new(socket), false));
+    }
+    let mut socket = RealUdpSocket::bind_with_fallback(listening, None, port_range).await?;
+    let local_addr = socket.local_addr()?;
+    if let Some(class) = traffic_class
+        && let Err(err) = socket.set_traffic_class(class)
+    {
+        eprintln!("Failed to set the traffic class: {err}");
+    }
+    if let Err(err) = socket.enable_ecn() {
+        eprintln!("ECN unavailable, congestion is only detected by loss: {err}");
+    }
+    if socket.local_addr()?.is_ipv6()
+        && let Err(err) = socket.enable_flow_labels()
+    {
+        eprintln!("Flow labels unavailable, leaving them to the kernel: {err}");
+    }
+    println!(
+        "{}",
+        i18n::listening(local_addr.green(), "UDP", local_addr.port())
+    );
+    let kernel_pacing = match txtime {
+        Some(clock) => match socket.enable_txtime(clock) {
+            Ok(()) => true,
+            Err(err) => {
+                eprintln!("SO_TXTIME unavailable, pacing in userspace: {err}");
+                false
+            }
+        },
+        None => false,
+    };
+    Ok((Box::new(socket), kernel_pacing))
 }
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> ExitCode {
+    exit_code(run().await)
+}
+
+async fn run() -> anyhow::Result<()> {
     debug_assert!(
         false,
         "Run in release mode instead for raptorq is too slow in debug mode."
     );
 
     let args = Args::parse();
+    set_parse_mode(args.parse_mode);
+    set_params(args.raptorq.params());
+    if args.cpu_accounting {
+        cpu::enable();
+    }
 
-    let public_key_file = File::open(args.public_key).unwrap();
+    let public_key_file = File::open(args.public_key).context(Failure::Disk)?;
     let lines = std::io::BufReader::new(public_key_file)
         .lines()
         .collect::<Result<Vec<_>, _>>()
-        .unwrap();
-    init(lines, None);
+        .context(Failure::Disk)?;
+    let mut public_keys = vec![];
+    let mut limits = HashMap::new();
+    for line in lines.iter().filter(|line| !line.trim().is_empty()) {
+        let (key, quota) = parse_key_line(line)
+            .map_err(anyhow::Error::msg)
+            .context(Failure::Auth)?;
+        if quota != Quota::default() {
+            limits.insert(key.clone(), quota);
+        }
+        public_keys.push(key);
+    }
+    let private_keys = match args.private_key.as_ref() {
+        Some(path) => {
+            let key = fs::read_to_string(path).context(Failure::Disk)?;
+            vec![key.trim().to_string()]
+        }
+        None => vec![],
+    };
+    init(public_keys, private_keys);
+    let quotas = QuotaBook::load(limits, args.quota_state).context(Failure::Disk)?;
 
-    let toml_str = fs::read_to_string(&args.plan_file)?;
-    let config: FileConfig = toml::from_str(&toml_str)?;
+    let toml_str = fs::read_to_string(&args.plan_file).context(Failure::BadPlan)?;
+    let config: FileConfig = toml::from_str(&toml_str).context(Failure::BadPlan)?;
+    config
+        .check_file_name()
+        .map_err(|err| anyhow::anyhow!("{err}."))
+        .context(Failure::BadPlan)?;
+    // Planning a sealed file again takes its content key, which the server never holds.
+    if config.sealed.is_some() && (args.watch.is_some() || args.accept_upload) {
+        return Err(anyhow::anyhow!(
+            "A sealed file can be neither watched nor uploaded to."
+        ));
+    }
 
-    let downloading_file = args.folder.join(config.file_name);
-    println!("Downloading file: {}", downloading_file.display());
+    let downloading_file = args.folder.join(&config.file_name);
+    if args.accept_upload {
+        let (socket, _) = bind_socket(
+            args.transport,
+            args.listening,
+            args.port_range,
+            args.traffic_class,
+            None,
+        )
+        .await?;
+        return accept_upload(socket, &config, &downloading_file).await;
+    }
+    let tokens = match args.token_key.as_ref() {
+        Some(token_key) => {
+            let key = parse_signing_key(&fs::read_to_string(token_key).context(Failure::Disk)?)
+                .ok_or_else(|| anyhow::anyhow!("{} is not a hex private key", token_key.display()))
+                .context(Failure::Auth)?;
+            Some(TokenChecker::new(
+                key.verifying_key(),
+                file_id(&config.file_name),
+            ))
+        }
+        None => None,
+    };
+    println!("{}", i18n::downloading_file(downloading_file.display()));
 
-    check_file_exist(&downloading_file)?;
-    println!("{} already exists.", downloading_file.display());
+    check_file_exist(&downloading_file).context(Failure::Disk)?;
+    println!("{}", i18n::already_exists(downloading_file.display()));
 
+    let mut index = ChunkIndex::new(
+        HashMap::from([
+            (0usize, OsString::from(&downloading_file)),
+            (PLAN_FILE_INDEX, OsString::from(&args.plan_file)),
+        ]),
+        chunks_with_plan(&config, toml_str.len()),
+    )
+    .with_lock_pages(args.mlock)
+    .with_epoch(config.epoch);
+    if args.verify_chunks {
+        index = index.with_verification(config.chunk_hashes());
+    }
     CHUNK_INDEX
-        .set(ChunkIndex {
-            files: HashMap::from([(0usize, OsString::from(downloading_file))]),
-            chunks: HashMap::from_iter(
-                config
-                    .chunks
-                    .iter()
-                    .map(|chunk| (chunk.chunk_id as u32, (0usize, chunk.offset, chunk.length))),
-            ),
-        })
+        .set(index)
         .map_err(|_| "Failed to init OnceLock")
         .unwrap();
 
+    validate_chunks(
+        CHUNK_INDEX.get().unwrap(),
+        &downloading_file,
+        &config,
+        args.spot_check,
+    );
+
+    // Scrubbing checks against the hashes of the first plan only.
+    if args.scrub_rate > 0 && args.watch.is_some() {
+        eprintln!("Not scrubbing, chunks change along with the watched file.");
+    } else if args.scrub_rate > 0 {
+        let expected = config
+            .chunks
+            .iter()
+            .map(|chunk| (chunk.chunk_id, chunk.hash.clone()))
+            .collect();
+        let scrubber = Scrubber::new(
+            CHUNK_INDEX.get().unwrap(),
+            expected,
+            args.scrub_rate * 1024 * 1024,
+        );
+        tokio::spawn(scrubber.run());
+    }
+
+    if let Some(period) = args.watch {
+        tokio::spawn(watch_file(
+            args.plan_file.clone(),
+            downloading_file.clone(),
+            config,
+            Duration::from_secs(period),
+        ));
+    }
+
+    if let Some(listen) = args.http {
+        let mut gateway = HttpGateway::new(&args.plan_file, &downloading_file)
+            .with_max_file_size(args.http_max_size);
+        if let Some(tokens) = tokens.clone() {
+            gateway = gateway.with_tokens(tokens);
+        }
+        if let (Some(cert), Some(key)) = (args.http_cert.as_ref(), args.http_key.as_ref()) {
+            gateway = gateway.with_tls(cert, key)?;
+        }
+        let listener = TcpListener::bind(listen).await?;
+        println!("{}", i18n::http_gateway(listener.local_addr()?.green()));
+        tokio::spawn(gateway.serve(listener));
+    }
+
     init_log("upload.log".into());
+    if let (Some(audit_log), Some(audit_key)) = (args.audit_log, args.audit_key) {
+        let key = parse_signing_key(&fs::read_to_string(&audit_key).context(Failure::Disk)?)
+            .ok_or_else(|| anyhow::anyhow!("{} is not a hex private key", audit_key.display()))
+            .context(Failure::Auth)?;
+        init_audit(audit_log, key)?;
+    }
 
     let bus: Arc<Bus<BusAddress, BusMessage<TRANSMISSION_INFO_LENGTH>>> = Arc::new(Bus::default());
-    let socket = RealUdpSocket::bind(args.listening).await.unwrap();
-    let sender =
-        sending::SendingSocket::new(socket, bus.clone().register(BusAddress::SenderSocket));
-    tokio::spawn(sender.run::<RaptorqSender>());
+    let (socket, kernel_pacing) = bind_socket(
+        args.transport,
+        args.listening,
+        args.port_range,
+        args.traffic_class,
+        args.txtime,
+    )
+    .await?;
+    let control = ControlState::new("server");
+    if let Some(path) = args.control {
+        let socket = ControlSocket::bind(&path).await?;
+        println!("{}", i18n::control_socket(path.display()));
+        tokio::spawn(socket.serve(control.clone(), bus.clone()));
+    }
+    if args.pause_on_suspend {
+        handle_pause_signals(control.clone())?;
+    }
+    let mut sender = sending::SendingSocket::new(
+        socket,
+        bus.clone().register(BusAddress::SenderSocket).unwrap(),
+    )
+    .with_kernel_pacing(kernel_pacing)
+    .with_quotas(quotas)
+    .with_max_encoders(args.max_encoders)
+    .with_interleaving(args.interleave)
+    .with_control(control);
+    if let Some(tokens) = tokens {
+        sender = sender.with_tokens(tokens);
+    }
+    if let Some(kbps) = args.cover_rate {
+        sender = sender.with_cover_traffic(kbps);
+    }
+    sender = sender.with_max_clock_skew(Duration::from_secs(args.max_clock_skew));
+    if let Some(kbps) = args.link_rate {
+        sender = sender.with_link_rate(kbps);
+    }
+    if let Some(repair) = args.fec_overhead {
+        sender = sender.with_repair(repair);
+    }
+    if let Some(path) = args.journal.clone() {
+        let journal = SessionJournal::load(path).context(Failure::Disk)?;
+        println!("{}", i18n::resuming_sessions(journal.sessions().count()));
+        sender = sender.with_journal(journal);
+    }
+    if args.max_sessions.is_some() || args.max_total_rate.is_some() {
+        sender = sender.with_admission(AdmissionLimits {
+            max_sessions: args.max_sessions,
+            max_total_kbps: args.max_total_rate,
+        });
+    }
+    tokio::spawn(SenderStats::new(bus.clone().register(BusAddress::SenderStats).unwrap()).run());
+    let mut sender = tokio::spawn(sender.run::<RaptorqSender>());
+    let mut interrupted = std::pin::pin!(tokio::signal::ctrl_c());
     loop {
-        tokio::time::sleep(Duration::from_secs(5)).await;
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+            _ = &mut interrupted => {
+                // Dropping the sender saves the quota usage it has not saved yet.
+                sender.abort();
+                let _ = sender.await;
+                return Ok(());
+            }
+            stopped = &mut sender => {
+                return match stopped {
+                    Ok(Ok(())) => Ok(()),
+                    Ok(Err(failure)) => Err(anyhow::anyhow!("Stopped serving, {failure}.")),
+                    Err(err) => Err(anyhow::anyhow!("Sender task failed: {err}")),
+                };
+            }
+        }
         bus.debug();
+        if let Some(path) = args.timings.as_ref()
+            && let Err(err) = telemetry().dump(path)
+        {
+            eprintln!("Failed to write timings to {}: {err}", path.display());
+        }
+        bus.detect_leaks(
+            Duration::from_secs(args.leak_after),
+            BusAddress::is_coder,
+            args.reap_leaks,
+        );
     }
 }
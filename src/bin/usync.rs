@@ -0,0 +1,464 @@
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use ed25519_dalek::VerifyingKey;
+use owo_colors::OwoColorize;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::SocketAddr;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Command as Process, ExitCode, ExitStatus};
+use usync::engine::control::DEFAULT_CONTROL_SOCKET;
+use usync::protocol::token::{DownloadToken, file_id};
+use usync::protocol::wire::layout::{FieldEncoding, describe};
+use usync::util::audit::{parse_signing_key, read_audit_log};
+use usync::util::exit::{Failure, exit_code, tag};
+use usync::util::i18n;
+use usync::util::log::current_timestamp_ms;
+use usync::util::plan::{FileConfig, parse_size};
+use usync::util::sync::{ConflictPolicy, SyncAction, decide, load_plans};
+use usync::util::uri::PlanUri;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Tools for operating usync servers", long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Query the audit log written by the server.
+    Audit {
+        /// The path to the audit log.
+        #[arg(short, long, value_name = "AUDIT_LOG")]
+        log: PathBuf,
+
+        /// Hex public key of the audit key. The signature chain is checked if given.
+        #[arg(short, long, value_name = "PUB_KEY")]
+        verify: Option<String>,
+
+        /// Only show tickets signed by this client key (hex).
+        #[arg(short, long, value_name = "CLIENT_KEY")]
+        client: Option<String>,
+
+        /// Only show tickets at or after this unix timestamp (ms).
+        #[arg(long, value_name = "MS")]
+        since: Option<u64>,
+
+        /// Only show tickets before this unix timestamp (ms).
+        #[arg(long, value_name = "MS")]
+        until: Option<u64>,
+    },
+    /// Send a command to the control socket of a running client or server.
+    Control {
+        /// The path to the control socket.
+        #[arg(short, long, value_name = "SOCKET", default_value = DEFAULT_CONTROL_SOCKET)]
+        socket: PathBuf,
+
+        /// `status`, `peers`, `chunks`, `timings [CHUNK]`, `set-rate <KBPS>` (0 resets the rate),
+        /// `pause` or `resume`.
+        #[arg(required = true, num_args = 1..)]
+        command: Vec<String>,
+    },
+    /// Download the file of a plan with the client, trying each server the plan suggests in
+    /// turn until one finishes it.
+    Get {
+        /// The path to the plan file.
+        plan: PathBuf,
+
+        /// Passed on to the client, e.g. `-- --private-key KEY`. With --server, only that
+        /// server is tried.
+        #[arg(last = true)]
+        client_args: Vec<String>,
+    },
+    /// Mint a download token for the file of a plan, to hand out instead of authorizing a key.
+    Token {
+        /// The path to the hex private key given to the server with `--token-key`.
+        #[arg(short, long, value_name = "KEY_FILE")]
+        key: PathBuf,
+
+        /// The path to the plan file the server serves.
+        #[arg(short, long, value_name = "PLAN_FILE")]
+        plan: PathBuf,
+
+        /// How long the token can be used for, in seconds.
+        #[arg(long, value_name = "SECS", default_value_t = 86400)]
+        valid_for: u64,
+
+        /// Serve no more than this many bytes for the token, e.g. `2G`.
+        #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+        max_bytes: Option<u64>,
+    },
+    /// Compare the plans of the local and the server's copies, then pull or push every file
+    /// whose copies differ, running the client for each.
+    Sync {
+        /// Plan of the local copy, or a directory of plans from `planner --out-dir`.
+        #[arg(long, value_name = "PLANS")]
+        local: PathBuf,
+
+        /// Plan the server published, or a directory of them.
+        #[arg(long, value_name = "PLANS")]
+        remote: PathBuf,
+
+        /// The folder holding the local copy of the planned files.
+        #[arg(short, long, value_name = "FOLDER")]
+        folder: PathBuf,
+
+        /// Socket Addr of the server, serving each pulled file and accepting each pushed one in turn.
+        #[arg(short, long, value_name = "SERVER")]
+        server: SocketAddr,
+
+        /// Which copy is kept when both differ.
+        #[arg(long, value_enum, default_value_t = ConflictPolicy::NewestWins)]
+        policy: ConflictPolicy,
+
+        /// Only print what would be transferred.
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Passed on to every client run, e.g. `-- --private-key KEY --server-key PUB_KEY`.
+        #[arg(last = true)]
+        client_args: Vec<String>,
+    },
+    /// Print a usync:// URI for the file of a plan, to hand out instead of the plan file.
+    Uri {
+        /// The path to the plan file the server serves.
+        #[arg(short, long, value_name = "PLAN_FILE")]
+        plan: PathBuf,
+
+        /// Host and port clients reach the server at, e.g. `files.example.com:7234`.
+        #[arg(short, long, value_name = "HOST:PORT")]
+        server: String,
+
+        /// A download token, or a hex private key the server authorizes, to put in the URI.
+        #[arg(long, value_name = "KEY")]
+        key: Option<String>,
+    },
+    /// Inspect the wire protocol.
+    Protocol {
+        #[command(subcommand)]
+        command: ProtocolCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ProtocolCommand {
+    /// Print the field-by-field layout of every packet and frame header.
+    Describe {
+        /// Print JSON instead of tables.
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+fn parse_verifying_key(key: &str) -> anyhow::Result<VerifyingKey> {
+    let mut bytes = [0u8; 32];
+    hex::decode_to_slice(key, &mut bytes)?;
+    Ok(VerifyingKey::from_bytes(&bytes)?)
+}
+
+fn print_layouts(json: bool) -> anyhow::Result<()> {
+    let layouts = describe();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&layouts)?);
+        return Ok(());
+    }
+    println!("A packet is the common packet header, the packet header selected by packet_type,");
+    println!("its frames, then the verification field. A frame is the common frame header,");
+    println!("the frame header selected by frame_type, then its body up to frame_length.");
+    for layout in layouts {
+        println!();
+        match layout.type_code {
+            Some(code) => println!(
+                "{} (type 0x{code:02x}, {} bytes)",
+                layout.name.bold(),
+                layout.size
+            ),
+            None => println!("{} ({} bytes)", layout.name.bold(), layout.size),
+        }
+        for field in layout.fields.iter() {
+            let encoding = match field.encoding {
+                FieldEncoding::BigEndian => format!("u{} big endian", field.size * 8),
+                FieldEncoding::Byte => String::from("u8"),
+                FieldEncoding::Bytes => format!("[u8; {}]", field.size),
+            };
+            println!(
+                "  {:>4}  {:<24} {}",
+                field.offset,
+                field.name.blue(),
+                encoding
+            );
+        }
+        if let Some(verification) = layout.verification {
+            println!("  Verified by {verification}.");
+        }
+    }
+    Ok(())
+}
+
+fn mint_token(
+    key: &Path,
+    plan: &Path,
+    valid_for: u64,
+    max_bytes: Option<u64>,
+) -> anyhow::Result<()> {
+    let issuer = parse_signing_key(&std::fs::read_to_string(key).context(Failure::Disk)?)
+        .ok_or_else(|| anyhow::anyhow!("{} is not a hex private key", key.display()))
+        .context(Failure::Auth)?;
+    let config = load_plan(plan)?;
+    let token = DownloadToken::mint(
+        &issuer,
+        file_id(&config.file_name),
+        current_timestamp_ms() + valid_for * 1000,
+        max_bytes,
+    );
+    println!("{}", token.encode());
+    Ok(())
+}
+
+fn load_plan(plan: &Path) -> anyhow::Result<FileConfig> {
+    let plan = std::fs::read_to_string(plan).context(Failure::BadPlan)?;
+    let plan: FileConfig = toml::from_str(&plan).context(Failure::BadPlan)?;
+    plan.check_file_name()
+        .map_err(|err| anyhow::anyhow!("{err}."))
+        .context(Failure::BadPlan)?;
+    Ok(plan)
+}
+
+// The kind of failure a client exited with, as its exit code tells.
+fn client_failure(status: ExitStatus) -> Option<Failure> {
+    status.code().and_then(Failure::from_code)
+}
+
+// Exits as the last client run did, so wrappers of `get` can branch on it too.
+fn get(plan: &Path, client_args: &[String]) -> anyhow::Result<()> {
+    let config = load_plan(plan)?;
+    let client = std::env::current_exe()?.with_file_name("client");
+    let given = client_args
+        .iter()
+        .any(|arg| arg == "-s" || arg == "--server" || arg.starts_with("--server="));
+    let servers = match (given, config.hints.as_ref()) {
+        (false, Some(hints)) if !hints.servers.is_empty() => hints.servers.clone(),
+        // Left to the client, which names what is missing.
+        _ => vec![],
+    };
+
+    let run = |server: Option<SocketAddr>| -> anyhow::Result<ExitStatus> {
+        let mut command = Process::new(&client);
+        command.arg("--plan-file").arg(plan);
+        if let Some(server) = server {
+            command.arg("--server").arg(server.to_string());
+        }
+        Ok(command.args(client_args).status()?)
+    };
+    if servers.is_empty() {
+        let status = run(None)?;
+        return match status.success() {
+            true => Ok(()),
+            false => Err(tag(
+                anyhow::anyhow!("Download of {} failed.", config.file_name),
+                client_failure(status),
+            )),
+        };
+    }
+    // Chunks fetched from one server are kept, the next one only sends the rest.
+    let mut failure = None;
+    for server in servers.iter() {
+        println!("{} {server}", i18n::trying().blue());
+        let status = run(Some(*server))?;
+        if status.success() {
+            return Ok(());
+        }
+        failure = client_failure(status);
+    }
+    Err(tag(
+        anyhow::anyhow!(
+            "None of the {} suggested servers finished {}.",
+            servers.len(),
+            config.file_name
+        ),
+        failure,
+    ))
+}
+
+struct SyncArgs {
+    local: PathBuf,
+    remote: PathBuf,
+    folder: PathBuf,
+    server: SocketAddr,
+    policy: ConflictPolicy,
+    dry_run: bool,
+    client_args: Vec<String>,
+}
+
+fn sync(args: SyncArgs) -> anyhow::Result<()> {
+    let local = load_plans(&args.local).context(Failure::BadPlan)?;
+    let remote = load_plans(&args.remote).context(Failure::BadPlan)?;
+    let client = std::env::current_exe()?.with_file_name("client");
+    let mut file_names: Vec<&String> = local.keys().chain(remote.keys()).collect();
+    file_names.sort();
+    file_names.dedup();
+
+    let mut failed = vec![];
+    for file_name in file_names {
+        let (local, remote) = (local.get(file_name), remote.get(file_name));
+        let action = decide(
+            local.map(|(_, plan)| plan),
+            remote.map(|(_, plan)| plan),
+            args.policy,
+        );
+        let plan_file = match action {
+            SyncAction::Skip => continue,
+            SyncAction::Conflict => {
+                println!("{} {file_name}", i18n::conflict().red());
+                failed.push(file_name);
+                continue;
+            }
+            SyncAction::Pull => remote.map(|(path, _)| path),
+            SyncAction::Push => local.map(|(path, _)| path),
+        };
+        let Some(plan_file) = plan_file else {
+            continue;
+        };
+        let push = action == SyncAction::Push;
+        match push {
+            true => println!("{} {file_name}", i18n::push().yellow()),
+            false => println!("{} {file_name}", i18n::pull().blue()),
+        }
+        if args.dry_run {
+            continue;
+        }
+
+        let mut command = Process::new(&client);
+        command
+            .arg("--plan-file")
+            .arg(plan_file)
+            .arg("--server")
+            .arg(args.server.to_string())
+            .arg("--downloading-file")
+            .arg(args.folder.join(file_name))
+            .args(&args.client_args);
+        if push {
+            command.arg("--upload");
+        }
+        if !command.status()?.success() {
+            failed.push(file_name);
+        }
+    }
+    if !failed.is_empty() {
+        return Err(anyhow::anyhow!(
+            "{} files left out of sync: {failed:?}",
+            failed.len()
+        ));
+    }
+    Ok(())
+}
+
+fn send_control(socket: &Path, command: &[String]) -> anyhow::Result<()> {
+    let mut stream = UnixStream::connect(socket)
+        .map_err(|err| anyhow::anyhow!("Cannot connect to {}: {err}", socket.display()))?;
+    writeln!(stream, "{}", command.join(" "))?;
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    let reply: serde_json::Value = serde_json::from_str(&reply)?;
+    if let Some(error) = reply.get("error").and_then(|error| error.as_str()) {
+        return Err(anyhow::anyhow!("{error}"));
+    }
+    println!("{}", serde_json::to_string_pretty(&reply)?);
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    exit_code(run())
+}
+
+fn run() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    match args.command {
+        Command::Audit {
+            log,
+            verify,
+            client,
+            since,
+            until,
+        } => {
+            let key = verify.as_deref().map(parse_verifying_key).transpose()?;
+            // Lines that do not parse or check out are invalid data.
+            let records = read_audit_log(&log, key.as_ref()).map_err(|err| {
+                let failure = match err.kind() {
+                    ErrorKind::InvalidData => Failure::Verification,
+                    _ => Failure::Disk,
+                };
+                anyhow::Error::from(err).context(failure)
+            })?;
+            if key.is_some() {
+                println!(
+                    "{} {}",
+                    i18n::verified().green(),
+                    i18n::records(records.len())
+                );
+            }
+
+            let mut served: BTreeMap<String, (usize, u64)> = BTreeMap::new();
+            for record in records.iter().filter(|record| {
+                client
+                    .as_ref()
+                    .is_none_or(|client| record.pub_key == *client)
+                    && since.is_none_or(|since| record.timestamp_ms >= since)
+                    && until.is_none_or(|until| record.timestamp_ms < until)
+            }) {
+                println!(
+                    "{} {} {} chunks {:?} served {}",
+                    record.timestamp_ms,
+                    record.pub_key.blue(),
+                    record.addr,
+                    record.chunk_ids,
+                    record.bytes_served.yellow()
+                );
+                let entry = served.entry(record.pub_key.clone()).or_default();
+                entry.0 += 1;
+                entry.1 += record.bytes_served;
+            }
+
+            for (pub_key, (tickets, bytes)) in served {
+                println!("{}", i18n::served_to_key(pub_key.blue(), tickets, bytes));
+            }
+        }
+        Command::Control { socket, command } => send_control(&socket, &command)?,
+        Command::Get { plan, client_args } => get(&plan, &client_args)?,
+        Command::Token {
+            key,
+            plan,
+            valid_for,
+            max_bytes,
+        } => mint_token(&key, &plan, valid_for, max_bytes)?,
+        Command::Sync {
+            local,
+            remote,
+            folder,
+            server,
+            policy,
+            dry_run,
+            client_args,
+        } => sync(SyncArgs {
+            local,
+            remote,
+            folder,
+            server,
+            policy,
+            dry_run,
+            client_args,
+        })?,
+        Command::Uri { plan, server, key } => {
+            // Hashed as served, byte for byte.
+            let plan = std::fs::read(plan).context(Failure::BadPlan)?;
+            println!("{}", PlanUri::new(&server, &plan, key));
+        }
+        Command::Protocol {
+            command: ProtocolCommand::Describe { json },
+        } => print_layouts(json)?,
+    }
+    Ok(())
+}
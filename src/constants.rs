@@ -11,3 +11,6 @@ pub const PRI_KEY_LENGTH: usize = 32;
 pub const SIGNATURE_LENGTH: usize = 32;
 
 pub const TRANSMISSION_INFO_LENGTH: usize = 12;
+
+pub const COOKIE_LENGTH: usize = 16;
+pub const UNDER_LOAD_TICKETS_PER_SEC: usize = 512;
@@ -0,0 +1,162 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use tokio::time::{Duration, Instant};
+
+// A session ends once its peer sends no tickets for this long.
+const SESSION_IDLE: Duration = Duration::from_secs(5);
+// Waiting peers are told to come back after this much per place ahead of them.
+const RETRY_AFTER_PER_PLACE: Duration = Duration::from_secs(2);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AdmissionLimits {
+    pub max_sessions: Option<usize>,
+    // Sum of the rates admitted peers ask for.
+    pub max_total_kbps: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Admitted,
+    // `position` is 1 for the next peer to be admitted.
+    Queued {
+        position: usize,
+        retry_after: Duration,
+    },
+}
+
+// Admits peers until the limits are reached, then queues new ones in arrival
+// order instead of splitting the link ever thinner.
+pub struct AdmissionQueue {
+    limits: AdmissionLimits,
+    // Last ticket and requested rate of every admitted peer.
+    active: HashMap<SocketAddr, (Instant, u32)>,
+    // Peers in order, with when they lose their place unless they ask again.
+    waiting: VecDeque<(SocketAddr, Instant)>,
+}
+
+impl AdmissionQueue {
+    pub fn new(limits: AdmissionLimits) -> Self {
+        Self {
+            limits,
+            active: HashMap::new(),
+            waiting: VecDeque::new(),
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.active
+            .retain(|_, (seen, _)| now - *seen < SESSION_IDLE);
+        self.waiting.retain(|(_, expires)| *expires > now);
+    }
+
+    fn has_room(&self, kbps: u32) -> bool {
+        if self.active.is_empty() {
+            return true;
+        }
+        let total: u64 = self.active.values().map(|(_, kbps)| *kbps as u64).sum();
+        self.limits
+            .max_sessions
+            .is_none_or(|max| self.active.len() < max)
+            && self
+                .limits
+                .max_total_kbps
+                .is_none_or(|max| total + kbps as u64 <= max)
+    }
+
+    pub fn admit(&mut self, peer: SocketAddr, kbps: u32, now: Instant) -> Admission {
+        self.expire(now);
+        if let Some(session) = self.active.get_mut(&peer) {
+            *session = (now, kbps);
+            return Admission::Admitted;
+        }
+        let first_waiting = self.waiting.front().is_none_or(|(first, _)| *first == peer);
+        if first_waiting && self.has_room(kbps) {
+            self.waiting.pop_front();
+            self.active.insert(peer, (now, kbps));
+            return Admission::Admitted;
+        }
+
+        let index = match self
+            .waiting
+            .iter()
+            .position(|(waiting, _)| *waiting == peer)
+        {
+            Some(index) => index,
+            None => {
+                self.waiting.push_back((peer, now));
+                self.waiting.len() - 1
+            }
+        };
+        let retry_after = (RETRY_AFTER_PER_PLACE * (index as u32 + 1)).min(MAX_RETRY_AFTER);
+        self.waiting[index].1 = now + retry_after + SESSION_IDLE;
+        Admission::Queued {
+            position: index + 1,
+            retry_after,
+        }
+    }
+
+    // A session the server had admitted before it restarted.
+    pub fn resume(&mut self, peer: SocketAddr, kbps: u32, now: Instant) {
+        self.waiting.retain(|(waiting, _)| *waiting != peer);
+        self.active.insert(peer, (now, kbps));
+    }
+
+    pub fn remove(&mut self, peer: SocketAddr) {
+        self.active.remove(&peer);
+        self.waiting.retain(|(waiting, _)| *waiting != peer);
+    }
+
+    pub fn waiting(&self) -> Vec<SocketAddr> {
+        self.waiting.iter().map(|(peer, _)| *peer).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(n: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, n], 7000))
+    }
+
+    #[test]
+    fn queue_beyond_limits_in_arrival_order() {
+        let mut queue = AdmissionQueue::new(AdmissionLimits {
+            max_sessions: Some(2),
+            max_total_kbps: Some(100_000),
+        });
+        let now = Instant::now();
+        assert_eq!(queue.admit(peer(1), 40_000, now), Admission::Admitted);
+        // Over the bandwidth limit, though a session is free.
+        assert_eq!(
+            queue.admit(peer(2), 80_000, now),
+            Admission::Queued {
+                position: 1,
+                retry_after: RETRY_AFTER_PER_PLACE,
+            }
+        );
+        // Fits, but must not jump the queue.
+        assert!(matches!(
+            queue.admit(peer(3), 1_000, now),
+            Admission::Queued { position: 2, .. }
+        ));
+        assert_eq!(queue.waiting(), vec![peer(2), peer(3)]);
+
+        // Peer 1 goes idle, peer 2 and then 3 get in.
+        let later = now + SESSION_IDLE;
+        assert_eq!(queue.admit(peer(2), 80_000, later), Admission::Admitted);
+        assert_eq!(queue.admit(peer(3), 1_000, later), Admission::Admitted);
+        assert!(matches!(
+            queue.admit(peer(4), 1_000, later),
+            Admission::Queued { position: 1, .. }
+        ));
+
+        // A waiting peer that does not come back loses its place.
+        let much_later = later + MAX_RETRY_AFTER + SESSION_IDLE;
+        queue.admit(peer(2), 80_000, much_later - Duration::from_secs(1));
+        queue.remove(peer(3));
+        queue.expire(much_later);
+        assert!(queue.waiting().is_empty());
+    }
+}
@@ -1,16 +1,117 @@
 use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
 use owo_colors::OwoColorize;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::{fmt::Debug, hash::Hash};
+use tokio::sync::oneshot;
+use tokio::time::{Duration, Instant};
+
+use super::supervisor::{Supervisor, TaskHealth};
 // use tokio::sync::mpsc::{self, Receiver, Sender};
 use flume::{Receiver, Sender};
 
+const REQUEST_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+// A message that expects an answer. The oneshot channel travels with the request,
+// so the reply always reaches the exact caller that asked.
+#[derive(Debug)]
+pub struct Request<Q, A> {
+    pub body: Q,
+    responder: oneshot::Sender<A>,
+}
+
+impl<Q, A> Request<Q, A> {
+    // Returns the answer back if the requester has given up waiting.
+    pub fn reply(self, answer: A) -> Result<(), A> {
+        self.responder.send(answer)
+    }
+}
+
+// An address registered twice would leave the first registration's receiver
+// without messages, e.g. a second encoder for the same chunk and peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressTaken<ADDRESS>(pub ADDRESS);
+
+impl<ADDRESS: Debug> std::fmt::Display for AddressTaken<ADDRESS> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} is already registered on the bus", self.0)
+    }
+}
+
+impl<ADDRESS: Debug> std::error::Error for AddressTaken<ADDRESS> {}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RequestError {
+    Unreachable,
+    Timeout,
+    Dropped,
+}
+
+// Kept per registration, shared by the bus and the interface.
+#[derive(Debug)]
+struct Activity {
+    registered: Instant,
+    // Since `registered`.
+    last_active_ms: AtomicU64,
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            registered: Instant::now(),
+            last_active_ms: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+        })
+    }
+
+    fn touch(&self) {
+        let now = self.registered.elapsed().as_millis() as u64;
+        self.last_active_ms.fetch_max(now, Ordering::Relaxed);
+    }
+}
+
+// What an address did since it registered. It is active when it sends, is
+// sent to or reads its queue.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerActivity {
+    pub age: Duration,
+    pub idle: Duration,
+    pub sent: u64,
+    pub received: u64,
+    pub unread: usize,
+}
+
+struct Peer<MESSAGE> {
+    sender: Sender<MESSAGE>,
+    activity: Arc<Activity>,
+}
+
+impl<MESSAGE> Peer<MESSAGE> {
+    fn report(&self) -> PeerActivity {
+        let activity = &self.activity;
+        let age = activity.registered.elapsed();
+        let last_active = Duration::from_millis(activity.last_active_ms.load(Ordering::Relaxed));
+        PeerActivity {
+            age,
+            idle: age.saturating_sub(last_active),
+            sent: activity.sent.load(Ordering::Relaxed),
+            received: activity.received.load(Ordering::Relaxed),
+            unread: self.sender.len(),
+        }
+    }
+}
+
 pub struct Bus<ADDRESS, MESSAGE>
 where
     ADDRESS: Eq + Hash + Clone + Debug,
     MESSAGE: Debug,
 {
-    peers: DashMap<ADDRESS, Sender<MESSAGE>>,
+    peers: DashMap<ADDRESS, Peer<MESSAGE>>,
+    supervisor: Supervisor<ADDRESS>,
 }
 
 impl<ADDRESS, MESSAGE> Default for Bus<ADDRESS, MESSAGE>
@@ -21,6 +122,7 @@ where
     fn default() -> Self {
         Self {
             peers: DashMap::new(),
+            supervisor: Supervisor::default(),
         }
     }
 }
@@ -34,37 +136,145 @@ where
 
         for entry in self.peers.iter() {
             let address = entry.key();
-            let sender = entry.value();
-            let len = sender.len();
-            eprintln!("Address: {address:?}, unread count: {len}");
+            let activity = entry.value().report();
+            eprintln!(
+                "Address: {address:?}, unread count: {}, sent {}, received {}, up {:?}, idle {:?}",
+                activity.unread, activity.sent, activity.received, activity.age, activity.idle
+            );
         }
+
+        let tasks = self.supervisor.health();
+        eprintln!("BUS tasks: {}", tasks.len());
+        for (address, health) in tasks {
+            if health != TaskHealth::Running {
+                eprintln!("Task: {address:?}, health: {:?}", health.yellow());
+            }
+        }
+    }
+
+    pub fn addresses(&self) -> Vec<ADDRESS> {
+        self.peers.iter().map(|entry| entry.key().clone()).collect()
+    }
+
+    pub fn is_registered(&self, address: &ADDRESS) -> bool {
+        self.peers.contains_key(address)
     }
 
-    pub fn register(self: Arc<Self>, id: ADDRESS) -> BusInterface<ADDRESS, MESSAGE> {
+    pub fn supervisor(&self) -> &Supervisor<ADDRESS> {
+        &self.supervisor
+    }
+
+    pub fn activity(&self) -> Vec<(ADDRESS, PeerActivity)> {
+        self.peers
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().report()))
+            .collect()
+    }
+
+    // Flags the addresses passing `filter` that have been idle for `threshold`,
+    // most likely tasks that are stuck or forgot to drop their interface. With
+    // `unregister` they are removed from the bus, so their `recv` returns None
+    // once their queue is read.
+    pub fn detect_leaks(
+        &self,
+        threshold: Duration,
+        filter: impl Fn(&ADDRESS) -> bool,
+        unregister: bool,
+    ) -> Vec<(ADDRESS, PeerActivity)> {
+        let leaked: Vec<_> = self
+            .activity()
+            .into_iter()
+            .filter(|(address, activity)| filter(address) && activity.idle >= threshold)
+            .collect();
+        for (address, activity) in leaked.iter() {
+            eprintln!(
+                "BUS: {:?} idle for {:?}, {} unread",
+                address.yellow(),
+                activity.idle,
+                activity.unread
+            );
+            if unregister {
+                self.unregister(address.clone(), None);
+            }
+        }
+        leaked
+    }
+
+    // Registers `id` unless it already is, in one step, so of two tasks racing
+    // for an address only one gets it. The registration in place is left alone,
+    // messages to the address keep going to it.
+    pub fn register(
+        self: Arc<Self>,
+        id: ADDRESS,
+    ) -> Result<BusInterface<ADDRESS, MESSAGE>, AddressTaken<ADDRESS>> {
+        let Entry::Vacant(entry) = self.peers.entry(id.clone()) else {
+            eprintln!("BUS:   {:?} already registered", &id.yellow());
+            return Err(AddressTaken(id));
+        };
         eprintln!("BUS:   Register {:?}", &id.green());
         // let (tx, rx) = flume::bounded(100);
         let (tx, rx) = flume::unbounded();
-        self.peers.insert(id.clone(), tx);
-        BusInterface {
+        let activity = Activity::new();
+        entry.insert(Peer {
+            sender: tx,
+            activity: activity.clone(),
+        });
+        Ok(BusInterface {
             address: id,
             bus: Arc::clone(&self),
             receiver: rx,
+            activity,
+        })
+    }
+
+    // Clones `msg` to every peer whose address passes `filter`.
+    // Returns the number of peers that received it.
+    pub async fn broadcast<M>(&self, filter: impl Fn(&ADDRESS) -> bool, msg: M) -> usize
+    where
+        M: Into<MESSAGE> + Clone,
+    {
+        // Collect first, never hold a DashMap guard across an await.
+        let targets: Vec<(Sender<MESSAGE>, Arc<Activity>)> = self
+            .peers
+            .iter()
+            .filter(|entry| filter(entry.key()))
+            .map(|entry| (entry.sender.clone(), entry.activity.clone()))
+            .collect();
+
+        let mut delivered = 0;
+        for (target, activity) in targets {
+            if target.send_async(msg.clone().into()).await.is_ok() {
+                activity.received.fetch_add(1, Ordering::Relaxed);
+                activity.touch();
+                delivered += 1;
+            }
         }
+        delivered
     }
 
     // Returns Err iff trying to send to an address that never existed or has been dropped.
-    async fn send(&self, to: ADDRESS, msg: MESSAGE) -> Result<(), MESSAGE> {
-        if let Some(sender) = self.peers.get(&to) {
-            sender.send_async(msg).await.map_err(|e| e.0)?;
-            Ok(())
-        } else {
-            Err(msg)
-        }
+    pub(super) async fn send(&self, to: ADDRESS, msg: MESSAGE) -> Result<(), MESSAGE> {
+        // Not holding the DashMap guard across the await.
+        let Some((sender, activity)) = self
+            .peers
+            .get(&to)
+            .map(|peer| (peer.sender.clone(), peer.activity.clone()))
+        else {
+            return Err(msg);
+        };
+        sender.send_async(msg).await.map_err(|e| e.0)?;
+        activity.received.fetch_add(1, Ordering::Relaxed);
+        activity.touch();
+        Ok(())
     }
 
-    fn unregister(&self, id: ADDRESS) {
+    // Only removes the registration `activity` belongs to, if given, so a
+    // dropped interface leaves a later registration of its address alone.
+    fn unregister(&self, id: ADDRESS, activity: Option<&Arc<Activity>>) {
         eprintln!("BUS: Unregister {:?}", &id.red());
-        self.peers.remove(&id);
+        self.peers.remove_if(&id, |_, peer| {
+            activity.is_none_or(|activity| Arc::ptr_eq(activity, &peer.activity))
+        });
     }
 }
 
@@ -76,6 +286,7 @@ where
     address: ADDRESS,
     bus: Arc<Bus<ADDRESS, MESSAGE>>,
     receiver: Receiver<MESSAGE>,
+    activity: Arc<Activity>,
 }
 
 impl<ADDRESS, MESSAGE> BusInterface<ADDRESS, MESSAGE>
@@ -89,23 +300,65 @@ where
     {
         let message: MESSAGE = message.into();
         // eprintln!("To {:?}: {:?}", &to.magenta(), &message.blue());
+        self.activity.sent.fetch_add(1, Ordering::Relaxed);
+        self.activity.touch();
         self.bus
             .send(to, message)
             .await
             .map_err(|err| M::try_from(err).ok())
     }
 
-    pub async fn recv<R: TryFrom<MESSAGE>>(&mut self) -> Option<R> {
-        self.receiver
-            .recv_async()
+    // Keeps retrying while `to` is not registered yet, until `timeout` expires.
+    pub async fn request<Q, A>(
+        &self,
+        to: ADDRESS,
+        body: Q,
+        timeout: Duration,
+    ) -> Result<A, RequestError>
+    where
+        Request<Q, A>: Into<MESSAGE> + TryFrom<MESSAGE>,
+    {
+        let deadline = Instant::now() + timeout;
+        let (responder, answer) = oneshot::channel();
+        let mut request = Request { body, responder };
+
+        loop {
+            match self.send(to.clone(), request).await {
+                Ok(()) => break,
+                Err(Some(returned)) if Instant::now() < deadline => {
+                    request = returned;
+                    tokio::time::sleep(REQUEST_RETRY_INTERVAL).await;
+                }
+                Err(_) => return Err(RequestError::Unreachable),
+            }
+        }
+
+        tokio::time::timeout_at(deadline, answer)
             .await
-            .ok()
-            .and_then(|message| R::try_from(message).ok())
+            .map_err(|_| RequestError::Timeout)?
+            .map_err(|_| RequestError::Dropped)
+    }
+
+    pub async fn recv<R: TryFrom<MESSAGE>>(&mut self) -> Option<R> {
+        let message = self.receiver.recv_async().await.ok();
+        self.activity.touch();
+        message.and_then(|message| R::try_from(message).ok())
+    }
+
+    pub async fn broadcast<M>(&self, filter: impl Fn(&ADDRESS) -> bool, message: M) -> usize
+    where
+        M: Into<MESSAGE> + Clone,
+    {
+        self.bus.broadcast(filter, message).await
     }
 
     pub fn get_bus(&self) -> Arc<Bus<ADDRESS, MESSAGE>> {
         self.bus.clone()
     }
+
+    pub fn address(&self) -> &ADDRESS {
+        &self.address
+    }
 }
 
 impl<ADDRESS, MESSAGE> Drop for BusInterface<ADDRESS, MESSAGE>
@@ -114,6 +367,123 @@ where
     MESSAGE: Debug,
 {
     fn drop(&mut self) {
-        self.bus.unregister(self.address.clone());
+        self.bus
+            .unregister(self.address.clone(), Some(&self.activity));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(derive_more::From, derive_more::TryInto, Debug)]
+    enum TestMessage {
+        Double(Request<u32, u32>),
+        Note(String),
+    }
+
+    #[tokio::test]
+    async fn broadcast_to_filtered_peers() {
+        let bus: Arc<Bus<u8, TestMessage>> = Arc::new(Bus::default());
+        let mut peers: Vec<_> = (0..4).map(|id| bus.clone().register(id).unwrap()).collect();
+
+        let delivered = bus.broadcast(|id| id % 2 == 0, String::from("hello")).await;
+        assert_eq!(delivered, 2);
+
+        for peer in peers.iter_mut().step_by(2) {
+            let note: String = peer.recv().await.unwrap();
+            assert_eq!(note, "hello");
+        }
+        assert!(peers[1].receiver.is_empty());
+        assert!(peers[3].receiver.is_empty());
+    }
+
+    #[tokio::test]
+    async fn request_waits_for_late_registration() {
+        let bus: Arc<Bus<u8, TestMessage>> = Arc::new(Bus::default());
+        let client = bus.clone().register(0).unwrap();
+
+        let server_bus = bus.clone();
+        let server = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let mut server = server_bus.register(1).unwrap();
+            let request: Request<u32, u32> = server.recv().await.unwrap();
+            let body = request.body;
+            request.reply(body * 2).unwrap();
+        });
+
+        let answer: Result<u32, _> = client.request(1, 21u32, Duration::from_secs(1)).await;
+        assert_eq!(answer, Ok(42));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn request_errors() {
+        let bus: Arc<Bus<u8, TestMessage>> = Arc::new(Bus::default());
+        let client = bus.clone().register(0).unwrap();
+
+        let answer: Result<u32, _> = client.request(1, 21u32, Duration::from_millis(30)).await;
+        assert_eq!(answer, Err(RequestError::Unreachable));
+
+        let _silent = bus.clone().register(1).unwrap();
+        let answer: Result<u32, _> = client.request(1, 21u32, Duration::from_millis(30)).await;
+        assert_eq!(answer, Err(RequestError::Timeout));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_peers_are_flagged_and_unregistered() {
+        let bus: Arc<Bus<u8, TestMessage>> = Arc::new(Bus::default());
+        let sender = bus.clone().register(0).unwrap();
+        let mut busy = bus.clone().register(1).unwrap();
+        let mut stuck = bus.clone().register(2).unwrap();
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        sender.send(1, String::from("work")).await.unwrap();
+        sender.send(2, String::from("work")).await.unwrap();
+        let _: String = busy.recv().await.unwrap();
+        tokio::time::advance(Duration::from_secs(50)).await;
+
+        let activity = bus.activity();
+        let of = |id: u8| {
+            activity
+                .iter()
+                .find(|(address, _)| *address == id)
+                .unwrap()
+                .1
+                .clone()
+        };
+        assert_eq!(of(0).sent, 2);
+        assert_eq!((of(1).received, of(1).unread), (1, 0));
+        assert_eq!((of(2).received, of(2).unread), (1, 1));
+        assert_eq!(of(2).idle, Duration::from_secs(50));
+
+        let leaked = bus.detect_leaks(Duration::from_secs(60), |id| *id != 0, false);
+        assert!(leaked.is_empty());
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let leaked = bus.detect_leaks(Duration::from_secs(60), |id| *id != 0, true);
+        assert_eq!(leaked.len(), 2);
+
+        // The queued message is still read, then the peer learns it is gone.
+        let _: String = stuck.recv().await.unwrap();
+        assert!(stuck.recv::<String>().await.is_none());
+        assert_eq!(bus.addresses(), vec![0]);
+
+        // Dropping a reaped interface leaves a new registration alone.
+        let _again = bus.clone().register(2).unwrap();
+        drop(stuck);
+        assert_eq!(bus.addresses().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn only_one_registration_wins() {
+        let bus: Arc<Bus<u8, TestMessage>> = Arc::new(Bus::default());
+        let mut first = bus.clone().register(1).unwrap();
+        assert_eq!(bus.clone().register(1).err(), Some(AddressTaken(1)));
+
+        // Messages for the address keep going to the first.
+        bus.send(1, String::from("work").into()).await.unwrap();
+        let _: String = first.recv().await.unwrap();
+        drop(first);
+        assert!(bus.clone().register(1).is_ok());
     }
 }
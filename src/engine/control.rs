@@ -0,0 +1,509 @@
+use dashmap::DashMap;
+use serde::Serialize;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+
+use super::supervisor::TaskHealth;
+use super::{Bus, BusAddress, BusMessage};
+use crate::protocol::wire::encoding::{ToleratedCounts, tolerated};
+use crate::util::cpu::{self, CpuReport};
+use crate::util::telemetry::{ChunkTimeline, telemetry};
+use crate::util::units::ChunkId;
+
+pub const DEFAULT_CONTROL_SOCKET: &str = "/run/usync.sock";
+
+#[derive(Debug, Clone, Copy)]
+pub struct PeerStatus {
+    pub last_seen: Instant,
+    pub rate_kbps: Option<u32>,
+    pub bytes: u64,
+    // IPv6 flow label and traffic class of what is sent to the peer.
+    pub flow_label: Option<u32>,
+    pub traffic_class: Option<u8>,
+}
+
+// Runtime state of a client or server, read and tweaked over the control socket.
+pub struct ControlState {
+    role: &'static str,
+    started: Instant,
+    // 0 leaves the rate to the peers.
+    rate_kbps: AtomicU32,
+    peers: DashMap<SocketAddr, PeerStatus>,
+    paused: watch::Sender<bool>,
+    // Peers waiting to be admitted, in order.
+    waiting: Mutex<Vec<SocketAddr>>,
+}
+
+impl ControlState {
+    pub fn new(role: &'static str) -> Arc<Self> {
+        Arc::new(Self {
+            role,
+            started: Instant::now(),
+            rate_kbps: AtomicU32::new(0),
+            peers: DashMap::new(),
+            paused: watch::Sender::new(false),
+            waiting: Mutex::new(vec![]),
+        })
+    }
+
+    pub fn rate_kbps(&self) -> Option<u32> {
+        match self.rate_kbps.load(Ordering::Relaxed) {
+            0 => None,
+            kbps => Some(kbps),
+        }
+    }
+
+    pub fn set_rate_kbps(&self, kbps: Option<u32>) {
+        self.rate_kbps.store(kbps.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    // Returns whether the state changed.
+    pub fn set_paused(&self, paused: bool) -> bool {
+        self.paused.send_replace(paused) != paused
+    }
+
+    pub async fn wait_resumed(&self) {
+        self.paused
+            .subscribe()
+            .wait_for(|paused| !paused)
+            .await
+            .ok();
+    }
+
+    pub fn set_waiting(&self, waiting: Vec<SocketAddr>) {
+        *self.waiting.lock().unwrap() = waiting;
+    }
+
+    pub fn record_peer(&self, addr: SocketAddr, bytes: u64, rate_kbps: Option<u32>) {
+        let now = Instant::now();
+        if self.peers.len() > 1024 {
+            self.peers
+                .retain(|_, peer| now - peer.last_seen < Duration::from_secs(600));
+        }
+        let mut peer = self.peers.entry(addr).or_insert(PeerStatus {
+            last_seen: now,
+            rate_kbps: None,
+            bytes: 0,
+            flow_label: None,
+            traffic_class: None,
+        });
+        peer.last_seen = now;
+        peer.bytes += bytes;
+        if rate_kbps.is_some() {
+            peer.rate_kbps = rate_kbps;
+        }
+    }
+
+    // Ignored for peers not recorded yet.
+    pub fn record_marking(
+        &self,
+        addr: SocketAddr,
+        flow_label: Option<u32>,
+        traffic_class: Option<u8>,
+    ) {
+        if let Some(mut peer) = self.peers.get_mut(&addr) {
+            peer.flow_label = flow_label;
+            peer.traffic_class = traffic_class;
+        }
+    }
+
+    pub fn forget_peer(&self, addr: SocketAddr) {
+        self.peers.remove(&addr);
+    }
+}
+
+// A rate file holds a rate in kbps, 0 or nothing to go back to the default.
+pub fn read_rate_file(path: &Path) -> io::Result<Option<u32>> {
+    let content = std::fs::read_to_string(path)?;
+    match content.trim() {
+        "" => Ok(None),
+        rate => rate
+            .parse::<u32>()
+            .map(|kbps| (kbps > 0).then_some(kbps))
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{rate}: {err}"))),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlCommand {
+    Status,
+    Peers,
+    Chunks,
+    // Timelines of one chunk, or of all.
+    Timings(Option<ChunkId>),
+    // None goes back to the rate configured at start.
+    SetRate(Option<u32>),
+    Pause,
+    Resume,
+}
+
+impl std::str::FromStr for ControlCommand {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some("status") => ControlCommand::Status,
+            Some("peers") => ControlCommand::Peers,
+            Some("chunks") => ControlCommand::Chunks,
+            Some("timings") => ControlCommand::Timings(
+                words
+                    .next()
+                    .map(|chunk_id| chunk_id.parse::<u32>().map(ChunkId))
+                    .transpose()
+                    .map_err(|err| format!("Invalid chunk id: {err}"))?,
+            ),
+            Some("pause") => ControlCommand::Pause,
+            Some("resume") => ControlCommand::Resume,
+            Some("set-rate") => {
+                let kbps = words
+                    .next()
+                    .ok_or("Usage: set-rate <KBPS>, 0 to reset")?
+                    .parse::<u32>()
+                    .map_err(|err| format!("Invalid rate: {err}"))?;
+                ControlCommand::SetRate((kbps > 0).then_some(kbps))
+            }
+            Some(command) => return Err(format!("Unknown command `{command}`")),
+            None => return Err(String::from("Empty command")),
+        };
+        match words.next() {
+            Some(extra) => Err(format!("Unexpected argument `{extra}`")),
+            None => Ok(command),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StatusReport {
+    pub role: &'static str,
+    pub uptime_ms: u64,
+    pub rate_kbps: Option<u32>,
+    pub paused: bool,
+    pub peers: usize,
+    pub waiting: usize,
+    pub encoders: usize,
+    pub decoders: usize,
+    pub failed_tasks: usize,
+    // Off-spec packets let through by `--parse-mode lenient`.
+    pub tolerated: ToleratedCounts,
+    // With `--cpu-accounting` only.
+    pub cpu: Option<CpuReport>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PeerReport {
+    pub addr: SocketAddr,
+    pub last_seen_ms: u64,
+    pub rate_kbps: Option<u32>,
+    pub bytes: u64,
+    // Place in the admission queue, 1 being next.
+    pub queue_position: Option<usize>,
+    // Chunks being sent to the peer.
+    pub chunks: Vec<ChunkId>,
+    pub flow_label: Option<u32>,
+    pub traffic_class: Option<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChunkReport {
+    pub chunk_id: ChunkId,
+    // The peer an encoder sends to, None for decoders.
+    pub peer: Option<SocketAddr>,
+    pub health: String,
+}
+
+// One JSON line per command, e.g. `{"status":{...}}` or `{"error":"..."}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlReply {
+    Status(StatusReport),
+    Peers(Vec<PeerReport>),
+    Chunks(Vec<ChunkReport>),
+    Timings(Vec<ChunkTimeline>),
+    Rate(Option<u32>),
+    Paused(bool),
+    Error(String),
+}
+
+fn health_of(health: &TaskHealth) -> String {
+    match health {
+        TaskHealth::Running => String::from("running"),
+        TaskHealth::Restarted(times) => format!("restarted {times} times"),
+        TaskHealth::Failed(reason) => format!("failed: {reason}"),
+    }
+}
+
+fn chunks<const N: usize>(bus: &Bus<BusAddress, BusMessage<N>>) -> Vec<ChunkReport> {
+    let mut chunks: Vec<ChunkReport> = bus
+        .supervisor()
+        .health()
+        .into_iter()
+        .filter_map(|(address, health)| {
+            let (chunk_id, peer) = match address {
+                BusAddress::FrameEncoder(chunk_id, peer) => (chunk_id, Some(peer)),
+                BusAddress::FrameDecoder(chunk_id) => (chunk_id, None),
+                _ => return None,
+            };
+            Some(ChunkReport {
+                chunk_id,
+                peer,
+                health: health_of(&health),
+            })
+        })
+        .collect();
+    chunks.sort_by_key(|chunk| (chunk.chunk_id, chunk.peer));
+    chunks
+}
+
+pub fn handle<const N: usize>(
+    command: ControlCommand,
+    state: &ControlState,
+    bus: &Bus<BusAddress, BusMessage<N>>,
+) -> ControlReply {
+    match command {
+        ControlCommand::Status => {
+            let chunks = chunks(bus);
+            ControlReply::Status(StatusReport {
+                role: state.role,
+                uptime_ms: state.started.elapsed().as_millis() as u64,
+                rate_kbps: state.rate_kbps(),
+                paused: state.is_paused(),
+                peers: state.peers.len(),
+                waiting: state.waiting.lock().unwrap().len(),
+                encoders: chunks.iter().filter(|chunk| chunk.peer.is_some()).count(),
+                decoders: chunks.iter().filter(|chunk| chunk.peer.is_none()).count(),
+                failed_tasks: chunks
+                    .iter()
+                    .filter(|chunk| chunk.health.starts_with("failed"))
+                    .count(),
+                tolerated: tolerated(),
+                cpu: cpu::report(),
+            })
+        }
+        ControlCommand::Peers => {
+            let chunks = chunks(bus);
+            let waiting = state.waiting.lock().unwrap().clone();
+            let mut peers: Vec<PeerReport> = state
+                .peers
+                .iter()
+                .map(|entry| PeerReport {
+                    addr: *entry.key(),
+                    last_seen_ms: entry.last_seen.elapsed().as_millis() as u64,
+                    rate_kbps: entry.rate_kbps,
+                    bytes: entry.bytes,
+                    queue_position: waiting
+                        .iter()
+                        .position(|peer| peer == entry.key())
+                        .map(|index| index + 1),
+                    chunks: chunks
+                        .iter()
+                        .filter(|chunk| chunk.peer == Some(*entry.key()))
+                        .map(|chunk| chunk.chunk_id)
+                        .collect(),
+                    flow_label: entry.flow_label,
+                    traffic_class: entry.traffic_class,
+                })
+                .collect();
+            peers.sort_by_key(|peer| peer.addr);
+            ControlReply::Peers(peers)
+        }
+        ControlCommand::Chunks => ControlReply::Chunks(chunks(bus)),
+        ControlCommand::Timings(chunk_id) => ControlReply::Timings(telemetry().timelines(chunk_id)),
+        ControlCommand::SetRate(kbps) => {
+            state.set_rate_kbps(kbps);
+            ControlReply::Rate(kbps)
+        }
+        ControlCommand::Pause => {
+            state.set_paused(true);
+            ControlReply::Paused(true)
+        }
+        ControlCommand::Resume => {
+            state.set_paused(false);
+            ControlReply::Paused(false)
+        }
+    }
+}
+
+// SIGTSTP (Ctrl-Z) pauses the transfer instead of stopping the process, SIGCONT
+// resumes it. Opt-in, as it takes job control away from the shell.
+pub fn handle_pause_signals(state: Arc<ControlState>) -> io::Result<()> {
+    let mut pause = signal(SignalKind::from_raw(libc::SIGTSTP))?;
+    let mut resume = signal(SignalKind::from_raw(libc::SIGCONT))?;
+    tokio::spawn(async move {
+        loop {
+            let paused = tokio::select! {
+                Some(()) = pause.recv() => true,
+                Some(()) = resume.recv() => false,
+                else => break,
+            };
+            if state.set_paused(paused) {
+                eprintln!("{}", if paused { "Paused." } else { "Resumed." });
+            }
+        }
+    });
+    Ok(())
+}
+
+pub struct ControlSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    // A socket file left behind by a previous run is replaced, a live one is not.
+    pub async fn bind(path: &Path) -> io::Result<Self> {
+        if path.exists() {
+            if UnixStream::connect(path).await.is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is in use by another process", path.display()),
+                ));
+            }
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        // Anyone who can connect may change the rate.
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn serve<const N: usize>(
+        self,
+        state: Arc<ControlState>,
+        bus: Arc<Bus<BusAddress, BusMessage<N>>>,
+    ) {
+        loop {
+            let stream = match self.listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    eprintln!("Control socket {} failed: {err}", self.path.display());
+                    return;
+                }
+            };
+            let state = state.clone();
+            let bus = bus.clone();
+            tokio::spawn(async move {
+                let (reader, mut writer) = stream.into_split();
+                let mut lines = BufReader::new(reader).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let reply = match line.parse() {
+                        Ok(command) => handle(command, &state, &bus),
+                        Err(err) => ControlReply::Error(err),
+                    };
+                    let mut reply = serde_json::to_vec(&reply).unwrap();
+                    reply.push(b'\n');
+                    if writer.write_all(&reply).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::TRANSMISSION_INFO_LENGTH;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!("status".parse(), Ok(ControlCommand::Status));
+        assert_eq!(
+            " set-rate  2048 ".parse(),
+            Ok(ControlCommand::SetRate(Some(2048)))
+        );
+        assert_eq!("set-rate 0".parse(), Ok(ControlCommand::SetRate(None)));
+        assert_eq!("pause".parse(), Ok(ControlCommand::Pause));
+        assert_eq!("timings".parse(), Ok(ControlCommand::Timings(None)));
+        assert_eq!(
+            "timings 12".parse(),
+            Ok(ControlCommand::Timings(Some(ChunkId(12))))
+        );
+        assert!("timings twelve".parse::<ControlCommand>().is_err());
+        assert!("set-rate".parse::<ControlCommand>().is_err());
+        assert!("peers all".parse::<ControlCommand>().is_err());
+        assert!("restart".parse::<ControlCommand>().is_err());
+    }
+
+    #[test]
+    fn rate_file() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        assert_eq!(read_rate_file(file.path()).unwrap(), None);
+        std::fs::write(file.path(), "8192\n").unwrap();
+        assert_eq!(read_rate_file(file.path()).unwrap(), Some(8192));
+        std::fs::write(file.path(), "fast").unwrap();
+        assert!(read_rate_file(file.path()).is_err());
+    }
+
+    #[tokio::test]
+    async fn wait_until_resumed() {
+        let state = ControlState::new("client");
+        state.wait_resumed().await;
+        assert!(state.set_paused(true));
+        assert!(!state.set_paused(true));
+
+        let waiting = tokio::spawn({
+            let state = state.clone();
+            async move { state.wait_resumed().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        state.set_paused(false);
+        waiting.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn serve_commands_over_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("usync.sock");
+        let state = ControlState::new("server");
+        let bus: Arc<Bus<BusAddress, BusMessage<TRANSMISSION_INFO_LENGTH>>> =
+            Arc::new(Bus::default());
+        let peer: SocketAddr = "10.0.0.2:7000".parse().unwrap();
+        state.record_peer(peer, 1500, Some(4096));
+        state.record_marking(peer, Some(0x12345), Some(0x28));
+
+        let socket = ControlSocket::bind(&path).await.unwrap();
+        tokio::spawn(socket.serve(state.clone(), bus));
+        assert!(ControlSocket::bind(&path).await.is_err());
+
+        let (reader, mut writer) = UnixStream::connect(&path).await.unwrap().into_split();
+        let mut lines = BufReader::new(reader).lines();
+        writer
+            .write_all(b"set-rate 1000\npeers\nbogus\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            lines.next_line().await.unwrap().unwrap(),
+            r#"{"rate":1000}"#
+        );
+        assert_eq!(state.rate_kbps(), Some(1000));
+        let peers = lines.next_line().await.unwrap().unwrap();
+        assert!(peers.contains(r#""addr":"10.0.0.2:7000""#), "{peers}");
+        assert!(peers.contains(r#""bytes":1500"#), "{peers}");
+        assert!(peers.contains(r#""flow_label":74565"#), "{peers}");
+        let error = lines.next_line().await.unwrap().unwrap();
+        assert!(error.starts_with(r#"{"error":"#), "{error}");
+    }
+}
@@ -1,47 +1,132 @@
-use super::{Bus, BusAddress, BusInterface, BusMessage, ReceivingChunkReport};
+use super::supervisor::RestartPolicy;
+use super::{ANNOUNCE_TIMEOUT, Bus, BusAddress, BusInterface, BusMessage, ReceivingChunkReport};
 use crate::protocol::{coding::FrameReceiver, wire::frames::ParsedDataFrame};
+use crate::util::cpu::{Subsystem, accounted};
+use crate::util::units::ChunkId;
+use std::collections::HashSet;
 use std::sync::Arc;
 use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
 
 pub fn spawn<FR, const INFO_LENGTH: usize>(
-    chunk_id: u32,
+    chunk_id: ChunkId,
     bus: Arc<Bus<BusAddress, BusMessage<INFO_LENGTH>>>,
-) -> JoinHandle<Option<Vec<u8>>>
+) -> JoinHandle<Option<DecodeOutcome>>
 where
     FR: FrameReceiver<INFO_LENGTH> + std::marker::Send + 'static,
 {
-    let bus_interface = bus.register(BusAddress::FrameDecoder(chunk_id));
-    let decoder: ChunkDecoder<INFO_LENGTH> = ChunkDecoder::new(chunk_id, bus_interface);
+    // A chunk is decoded once at a time, the decoder in place gets its frames.
+    let mut bus_interface = match bus.clone().register(BusAddress::FrameDecoder(chunk_id)) {
+        Ok(bus_interface) => Some(bus_interface),
+        Err(err) => {
+            eprintln!("Not decoding chunk {chunk_id}: {err}");
+            return tokio::spawn(async { None });
+        }
+    };
+    let task_bus = bus.clone();
+
+    // A restarted decoder announces itself again and keeps decoding from fresh frames.
+    bus.supervisor().spawn(
+        BusAddress::FrameDecoder(chunk_id),
+        RestartPolicy::Restart { max_restarts: 1 },
+        move || {
+            let bus_interface = bus_interface.take().or_else(|| {
+                task_bus
+                    .clone()
+                    .register(BusAddress::FrameDecoder(chunk_id))
+                    .ok()
+            });
+            accounted(Subsystem::Decoder, async move {
+                let decoder: ChunkDecoder<INFO_LENGTH> =
+                    ChunkDecoder::new(chunk_id, bus_interface?);
+                decoder.run::<FR>().await
+            })
+        },
+    )
+}
 
-    tokio::spawn(decoder.run::<FR>())
+// What a decoder saw of its chunk, kept whether or not decoding succeeded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DecodeStats {
+    pub frames_received: u64,
+    pub bytes_received: u64,
+    // Frames whose offset arrived before, i.e. retransmissions.
+    pub duplicate_frames: u64,
+    pub max_frame_length: usize,
+    pub elapsed: Duration,
+}
+
+pub struct DecodeOutcome {
+    pub data: Option<Vec<u8>>,
+    pub stats: DecodeStats,
+    // As the first frame carried it, empty if none arrived.
+    pub transmission_info: Vec<u8>,
 }
 
 pub struct ChunkDecoder<const INFO_LENGTH: usize> {
-    chunk_id: u32,
+    chunk_id: ChunkId,
     bus_interface: BusInterface<BusAddress, BusMessage<INFO_LENGTH>>,
+    stats: DecodeStats,
+    seen: HashSet<u32>,
+    transmission_info: Vec<u8>,
 }
 
 impl<const INFO_LENGTH: usize> ChunkDecoder<INFO_LENGTH> {
     pub fn new(
-        chunk_id: u32,
+        chunk_id: ChunkId,
         bus_interface: BusInterface<BusAddress, BusMessage<INFO_LENGTH>>,
     ) -> Self {
         Self {
             chunk_id,
             bus_interface,
+            stats: DecodeStats::default(),
+            seen: HashSet::new(),
+            transmission_info: vec![],
+        }
+    }
+
+    // None once the server reports it can not serve this chunk.
+    async fn next_frame(&mut self) -> Option<ParsedDataFrame<INFO_LENGTH>> {
+        loop {
+            match self.bus_interface.recv::<BusMessage<INFO_LENGTH>>().await? {
+                BusMessage::ReceivingData(frame) => {
+                    self.stats.frames_received += 1;
+                    self.stats.bytes_received += frame.data.len() as u64;
+                    self.stats.max_frame_length = self.stats.max_frame_length.max(frame.data.len());
+                    if !self.seen.insert(frame.frame_offset) {
+                        self.stats.duplicate_frames += 1;
+                    }
+                    return Some(frame);
+                }
+                BusMessage::ChunkError((chunk_id, reason)) => {
+                    eprintln!("Server failed to serve chunk {chunk_id}: {reason:?}");
+                    return None;
+                }
+                _ => {}
+            }
         }
     }
 
-    pub async fn run<FR: FrameReceiver<INFO_LENGTH>>(mut self) -> Option<Vec<u8>> {
+    pub async fn run<FR: FrameReceiver<INFO_LENGTH>>(mut self) -> Option<DecodeOutcome> {
+        let start = Instant::now();
+        let data = self.decode::<FR>().await;
+        self.stats.elapsed = start.elapsed();
+        Some(DecodeOutcome {
+            data,
+            stats: self.stats,
+            transmission_info: self.transmission_info,
+        })
+    }
+
+    async fn decode<FR: FrameReceiver<INFO_LENGTH>>(&mut self) -> Option<Vec<u8>> {
         self.bus_interface
-            .send(
-                BusAddress::ReceiverSocket,
-                (self.chunk_id, ReceivingChunkReport::WantNext(0)),
-            )
+            .request::<ChunkId, ()>(BusAddress::ReceiverSocket, self.chunk_id, ANNOUNCE_TIMEOUT)
             .await
-            .ok();
+            .inspect_err(|err| eprintln!("Chunk {} failed to announce: {err:?}", self.chunk_id))
+            .ok()?;
 
-        let first_chunk: ParsedDataFrame<INFO_LENGTH> = self.bus_interface.recv().await?;
+        let first_chunk = self.next_frame().await?;
+        self.transmission_info = first_chunk.transmission_info.to_vec();
 
         let mut decoder = FR::try_init(&first_chunk.transmission_info)?;
 
@@ -52,7 +137,7 @@ impl<const INFO_LENGTH: usize> ChunkDecoder<INFO_LENGTH> {
         drop(first_chunk);
 
         loop {
-            let frame: ParsedDataFrame<INFO_LENGTH> = self.bus_interface.recv().await?;
+            let frame = self.next_frame().await?;
 
             if let Some(data) = decoder.update(frame.frame_offset, &frame.data) {
                 self.bus_interface
@@ -0,0 +1,585 @@
+use owo_colors::OwoColorize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::{Semaphore, watch};
+use tokio::task::JoinSet;
+use tokio::time::{Duration, Instant, interval};
+
+use super::control::ControlState;
+use super::decoding::DecodeOutcome;
+use super::plans::{PlanSpaceError, bus_id, check_plan_space};
+use super::{Bus, BusAddress, BusMessage, decoding};
+use crate::protocol::coding::FrameReceiver;
+use crate::protocol::wire::frames::{Priority, StopReason};
+use crate::util::file::{available_space, write_at};
+use crate::util::plan::FileChunk;
+use crate::util::seal::{ContentKey, plain_range};
+use crate::util::summary::{ChunkSummary, TransferSummary};
+use crate::util::units::ChunkId;
+
+const SPACE_CHECK_PERIOD: Duration = Duration::from_secs(5);
+
+// A chunk that decodes but fails verification this many times most likely
+// differs on the server, e.g. it has another version of the file, and is not
+// asked of it again.
+const MISMATCHES_TO_BLACKLIST: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkState {
+    Written,
+    // Did not decode, was rejected by a verifier or could not be written, on
+    // every attempt.
+    Failed,
+    OutOfSpace,
+    // Not started, the byte or time budget of the run is spent.
+    OverBudget,
+    // Failed verification `MISMATCHES_TO_BLACKLIST` times.
+    Blacklisted,
+}
+
+// A check a decoded chunk must pass before it is written to the destination.
+// The reason a chunk is rejected is logged, and the chunk is retried.
+pub trait ChunkVerifier: Send + Sync {
+    fn verify(&self, chunk: &FileChunk, data: &[u8]) -> Result<(), String>;
+}
+
+// The check every download runs first: the chunk has the planned length and
+// blake3 hash.
+pub struct PlanHashVerifier;
+
+impl ChunkVerifier for PlanHashVerifier {
+    fn verify(&self, chunk: &FileChunk, data: &[u8]) -> Result<(), String> {
+        if data.len() != chunk.length {
+            return Err(format!(
+                "{} bytes instead of the planned {}",
+                data.len(),
+                chunk.length
+            ));
+        }
+        let hash = hex::encode(blake3::hash(data).as_bytes());
+        match hash == chunk.hash {
+            true => Ok(()),
+            false => Err(format!("hash {hash} instead of the planned {}", chunk.hash)),
+        }
+    }
+}
+
+// What is known of a chunk kept by `with_keep_damaged`, next to its data.
+#[derive(Debug, Serialize)]
+struct DamagedChunk<'a> {
+    attempt: usize,
+    reason: &'a str,
+    planned_hash: &'a str,
+    hash: String,
+    transmission_info: String,
+    received: ChunkSummary,
+}
+
+// Writes the decoded data of a rejected chunk to `<file>.bad/<chunk_id>`, and
+// what it was rejected for, its transmission info and frame stats to
+// `<chunk_id>.toml` there. A later attempt replaces both.
+fn keep_damaged(
+    path: &Path,
+    chunk: &FileChunk,
+    attempt: usize,
+    outcome: &DecodeOutcome,
+    data: &[u8],
+    reason: &str,
+) -> std::io::Result<PathBuf> {
+    let mut dir = path.as_os_str().to_owned();
+    dir.push(".bad");
+    let dir = PathBuf::from(dir);
+    std::fs::create_dir_all(&dir)?;
+    let damaged = DamagedChunk {
+        attempt,
+        reason,
+        planned_hash: &chunk.hash,
+        hash: hex::encode(blake3::hash(data).as_bytes()),
+        transmission_info: hex::encode(&outcome.transmission_info),
+        received: ChunkSummary::new(chunk.chunk_id, chunk.length, &outcome.stats, false),
+    };
+    let info = toml::to_string(&damaged).map_err(std::io::Error::other)?;
+    let kept = dir.join(chunk.chunk_id.to_string());
+    std::fs::write(&kept, data)?;
+    std::fs::write(dir.join(format!("{}.toml", chunk.chunk_id)), info)?;
+    Ok(kept)
+}
+
+// Where a verified chunk goes and what is written there, opened with
+// `content_key` if the chunks are sealed.
+pub fn open_chunk(
+    content_key: Option<&ContentKey>,
+    chunk: &FileChunk,
+    data: Vec<u8>,
+) -> Result<(u64, Vec<u8>), String> {
+    match content_key {
+        Some(content_key) => Ok((plain_range(chunk).0, content_key.open(&data)?)),
+        None => Ok((chunk.offset, data)),
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DownloadProgress {
+    pub total: usize,
+    pub written: usize,
+    pub failed: usize,
+    pub bytes_written: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DownloadReport {
+    // One entry per decode attempt, so retried chunks show up more than once.
+    pub summary: TransferSummary,
+    pub written: Vec<ChunkId>,
+    pub failed: Vec<ChunkId>,
+    // Stopped early, every chunk written so far passed its hash check.
+    pub out_of_space: bool,
+    // Chunks left for a later run by `with_max_bytes` or `with_deadline`.
+    pub over_budget: Vec<ChunkId>,
+    // Failed chunks the server's copy of differs from the plan, which another
+    // source may still have.
+    pub blacklisted: Vec<ChunkId>,
+}
+
+// One of several files downloaded together, see `DownloadManager::run_plans`.
+pub struct PlannedFile {
+    // Must exist, chunks are written at their planned offsets.
+    pub path: PathBuf,
+    pub chunks: Vec<FileChunk>,
+    // Files start chunks in proportion to the weight of their priority.
+    pub priority: Priority,
+}
+
+// Downloads the chunks of one file: decodes at most `concurrency` chunks at a
+// time, checks each against its planned hash and any added verifiers, and
+// writes it in place, opened first if the chunks are sealed.
+pub struct DownloadManager<const INFO_LENGTH: usize> {
+    bus: Arc<Bus<BusAddress, BusMessage<INFO_LENGTH>>>,
+    path: PathBuf,
+    concurrency: usize,
+    retries: usize,
+    min_free: u64,
+    control: Option<Arc<ControlState>>,
+    progress: watch::Sender<DownloadProgress>,
+    max_bytes: Option<u64>,
+    deadline: Option<Instant>,
+    // Received so far plus the length of every chunk still decoding.
+    committed_bytes: AtomicU64,
+    verifiers: Vec<Arc<dyn ChunkVerifier>>,
+    content_key: Option<Arc<ContentKey>>,
+    keep_damaged: bool,
+}
+
+impl<const INFO_LENGTH: usize> DownloadManager<INFO_LENGTH> {
+    // The file at `path` must exist, chunks are written at their planned offsets.
+    pub fn new(bus: Arc<Bus<BusAddress, BusMessage<INFO_LENGTH>>>, path: &Path) -> Self {
+        Self {
+            bus,
+            path: path.to_path_buf(),
+            concurrency: 8,
+            retries: 0,
+            min_free: 0,
+            control: None,
+            progress: watch::Sender::new(DownloadProgress::default()),
+            max_bytes: None,
+            deadline: None,
+            committed_bytes: AtomicU64::new(0),
+            verifiers: vec![Arc::new(PlanHashVerifier)],
+            content_key: None,
+            keep_damaged: false,
+        }
+    }
+
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    // Chunks failing their hash check are downloaded again up to `retries` times.
+    pub fn with_retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    // Stops before free space at the destination drops below `min_free` bytes.
+    pub fn with_min_free(mut self, min_free: u64) -> Self {
+        self.min_free = min_free;
+        self
+    }
+
+    // Decoded chunks are held back from the disk while `control` is paused.
+    pub fn with_control(mut self, control: Arc<ControlState>) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    // No chunk is started that could take the bytes received past `max_bytes`,
+    // assuming it needs its own length. Chunks already decoding are finished.
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    // No chunk is started after `deadline`, chunks already decoding are finished.
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    // Runs `verifier` on every chunk after the plan hash check and any verifier
+    // added before it, e.g. against a signed manifest or a virus scanner.
+    pub fn with_verifier(mut self, verifier: Arc<dyn ChunkVerifier>) -> Self {
+        self.verifiers.push(verifier);
+        self
+    }
+
+    // The chunks are sealed with `content_key`. Verifiers check them sealed,
+    // and they are written opened, at their plaintext offsets.
+    pub fn with_content_key(mut self, content_key: Arc<ContentKey>) -> Self {
+        self.content_key = Some(content_key);
+        self
+    }
+
+    // Chunks that decode but fail verification are kept for inspection instead
+    // of dropped, see `keep_damaged`.
+    pub fn with_keep_damaged(mut self, keep_damaged: bool) -> Self {
+        self.keep_damaged = keep_damaged;
+        self
+    }
+
+    fn verify(&self, chunk: &FileChunk, data: &[u8]) -> Result<(), String> {
+        self.verifiers
+            .iter()
+            .try_for_each(|verifier| verifier.verify(chunk, data))
+    }
+
+    // Reserves `length` bytes of the budget unless that would overdraw it.
+    fn start_within_budget(&self, length: u64) -> bool {
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return false;
+        }
+        let Some(max_bytes) = self.max_bytes else {
+            self.committed_bytes.fetch_add(length, Ordering::Relaxed);
+            return true;
+        };
+        self.committed_bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |committed| {
+                (committed + length <= max_bytes).then_some(committed + length)
+            })
+            .is_ok()
+    }
+
+    pub fn progress(&self) -> watch::Receiver<DownloadProgress> {
+        self.progress.subscribe()
+    }
+
+    // Unknown free space does not block, a full disk still fails the write.
+    fn has_space_for(&self, path: &Path, length: u64) -> bool {
+        available_space(path)
+            .ok()
+            .is_none_or(|free| free >= length + self.min_free)
+    }
+
+    // Chunk ids are those of the plan, the chunk goes by `bus_id(plan, ..)`
+    // on the bus.
+    async fn download<FR>(
+        self: Arc<Self>,
+        plan: usize,
+        path: Arc<Path>,
+        chunk: FileChunk,
+        semaphore: Arc<Semaphore>,
+    ) -> (usize, ChunkId, Vec<ChunkSummary>, ChunkState)
+    where
+        FR: FrameReceiver<INFO_LENGTH> + Send + 'static,
+    {
+        let chunk_id = chunk.chunk_id;
+        let mut summaries = vec![];
+        // Out of the ids `run_plans` checked the plans fit.
+        let Some(bus_id) = bus_id(plan, chunk_id) else {
+            return (plan, chunk_id, summaries, ChunkState::Failed);
+        };
+        let mut mismatches = 0;
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                eprintln!("Retrying chunk {}, attempt {attempt}.", chunk_id.yellow());
+            }
+            let permit = semaphore.acquire().await.unwrap();
+            if !self.start_within_budget(chunk.length as u64) {
+                return (plan, chunk_id, summaries, ChunkState::OverBudget);
+            }
+            let outcome = decoding::spawn::<FR, INFO_LENGTH>(bus_id, self.bus.clone())
+                .await
+                .ok()
+                .flatten();
+            drop(permit);
+            // What was reserved is replaced by what was actually received.
+            let received = outcome
+                .as_ref()
+                .map_or(0, |outcome| outcome.stats.bytes_received);
+            self.committed_bytes.fetch_add(received, Ordering::Relaxed);
+            self.committed_bytes
+                .fetch_sub(chunk.length as u64, Ordering::Relaxed);
+
+            let Some(mut outcome) = outcome else {
+                eprintln!("Downloaded chunk {} currupted.", chunk_id.on_red());
+                continue;
+            };
+            let decoded = outcome.data.is_some();
+            let verified = match outcome.data.take() {
+                Some(data) => match self.verify(&chunk, &data) {
+                    Ok(()) => open_chunk(self.content_key.as_deref(), &chunk, data),
+                    Err(reason) if self.keep_damaged => {
+                        match keep_damaged(&path, &chunk, attempt, &outcome, &data, &reason) {
+                            Ok(kept) => eprintln!("Kept chunk {chunk_id} at {}", kept.display()),
+                            Err(err) => eprintln!("Failed to keep chunk {chunk_id}: {err}"),
+                        }
+                        Err(reason)
+                    }
+                    Err(reason) => Err(reason),
+                },
+                None => Err("not decoded".to_string()),
+            };
+            summaries.push(ChunkSummary::new(
+                chunk_id,
+                chunk.length,
+                &outcome.stats,
+                verified.is_ok(),
+            ));
+            let (offset, data) = match verified {
+                Ok(verified) => verified,
+                Err(reason) => {
+                    eprintln!("Downloaded chunk {} rejected: {reason}", chunk_id.on_red());
+                    if decoded {
+                        mismatches += 1;
+                        let rejected = (bus_id, StopReason::HashMismatch);
+                        self.bus
+                            .broadcast(|addr| *addr == BusAddress::ReceiverSocket, rejected)
+                            .await;
+                    }
+                    if mismatches >= MISMATCHES_TO_BLACKLIST {
+                        eprintln!(
+                            "Chunk {} differs on the server, not asking it again.",
+                            chunk_id.on_red()
+                        );
+                        return (plan, chunk_id, summaries, ChunkState::Blacklisted);
+                    }
+                    continue;
+                }
+            };
+
+            // Paused transfers keep decoded chunks in memory, off the disk.
+            if let Some(control) = self.control.as_ref() {
+                control.wait_resumed().await;
+            }
+            if !self.has_space_for(&path, chunk.length as u64) {
+                eprintln!("Not enough space to write chunk {}.", chunk_id.on_red());
+                return (plan, chunk_id, summaries, ChunkState::OutOfSpace);
+            }
+            return match write_at(&path, offset, &data) {
+                Ok(()) => {
+                    eprintln!(
+                        "Succeed in download chunk {}, at [{},{})",
+                        chunk_id.green(),
+                        offset.magenta(),
+                        (offset + data.len() as u64).magenta()
+                    );
+                    (plan, chunk_id, summaries, ChunkState::Written)
+                }
+                Err(err) => {
+                    eprintln!("Failed to write chunk {}: {err}", chunk_id.on_red());
+                    (plan, chunk_id, summaries, ChunkState::Failed)
+                }
+            };
+        }
+        (plan, chunk_id, summaries, ChunkState::Failed)
+    }
+
+    pub async fn run<FR>(self, chunks: Vec<FileChunk>) -> DownloadReport
+    where
+        FR: FrameReceiver<INFO_LENGTH> + Send + 'static,
+    {
+        let path = self.path.clone();
+        let file = PlannedFile {
+            path,
+            chunks,
+            priority: Priority::Normal,
+        };
+        // A single plan has every id to itself.
+        let mut reports = self.run_plans::<FR>(vec![file]).await.unwrap_or_default();
+        reports.pop().unwrap_or_default()
+    }
+
+    // Downloads several files at once, one report per file. The chunks of
+    // file `n` are those of plan `n` on the bus, see `plans`, and files take
+    // turns starting chunks, as many a turn as the weight of their priority,
+    // so each gets its share of the concurrency.
+    // Progress counts the chunks of every file. Fails if the files do not
+    // fit the ids of the bus, see `check_plan_space`.
+    pub async fn run_plans<FR>(
+        self,
+        files: Vec<PlannedFile>,
+    ) -> Result<Vec<DownloadReport>, PlanSpaceError>
+    where
+        FR: FrameReceiver<INFO_LENGTH> + Send + 'static,
+    {
+        check_plan_space(
+            files.len(),
+            files.iter().enumerate().flat_map(|(plan, file)| {
+                file.chunks.iter().map(move |chunk| (plan, chunk.chunk_id))
+            }),
+        )?;
+        let start = Instant::now();
+        let lengths: HashMap<(usize, ChunkId), u64> = files
+            .iter()
+            .enumerate()
+            .flat_map(|(plan, file)| {
+                file.chunks
+                    .iter()
+                    .map(move |chunk| ((plan, chunk.chunk_id), chunk.length as u64))
+            })
+            .collect();
+        self.progress
+            .send_modify(|progress| progress.total = lengths.len());
+
+        let paths: Vec<Arc<Path>> = files
+            .iter()
+            .map(|file| Arc::from(file.path.as_path()))
+            .collect();
+        let manager = Arc::new(self);
+        let semaphore = Arc::new(Semaphore::new(manager.concurrency));
+        let mut downloads = JoinSet::new();
+        // Spawned round robin, the semaphore hands permits out in that order.
+        let mut queues: Vec<_> = files
+            .into_iter()
+            .map(|file| (file.priority.weight(), file.chunks.into_iter()))
+            .collect();
+        loop {
+            let mut spawned = false;
+            for (plan, (weight, queue)) in queues.iter_mut().enumerate() {
+                for chunk in queue.take(*weight as usize) {
+                    let download = manager.clone().download::<FR>(
+                        plan,
+                        paths[plan].clone(),
+                        chunk,
+                        semaphore.clone(),
+                    );
+                    downloads.spawn(download);
+                    spawned = true;
+                }
+            }
+            if !spawned {
+                break;
+            }
+        }
+
+        let mut reports: Vec<DownloadReport> =
+            paths.iter().map(|_| DownloadReport::default()).collect();
+        let mut summaries: Vec<Vec<ChunkSummary>> = paths.iter().map(|_| vec![]).collect();
+        let mut out_of_space = false;
+        let mut space_check = interval(SPACE_CHECK_PERIOD);
+        while !out_of_space {
+            tokio::select! {
+                joined = downloads.join_next() => {
+                    let Some(joined) = joined else {
+                        break;
+                    };
+                    let (plan, chunk_id, attempts, state) = match joined {
+                        Ok(download) => download,
+                        Err(err) => {
+                            eprintln!("Download task failed: {err}");
+                            continue;
+                        }
+                    };
+                    summaries[plan].extend(attempts);
+                    let report = &mut reports[plan];
+                    match state {
+                        ChunkState::Written => report.written.push(chunk_id),
+                        ChunkState::Failed => report.failed.push(chunk_id),
+                        ChunkState::OutOfSpace => {
+                            report.out_of_space = true;
+                            out_of_space = true;
+                        }
+                        ChunkState::OverBudget => report.over_budget.push(chunk_id),
+                        ChunkState::Blacklisted => {
+                            report.failed.push(chunk_id);
+                            report.blacklisted.push(chunk_id);
+                        }
+                    }
+                    manager.progress.send_modify(|progress| match state {
+                        ChunkState::Written => {
+                            progress.written += 1;
+                            progress.bytes_written += lengths[&(plan, chunk_id)];
+                        }
+                        ChunkState::Failed | ChunkState::Blacklisted => progress.failed += 1,
+                        ChunkState::OutOfSpace | ChunkState::OverBudget => {}
+                    });
+                },
+                _ = space_check.tick() => {
+                    for (report, path) in reports.iter_mut().zip(&paths) {
+                        report.out_of_space = !manager.has_space_for(path, 0);
+                        out_of_space |= report.out_of_space;
+                    }
+                },
+            }
+        }
+        downloads.abort_all();
+
+        let elapsed = start.elapsed();
+        for (report, summaries) in reports.iter_mut().zip(summaries) {
+            report.over_budget.sort_unstable();
+            report.summary = TransferSummary::new(summaries, elapsed);
+        }
+        Ok(reports)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::decoding::DecodeStats;
+    use tempfile::tempdir;
+
+    #[test]
+    fn damaged_chunks_are_kept_with_their_stats() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("file.bin");
+        let chunk = FileChunk {
+            chunk_id: ChunkId(7),
+            offset: 0,
+            length: 4,
+            hash: "planned".to_string(),
+        };
+        let outcome = DecodeOutcome {
+            data: None,
+            stats: DecodeStats {
+                frames_received: 3,
+                ..Default::default()
+            },
+            transmission_info: vec![0xab, 0xcd],
+        };
+        let kept = keep_damaged(&path, &chunk, 1, &outcome, b"data", "hash mismatch").unwrap();
+        assert_eq!(kept, dir.path().join("file.bin.bad").join("7"));
+        assert_eq!(std::fs::read(&kept).unwrap(), b"data");
+
+        let info = std::fs::read_to_string(dir.path().join("file.bin.bad/7.toml")).unwrap();
+        let info: toml::Table = toml::from_str(&info).unwrap();
+        assert_eq!(info["transmission_info"].as_str(), Some("abcd"));
+        assert_eq!(info["reason"].as_str(), Some("hash mismatch"));
+        assert_eq!(info["received"]["frames_received"].as_integer(), Some(3));
+    }
+
+    #[test]
+    fn unknown_free_space_does_not_block() {
+        let dir = tempdir().unwrap();
+        let manager = DownloadManager::<12>::new(Arc::new(Bus::default()), dir.path())
+            .with_min_free(u64::MAX / 2);
+        assert!(!manager.has_space_for(dir.path(), 0));
+        assert!(manager.has_space_for(&dir.path().join("missing"), 0));
+    }
+}
@@ -1,51 +1,362 @@
-use crate::protocol::{coding::FrameSender, wire::frames::DataFrame};
-use crate::util::Compare;
-use crate::util::file::{CHUNK_INDEX, mmap_segment};
+use crate::protocol::coding::FrameSender;
+use crate::protocol::wire::PacketIds;
+use crate::protocol::wire::frames::{DataFrame, ErrorFrame, ErrorReason};
+use crate::util::clock::SharedClock;
+use crate::util::cpu::{Subsystem, accounted, measure};
+use crate::util::file::{CHUNK_INDEX, ChunkIndex, mmap_segment, prefetch_segment};
+use crate::util::store::{ChunkData, ChunkStore};
+use crate::util::telemetry::{ChunkEnd, ChunkEvent, record};
 use crate::util::timer::{SenderTimer, SenderTimerOutput};
+use crate::util::units::ChunkId;
 use bytes::Bytes;
-use memmap2::Mmap;
+use dashmap::DashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use tokio::sync::Mutex;
 use tokio::time::{Duration, Instant};
 
-use super::{Bus, BusAddress, BusInterface, BusMessage, SendingOrder};
-
-use crate::util::timer_logger::print_relative_time;
+use super::stats::{EncoderProgress, PROGRESS_PERIOD};
+use super::supervisor::RestartPolicy;
+use super::window::{FrameWindow, serial_lt};
+use super::{BuiltDataPacket, Bus, BusAddress, BusInterface, BusMessage, PeerEvent, SendingOrder};
 
+#[allow(clippy::too_many_arguments)]
 pub async fn spawn<FS, const INFO_LENGTH: usize>(
     start_order: SendingOrder,
     bus: Arc<Bus<BusAddress, BusMessage<INFO_LENGTH>>>,
     sock_addr: SocketAddr,
     bus_addr: BusAddress,
-) where
+    prepared: Arc<PreparedEncoders<FS>>,
+    clock: SharedClock,
+    pad_to: Option<usize>,
+    repair: RepairStrategy,
+) -> Result<(), ErrorReason>
+where
     FS: FrameSender<INFO_LENGTH> + std::marker::Send + 'static,
 {
-    let chunk_info = CHUNK_INDEX
-        .get()
-        .and_then(|index| index.get(start_order.chunk_id));
-    if chunk_info.is_none() {
+    let index = CHUNK_INDEX.get().ok_or(ErrorReason::UnknownChunk)?;
+    let (path, offset, length) = index
+        .get(start_order.chunk_id)
+        .ok_or(ErrorReason::UnknownChunk)?;
+    // Checked after the lookup, so the chunk is never from a newer plan than the peer's.
+    if index.epoch() != start_order.generation {
+        return Err(ErrorReason::StaleGeneration);
+    }
+    if !index.is_available(start_order.chunk_id) {
+        return Err(ErrorReason::ChunkUnavailable);
+    }
+    record(start_order.chunk_id, sock_addr, ChunkEvent::Order);
+    let lock_pages = index.lock_pages();
+    let store = index.store();
+    let chunk_id = start_order.chunk_id;
+
+    // Registered before spawning, so orders arriving during init are queued
+    // instead of spawning a second encoder. An order racing one that already
+    // started the encoder, e.g. while it restarts, is handed to that one.
+    let Ok(bus_interface) = bus.clone().register(bus_addr.clone()) else {
+        bus.send(bus_addr, start_order.into()).await.ok();
+        return Ok(());
+    };
+    let mut bus_interface = Some(bus_interface);
+    let task_bus = bus.clone();
+    let task_addr = bus_addr.clone();
+    // Only the first run may use a prepared encoder, a restart starts from scratch.
+    let mut prepared = prepared.take(start_order.chunk_id, start_order.generation);
+
+    bus.supervisor().spawn(
+        bus_addr,
+        RestartPolicy::Restart { max_restarts: 1 },
+        move || {
+            // A restart finding its address taken over leaves it to the new encoder.
+            let bus_interface = bus_interface
+                .take()
+                .or_else(|| task_bus.clone().register(task_addr.clone()).ok());
+            let start_order = start_order.clone();
+            let path = path.clone();
+            let prepared = prepared.take();
+            let clock = clock.clone();
+            accounted(Subsystem::Encoder, async move {
+                let bus_interface = bus_interface?;
+                // An empty slot failed to load or verify the chunk, which is not read again.
+                if let Some(prepared) = prepared {
+                    let Some(mut encoder) = prepared.lock().await.take() else {
+                        record(
+                            start_order.chunk_id,
+                            sock_addr,
+                            ChunkEvent::End(ChunkEnd::Failed),
+                        );
+                        report_error(
+                            &bus_interface,
+                            sock_addr,
+                            start_order.chunk_id,
+                            ErrorReason::StaleGeneration,
+                        )
+                        .await;
+                        return None;
+                    };
+                    record(
+                        start_order.chunk_id,
+                        sock_addr,
+                        ChunkEvent::InitDone { prepared: true },
+                    );
+                    encoder.seek(start_order.offset_next.0);
+                    ChunkEncoder::<FS, INFO_LENGTH>::with_encoder(
+                        encoder,
+                        start_order,
+                        bus_interface,
+                        sock_addr,
+                        clock,
+                    )
+                    .with_padding(pad_to)
+                    .with_repair(repair)
+                    .run()
+                    .await;
+                    return Some(());
+                }
+                let chunk_data = tokio::task::spawn_blocking(move || {
+                    load_verified(
+                        index,
+                        chunk_id,
+                        store.load(path, offset, length, lock_pages),
+                    )
+                })
+                .await
+                .map_err(std::io::Error::other)
+                .and_then(|result| result);
+                let chunk_data = match chunk_data {
+                    Ok(chunk_data) => chunk_data,
+                    Err(err) => {
+                        eprintln!("Failed to load chunk {}: {err}", start_order.chunk_id);
+                        record(
+                            start_order.chunk_id,
+                            sock_addr,
+                            ChunkEvent::End(ChunkEnd::Failed),
+                        );
+                        // Contents not matching the plan mean the file changed since.
+                        let reason = if err.kind() == std::io::ErrorKind::InvalidData {
+                            ErrorReason::StaleGeneration
+                        } else {
+                            ErrorReason::ChunkUnavailable
+                        };
+                        report_error(&bus_interface, sock_addr, start_order.chunk_id, reason).await;
+                        return None;
+                    }
+                };
+                let encoder: ChunkEncoder<FS, INFO_LENGTH> =
+                    ChunkEncoder::new(chunk_data, start_order, bus_interface, sock_addr, clock)
+                        .await?;
+                encoder.with_padding(pad_to).with_repair(repair).run().await;
+                Some(())
+            })
+        },
+    );
+    Ok(())
+}
+
+// A loaded chunk not matching the plan is refused, and the chunk marked
+// unavailable, when the index verifies chunks.
+fn load_verified(
+    index: &ChunkIndex,
+    chunk_id: ChunkId,
+    loaded: std::io::Result<ChunkData>,
+) -> std::io::Result<ChunkData> {
+    let chunk_data = loaded?;
+    if !index.verify_chunk(chunk_id, chunk_data.as_ref()) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "contents do not match the plan",
+        ));
+    }
+    Ok(chunk_data)
+}
+
+type PreparedSlot<FS> = Arc<Mutex<Option<FS>>>;
+type PreparedSlots<FS> = DashMap<ChunkId, (Instant, u64, PreparedSlot<FS>)>; // (born, epoch, slot)
+
+// Encoders initialized before their chunk is ordered, so RaptorQ setup overlaps
+// with the chunks still streaming. The first peer ordering a chunk takes its encoder.
+pub struct PreparedEncoders<FS> {
+    slots: Arc<PreparedSlots<FS>>,
+}
+
+impl<FS> Default for PreparedEncoders<FS> {
+    fn default() -> Self {
+        Self {
+            slots: Arc::new(DashMap::new()),
+        }
+    }
+}
+
+// Each holds a whole encoded chunk in memory.
+const MAX_PREPARED: usize = 8;
+const PREPARED_EXPIRY: Duration = Duration::from_secs(60);
+
+impl<FS> PreparedEncoders<FS>
+where
+    FS: Send + 'static,
+{
+    // One prepared from another plan epoch is dropped.
+    fn take(&self, chunk_id: ChunkId, epoch: u64) -> Option<PreparedSlot<FS>> {
+        self.slots
+            .remove(&chunk_id)
+            .and_then(|(_, (_, born_in, slot))| (born_in == epoch).then_some(slot))
+    }
+
+    // Starts initializing an encoder for `chunk_id` in the background. Does
+    // nothing if one is already prepared, and only reads the chunk ahead if
+    // too many are.
+    pub fn prepare<const INFO_LENGTH: usize>(&self, chunk_id: ChunkId, clock: &SharedClock)
+    where
+        FS: FrameSender<INFO_LENGTH>,
+    {
+        let now = clock.now();
+        self.slots
+            .retain(|_, (born, _, _)| now - *born < PREPARED_EXPIRY);
+        if self.slots.contains_key(&chunk_id) {
+            return;
+        }
+        if self.slots.len() >= MAX_PREPARED {
+            prefetch(chunk_id);
+            return;
+        }
+        let Some(index) = CHUNK_INDEX.get() else {
+            return;
+        };
+        let Some((path, offset, length)) = index.get(chunk_id) else {
+            return;
+        };
+        let epoch = index.epoch();
+        if !index.is_available(chunk_id) {
+            return;
+        }
+        let (store, lock_pages) = (index.store(), index.lock_pages());
+
+        // Locked until the encoder is ready, so a peer ordering the chunk meanwhile waits for it.
+        let slot = Arc::new(Mutex::new(None));
+        let Ok(mut guard) = slot.clone().try_lock_owned() else {
+            return;
+        };
+        self.slots.insert(chunk_id, (now, epoch, slot.clone()));
+        let slots = self.slots.clone();
+        tokio::task::spawn_blocking(move || {
+            match load_verified(
+                index,
+                chunk_id,
+                store.load(path, offset, length, lock_pages),
+            ) {
+                Ok(chunk_data) => *guard = Some(FS::init(chunk_data, 0)),
+                Err(err) => {
+                    eprintln!("Failed to prepare chunk {chunk_id}: {err}");
+                    // Left in place, it would only send the next order to read the chunk again.
+                    slots.remove_if(&chunk_id, |_, (_, _, other)| Arc::ptr_eq(other, &slot));
+                }
+            }
+        });
+    }
+}
+
+// Starts reading a chunk into the page cache ahead of its first order.
+pub fn prefetch(chunk_id: ChunkId) {
+    let Some(index) = CHUNK_INDEX.get() else {
+        return;
+    };
+    if index.store() != ChunkStore::Mmap || !index.is_available(chunk_id) {
         return;
     }
-    let chunk_info = chunk_info.unwrap();
+    let Some((path, offset, length)) = index.get(chunk_id) else {
+        return;
+    };
+    let path = path.clone();
+    tokio::task::spawn_blocking(move || {
+        mmap_segment(path, offset, length).and_then(|chunk| prefetch_segment(&chunk, false))
+    });
+}
 
-    let chunk_data = mmap_segment(chunk_info.0, chunk_info.1, chunk_info.2).unwrap();
+// How much repair an encoder sends unasked. Streaming goes on for as long as
+// the peer's window is open. Proactive sends the source frames and
+// `overhead_percent` more, then waits: every order coming once those are out
+// means the peer still wants more, and gets a round of that much again, at
+// least `MIN_REPAIR_ROUND` frames. Low loss links then carry little unneeded
+// repair, at the cost of a ticket period for every round lost ones need.
+// Adaptive is proactive with the overhead following the loss towards each
+// peer, see `LossEstimate` in `sending`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RepairStrategy {
+    #[default]
+    Stream,
+    Proactive {
+        overhead_percent: u32,
+    },
+    Adaptive,
+}
 
-    let bus_interface = bus.register(bus_addr);
-    let encoder: ChunkEncoder<FS, INFO_LENGTH> =
-        ChunkEncoder::new(chunk_data, start_order, bus_interface, sock_addr).await;
+// Overhead of adaptive repair until the loss towards a peer is known.
+pub const ADAPTIVE_START_PERCENT: u32 = 10;
 
-    tokio::spawn(encoder.run());
+// `auto` for adaptive repair, or a fixed overhead in percent.
+pub fn parse_repair(value: &str) -> Result<RepairStrategy, String> {
+    match value {
+        "auto" => Ok(RepairStrategy::Adaptive),
+        percent => percent
+            .parse()
+            .map(|overhead_percent| RepairStrategy::Proactive { overhead_percent })
+            .map_err(|_| format!("`{percent}` is neither a percentage nor `auto`")),
+    }
+}
+
+const MIN_REPAIR_ROUND: u32 = 16;
+
+impl RepairStrategy {
+    // Frames sent unasked at first and in every later round, None for no limit.
+    fn rounds(&self, source_frames: u32) -> Option<(u32, u32)> {
+        let overhead_percent = match *self {
+            RepairStrategy::Stream => return None,
+            RepairStrategy::Proactive { overhead_percent } => overhead_percent,
+            RepairStrategy::Adaptive => ADAPTIVE_START_PERCENT,
+        };
+        let repair = (source_frames as u64 * overhead_percent as u64 / 100).min(u32::MAX as u64);
+        Some((
+            source_frames.saturating_add(repair as u32),
+            (repair as u32).max(MIN_REPAIR_ROUND),
+        ))
+    }
+}
+
+async fn report_error<const INFO_LENGTH: usize>(
+    bus_interface: &BusInterface<BusAddress, BusMessage<INFO_LENGTH>>,
+    sock_addr: SocketAddr,
+    chunk_id: ChunkId,
+    reason: ErrorReason,
+) {
+    bus_interface
+        .send(
+            BusAddress::SenderSocket,
+            (sock_addr, ErrorFrame::new(chunk_id, reason)),
+        )
+        .await
+        .ok();
 }
 
 pub struct ChunkEncoder<FS: FrameSender<INFO_LENGTH>, const INFO_LENGTH: usize> {
-    chunk_id: u32,
+    chunk_id: ChunkId,
+    // Plan epoch of the chunk data.
+    generation: u64,
     encoder: FS,
     transmission_info: [u8; INFO_LENGTH],
     bus_interface: BusInterface<BusAddress, BusMessage<INFO_LENGTH>>,
-    max_frame_offset: u32,
-    max_sent_offset: u32,
+    window: FrameWindow,
     timer: SenderTimer,
+    clock: SharedClock,
     sock_addr: SocketAddr,
+    frames_sent: u64,
+    bytes_sent: u64,
+    last_report: Instant,
+    pad_to: Option<usize>,
+    ids: Arc<PacketIds>,
+    source_frames: u32,
+    repair: RepairStrategy,
+    // Frames from here on wait for the peer to ask again, see `RepairStrategy`.
+    repair_limit: Option<u32>,
 }
 
 impl<FS: FrameSender<INFO_LENGTH>, const INFO_LENGTH: usize> ChunkEncoder<FS, INFO_LENGTH>
@@ -53,77 +364,244 @@ where
     FS: Send + 'static,
 {
     pub async fn new(
-        chunk_data: Mmap,
+        chunk_data: ChunkData,
         start_order: SendingOrder,
         bus_interface: BusInterface<BusAddress, BusMessage<INFO_LENGTH>>,
         sock_addr: SocketAddr,
-    ) -> Self {
-        print_relative_time(start_order.chunk_id, "Start init sender", Instant::now());
-        let encoder =
-            tokio::task::spawn_blocking(move || FS::init(chunk_data, start_order.offset_next))
-                .await
-                .unwrap();
+        clock: SharedClock,
+    ) -> Option<Self> {
+        record(start_order.chunk_id, sock_addr, ChunkEvent::InitStarted);
+        let encoder = match tokio::task::spawn_blocking(move || {
+            measure(Subsystem::Encoder, || {
+                FS::init(chunk_data, start_order.offset_next.0)
+            })
+        })
+        .await
+        {
+            Ok(encoder) => encoder,
+            Err(err) => {
+                eprintln!(
+                    "Failed to init encoder for chunk {}: {err}",
+                    start_order.chunk_id
+                );
+                record(
+                    start_order.chunk_id,
+                    sock_addr,
+                    ChunkEvent::End(ChunkEnd::Failed),
+                );
+                report_error(
+                    &bus_interface,
+                    sock_addr,
+                    start_order.chunk_id,
+                    ErrorReason::EncoderFailed,
+                )
+                .await;
+                return None;
+            }
+        };
+
+        let sender = Self::with_encoder(
+            encoder,
+            start_order.clone(),
+            bus_interface,
+            sock_addr,
+            clock,
+        );
+        record(
+            start_order.chunk_id,
+            sock_addr,
+            ChunkEvent::InitDone { prepared: false },
+        );
+        Some(sender)
+    }
 
+    pub fn with_encoder(
+        encoder: FS,
+        start_order: SendingOrder,
+        bus_interface: BusInterface<BusAddress, BusMessage<INFO_LENGTH>>,
+        sock_addr: SocketAddr,
+        clock: SharedClock,
+    ) -> Self {
         let transmission_info = encoder.get_trasmission_info();
-        let sender = Self {
+        let source_frames = encoder.source_frames();
+        Self {
             chunk_id: start_order.chunk_id,
+            generation: start_order.generation,
             encoder,
             transmission_info,
             bus_interface,
-            timer: SenderTimer::new(
+            timer: SenderTimer::with_clock(
                 start_order
                     .sending_interval
                     .unwrap_or(Duration::from_millis(20)),
+                clock.clone(),
             ),
-            max_sent_offset: 0,
-            max_frame_offset: start_order.offset_next + start_order.offset_no_more_than,
+            window: FrameWindow::new(start_order.offset_next.0, start_order.offset_no_more_than.0),
             sock_addr,
+            frames_sent: 0,
+            bytes_sent: 0,
+            last_report: clock.now(),
+            clock,
+            pad_to: None,
+            ids: PacketIds::for_peer(sock_addr),
+            source_frames,
+            repair: RepairStrategy::Stream,
+            repair_limit: None,
+        }
+    }
+
+    // Every data packet is padded to `pad_to` bytes when given.
+    pub fn with_padding(mut self, pad_to: Option<usize>) -> Self {
+        self.pad_to = pad_to;
+        self
+    }
+
+    pub fn with_repair(mut self, repair: RepairStrategy) -> Self {
+        self.repair = repair;
+        self.repair_limit = repair
+            .rounds(self.source_frames)
+            .map(|(first, _)| self.window.next().wrapping_add(first));
+        self
+    }
+
+    fn within_repair_limit(&self) -> bool {
+        self.repair_limit
+            .is_none_or(|limit| serial_lt(self.window.next(), limit))
+    }
+
+    async fn report_progress(&mut self, finished: bool) {
+        self.last_report = self.clock.now();
+        let progress = EncoderProgress {
+            chunk_id: self.chunk_id,
+            peer: self.sock_addr,
+            frames_sent: self.frames_sent,
+            bytes_sent: self.bytes_sent,
+            max_sent_offset: self.window.next().wrapping_sub(1),
+            max_frame_offset: self.window.limit(),
+            source_frames: self.source_frames,
+            finished,
         };
-        print_relative_time(start_order.chunk_id, "Finish init sender", Instant::now());
-        sender
+        // Nobody may be listening.
+        self.bus_interface
+            .send(BusAddress::SenderStats, progress)
+            .await
+            .ok();
+    }
+
+    fn record(&self, event: ChunkEvent) {
+        record(self.chunk_id, self.sock_addr, event);
     }
 
     pub async fn run(mut self) {
-        loop {
+        let end = loop {
             tokio::select! {
-                Some(order) = self.bus_interface.recv::<SendingOrder>() => {
-                    let now = Instant::now();
-                    print_relative_time(self.chunk_id, "Got Order", now);
-                    self.timer.set_rate(now, order.sending_interval);
-                    self.max_frame_offset.cmax(order.offset_no_more_than);
-                    if order.close_now {
-                        print_relative_time(self.chunk_id, "FINISH", now);
-                        break;
+                Some(message) = self.bus_interface.recv::<BusMessage<INFO_LENGTH>>() => {
+                    let now = self.clock.now();
+                    match message {
+                        BusMessage::SendingOrder(order) => {
+                            self.record(ChunkEvent::Order);
+                            self.timer.set_rate(now, order.sending_interval);
+                            self.window.extend(order.offset_no_more_than.0);
+                            if order.close_now {
+                                break ChunkEnd::Finished;
+                            }
+                            // The peer moved on to another plan, the next order starts a fresh encoder.
+                            if order.generation != self.generation {
+                                break ChunkEnd::OtherGeneration;
+                            }
+                            if let Some((_, round)) = self.repair.rounds(self.source_frames)
+                                && !self.within_repair_limit()
+                            {
+                                self.repair_limit = Some(self.window.next().wrapping_add(round));
+                            }
+                        }
+                        BusMessage::PeerEvent(PeerEvent::RateChanged(interval)) => {
+                            self.timer.set_interval(now, interval);
+                        }
+                        // Taken for the rounds still to come.
+                        BusMessage::PeerEvent(PeerEvent::RepairChanged(overhead_percent))
+                            if self.repair != RepairStrategy::Stream =>
+                        {
+                            self.repair = RepairStrategy::Proactive { overhead_percent };
+                        }
+                        BusMessage::PeerEvent(PeerEvent::Disconnected) => {
+                            break ChunkEnd::PeerDisconnected;
+                        }
+                        _ => {}
                     }
                 },
 
                 output = &mut self.timer => {
                     match output {
                         SenderTimerOutput::Send(x) => {
+                            let mut sent = 0;
+                            let mut failed = false;
                             for _ in 0..x{
-                                if self.max_sent_offset >= self.max_frame_offset {break;}
+                                if !self.window.can_send() || !self.within_repair_limit() {break;}
                                 let (frame_offset, frame) = self.encoder.next_frame();
+                                let length = frame.len() as u64;
                                 let data_frame = DataFrame::new(self.chunk_id, frame_offset, self.transmission_info, Bytes::from(frame));
 
-                                if self.bus_interface.send(BusAddress::SenderSocket,(self.sock_addr, data_frame )).await.is_err(){
-                                    print_relative_time(self.chunk_id, "Can not send", Instant::now());
+                                let packet = BuiltDataPacket::new(data_frame, self.pad_to, &self.ids);
+                                if self.bus_interface.send(BusAddress::SenderSocket,(self.sock_addr, packet)).await.is_err(){
+                                    failed = true;
                                     break;
                                 }
+                                sent += 1;
 
-                                if frame_offset % 4096 == 0{
-                                    print_relative_time(self.chunk_id, format!("Send {frame_offset}").as_str(), Instant::now());
-                                }
-
-                                self.max_sent_offset = frame_offset;
+                                self.frames_sent += 1;
+                                self.bytes_sent += length;
+                                self.window.sent(frame_offset);
+                            }
+                            self.record(ChunkEvent::Frames(sent));
+                            if failed {
+                                break ChunkEnd::SendFailed;
+                            }
+                            if self.clock.now() - self.last_report >= PROGRESS_PERIOD {
+                                self.report_progress(false).await;
                             }
                         },
                         SenderTimerOutput::Close => {
-                            print_relative_time(self.chunk_id, "CLOSE", Instant::now());
-                            break;
+                            break ChunkEnd::TimedOut;
                         }
                     };
                 }
             }
-        }
+        };
+        self.record(ChunkEvent::End(end));
+        self.report_progress(true).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proactive_repair_rounds() {
+        assert_eq!(RepairStrategy::Stream.rounds(750), None);
+        let proactive = |overhead_percent| RepairStrategy::Proactive { overhead_percent };
+        assert_eq!(proactive(10).rounds(750), Some((825, 75)));
+        // Small chunks still get a useful round.
+        assert_eq!(proactive(10).rounds(40), Some((44, MIN_REPAIR_ROUND)));
+        assert_eq!(proactive(0).rounds(750), Some((750, MIN_REPAIR_ROUND)));
+        assert_eq!(
+            proactive(u32::MAX).rounds(u32::MAX),
+            Some((u32::MAX, u32::MAX))
+        );
+        assert_eq!(RepairStrategy::Adaptive.rounds(750), Some((825, 75)));
+    }
+
+    #[test]
+    fn parse_repair_strategies() {
+        assert_eq!(parse_repair("auto"), Ok(RepairStrategy::Adaptive));
+        assert_eq!(
+            parse_repair("15"),
+            Ok(RepairStrategy::Proactive {
+                overhead_percent: 15
+            })
+        );
+        assert!(parse_repair("-3").is_err());
+        assert!(parse_repair("lots").is_err());
     }
 }
@@ -0,0 +1,455 @@
+use dashmap::DashMap;
+use std::io::{self, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{Duration, timeout};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::protocol::token::{DownloadToken, TokenChecker};
+use crate::util::file::read_segment;
+use crate::util::log::current_timestamp_ms;
+
+pub const PLAN_PATH: &str = "/plan";
+pub const FILE_PATH: &str = "/file";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_HEADER_LINES: usize = 64;
+// Bodies are read from the file and sent this much at a time.
+const BODY_PIECE: u64 = 1 << 20;
+
+#[derive(Debug, Default)]
+struct Request {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// The status line and the headers, up to the blank line.
+async fn read_head<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> io::Result<Vec<String>> {
+    let mut lines = vec![];
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "closed mid request"));
+        }
+        let line = line.trim_end().to_string();
+        if line.is_empty() {
+            return Ok(lines);
+        }
+        if lines.len() == MAX_HEADER_LINES {
+            return Err(Error::new(ErrorKind::InvalidData, "too many headers"));
+        }
+        lines.push(line);
+    }
+}
+
+fn parse_headers(lines: &[String]) -> Vec<(String, String)> {
+    lines
+        .iter()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+async fn read_request<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> io::Result<Request> {
+    let lines = read_head(reader).await?;
+    let mut words = lines
+        .first()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "empty request"))?
+        .split_whitespace();
+    let (Some(method), Some(target)) = (words.next(), words.next()) else {
+        return Err(Error::new(ErrorKind::InvalidData, "malformed request line"));
+    };
+    Ok(Request {
+        method: method.to_string(),
+        path: target.split('?').next().unwrap_or_default().to_string(),
+        headers: parse_headers(&lines[1..]),
+    })
+}
+
+// The bytes `[start, end)` of a `length` byte body a `Range` header asks for.
+// None for ranges that cannot be served, and whole bodies are sent for those
+// not understood, e.g. several ranges at once.
+fn parse_range(range: &str, length: u64) -> Option<Option<(u64, u64)>> {
+    let Some(spec) = range.trim().strip_prefix("bytes=") else {
+        return Some(None);
+    };
+    if spec.contains(',') {
+        return Some(None);
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return Some(None);
+    };
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().ok()?;
+            (length.saturating_sub(suffix), length)
+        }
+        (start, "") => (start.parse().ok()?, length),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.saturating_add(1),
+        ),
+    };
+    let end = end.min(length);
+    (start < end).then_some(Some((start, end)))
+}
+
+enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    // Bytes `[start, end)` of the served file.
+    File(u64, u64),
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Body,
+}
+
+impl Response {
+    fn status(status: u16) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: Body::Empty,
+        }
+    }
+
+    fn with_header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    fn body_length(&self) -> u64 {
+        match &self.body {
+            Body::Empty => 0,
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::File(start, end) => end - start,
+        }
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        206 => "Partial Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        416 => "Range Not Satisfiable",
+        _ => "Internal Server Error",
+    }
+}
+
+// Serves the plan file, and the planned file too if it is small enough, over
+// HTTP or HTTPS, for clients that cannot get UDP through at all. The file is
+// only sent for a download token in an `Authorization: Bearer` header.
+pub struct HttpGateway {
+    plan_file: PathBuf,
+    file: PathBuf,
+    max_file_size: u64,
+    tokens: Option<TokenChecker>,
+    tls: Option<TlsAcceptor>,
+    // Bytes sent so far for each token carrying a byte limit, by its key.
+    served: DashMap<[u8; 32], u64>,
+}
+
+impl HttpGateway {
+    // Only serves the plan until given a token checker and a file size.
+    pub fn new(plan_file: &Path, file: &Path) -> Self {
+        Self {
+            plan_file: plan_file.to_path_buf(),
+            file: file.to_path_buf(),
+            max_file_size: 0,
+            tokens: None,
+            tls: None,
+            served: DashMap::new(),
+        }
+    }
+
+    // Files longer than `max_file_size` bytes are left to the UDP path.
+    pub fn with_max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    pub fn with_tokens(mut self, tokens: TokenChecker) -> Self {
+        self.tokens = Some(tokens);
+        self
+    }
+
+    // Speaks HTTPS with the certificate chain and private key in these PEM files.
+    pub fn with_tls(mut self, cert: &Path, key: &Path) -> io::Result<Self> {
+        let certs = CertificateDer::pem_file_iter(cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|err| Error::other(format!("{}: {err}", cert.display())))?;
+        let key = PrivateKeyDer::from_pem_file(key)
+            .map_err(|err| Error::other(format!("{}: {err}", key.display())))?;
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(Error::other)?;
+        self.tls = Some(TlsAcceptor::from(Arc::new(config)));
+        Ok(self)
+    }
+
+    pub async fn serve(self, listener: TcpListener) {
+        let gateway = Arc::new(self);
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    eprintln!("HTTP gateway failed to accept: {err}");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let gateway = gateway.clone();
+            tokio::spawn(async move {
+                let handled = match gateway.tls.clone() {
+                    Some(tls) => match tls.accept(stream).await {
+                        Ok(stream) => gateway.handle(stream).await,
+                        Err(err) => Err(err),
+                    },
+                    None => gateway.handle(stream).await,
+                };
+                if let Err(err) = handled {
+                    eprintln!("HTTP request from {peer} failed: {err}");
+                }
+            });
+        }
+    }
+
+    // One request per connection.
+    async fn handle<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S) -> io::Result<()> {
+        let mut stream = BufReader::new(stream);
+        let request = timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "request timed out"))??;
+        let response = self.respond(&request)?;
+        let stream = stream.get_mut();
+
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            response.status,
+            reason(response.status)
+        );
+        for (name, value) in response.headers.iter() {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            response.body_length()
+        ));
+        stream.write_all(head.as_bytes()).await?;
+        if request.method != "HEAD" {
+            match response.body {
+                Body::Empty => {}
+                Body::Bytes(bytes) => stream.write_all(&bytes).await?,
+                Body::File(mut start, end) => {
+                    while start < end {
+                        let length = (end - start).min(BODY_PIECE);
+                        stream
+                            .write_all(&read_segment(&self.file, start, length as usize)?)
+                            .await?;
+                        start += length;
+                    }
+                }
+            }
+        }
+        stream.shutdown().await
+    }
+
+    // Lets the request have `length` more bytes of the file, or says how to refuse it.
+    fn authorize(&self, request: &Request, length: u64) -> Result<(), u16> {
+        let tokens = self.tokens.as_ref().ok_or(403u16)?;
+        let token = request
+            .header("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(DownloadToken::decode)
+            .ok_or(401u16)?;
+        let key = token.key.verifying_key();
+        if !tokens.check(&key, &token.grant, current_timestamp_ms()) {
+            return Err(401);
+        }
+        let Some(max_bytes) = token.max_bytes() else {
+            return Ok(());
+        };
+        let mut served = self.served.entry(key.to_bytes()).or_default();
+        if *served + length > max_bytes {
+            return Err(403);
+        }
+        *served += length;
+        Ok(())
+    }
+
+    fn respond(&self, request: &Request) -> io::Result<Response> {
+        if request.method != "GET" && request.method != "HEAD" {
+            return Ok(Response::status(405).with_header("Allow", "GET, HEAD".to_string()));
+        }
+        match request.path.as_str() {
+            PLAN_PATH => Ok(Response {
+                body: Body::Bytes(std::fs::read(&self.plan_file)?),
+                ..Response::status(200).with_header("Content-Type", "application/toml".into())
+            }),
+            FILE_PATH => {
+                let length = std::fs::metadata(&self.file)?.len();
+                if length > self.max_file_size {
+                    return Ok(Response::status(404));
+                }
+                let range = match request.header("Range") {
+                    Some(range) => parse_range(range, length),
+                    None => Some(None),
+                };
+                let Some(range) = range else {
+                    return Ok(Response::status(416)
+                        .with_header("Content-Range", format!("bytes */{length}")));
+                };
+                let (start, end) = range.unwrap_or((0, length));
+                let sent = match request.method.as_str() {
+                    "HEAD" => 0,
+                    _ => end - start,
+                };
+                if let Err(status) = self.authorize(request, sent) {
+                    return Ok(Response::status(status));
+                }
+                let response = match range {
+                    Some(_) => Response::status(206).with_header(
+                        "Content-Range",
+                        format!("bytes {start}-{}/{length}", end - 1),
+                    ),
+                    None => Response::status(200),
+                };
+                Ok(Response {
+                    body: Body::File(start, end),
+                    ..response
+                        .with_header("Accept-Ranges", "bytes".into())
+                        .with_header("Content-Type", "application/octet-stream".into())
+                })
+            }
+            _ => Ok(Response::status(404)),
+        }
+    }
+}
+
+async fn get<S: AsyncRead + AsyncWrite + Unpin>(stream: S, request: &str) -> io::Result<Vec<u8>> {
+    let mut stream = BufReader::new(stream);
+    stream.get_mut().write_all(request.as_bytes()).await?;
+    let lines = read_head(&mut stream).await?;
+    let status = lines
+        .first()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "malformed response"))?;
+    if status != 200 && status != 206 {
+        return Err(Error::other(format!("HTTP {status} {}", reason(status))));
+    }
+    let headers = parse_headers(&lines[1..]);
+    let length = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+        .and_then(|(_, value)| value.parse::<usize>().ok());
+    let mut body = vec![];
+    match length {
+        Some(length) => {
+            body.resize(length, 0);
+            stream.read_exact(&mut body).await?;
+        }
+        None => {
+            stream.read_to_end(&mut body).await?;
+        }
+    }
+    Ok(body)
+}
+
+// Fetches `path` from a gateway at `base`, e.g. `https://files.example.com:8443`,
+// or bytes `[start, end)` of it. HTTPS servers are checked against the web's
+// root certificates.
+pub async fn http_get(
+    base: &str,
+    path: &str,
+    token: Option<&str>,
+    range: Option<(u64, u64)>,
+) -> io::Result<Vec<u8>> {
+    let (tls, rest) = match base.split_once("://") {
+        Some(("http", rest)) => (false, rest),
+        Some(("https", rest)) => (true, rest),
+        _ => return Err(Error::other(format!("{base} is not an http(s) URL"))),
+    };
+    let (authority, prefix) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], rest[slash..].trim_end_matches('/')),
+        None => (rest, ""),
+    };
+    // `host[:port]`, or `[v6 address][:port]`.
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (
+            host,
+            port.parse::<u16>()
+                .map_err(|err| Error::new(ErrorKind::InvalidInput, format!("{port}: {err}")))?,
+        ),
+        _ => (authority, if tls { 443 } else { 80 }),
+    };
+    let host = host.trim_matches(['[', ']']);
+
+    let mut request =
+        format!("GET {prefix}{path} HTTP/1.1\r\nHost: {authority}\r\nConnection: close\r\n");
+    if let Some(token) = token {
+        request.push_str(&format!("Authorization: Bearer {token}\r\n"));
+    }
+    if let Some((start, end)) = range {
+        request.push_str(&format!("Range: bytes={start}-{}\r\n", end - 1));
+    }
+    request.push_str("\r\n");
+
+    let stream = TcpStream::connect((host, port)).await?;
+    if !tls {
+        return get(stream, &request).await;
+    }
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let name = ServerName::try_from(host.to_string())
+        .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(name, stream)
+        .await?;
+    get(stream, &request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_are_clamped_to_the_body() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Some((0, 100))));
+        assert_eq!(parse_range("bytes=900-", 1000), Some(Some((900, 1000))));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(Some((900, 1000))));
+        assert_eq!(parse_range("bytes=990-2000", 1000), Some(Some((990, 1000))));
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=5-1", 1000), None);
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), Some(None));
+        assert_eq!(parse_range("items=0-1", 1000), Some(None));
+    }
+}
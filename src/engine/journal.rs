@@ -0,0 +1,169 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{self, Error};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use crate::util::log::current_timestamp_ms;
+use crate::util::units::ChunkId;
+
+// Sessions without a ticket for this long are not resumed after a restart.
+pub const RESUME_WINDOW_MS: u64 = 60_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournaledChunk {
+    pub chunk_id: ChunkId,
+    // Next offset the peer asked for.
+    pub offset: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournaledSession {
+    pub addr: SocketAddr,
+    pub pub_key: String,
+    pub rate_kbps: Option<u32>,
+    pub backoff: f64,
+    pub chunks: Vec<JournaledChunk>,
+    pub last_ticket_ms: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct JournalFile {
+    sessions: Vec<JournaledSession>,
+}
+
+// The sessions being served, kept in a TOML file so a restarted server picks
+// them up where it stopped instead of making every client start over.
+pub struct SessionJournal {
+    sessions: HashMap<SocketAddr, JournaledSession>,
+    path: PathBuf,
+    dirty: bool,
+}
+
+impl SessionJournal {
+    // Sessions that went quiet before the restart are left out.
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let sessions = match path.exists() {
+            true => {
+                toml::from_str::<JournalFile>(&std::fs::read_to_string(&path)?)
+                    .map_err(Error::other)?
+                    .sessions
+            }
+            false => vec![],
+        };
+        let now_ms = current_timestamp_ms();
+        Ok(Self {
+            sessions: sessions
+                .into_iter()
+                .filter(|session| now_ms.saturating_sub(session.last_ticket_ms) < RESUME_WINDOW_MS)
+                .map(|session| (session.addr, session))
+                .collect(),
+            path,
+            dirty: false,
+        })
+    }
+
+    pub fn sessions(&self) -> impl Iterator<Item = &JournaledSession> {
+        self.sessions.values()
+    }
+
+    // `chunks` are the (chunk id, next offset, closed) of the ticket's GetChunk frames.
+    pub fn record_ticket(
+        &mut self,
+        addr: SocketAddr,
+        pub_key: &str,
+        rate_kbps: Option<u32>,
+        backoff: f64,
+        chunks: impl IntoIterator<Item = (ChunkId, u32, bool)>,
+    ) {
+        let session = self
+            .sessions
+            .entry(addr)
+            .or_insert_with(|| JournaledSession {
+                addr,
+                pub_key: pub_key.to_string(),
+                rate_kbps,
+                backoff,
+                chunks: vec![],
+                last_ticket_ms: 0,
+            });
+        session.pub_key = pub_key.to_string();
+        session.rate_kbps = rate_kbps.or(session.rate_kbps);
+        session.backoff = backoff;
+        session.last_ticket_ms = current_timestamp_ms();
+        for (chunk_id, offset, closed) in chunks {
+            session.chunks.retain(|chunk| chunk.chunk_id != chunk_id);
+            if !closed {
+                session.chunks.push(JournaledChunk { chunk_id, offset });
+            }
+        }
+        session.chunks.sort_by_key(|chunk| chunk.chunk_id);
+        self.dirty = true;
+    }
+
+    pub fn forget(&mut self, addr: SocketAddr) {
+        self.dirty |= self.sessions.remove(&addr).is_some();
+    }
+
+    // Writes the journal out if it changed since the last save.
+    pub fn save(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let mut sessions: Vec<_> = self.sessions.values().cloned().collect();
+        sessions.sort_by_key(|session| session.addr);
+        let file = JournalFile { sessions };
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, toml::to_string(&file).map_err(Error::other)?)?;
+        std::fs::rename(tmp, &self.path)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_survive_a_restart() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("journal.toml");
+        let peer: SocketAddr = "10.0.0.2:7000".parse().unwrap();
+        let quiet: SocketAddr = "10.0.0.3:7000".parse().unwrap();
+
+        let mut journal = SessionJournal::load(path.clone())?;
+        journal.record_ticket(
+            peer,
+            "ab",
+            Some(8000),
+            1.25,
+            [(ChunkId(3), 10, false), (ChunkId(4), 0, false)],
+        );
+        journal.record_ticket(
+            peer,
+            "ab",
+            None,
+            1.5,
+            [(ChunkId(3), 42, false), (ChunkId(4), 90, true)],
+        );
+        journal.record_ticket(quiet, "cd", Some(100), 1.0, [(ChunkId(1), 0, false)]);
+        journal.sessions.get_mut(&quiet).unwrap().last_ticket_ms -= RESUME_WINDOW_MS;
+        journal.save()?;
+
+        let journal = SessionJournal::load(path)?;
+        let sessions: Vec<_> = journal.sessions().collect();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].addr, peer);
+        assert_eq!(sessions[0].rate_kbps, Some(8000));
+        assert_eq!(sessions[0].backoff, 1.5);
+        assert_eq!(
+            sessions[0].chunks,
+            vec![JournaledChunk {
+                chunk_id: ChunkId(3),
+                offset: 42
+            }]
+        );
+        Ok(())
+    }
+}
@@ -1,36 +1,140 @@
-pub mod decoding;
-pub mod encoding;
-pub mod receiving;
-pub mod sending;
+internal_mods! {
+    admission;
+    control;
+    decoding;
+    download;
+    encoding;
+    gateway;
+    journal;
+    receiving;
+    scrubbing;
+    sending;
+    stats;
+    transfer;
+}
+pub(crate) mod plans;
+pub(crate) mod supervisor;
+pub(crate) mod window;
 
 // TODO
 // Potential Dead load with tokio::mpsc or flume::
 mod bus_flume;
 // mod bus_tokio;
 
-pub use bus_flume::{Bus, BusInterface};
+pub use bus_flume::{Bus, BusInterface, Request};
+#[cfg(feature = "internals")]
+pub use bus_flume::{AddressTaken, PeerActivity, RequestError};
 // pub use bus_tokio::{Bus, BusInterface};
 
 use std::net::SocketAddr;
 use tokio::time::{Duration, Instant};
 
-use crate::protocol::wire::frames::{DataFrame, ParsedDataFrame};
+pub const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+use crate::protocol::wire::PacketIds;
+use crate::protocol::wire::encoding::PacketExt;
+use crate::protocol::wire::frames::{
+    DataFrame, ErrorFrame, ErrorReason, ParsedDataFrame, StopReason,
+};
+use crate::protocol::wire::packets::DataPacket;
+use crate::util::units::{ChunkId, FrameOffset, SessionId};
+use bytes::Bytes;
 use derive_more::{self, Debug};
+use stats::EncoderProgress;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum BusAddress {
     SenderSocket,
     ReceiverSocket,
-    FrameEncoder(u32, SocketAddr),
-    FrameDecoder(u32),
+    // The receiving socket of one of several plans, see `plans`.
+    PlanReceiver(u32),
+    SenderStats,
+    FrameEncoder(ChunkId, SocketAddr),
+    FrameDecoder(ChunkId),
+}
+
+impl BusAddress {
+    // One per chunk in flight, the ones that leak if a transfer goes wrong.
+    pub fn is_coder(&self) -> bool {
+        matches!(
+            self,
+            BusAddress::FrameEncoder(..) | BusAddress::FrameDecoder(_)
+        )
+    }
 }
 
 #[derive(derive_more::From, derive_more::TryInto, Debug)]
 pub enum BusMessage<const INFO_LENGTH: usize> {
     SendingOrder(SendingOrder),
-    ReceivingChunkReport((u32, ReceivingChunkReport)),
-    SendingData((SocketAddr, DataFrame<INFO_LENGTH>)),
+    ReceivingChunkReport((ChunkId, ReceivingChunkReport)),
+    AnnounceChunk(Request<ChunkId, ()>),
+    PeerEvent(PeerEvent),
+    SendingData((SocketAddr, BuiltDataPacket)),
+    SendingControl((SocketAddr, ErrorFrame)),
     ReceivingData(ParsedDataFrame<INFO_LENGTH>),
+    ChunkError((ChunkId, ErrorReason)),
+    // A decoded chunk the client threw away, to be told to the server.
+    ChunkRejected((ChunkId, StopReason)),
+    EncoderProgress(EncoderProgress),
+}
+
+// A data packet built, checksum and all, by the encoder of its chunk, so the
+// send path is left with the syscalls.
+#[derive(Debug)]
+pub struct BuiltDataPacket {
+    pub chunk_id: ChunkId,
+    pub session_id: SessionId,
+    pub packet_id: u32,
+    pub parts: Vec<Bytes>,
+}
+
+// Carried by cover packets, which belong to no chunk.
+pub const COVER_CHUNK_ID: ChunkId = ChunkId(u32::MAX);
+// The server's plan file, served like a chunk to clients given a usync:// URI.
+pub const PLAN_CHUNK_ID: ChunkId = ChunkId(u32::MAX - 1);
+
+impl BuiltDataPacket {
+    // Padded to `pad_to` bytes when given, so data packets all look alike.
+    // Numbered in the session of `ids`, that of the peer it goes to.
+    pub fn new<const INFO_LENGTH: usize>(
+        frame: DataFrame<INFO_LENGTH>,
+        pad_to: Option<usize>,
+        ids: &PacketIds,
+    ) -> Self {
+        let chunk_id = frame.chunk_id();
+        let packet = DataPacket::from(frame);
+        let packet = match pad_to {
+            Some(size) => packet.padded_to(size),
+            None => packet,
+        };
+        let (parts, packet_id) = packet.build_in(ids);
+        Self {
+            chunk_id,
+            session_id: ids.session_id(),
+            packet_id,
+            parts,
+        }
+    }
+
+    // A packet of `size` bytes without data, sent when there is nothing else
+    // to send so the traffic keeps a constant rate.
+    pub fn cover(size: usize, ids: &PacketIds) -> Self {
+        let (parts, packet_id) = DataPacket::<0>::cover().padded_to(size).build_in(ids);
+        Self {
+            chunk_id: COVER_CHUNK_ID,
+            session_id: ids.session_id(),
+            packet_id,
+            parts,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.parts.iter().map(|part| part.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
@@ -59,14 +163,26 @@ impl PartialOrd for ReceivingChunkReport {
     }
 }
 
-#[derive(Debug)]
+// Broadcast to every encoder serving one peer.
+#[derive(Debug, Clone)]
+pub enum PeerEvent {
+    Disconnected,
+    RateChanged(Duration),
+    // Proactive repair in percent, adapted to the loss towards the peer.
+    RepairChanged(u32),
+}
+
+#[derive(Debug, Clone)]
 pub struct SendingOrder {
-    pub chunk_id: u32,
+    pub chunk_id: ChunkId,
     pub sending_interval: Option<Duration>,
     pub time_stamp: Instant,
-    pub offset_next: u32,
-    pub offset_no_more_than: u32,
+    pub offset_next: FrameOffset,
+    // Exclusive, and like every frame offset wrapping at u32::MAX, see `window`.
+    pub offset_no_more_than: FrameOffset,
     pub close_now: bool,
+    // Plan epoch of the GetChunk frame.
+    pub generation: u64,
 }
 
 // use dashmap::{DashMap, DashSet};
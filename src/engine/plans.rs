@@ -0,0 +1,179 @@
+use super::{BusAddress, BusInterface, BusMessage};
+use crate::protocol::wire::frames::Priority;
+use crate::util::pacing::weighted_shares;
+use crate::util::units::ChunkId;
+
+// Several plans downloaded at once share one bus, so their chunk ids must not
+// collide on it: plan `n` numbers its chunks from `chunk_base(n)` on, and its
+// receiving socket, see `ReceivingSocket::with_chunk_base`, turns them back
+// into the ids its server knows.
+pub const PLAN_CHUNK_SPACE: u32 = 1 << 24;
+pub const MAX_PLANS: usize = (1 << 32) / PLAN_CHUNK_SPACE as usize;
+
+// None past the last plan the ids have room for.
+pub fn chunk_base(plan: usize) -> Option<u32> {
+    u32::try_from(plan).ok()?.checked_mul(PLAN_CHUNK_SPACE)
+}
+
+// Chunk `chunk_id` of plan `plan` on the bus.
+pub fn bus_id(plan: usize, chunk_id: ChunkId) -> Option<ChunkId> {
+    chunk_base(plan)?.checked_add(chunk_id.0).map(ChunkId)
+}
+
+// Why plans can not be downloaded together.
+#[derive(Debug, Clone, PartialEq, Eq, derive_more::Display, derive_more::Error)]
+pub enum PlanSpaceError {
+    #[display("{_0} files given, at most {MAX_PLANS} are downloaded together")]
+    TooManyPlans(#[error(not(source))] usize),
+    #[display(
+        "chunk {chunk_id} of file {plan} is past the {PLAN_CHUNK_SPACE} chunks a file may have when downloaded with others"
+    )]
+    ChunkOutOfSpace { plan: usize, chunk_id: u32 },
+}
+
+// Plans downloaded together must each fit their share of the ids, a plan
+// alone may use all of them.
+pub fn check_plan_space(
+    plans: usize,
+    chunk_ids: impl IntoIterator<Item = (usize, ChunkId)>,
+) -> Result<(), PlanSpaceError> {
+    if plans > MAX_PLANS {
+        return Err(PlanSpaceError::TooManyPlans(plans));
+    }
+    if plans == 1 {
+        return Ok(());
+    }
+    match chunk_ids
+        .into_iter()
+        .find(|(_, chunk_id)| chunk_id.0 >= PLAN_CHUNK_SPACE)
+    {
+        Some((plan, chunk_id)) => Err(PlanSpaceError::ChunkOutOfSpace {
+            plan,
+            chunk_id: chunk_id.0,
+        }),
+        None => Ok(()),
+    }
+}
+
+pub fn plan_of(chunk_id: ChunkId) -> u32 {
+    chunk_id.0 / PLAN_CHUNK_SPACE
+}
+
+// The rate each receiving socket asks for, `total_kbps` split between the
+// plans by the weight of their priority.
+pub fn rate_shares(total_kbps: u64, priorities: &[Priority]) -> Vec<u64> {
+    let demands: Vec<(u64, u32)> = priorities
+        .iter()
+        .map(|priority| (u64::MAX, priority.weight()))
+        .collect();
+    weighted_shares(total_kbps, &demands)
+}
+
+// The plan a message for the receiving socket is about, None if it is about
+// no chunk.
+fn plan_of_message<const INFO_LENGTH: usize>(message: &BusMessage<INFO_LENGTH>) -> Option<u32> {
+    match message {
+        BusMessage::ReceivingChunkReport((chunk_id, _))
+        | BusMessage::ChunkRejected((chunk_id, _)) => Some(plan_of(*chunk_id)),
+        BusMessage::AnnounceChunk(request) => Some(plan_of(request.body)),
+        _ => None,
+    }
+}
+
+// Registered at `BusAddress::ReceiverSocket`, where decoders and the download
+// manager send, in place of a single receiving socket. Passes every message on
+// to the receiving socket of its chunk's plan, at `BusAddress::PlanReceiver`.
+pub async fn route<const INFO_LENGTH: usize>(
+    mut bus_interface: BusInterface<BusAddress, BusMessage<INFO_LENGTH>>,
+) {
+    while let Some(message) = bus_interface.recv::<BusMessage<INFO_LENGTH>>().await {
+        let Some(plan) = plan_of_message(&message) else {
+            continue;
+        };
+        if bus_interface
+            .send(BusAddress::PlanReceiver(plan), message)
+            .await
+            .is_err()
+        {
+            eprintln!("No receiving socket for plan {plan}, message dropped.");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Bus, ReceivingChunkReport};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn messages_reach_the_plan_of_their_chunk() {
+        let bus = Arc::new(Bus::<BusAddress, BusMessage<16>>::default());
+        let router = tokio::spawn(route(
+            bus.clone().register(BusAddress::ReceiverSocket).unwrap(),
+        ));
+        let mut first = bus.clone().register(BusAddress::PlanReceiver(0)).unwrap();
+        let mut second = bus.clone().register(BusAddress::PlanReceiver(1)).unwrap();
+        let decoder = bus
+            .clone()
+            .register(BusAddress::FrameDecoder(ChunkId(0)))
+            .unwrap();
+
+        for chunk_id in [ChunkId(3), bus_id(1, ChunkId(3)).unwrap()] {
+            let report = (chunk_id, ReceivingChunkReport::Finished(7));
+            decoder
+                .send(BusAddress::ReceiverSocket, report)
+                .await
+                .unwrap();
+        }
+        let received = |message: Option<BusMessage<16>>| match message {
+            Some(BusMessage::ReceivingChunkReport((chunk_id, _))) => chunk_id,
+            other => panic!("unexpected {other:?}"),
+        };
+        assert_eq!(received(first.recv().await), ChunkId(3));
+        assert_eq!(
+            received(second.recv().await),
+            bus_id(1, ChunkId(3)).unwrap()
+        );
+        assert_eq!(
+            plan_of(bus_id(2, ChunkId(PLAN_CHUNK_SPACE - 1)).unwrap()),
+            2
+        );
+        router.abort();
+    }
+
+    #[test]
+    fn plans_stay_within_their_ids() {
+        assert_eq!(
+            bus_id(MAX_PLANS - 1, ChunkId(PLAN_CHUNK_SPACE - 1)),
+            Some(ChunkId(u32::MAX))
+        );
+        assert_eq!(bus_id(MAX_PLANS, ChunkId(0)), None);
+        assert_eq!(bus_id(1, ChunkId(u32::MAX)), None);
+
+        let chunks = |plan, ids: &[u32]| {
+            ids.iter()
+                .map(move |id| (plan, ChunkId(*id)))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(check_plan_space(1, chunks(0, &[PLAN_CHUNK_SPACE])), Ok(()));
+        assert_eq!(
+            check_plan_space(2, chunks(0, &[1, PLAN_CHUNK_SPACE])),
+            Err(PlanSpaceError::ChunkOutOfSpace {
+                plan: 0,
+                chunk_id: PLAN_CHUNK_SPACE
+            })
+        );
+        assert_eq!(check_plan_space(MAX_PLANS, vec![]), Ok(()));
+        assert_eq!(
+            check_plan_space(MAX_PLANS + 1, vec![]),
+            Err(PlanSpaceError::TooManyPlans(MAX_PLANS + 1))
+        );
+    }
+
+    #[test]
+    fn urgent_plans_get_the_larger_rate() {
+        let priorities = [Priority::High, Priority::Background, Priority::Normal];
+        assert_eq!(rate_shares(42_000, &priorities), vec![32_000, 2_000, 8_000]);
+    }
+}
@@ -1,38 +1,248 @@
+use super::control::ControlState;
+use super::plans::PLAN_CHUNK_SPACE;
 use super::{BusAddress, BusInterface, BusMessage, ReceivingChunkReport};
+use crate::constants::MTU;
+use crate::protocol::KEY_RING;
 use crate::protocol::wire::encoding::{PacketExt, parse_packet};
-use crate::protocol::wire::frames::ParsedFrameVariant;
-use crate::protocol::wire::packets::TicketPacket;
-use crate::transmission::UdpSocketLike;
+use crate::protocol::wire::frames::{
+    ErrorReason, FEATURE_STOP_ACK, GrantFrame, ParsedFrameVariant, Priority, SUPPORTED_FEATURES,
+    StopReason,
+};
+use crate::protocol::wire::packets::{ControlPacket, ParsedPacketVariant, TicketPacket};
+use crate::protocol::wire::{PacketIds, random_factor};
+use crate::transmission::errors::{SocketErrorKind, SocketFailure, classify};
+use crate::transmission::multipath::PathManager;
+use crate::transmission::{Ecn, UdpSocketLike};
 use crate::util::Compare;
+use crate::util::clock::{SharedClock, SkewEstimate, offset_ms, system_clock};
+use crate::util::cpu::{Subsystem, accounted};
+use crate::util::log::packet_log;
+use crate::util::units::ChunkId;
 use bytes::Bytes;
 use owo_colors::*;
 use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
-use tokio::time::{Duration, interval};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::time::{Duration, Instant, interval};
+
+const TICKET_PERIOD: Duration = Duration::from_secs(1);
+
+pub const DEFAULT_RATE_KBPS: u64 = 40960;
+
+// How many chunks ahead of the active ones are announced in Prefetch frames.
+const PREFETCH_DEPTH: usize = 4;
+
+// The server counts as silent once it sent nothing for this long while asked for chunks.
+const SILENCE_TIMEOUT: Duration = Duration::from_secs(3);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+// Tickets to a silent server cost it nothing and it may come back any moment,
+// so they are not spaced out further than this.
+const MAX_SILENT_BACKOFF: Duration = Duration::from_secs(4);
+
+// Active chunks that got no further for this long, while the server was
+// sending, are given up on. Their decoder most likely died without a word.
+const STALE_CHUNK_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Well below the 30s some NATs keep idle UDP bindings for.
+pub const DEFAULT_KEEPALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, derive_more::Error)]
+pub struct ServerUnreachable {
+    pub waited: Duration,
+    // The server turned every identity we have away meanwhile.
+    pub rejected: bool,
+}
+
+impl std::fmt::Display for ServerUnreachable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.rejected {
+            true => write!(f, "server rejected our identities for {:?}", self.waited),
+            false => write!(f, "server busy or unreachable for {:?}", self.waited),
+        }
+    }
+}
+
+// Why a session with a peer ended before the transfer did.
+#[derive(Debug, derive_more::Display, derive_more::Error, derive_more::From)]
+pub enum SessionError {
+    #[display("{_0}")]
+    Unreachable(ServerUnreachable),
+    #[display("{_0}")]
+    Socket(SocketFailure),
+}
+
+// Spaces tickets out exponentially while the server is busy, silent or rejects
+// us, until it sends data again or `max_wait` has passed.
+#[derive(Default)]
+struct Backoff {
+    max_wait: Option<Duration>,
+    stalled_since: Option<Instant>,
+    failures: u32,
+    next_try: Option<Instant>,
+    rejected: bool,
+}
+
+impl Backoff {
+    fn reset(&mut self) {
+        self.stalled_since = None;
+        self.failures = 0;
+        self.next_try = None;
+        self.rejected = false;
+    }
+
+    fn can_send(&self, now: Instant) -> bool {
+        self.next_try.is_none_or(|next_try| now >= next_try)
+    }
+
+    // Returns how long to hold tickets back, never less than `at_least`.
+    fn stall(&mut self, now: Instant, at_least: Duration) -> Duration {
+        self.stall_within(now, at_least, MAX_BACKOFF)
+    }
+
+    // For a server that sent nothing, rather than turning us away.
+    fn stall_silent(&mut self, now: Instant) -> Duration {
+        self.stall_within(now, Duration::ZERO, MAX_SILENT_BACKOFF)
+    }
+
+    fn stall_within(&mut self, now: Instant, at_least: Duration, at_most: Duration) -> Duration {
+        self.stalled_since.get_or_insert(now);
+        let delay = TICKET_PERIOD
+            .saturating_mul(1 << self.failures.min(16))
+            .min(at_most);
+        // Jitter keeps clients turned away together from coming back together.
+        let delay = delay.mul_f64(random_factor(0.75..1.25)).max(at_least);
+        self.failures += 1;
+        self.next_try = Some(now + delay);
+        delay
+    }
+
+    fn gave_up(&self, now: Instant) -> Option<ServerUnreachable> {
+        let waited = now - self.stalled_since?;
+        self.max_wait
+            .is_some_and(|max_wait| waited >= max_wait)
+            .then_some(ServerUnreachable {
+                waited,
+                rejected: self.rejected,
+            })
+    }
+}
 
 #[derive(Default)]
 struct Reporter {
-    activate_data: HashMap<u32, ReceivingChunkReport>,
-    exiting_data: VecDeque<HashMap<u32, ReceivingChunkReport>>,
+    activate_data: HashMap<ChunkId, ReceivingChunkReport>,
+    exiting_data: VecDeque<HashMap<ChunkId, ReceivingChunkReport>>,
+    // Chunks still to be fetched, in the order they will be.
+    upcoming: VecDeque<ChunkId>,
+    // Why finished chunks were stopped, where that was not completing them.
+    stopped: HashMap<ChunkId, StopReason>,
+    // Where each active chunk last got to and when, see `expire`.
+    progress: HashMap<ChunkId, (u32, Instant)>,
+    // The server acknowledges stopped chunks, which are reported until it does
+    // rather than in the next few tickets.
+    acknowledged: bool,
 }
 
 impl Reporter {
     fn is_empty(&self) -> bool {
-        let exited = self.exiting_data.iter().map(|s| s.len()).sum();
+        let exited: usize = self.exiting_data.iter().map(|s| s.len()).sum();
         dbg!(exited);
         self.activate_data.is_empty() && 0usize == exited
     }
 
-    fn update(&mut self, chunk_id: u32, report: ReceivingChunkReport) {
+    fn update(&mut self, chunk_id: ChunkId, report: ReceivingChunkReport) {
+        self.upcoming.retain(|upcoming| *upcoming != chunk_id);
         self.activate_data
             .entry(chunk_id)
             .and_modify(|x| x.cmax(report.clone()))
             .or_insert_with_key(|_| report);
     }
 
-    fn generate(&mut self, rate_kbps: u32) -> TicketPacket {
-        if self.exiting_data.len() >= 3 {
-            self.exiting_data.pop_back();
+    // A chunk downloaded again must not be reported finished from its last run.
+    fn announce(&mut self, chunk_id: ChunkId) {
+        for exiting in self.exiting_data.iter_mut() {
+            exiting.remove(&chunk_id);
+        }
+        self.activate_data.remove(&chunk_id);
+        self.stopped.remove(&chunk_id);
+        self.update(chunk_id, ReceivingChunkReport::WantNext(0));
+    }
+
+    fn abort(&mut self, chunk_id: ChunkId) {
+        self.activate_data.remove(&chunk_id);
+        self.progress.remove(&chunk_id);
+    }
+
+    // The server closed `chunk_id`, reporting it finished is over.
+    fn acknowledge(&mut self, chunk_id: ChunkId) {
+        for exiting in self.exiting_data.iter_mut() {
+            if exiting.remove(&chunk_id).is_some() {
+                self.stopped.remove(&chunk_id);
+            }
+        }
+    }
+
+    // Hands over the active chunks whose decoder is no longer `alive`, or that
+    // got no further for `STALE_CHUNK_TIMEOUT`, so tickets stop asking for them.
+    // Chunks do not age while `stalled`, nothing is expected of the server then.
+    fn expire(
+        &mut self,
+        now: Instant,
+        stalled: bool,
+        alive: impl Fn(ChunkId) -> bool,
+    ) -> Vec<ChunkId> {
+        self.progress
+            .retain(|chunk_id, _| self.activate_data.contains_key(chunk_id));
+        let mut expired = vec![];
+        for (chunk_id, report) in self.activate_data.iter() {
+            let ReceivingChunkReport::WantNext(n) = *report else {
+                continue;
+            };
+            let (got_to, since) = self.progress.entry(*chunk_id).or_insert((n, now));
+            if *got_to != n || stalled {
+                *got_to = n;
+                *since = now;
+            }
+            if !alive(*chunk_id) || now - *since >= STALE_CHUNK_TIMEOUT {
+                expired.push(*chunk_id);
+            }
+        }
+        for chunk_id in expired.iter() {
+            self.hand_over(*chunk_id);
+            self.progress.remove(chunk_id);
+        }
+        expired
+    }
+
+    // Stops a chunk for `reason`, the server is told with the next tickets.
+    fn stop(&mut self, chunk_id: ChunkId, reason: StopReason) {
+        self.stopped.insert(chunk_id, reason);
+        self.update(chunk_id, ReceivingChunkReport::Finished(0));
+    }
+
+    // Takes a chunk off this path: reported finished where it got to, so the
+    // server closes its encoder, and handed back to be asked for elsewhere.
+    fn hand_over(&mut self, chunk_id: ChunkId) -> Option<ReceivingChunkReport> {
+        let report = self.activate_data.remove(&chunk_id)?;
+        if let ReceivingChunkReport::WantNext(n) = report {
+            self.stopped.insert(chunk_id, StopReason::Aborted);
+            self.update(chunk_id, ReceivingChunkReport::Finished(n));
+        }
+        Some(report)
+    }
+
+    // While paused every window is 0, so the server closes its encoders, and the
+    // offsets reported bring them back where they left off once resumed.
+    fn generate(&mut self, rate_kbps: u64, paused: bool) -> TicketPacket {
+        if self.exiting_data.len() >= 3
+            && !self.acknowledged
+            && let Some(exited) = self.exiting_data.pop_back()
+        {
+            for chunk_id in exited.keys() {
+                if !self.activate_data.contains_key(chunk_id) {
+                    self.stopped.remove(chunk_id);
+                }
+            }
         }
 
         self.exiting_data.push_front(
@@ -40,41 +250,237 @@ impl Reporter {
                 .extract_if(|_k, v| *v >= ReceivingChunkReport::Finished(0))
                 .collect(),
         );
+        if self.acknowledged {
+            self.exiting_data.retain(|exiting| !exiting.is_empty());
+        }
 
         self.activate_data
             .iter()
             .chain(self.exiting_data.iter().flat_map(|s| s.iter()))
             .fold(
-                TicketPacket::new().set_rate_limit(rate_kbps),
+                TicketPacket::new().set_rate_limit(rate_kbps).set_prefetch(
+                    self.upcoming
+                        .iter()
+                        .take(if paused { 0 } else { PREFETCH_DEPTH })
+                        .copied(),
+                ),
                 |packet: TicketPacket, (chunk_id, result)| match result {
+                    ReceivingChunkReport::WantNext(n) if paused => {
+                        packet.set_get_chunk(*chunk_id, *n, 0)
+                    }
                     ReceivingChunkReport::WantNext(n) => {
                         packet.set_get_chunk(*chunk_id, *n, 8192.max(*n / 5))
                     }
-                    ReceivingChunkReport::Finished(n) => packet.set_get_chunk(*chunk_id, *n, 0),
+                    ReceivingChunkReport::Finished(n) => {
+                        packet.set_get_chunk(*chunk_id, *n, 0).set_stop_chunk(
+                            *chunk_id,
+                            self.stopped
+                                .get(chunk_id)
+                                .copied()
+                                .unwrap_or(StopReason::Finished),
+                        )
+                    }
                 },
             )
     }
 }
 
+// Waits for an authorized peer to offer `file_id` for upload, and returns its
+// address for the receiving socket to send tickets to. Anything else is dropped.
+pub async fn await_offer<S: UdpSocketLike, const INFO_LENGTH: usize>(
+    socket: &S,
+    file_id: u64,
+) -> std::io::Result<SocketAddr> {
+    let mut buf = vec![0u8; MTU];
+    loop {
+        let (length, from) = socket.recv_from(&mut buf).await?;
+        let Ok(packet) = parse_packet::<INFO_LENGTH>(Bytes::copy_from_slice(&buf[..length])) else {
+            continue;
+        };
+        let ParsedPacketVariant::TicketPacket { .. } = packet.specific_packet_header else {
+            continue;
+        };
+        for frame in packet.frames {
+            match frame {
+                ParsedFrameVariant::Upload(upload) if upload.file_id.get() == file_id => {
+                    return Ok(from);
+                }
+                ParsedFrameVariant::Upload(_) => {
+                    eprintln!("{from} offered another file, ignored.");
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
 pub struct ReceivingSocket<S: UdpSocketLike, const INFO_LENGTH: usize> {
-    socket: S,
+    paths: PathManager<S>,
     bus_interface: BusInterface<BusAddress, BusMessage<INFO_LENGTH>>,
+    upcoming: Vec<ChunkId>,
+    clock: SharedClock,
+    control: Option<Arc<ControlState>>,
+    // Asked of the server unless the control state sets another rate.
+    rate_kbps: u64,
+    max_wait: Option<Duration>,
+    generation: Arc<AtomicU64>,
+    grant: Option<GrantFrame>,
+    priority: Priority,
+    // Added to the server's chunk ids on the bus, see `plans`.
+    chunk_base: Option<u32>,
+    keepalive: Option<Duration>,
 }
-impl<S: UdpSocketLike, const INFO_LENGTH: usize> ReceivingSocket<S, INFO_LENGTH> {
+impl<S: UdpSocketLike + 'static, const INFO_LENGTH: usize> ReceivingSocket<S, INFO_LENGTH> {
     pub fn new(
         socket: S,
         bus_interface: BusInterface<BusAddress, BusMessage<INFO_LENGTH>>,
+    ) -> Self {
+        Self::over_paths(PathManager::new(vec![socket]), bus_interface)
+    }
+
+    // Fetches every chunk over one of the paths, see `PathManager`.
+    pub fn over_paths(
+        paths: PathManager<S>,
+        bus_interface: BusInterface<BusAddress, BusMessage<INFO_LENGTH>>,
     ) -> Self {
         Self {
-            socket,
+            paths,
             bus_interface,
+            upcoming: vec![],
+            clock: system_clock(),
+            control: None,
+            rate_kbps: DEFAULT_RATE_KBPS,
+            max_wait: None,
+            generation: Arc::default(),
+            grant: None,
+            priority: Priority::Normal,
+            chunk_base: None,
+            keepalive: Some(DEFAULT_KEEPALIVE),
         }
     }
 
-    pub async fn run(mut self, server_addr: SocketAddr) {
-        let mut buffer = [0u8; 65537];
-        let mut reporter = Reporter::default();
-        let mut ticker = interval(Duration::from_secs(1));
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_rate_kbps(mut self, rate_kbps: u64) -> Self {
+        self.rate_kbps = rate_kbps;
+        self
+    }
+
+    // Gives up once the server has been busy, silent or rejecting us for `max_wait`.
+    pub fn with_max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = Some(max_wait);
+        self
+    }
+
+    // The plan epoch chunks are asked for in, read for every ticket so a
+    // follower can move on to the next epoch.
+    pub fn with_generation(mut self, generation: Arc<AtomicU64>) -> Self {
+        self.generation = generation;
+        self
+    }
+
+    // The server is reported to `control`. A rate set there is asked of the
+    // server from the next ticket on.
+    pub fn with_control(mut self, control: Arc<ControlState>) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    // Sent in every ticket, for a key from a download token.
+    pub fn with_grant(mut self, grant: GrantFrame) -> Self {
+        self.grant = Some(grant);
+        self
+    }
+
+    // Sent in every ticket, for servers sharing their link by priority.
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    // The chunks to be downloaded in fetch order, hinted to the server ahead of
+    // time. In the server's ids, whatever the chunk base.
+    pub fn with_upcoming(mut self, chunk_ids: Vec<ChunkId>) -> Self {
+        self.upcoming = chunk_ids;
+        self
+    }
+
+    // One of several plans on the bus: chunk `n` of the server is chunk
+    // `chunk_base + n` there, see `plans`.
+    pub fn with_chunk_base(mut self, chunk_base: u32) -> Self {
+        self.chunk_base = Some(chunk_base);
+        self
+    }
+
+    // A path sending the server nothing for `keepalive` sends it an empty
+    // control packet, so NAT bindings outlive pauses without tickets, such as
+    // with nothing to ask for or while backing off. None sends none.
+    pub fn with_keepalive(mut self, keepalive: Option<Duration>) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    // The id of a chunk of the server on the bus, None if it is past the
+    // ids of the plan. A plan alone on the bus has all of them.
+    fn on_bus(&self, chunk_id: ChunkId) -> Option<ChunkId> {
+        match self.chunk_base {
+            Some(chunk_base) if chunk_id.0 < PLAN_CHUNK_SPACE => {
+                chunk_base.checked_add(chunk_id.0).map(ChunkId)
+            }
+            Some(_) => None,
+            None => Some(chunk_id),
+        }
+    }
+
+    fn on_wire(&self, chunk_id: ChunkId) -> ChunkId {
+        ChunkId(chunk_id.0.wrapping_sub(self.chunk_base.unwrap_or(0)))
+    }
+
+    // A socket failing for good is also reported to the bus's supervisor.
+    pub async fn run(self, server_addr: SocketAddr) -> Result<(), SessionError> {
+        let bus = self.bus_interface.get_bus();
+        let address = self.bus_interface.address().clone();
+        let result = accounted(Subsystem::Socket, self.receive(server_addr)).await;
+        if let Err(SessionError::Socket(failure)) = &result {
+            eprintln!("{} {failure}.", "Receiving socket stopped:".red());
+            bus.supervisor().fail(address, failure.to_string());
+        }
+        result
+    }
+
+    async fn receive(mut self, server_addr: SocketAddr) -> Result<(), SessionError> {
+        // One reporter per path, each asking for the chunks on its path. Hints go
+        // over the first, prepared encoders serve whichever peer orders first.
+        let mut reporters: Vec<Reporter> =
+            (0..self.paths.len()).map(|_| Reporter::default()).collect();
+        reporters[0].upcoming = std::mem::take(&mut self.upcoming).into();
+        let incoming = self.paths.incoming();
+        let mut ticker = interval(TICKET_PERIOD);
+        let mut last_rotation = None;
+        // The server hands out cookies per address, so per path.
+        let mut cookies = vec![None; self.paths.len()];
+        // (CE marked, total) packets since the last ticket, per path.
+        let mut ecn_counts = vec![(0u32, 0u32); self.paths.len()];
+        // How far the server's clock is behind ours, from its Clock frames, per path.
+        let mut skews = vec![SkewEstimate::default(); self.paths.len()];
+        // Bytes received from the server since the last tick, for the control socket.
+        let mut received = 0u64;
+        let mut requested_kbps = self.rate_kbps;
+        let mut backoff = Backoff {
+            max_wait: self.max_wait,
+            ..Default::default()
+        };
+        let mut last_heard = self.clock.now();
+        // When each path last sent the server anything, for the keepalives.
+        let mut last_sent = vec![last_heard; self.paths.len()];
+        let ids = PacketIds::for_peer(server_addr);
+        // Until the server lists its features, only frames every server reads go out.
+        let mut server_features = 0;
+        // As heard, echoed to the server so it can tell them lost or altered.
+        let mut heard_features = 0;
 
         loop {
             tokio::select! {
@@ -82,37 +488,557 @@ impl<S: UdpSocketLike, const INFO_LENGTH: usize> ReceivingSocket<S, INFO_LENGTH>
 
                 _ = ticker.tick() => {
                     eprintln!("{}", "Tick".yellow());
-                    if !reporter.is_empty() {
-                        let packet = reporter.generate(40960).build().0; // 40Mbps
-                        if let Err(e) = self.socket.send_to(packet.as_slice(), server_addr).await {
-                            eprintln!("{e} {}", "Failed to send report to server!".red());
-                            break;
+                    let rate_kbps = self.control.as_ref().and_then(|control| control.rate_kbps()).map_or(self.rate_kbps, u64::from);
+                    let paused = self.control.as_ref().is_some_and(|control| control.is_paused());
+                    if rate_kbps != requested_kbps {
+                        eprintln!("Requesting {} kbps from the server.", rate_kbps.yellow());
+                        requested_kbps = rate_kbps;
+                    }
+                    if let Some(control) = self.control.as_ref()
+                        && received > 0
+                    {
+                        let socket = self.paths.socket(0);
+                        control.record_peer(server_addr, std::mem::take(&mut received), Some(rate_kbps.try_into().unwrap_or(u32::MAX)));
+                        control.record_marking(server_addr, socket.flow_label(server_addr), socket.traffic_class());
+                    }
+                    let now = self.clock.now();
+                    for (chunk_id, from, to) in self.paths.rebalance(now) {
+                        if let Some(report) = reporters[from].hand_over(chunk_id) {
+                            eprintln!("Moving chunk {} from path {from} to path {to}.", chunk_id.yellow());
+                            reporters[to].update(chunk_id, report);
+                        }
+                    }
+                    let bus = self.bus_interface.get_bus();
+                    let stalled = paused || backoff.stalled_since.is_some();
+                    for reporter in reporters.iter_mut() {
+                        let expired = reporter.expire(now, stalled, |chunk_id| {
+                            self.on_bus(chunk_id)
+                                .is_some_and(|chunk_id| bus.is_registered(&BusAddress::FrameDecoder(chunk_id)))
+                        });
+                        for chunk_id in expired {
+                            eprintln!("{}", format!("Chunk {chunk_id} went stale, no longer asking for it.").yellow());
+                            self.paths.finish(chunk_id);
+                            // A live decoder would otherwise wait for frames forever.
+                            if let Some(chunk_id) = self.on_bus(chunk_id) {
+                                let _ = self.bus_interface.send(BusAddress::FrameDecoder(chunk_id), (chunk_id, ErrorReason::ChunkUnavailable)).await;
+                            }
+                        }
+                    }
+                    let nothing_to_ask = reporters.iter().all(Reporter::is_empty);
+                    // Nothing is expected back while there is nothing to ask for.
+                    if paused || nothing_to_ask {
+                        last_heard = now;
+                    }
+                    if let Some(unreachable) = backoff.gave_up(now) {
+                        eprintln!("{} Giving up.", unreachable.red());
+                        return Err(unreachable.into());
+                    }
+                    for (path, last_sent) in last_sent.iter_mut().enumerate() {
+                        if self.keepalive.is_none_or(|keepalive| now - *last_sent < keepalive) {
+                            continue;
+                        }
+                        *last_sent = now;
+                        let (packet, _) = ControlPacket::new().build_in(&ids);
+                        if let Err(e) = self.paths.send_to(path, packet.as_slice(), server_addr).await {
+                            if classify(&e) == SocketErrorKind::Fatal {
+                                return Err(SocketFailure { context: "sending keepalives", source: e }.into());
+                            }
+                            eprintln!("{e} {} over path {path}.", "Failed to send keepalive to server".red());
+                        }
+                    }
+                    if !backoff.can_send(now) || nothing_to_ask {
+                        continue;
+                    }
+                    let mut sent_any = false;
+                    for (path, reporter) in reporters.iter_mut().enumerate() {
+                        if reporter.is_empty() {
+                            continue;
                         }
+                        let (ce_packets, total_packets) = std::mem::take(&mut ecn_counts[path]);
+                        let ticket = reporter
+                            .generate(rate_kbps, paused)
+                            .set_features(server_features)
+                            .set_features_echo(heard_features)
+                            .set_priority(self.priority)
+                            .set_cookie(cookies[path])
+                            .set_congestion(ce_packets, total_packets)
+                            .set_generation(self.generation.load(Ordering::Relaxed))
+                            .set_grant(self.grant)
+                            .set_timestamp(self.clock.unix_ms());
+                        for ticket in ticket.split() {
+                            let packet = ticket.build_in(&ids).0;
+                            match self.paths.send_to(path, packet.as_slice(), server_addr).await {
+                                Ok(_) => {
+                                    sent_any = true;
+                                    last_sent[path] = now;
+                                }
+                                Err(e) if classify(&e) == SocketErrorKind::Fatal => {
+                                    return Err(SocketFailure { context: "sending tickets", source: e }.into());
+                                }
+                                Err(e) => eprintln!("{e} {} over path {path}.", "Failed to send report to server".red()),
+                            }
+                        }
+                    }
+                    if !sent_any {
+                        let delay = backoff.stall_silent(now);
+                        eprintln!("{}, retrying in {delay:?}.", "No report reached the server".red());
+                    } else if now - last_heard >= SILENCE_TIMEOUT {
+                        let delay = backoff.stall_silent(now);
+                        eprintln!(
+                            "{} for {:?}, retrying in {delay:?}.",
+                            "No reply from server".yellow(),
+                            now - last_heard
+                        );
                     }
                 },
 
-                Ok((length, _)) = self.socket.recv_from(&mut buffer) => {
-                    let packet = Bytes::from(Vec::from(&buffer[0..length]));
-                    if let Ok(packet) = parse_packet::<INFO_LENGTH>(packet){
+                Some(packet) = incoming.recv() => {
+                    let packet = packet?;
+                    let path = packet.path;
+                    ecn_counts[path].0 += (packet.ecn == Ecn::Ce) as u32;
+                    ecn_counts[path].1 += 1;
+                    received += packet.length as u64;
+                    if let Some(packet) = packet.packet {
+                        last_heard = self.clock.now();
+                        packet_log(ids.session_id(), packet.get_common_packet_header().packet_id(), 0x19260817);
+                        if let ParsedPacketVariant::CookieReplyPacket { cookie: new_cookie, .. } = packet.specific_packet_header {
+                            eprintln!("{}", "Server under load, got cookie.".yellow());
+                            cookies[path] = Some(new_cookie);
+                        }
                         for frame in packet.frames{
-                            if let ParsedFrameVariant::Data(data_frame) = frame{
-                                let _ = self.bus_interface.send(BusAddress::FrameDecoder(data_frame.chunk_id), data_frame).await;
+                            match frame {
+                                ParsedFrameVariant::Data(mut data_frame) => {
+                                    if backoff.stalled_since.is_some() {
+                                        eprintln!("{}", "Server is sending again.".green());
+                                        backoff.reset();
+                                    }
+                                    self.paths.record_frame(path, data_frame.chunk_id, data_frame.frame_offset, data_frame.data.len(), last_heard);
+                                    let Some(chunk_id) = self.on_bus(data_frame.chunk_id) else {
+                                        continue;
+                                    };
+                                    data_frame.chunk_id = chunk_id;
+                                    let _ = self.bus_interface.send(BusAddress::FrameDecoder(chunk_id), data_frame).await;
+                                }
+                                ParsedFrameVariant::Error(error_frame) if error_frame.reason() == ErrorReason::AuthFailed => {
+                                    // Tickets already in flight fail too, rotate once per ticket period.
+                                    let now = self.clock.now();
+                                    if last_rotation.is_some_and(|at: Instant| now - at < TICKET_PERIOD) {
+                                        continue;
+                                    }
+                                    last_rotation = Some(now);
+                                    let key_ring = KEY_RING.get().unwrap();
+                                    if key_ring.rotate_private_key() {
+                                        eprintln!("{}", "Server rejected our identity, trying the next one.".yellow());
+                                    } else {
+                                        backoff.rejected = true;
+                                        let delay = backoff.stall(now, Duration::ZERO);
+                                        eprintln!(
+                                            "{} Retrying in {delay:?}.",
+                                            "Server accepts none of our identities!".red()
+                                        );
+                                    }
+                                }
+                                ParsedFrameVariant::Features(features) => {
+                                    heard_features = u32::from(features.flags);
+                                    server_features = heard_features & SUPPORTED_FEATURES;
+                                    for reporter in reporters.iter_mut() {
+                                        reporter.acknowledged = server_features & FEATURE_STOP_ACK != 0;
+                                    }
+                                }
+                                // Tickets of a path get their replies over it.
+                                ParsedFrameVariant::StopAck(ack) => {
+                                    reporters[path].acknowledge(ChunkId::from(ack.chunk_id));
+                                }
+                                ParsedFrameVariant::Clock(clock) => {
+                                    let echo_ms = clock.echo_timestamp_ms.get();
+                                    let round_trip_ms = self.clock.unix_ms().saturating_sub(echo_ms);
+                                    // Stamped about half a round trip after our ticket left.
+                                    skews[path].observe(offset_ms(echo_ms + round_trip_ms / 2, clock.timestamp_ms.get()));
+                                    eprintln!("Server clock is {}, {round_trip_ms} ms round trip.", skews[path]);
+                                }
+                                ParsedFrameVariant::Busy(busy) => {
+                                    let retry_after = Duration::from_millis(u32::from(busy.retry_after_ms).into());
+                                    let delay = backoff.stall(self.clock.now(), retry_after);
+                                    eprintln!(
+                                        "Server busy, {} in line, retrying in {delay:?}.",
+                                        u32::from(busy.queue_position).yellow()
+                                    );
+                                }
+                                ParsedFrameVariant::Error(error_frame) => {
+                                    let chunk_id = ChunkId::from(error_frame.chunk_id);
+                                    if error_frame.reason() == ErrorReason::StaleGeneration {
+                                        eprintln!("{}", format!("Chunk {chunk_id} changed on the server since our plan.").yellow());
+                                    }
+                                    reporters[self.paths.path_of(chunk_id)].abort(chunk_id);
+                                    self.paths.finish(chunk_id);
+                                    if let Some(chunk_id) = self.on_bus(chunk_id) {
+                                        let _ = self.bus_interface.send(BusAddress::FrameDecoder(chunk_id), (chunk_id, error_frame.reason())).await;
+                                    }
+                                }
+                                _ => {}
                             }
                         }
                     }
                 },
 
-                Some((chunk_id, report)) = self.bus_interface.recv::<(u32,  ReceivingChunkReport)>() => {
-                    reporter.update(chunk_id, report);
+                Some(message) = self.bus_interface.recv::<BusMessage<INFO_LENGTH>>() => {
+                    match message {
+                        BusMessage::ReceivingChunkReport((chunk_id, report)) => {
+                            let chunk_id = self.on_wire(chunk_id);
+                            let finished = matches!(report, ReceivingChunkReport::Finished(_));
+                            reporters[self.paths.path_of(chunk_id)].update(chunk_id, report);
+                            if finished {
+                                self.paths.finish(chunk_id);
+                            }
+                        }
+                        BusMessage::ChunkRejected((chunk_id, reason)) => {
+                            let chunk_id = self.on_wire(chunk_id);
+                            reporters[self.paths.path_of(chunk_id)].stop(chunk_id, reason);
+                        }
+                        BusMessage::AnnounceChunk(request) => {
+                            let chunk_id = self.on_wire(request.body);
+                            let path = self.paths.assign(chunk_id, self.clock.now());
+                            for reporter in reporters.iter_mut() {
+                                reporter.upcoming.retain(|upcoming| *upcoming != chunk_id);
+                            }
+                            reporters[path].announce(chunk_id);
+                            request.reply(()).ok();
+                        }
+                        _ => {}
+                    }
                 },
 
 
 
                 else => {
                     eprintln!("{}", "SenderSocketexit".red());
-                    break;
+                    return Ok(());
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::mock_init;
+    use crate::protocol::wire::encoding::parse_packet;
+    use bytes::Bytes;
+
+    #[test]
+    fn ticket_carries_requested_rate() {
+        mock_init();
+        let mut reporter = Reporter::default();
+        reporter.update(ChunkId(3), ReceivingChunkReport::WantNext(0));
+        for rate_kbps in [DEFAULT_RATE_KBPS, 512] {
+            let packet = reporter.generate(rate_kbps, false).build().0.concat();
+            let packet = parse_packet::<16>(Bytes::from(packet)).unwrap();
+            let requested = packet.frames.iter().find_map(|frame| match frame {
+                ParsedFrameVariant::RateLimit(header) => Some(u32::from(header.desired_max_kbps)),
+                _ => None,
+            });
+            assert_eq!(requested.map(u64::from), Some(rate_kbps));
+        }
+    }
+
+    #[test]
+    fn backoff_grows_and_gives_up() {
+        let mut backoff = Backoff {
+            max_wait: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let mut now = start;
+        let mut delays = vec![];
+        while backoff.gave_up(now).is_none() {
+            assert!(backoff.can_send(now));
+            let delay = backoff.stall(now, Duration::ZERO);
+            assert!(!backoff.can_send(now));
+            delays.push(delay);
+            now += delay;
+        }
+        assert!(delays.windows(2).take(4).all(|pair| pair[1] > pair[0]));
+        assert!(
+            delays
+                .iter()
+                .all(|delay| *delay <= MAX_BACKOFF.mul_f64(1.25))
+        );
+        assert!(now - start >= Duration::from_secs(60));
+        assert!(!backoff.gave_up(now).unwrap().rejected);
+
+        // A silent server is tried again every few seconds however long it was silent.
+        backoff.reset();
+        let silent: Vec<_> = (0..8).map(|_| backoff.stall_silent(now)).collect();
+        assert!(
+            silent
+                .iter()
+                .all(|delay| *delay <= MAX_SILENT_BACKOFF.mul_f64(1.25))
+        );
+
+        // A busy server's hint is never undercut.
+        backoff.reset();
+        assert!(backoff.gave_up(now).is_none());
+        assert!(backoff.stall(now, Duration::from_secs(20)) >= Duration::from_secs(20));
+
+        // Giving up on a server that rejects us says so.
+        backoff.rejected = true;
+        let gave_up = backoff.gave_up(now + Duration::from_secs(60)).unwrap();
+        assert!(gave_up.rejected);
+        assert!(gave_up.to_string().starts_with("server rejected"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_paths_send_keepalives() {
+        use crate::engine::Bus;
+        use crate::transmission::mock::MockSocket;
+
+        mock_init();
+        let (client, server): (SocketAddr, SocketAddr) = (
+            "127.0.0.1:7300".parse().unwrap(),
+            "127.0.0.1:7301".parse().unwrap(),
+        );
+        let (socket, remote) = MockSocket::pair(client, server);
+        let bus: Arc<Bus<BusAddress, BusMessage<16>>> = Arc::new(Bus::default());
+        let receiver =
+            ReceivingSocket::new(socket, bus.register(BusAddress::ReceiverSocket).unwrap())
+                .with_keepalive(Some(Duration::from_secs(5)));
+        let start = Instant::now();
+        let task = tokio::spawn(receiver.run(server));
+
+        // Nothing to ask for, so nothing but keepalives goes out.
+        let mut buf = vec![0u8; MTU];
+        for expected in [5, 10] {
+            let (length, _) = remote.recv_from(&mut buf).await.unwrap();
+            assert_eq!(start.elapsed().as_secs(), expected);
+            let packet = parse_packet::<16>(Bytes::copy_from_slice(&buf[..length])).unwrap();
+            assert!(matches!(
+                packet.specific_packet_header,
+                ParsedPacketVariant::ControlPacket()
+            ));
+            assert!(packet.frames.is_empty());
+        }
+        task.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_chunks_fail_their_decoder() {
+        use crate::engine::Bus;
+        use crate::transmission::mock::MockSocket;
+
+        mock_init();
+        let (client, server): (SocketAddr, SocketAddr) = (
+            "127.0.0.1:7310".parse().unwrap(),
+            "127.0.0.1:7311".parse().unwrap(),
+        );
+        let (socket, remote) = MockSocket::pair(client, server);
+        let bus: Arc<Bus<BusAddress, BusMessage<16>>> = Arc::new(Bus::default());
+        let receiver = ReceivingSocket::new(
+            socket,
+            bus.clone().register(BusAddress::ReceiverSocket).unwrap(),
+        );
+        let mut decoder = bus.register(BusAddress::FrameDecoder(ChunkId(3))).unwrap();
+        // The decoder asks for its chunk and then hears nothing of it.
+        decoder
+            .send(
+                BusAddress::ReceiverSocket,
+                (ChunkId(3), ReceivingChunkReport::WantNext(0)),
+            )
+            .await
+            .unwrap();
+        let task = tokio::spawn(receiver.run(server));
+
+        // The server answers every ticket but never sends a frame of the chunk.
+        let responder = tokio::spawn(async move {
+            let mut buf = vec![0u8; MTU];
+            loop {
+                remote.recv_from(&mut buf).await.unwrap();
+                let (packet, _) = ControlPacket::new().build();
+                remote.send_to(packet.as_slice(), client).await.unwrap();
+            }
+        });
+        let start = Instant::now();
+        let message =
+            tokio::time::timeout(STALE_CHUNK_TIMEOUT * 2, decoder.recv::<BusMessage<16>>())
+                .await
+                .expect("the decoder is told")
+                .unwrap();
+        assert!(matches!(
+            message,
+            BusMessage::ChunkError((ChunkId(3), ErrorReason::ChunkUnavailable))
+        ));
+        assert!(start.elapsed() >= STALE_CHUNK_TIMEOUT);
+        task.abort();
+        responder.abort();
+    }
+
+    #[test]
+    fn chunk_base_maps_server_ids() {
+        use crate::engine::Bus;
+        use crate::engine::plans::{bus_id, chunk_base};
+        use crate::transmission::mock::MockSocket;
+
+        let bus = Arc::new(Bus::<BusAddress, BusMessage<16>>::default());
+        let receiver = |plan| {
+            let (socket, _) = MockSocket::pair(
+                "127.0.0.1:10000".parse().unwrap(),
+                "127.0.0.1:10001".parse().unwrap(),
+            );
+            ReceivingSocket::new(
+                socket,
+                bus.clone()
+                    .register(BusAddress::PlanReceiver(plan as u32))
+                    .unwrap(),
+            )
+            .with_chunk_base(chunk_base(plan).unwrap())
+        };
+        let (first, second) = (receiver(0), receiver(1));
+        let on_bus = bus_id(1, ChunkId(3)).unwrap();
+        assert_eq!(second.on_bus(ChunkId(3)), Some(on_bus));
+        assert_eq!(second.on_wire(on_bus), ChunkId(3));
+        // A server can not reach into the chunks of the next plan, from the first plan either.
+        assert_eq!(second.on_bus(ChunkId(PLAN_CHUNK_SPACE)), None);
+        assert_eq!(first.on_bus(ChunkId(PLAN_CHUNK_SPACE)), None);
+    }
+
+    #[test]
+    fn paused_ticket_closes_windows() {
+        mock_init();
+        let mut reporter = Reporter {
+            upcoming: [ChunkId(4), ChunkId(5)].into(),
+            ..Default::default()
+        };
+        reporter.update(ChunkId(3), ReceivingChunkReport::WantNext(120));
+        let packet = reporter
+            .generate(DEFAULT_RATE_KBPS, true)
+            .build()
+            .0
+            .concat();
+        let packet = parse_packet::<16>(Bytes::from(packet)).unwrap();
+        let windows: Vec<(u32, u32, u32)> = packet
+            .frames
+            .iter()
+            .filter_map(|frame| match frame {
+                ParsedFrameVariant::GetChunk(header) => Some((
+                    u32::from(header.chunk_id),
+                    u32::from(header.next_receive_offset),
+                    u32::from(header.receive_window_frames),
+                )),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(windows, vec![(3, 120, 0)]);
+        assert!(
+            !packet
+                .frames
+                .iter()
+                .any(|frame| matches!(frame, ParsedFrameVariant::Prefetch(_)))
+        );
+    }
+
+    #[test]
+    fn finished_chunks_stop_with_a_reason() {
+        use crate::protocol::wire::frames::FEATURE_STOP_CHUNK;
+
+        mock_init();
+        let mut reporter = Reporter::default();
+        reporter.update(ChunkId(3), ReceivingChunkReport::WantNext(10));
+        reporter.update(ChunkId(4), ReceivingChunkReport::WantNext(20));
+        reporter.update(ChunkId(3), ReceivingChunkReport::Finished(30));
+        reporter.hand_over(ChunkId(4));
+        reporter.stop(ChunkId(5), StopReason::HashMismatch);
+        // Finished chunks stay in the next few tickets.
+        let mut stops = |features| {
+            let packet = reporter
+                .generate(DEFAULT_RATE_KBPS, false)
+                .set_features(features)
+                .build()
+                .0
+                .concat();
+            let packet = parse_packet::<16>(Bytes::from(packet)).unwrap();
+            packet
+                .frames
+                .iter()
+                .filter_map(|frame| match frame {
+                    ParsedFrameVariant::StopChunk(header) => {
+                        Some((u32::from(header.chunk_id), header.reason()))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            stops(FEATURE_STOP_CHUNK),
+            vec![
+                (3, StopReason::Finished),
+                (4, StopReason::Aborted),
+                (5, StopReason::HashMismatch)
+            ]
+        );
+        // Servers without the frame get the closed windows only.
+        assert!(stops(0).is_empty());
+    }
+
+    #[test]
+    fn acknowledged_stops_are_reported_until_acked() {
+        mock_init();
+        let mut reporter = Reporter {
+            acknowledged: true,
+            ..Default::default()
+        };
+        reporter.stop(ChunkId(3), StopReason::HashMismatch);
+        reporter.update(ChunkId(4), ReceivingChunkReport::Finished(10));
+        for _ in 0..5 {
+            reporter.generate(DEFAULT_RATE_KBPS, false);
+        }
+        assert!(!reporter.is_empty());
+        reporter.acknowledge(ChunkId(3));
+        assert!(!reporter.stopped.contains_key(&ChunkId(3)));
+        reporter.acknowledge(ChunkId(4));
+        reporter.generate(DEFAULT_RATE_KBPS, false);
+        assert!(reporter.is_empty() && reporter.exiting_data.is_empty());
+    }
+
+    #[test]
+    fn dead_and_stale_chunks_expire() {
+        mock_init();
+        let mut reporter = Reporter::default();
+        for chunk_id in [3, 4, 5] {
+            reporter.update(ChunkId(chunk_id), ReceivingChunkReport::WantNext(10));
+        }
+        let start = Instant::now();
+        let alive = |chunk_id: ChunkId| chunk_id != ChunkId(4);
+        assert_eq!(reporter.expire(start, false, alive), vec![ChunkId(4)]);
+
+        // Moving keeps a chunk, nothing ages while stalled.
+        let later = start + STALE_CHUNK_TIMEOUT / 2;
+        reporter.update(ChunkId(3), ReceivingChunkReport::WantNext(20));
+        assert!(reporter.expire(later, false, alive).is_empty());
+        assert!(
+            reporter
+                .expire(later + STALE_CHUNK_TIMEOUT, true, alive)
+                .is_empty()
+        );
+        let now = later + STALE_CHUNK_TIMEOUT * 2;
+        reporter.update(ChunkId(3), ReceivingChunkReport::WantNext(30));
+        assert_eq!(reporter.expire(now, false, alive), vec![ChunkId(5)]);
+        let wanted: Vec<_> = reporter
+            .activate_data
+            .iter()
+            .filter(|(_, report)| matches!(report, ReceivingChunkReport::WantNext(_)))
+            .map(|(chunk_id, _)| *chunk_id)
+            .collect();
+        assert_eq!(wanted, vec![ChunkId(3)]);
+        assert_eq!(
+            reporter.stopped.get(&ChunkId(5)),
+            Some(&StopReason::Aborted)
+        );
+
+        // Stop reasons go with the last ticket naming their chunk.
+        for _ in 0..4 {
+            reporter.generate(DEFAULT_RATE_KBPS, false);
+        }
+        assert!(reporter.stopped.is_empty());
+        assert_eq!(reporter.progress.len(), 1);
+    }
+}
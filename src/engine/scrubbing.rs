@@ -0,0 +1,122 @@
+use crate::util::file::{ChunkIndex, chunk_hash};
+use crate::util::units::ChunkId;
+use owo_colors::OwoColorize;
+use tokio::time::{Duration, sleep};
+
+// Re-hashes chunks against the plan in the background, slowly enough not to
+// compete with serving. Corrupted chunks are marked unavailable.
+pub struct Scrubber {
+    index: &'static ChunkIndex,
+    expected: Vec<(ChunkId, String)>, // (chunk_id, hash)
+    bytes_per_sec: u64,
+}
+
+impl Scrubber {
+    pub fn new(
+        index: &'static ChunkIndex,
+        expected: Vec<(ChunkId, String)>,
+        bytes_per_sec: u64,
+    ) -> Self {
+        Self {
+            index,
+            expected,
+            bytes_per_sec,
+        }
+    }
+
+    // Scrubs every available chunk once, returns the ones found corrupted.
+    pub async fn run_once(&self) -> Vec<ChunkId> {
+        let mut corrupted = vec![];
+        for (chunk_id, expected) in self.expected.iter() {
+            let chunk_id = *chunk_id;
+            if !self.index.is_available(chunk_id) {
+                continue;
+            }
+            let Some((path, offset, length)) = self.index.get(chunk_id) else {
+                continue;
+            };
+
+            let hash = tokio::task::spawn_blocking(move || chunk_hash(path, offset, length)).await;
+            match hash {
+                Ok(Ok(hash)) if hash == *expected => {}
+                Ok(Ok(hash)) => {
+                    eprintln!(
+                        "{} chunk {}: expected {}, actual {}",
+                        "Scrubber found corrupted".on_red(),
+                        chunk_id,
+                        expected.yellow(),
+                        hash.yellow()
+                    );
+                    self.index.mark_unavailable(chunk_id);
+                    corrupted.push(chunk_id);
+                }
+                Ok(Err(err)) => {
+                    eprintln!(
+                        "{} chunk {}: {err}",
+                        "Scrubber failed to read".on_red(),
+                        chunk_id
+                    );
+                    self.index.mark_unavailable(chunk_id);
+                    corrupted.push(chunk_id);
+                }
+                Err(err) => eprintln!("Scrubber task failed on chunk {chunk_id}: {err}"),
+            }
+
+            sleep(Duration::from_secs_f64(
+                length as f64 / self.bytes_per_sec.max(1) as f64,
+            ))
+            .await;
+        }
+        corrupted
+    }
+
+    pub async fn run(self) {
+        loop {
+            let corrupted = self.run_once().await;
+            eprintln!(
+                "Scrubbed {} chunks, {} newly corrupted.",
+                self.expected.len(),
+                corrupted.len()
+            );
+            // Avoid spinning once every chunk became unavailable.
+            sleep(Duration::from_secs(1)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::file::{create_sparse_file, write_at};
+    use std::collections::HashMap;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn scrub_marks_corrupted_chunks() -> std::io::Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("scrub.bin");
+        create_sparse_file(&file_path, 2 * 4096)?;
+        let zero_hash = chunk_hash(&file_path, 0, 4096)?;
+
+        let index: &'static ChunkIndex = Box::leak(Box::new(ChunkIndex::new(
+            HashMap::from([(0, file_path.clone().into_os_string())]),
+            HashMap::from([(ChunkId(0), (0, 0, 4096)), (ChunkId(1), (0, 4096, 4096))]),
+        )));
+        let scrubber = Scrubber::new(
+            index,
+            vec![(ChunkId(0), zero_hash.clone()), (ChunkId(1), zero_hash)],
+            u64::MAX,
+        );
+
+        assert!(scrubber.run_once().await.is_empty());
+
+        write_at(&file_path, 4096, &[0x42; 16])?;
+        assert_eq!(scrubber.run_once().await, vec![ChunkId(1)]);
+        assert!(index.is_available(ChunkId(0)));
+        assert!(!index.is_available(ChunkId(1)));
+
+        // Unavailable chunks are skipped from now on.
+        assert!(scrubber.run_once().await.is_empty());
+        Ok(())
+    }
+}
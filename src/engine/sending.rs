@@ -1,24 +1,583 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::io::ErrorKind;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
 
-use super::{BusAddress, BusInterface, BusMessage, SendingOrder};
-use crate::constants::MTU;
+use super::admission::{Admission, AdmissionLimits, AdmissionQueue};
+use super::control::ControlState;
+use super::encoding::{ADAPTIVE_START_PERCENT, PreparedEncoders, RepairStrategy};
+use super::journal::SessionJournal;
+use super::receiving::{ServerUnreachable, SessionError};
+use super::{
+    BuiltDataPacket, BusAddress, BusInterface, BusMessage, COVER_CHUNK_ID, PLAN_CHUNK_ID,
+    PeerEvent, SendingOrder,
+};
+use crate::constants::{MTU, UNDER_LOAD_TICKETS_PER_SEC};
 use crate::protocol::coding::FrameSender;
-use crate::protocol::wire::encoding::{PacketExt, ParsedPacket, parse_packet};
-use crate::protocol::wire::frames::ParsedFrameVariant;
-use crate::protocol::wire::packets::ParsedPacketVariant;
-use crate::protocol::wire::{frames::DataFrame, packets::DataPacket};
-use crate::transmission::UdpSocketLike;
+use crate::protocol::cookie::{CookieChecker, LoadMonitor};
+use crate::protocol::quota::{Quota, QuotaBook};
+use crate::protocol::token::TokenChecker;
+use crate::protocol::wire::PacketIds;
+use crate::protocol::wire::encoding::{
+    PacketExt, ParseError, ParsedPacket, parse_packet_with_precheck,
+};
+use crate::protocol::wire::frames::{
+    BusyFrame, ClockFrame, ErrorFrame, ErrorReason, FeaturesFrame, ParsedFrameVariant, Priority,
+    SUPPORTED_FEATURES, StopAckFrame,
+};
+use crate::protocol::wire::packets::{
+    ControlPacket, CookieReplyPacket, ParsedPacketVariant, TicketPacket,
+};
+use crate::transmission::errors::{
+    RETRY_DELAY, SocketErrorKind, SocketErrors, SocketFailure, classify,
+};
+use crate::transmission::{UdpSocketLike, pool::BufferPool};
+use crate::util::audit::{self, AuditRecord};
+use crate::util::clock::{SharedClock, SkewEstimate, offset_ms, system_clock};
+use crate::util::cpu::{Subsystem, accounted};
+use crate::util::file::CHUNK_INDEX;
 use crate::util::log::packet_log;
+use crate::util::pacing::{interval_for_rate, rate_for_interval, weighted_shares};
+use crate::util::units::{ChunkId, FrameOffset, SessionId};
 
-use bytes::Bytes;
-
-use tokio::time::Instant;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
 
 pub struct SendingSocket<S: UdpSocketLike, const INFO_LENGTH: usize> {
-    socket: S,
+    socket: Arc<S>,
     bus_interface: BusInterface<BusAddress, BusMessage<INFO_LENGTH>>,
+    cookies: CookieChecker,
+    load: LoadMonitor,
+    rates: HashMap<SocketAddr, Duration>,
+    backoff: HashMap<SocketAddr, f64>,
+    // Next departure of each (peer, chunk) stream, when the kernel paces for us.
+    departures: Option<HashMap<(SocketAddr, ChunkId), Instant>>,
+    workers: HashMap<SocketAddr, PeerWorker>,
+    // Peers a worker found unreachable, to be forgotten.
+    unreachable: (flume::Sender<SocketAddr>, flume::Receiver<SocketAddr>),
+    quotas: Option<QuotaBook>,
+    quota_saved: Instant,
+    // Key of each peer's last ticket, charged for what is sent until the next.
+    quota_keys: HashMap<SocketAddr, String>,
+    // When an encoder was last prepared for each chunk, on a client's hint or in plan order.
+    warmed: HashMap<ChunkId, Instant>,
+    clock: SharedClock,
+    control: Option<Arc<ControlState>>,
+    encoders: EncoderLru,
+    admission: Option<AdmissionQueue>,
+    journal: Option<SessionJournal>,
+    journal_saved: Instant,
+    // Peers of journaled sessions, spared the cookie round trip until then.
+    resumed: HashMap<SocketAddr, Instant>,
+    applied: AppliedTickets,
+    clocks: TicketClocks,
+    // Packets per peer are sent one this far apart, data or cover.
+    cover_interval: Option<Duration>,
+    // Handed to every encoder spawned from here.
+    repair: RepairStrategy,
+    // Workers take the chunks of their peer in turn, see `Interleaver`.
+    interleave: bool,
+    tokens: Option<TokenChecker>,
+    offer: Option<Offer>,
+    // Split between the peers by priority, see `with_link_rate`.
+    link_kbps: Option<u64>,
+    // Rate asked, priority and last ticket of every peer, for the link shares.
+    demands: HashMap<SocketAddr, (u64, Priority, Instant)>,
+    // With adaptive repair only.
+    losses: HashMap<SocketAddr, LossEstimate>,
+}
+
+// A peer without tickets for this long no longer takes a share of the link.
+const DEMAND_IDLE: Duration = Duration::from_secs(5);
+
+// An upload is over once the receiver sent no ticket for this long.
+const UPLOAD_IDLE: Duration = Duration::from_secs(10);
+
+// Offers are repeated this often until the receiver sends a ticket.
+const OFFER_PERIOD: Duration = Duration::from_secs(1);
+
+// The file offered to a receiver, which asks for its chunks with tickets of
+// its own, see `SendingSocket::push`.
+struct Offer {
+    target: SocketAddr,
+    file_id: u64,
+    max_wait: Duration,
+    // When the offer was first made, then when the target last sent a ticket.
+    last_ticket: Instant,
+    accepted: bool,
+}
+
+// Resumed sessions get this long to send a ticket without a cookie.
+const RESUME_GRACE: Duration = Duration::from_secs(30);
+
+// A worker leaves after this long without frames, and is started again on the next one.
+const PEER_WORKER_IDLE: Duration = Duration::from_secs(30);
+
+// Data packets for one peer are sent on a task of its own, so a slow
+// peer holds up no other. Tickets and control packets stay with the run loop.
+struct PeerWorker {
+    packets: flume::Sender<(BuiltDataPacket, Duration)>,
+    served: Arc<Served>,
+    // The peer's interval between frames of a chunk in ns, 0 while unknown.
+    interval_ns: Arc<AtomicU64>,
+}
+
+// Sent to a peer since its last ticket.
+#[derive(Default)]
+struct Served {
+    // Of data, for the audit log.
+    bytes: AtomicU64,
+    // Data and cover, for the loss estimate.
+    packets: AtomicU64,
+}
+
+// Sends one packet every `interval`, a cover packet whenever no data packet is
+// queued. The data rate is capped at that of the cover traffic, and stays
+// hidden in it.
+struct CoverTraffic {
+    ticks: Interval,
+    last_data: Instant,
+}
+
+impl CoverTraffic {
+    fn new(interval: Duration) -> Self {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            ticks,
+            last_data: Instant::now(),
+        }
+    }
+
+    // None once the peer went without data for `PEER_WORKER_IDLE`.
+    async fn next(
+        &mut self,
+        packets: &flume::Receiver<(BuiltDataPacket, Duration)>,
+        ids: &PacketIds,
+    ) -> Option<(BuiltDataPacket, Duration)> {
+        self.ticks.tick().await;
+        match packets.try_recv() {
+            // Paced here instead of by the kernel.
+            Ok((packet, _)) => {
+                self.last_data = Instant::now();
+                Some((packet, Duration::ZERO))
+            }
+            Err(flume::TryRecvError::Empty) if self.last_data.elapsed() < PEER_WORKER_IDLE => {
+                Some((BuiltDataPacket::cover(MTU, ids), Duration::ZERO))
+            }
+            Err(_) => None,
+        }
+    }
+}
+
+// Past this many queued packets the interleaver sends without spacing them,
+// so it never holds encoders below the peer's rate.
+const MAX_INTERLEAVED_BACKLOG: usize = 256;
+
+// Packets of a peer queued by chunk and sent one chunk after the other, one
+// interval of the peer split between the queued chunks apart. The chunks of a
+// peer then advance together and complete one after the other at a steady
+// pace, instead of its decoder getting each encoder's timer ticks in bursts.
+// Paced here instead of by the kernel.
+struct Interleaver {
+    // Never empty queues, the next to send from first.
+    queues: VecDeque<(ChunkId, VecDeque<BuiltDataPacket>)>,
+    queued: usize,
+    interval_ns: Arc<AtomicU64>,
+    next_departure: Instant,
+}
+
+impl Interleaver {
+    fn new(interval_ns: Arc<AtomicU64>) -> Self {
+        Self {
+            queues: VecDeque::new(),
+            queued: 0,
+            interval_ns,
+            next_departure: Instant::now(),
+        }
+    }
+
+    fn push(&mut self, packet: BuiltDataPacket) {
+        self.queued += 1;
+        match self
+            .queues
+            .iter_mut()
+            .find(|(chunk_id, _)| *chunk_id == packet.chunk_id)
+        {
+            Some((_, queue)) => queue.push_back(packet),
+            None => self
+                .queues
+                .push_back((packet.chunk_id, VecDeque::from([packet]))),
+        }
+    }
+
+    fn pop(&mut self) -> Option<BuiltDataPacket> {
+        let (chunk_id, mut queue) = self.queues.pop_front()?;
+        let packet = queue.pop_front();
+        if !queue.is_empty() {
+            self.queues.push_back((chunk_id, queue));
+        }
+        self.queued -= 1;
+        packet
+    }
+
+    // None once the peer went without data for `PEER_WORKER_IDLE`.
+    async fn next(
+        &mut self,
+        packets: &flume::Receiver<(BuiltDataPacket, Duration)>,
+    ) -> Option<(BuiltDataPacket, Duration)> {
+        if self.queued == 0 {
+            let (packet, _) = tokio::time::timeout(PEER_WORKER_IDLE, packets.recv_async())
+                .await
+                .ok()?
+                .ok()?;
+            self.push(packet);
+        }
+        tokio::time::sleep_until(self.next_departure).await;
+        while let Ok((packet, _)) = packets.try_recv() {
+            self.push(packet);
+        }
+        let gap = match self.queued > MAX_INTERLEAVED_BACKLOG {
+            true => Duration::ZERO,
+            false => {
+                Duration::from_nanos(self.interval_ns.load(Ordering::Relaxed))
+                    / self.queues.len() as u32
+            }
+        };
+        self.next_departure = self.next_departure.max(Instant::now()) + gap;
+        self.pop().map(|packet| (packet, Duration::ZERO))
+    }
+}
+
+async fn send_to_peer<S: UdpSocketLike>(
+    socket: Arc<S>,
+    addr: SocketAddr,
+    packets: flume::Receiver<(BuiltDataPacket, Duration)>,
+    served: Arc<Served>,
+    unreachable: flume::Sender<SocketAddr>,
+    mut cover: Option<CoverTraffic>,
+    mut interleaver: Option<Interleaver>,
+) {
+    let ids = PacketIds::for_peer(addr);
+    loop {
+        let next = match (cover.as_mut(), interleaver.as_mut()) {
+            (Some(cover), _) => cover.next(&packets, &ids).await,
+            (None, Some(interleaver)) => interleaver.next(&packets).await,
+            (None, None) => tokio::time::timeout(PEER_WORKER_IDLE, packets.recv_async())
+                .await
+                .ok()
+                .and_then(Result::ok),
+        };
+        let Some((packet, delay)) = next else {
+            break;
+        };
+        if packet.chunk_id != COVER_CHUNK_ID {
+            served
+                .bytes
+                .fetch_add(packet.len() as u64, Ordering::Relaxed);
+        }
+        served.packets.fetch_add(1, Ordering::Relaxed);
+        if let Err(err) = socket.send_to_after(&packet.parts, addr, delay).await {
+            eprintln!("Failed to send to {addr}: {err}");
+            if matches!(
+                err.kind(),
+                ErrorKind::ConnectionRefused
+                    | ErrorKind::HostUnreachable
+                    | ErrorKind::NetworkUnreachable
+            ) {
+                unreachable.send(addr).ok();
+                return;
+            }
+            // The socket failed for good, the run loop ends on it too.
+            if classify(&err) == SocketErrorKind::Fatal {
+                return;
+            }
+        }
+        packet_log(packet.session_id, packet.packet_id, 0x20250819);
+    }
+}
+
+// Running encoders by when they were last ordered. Each holds a whole chunk,
+// so past `capacity` the least recently ordered are closed.
+#[derive(Default)]
+struct EncoderLru {
+    last_ordered: HashMap<BusAddress, Instant>,
+    capacity: Option<usize>,
+}
+
+impl EncoderLru {
+    fn touch(&mut self, addr: &BusAddress, now: Instant) {
+        if let Some(at) = self.last_ordered.get_mut(addr) {
+            *at = now;
+        }
+    }
+
+    // Encoders that exit on their own stay until evicted, so nothing is kept without a capacity.
+    fn insert(&mut self, addr: BusAddress, now: Instant) {
+        if self.capacity.is_some() {
+            self.last_ordered.insert(addr, now);
+        }
+    }
+
+    fn remove(&mut self, addr: &BusAddress) {
+        self.last_ordered.remove(addr);
+    }
+
+    fn remove_peer(&mut self, peer: SocketAddr) {
+        self.last_ordered
+            .retain(|addr, _| !is_encoder_of(peer)(addr));
+    }
+
+    // The encoders to close so one more fits, least recently ordered first.
+    fn evict_for_one(&mut self) -> Vec<BusAddress> {
+        let Some(capacity) = self.capacity else {
+            return vec![];
+        };
+        let mut evicted = vec![];
+        while self.last_ordered.len() >= capacity.max(1) {
+            let Some(oldest) = self
+                .last_ordered
+                .iter()
+                .min_by_key(|(_, at)| **at)
+                .map(|(addr, _)| addr.clone())
+            else {
+                break;
+            };
+            self.last_ordered.remove(&oldest);
+            evicted.push(oldest);
+        }
+        evicted
+    }
+}
+
+fn audit_ticket<const INFO_LENGTH: usize>(
+    packet: &ParsedPacket<INFO_LENGTH>,
+    addr: SocketAddr,
+    bytes_served: u64,
+) {
+    let ParsedPacketVariant::TicketPacket {
+        pub_key,
+        timestamp_ms,
+    } = &packet.specific_packet_header
+    else {
+        return;
+    };
+    let chunk_ids = packet
+        .frames
+        .iter()
+        .filter_map(|frame| match frame {
+            ParsedFrameVariant::GetChunk(header) => Some(u32::from(header.chunk_id)),
+            ParsedFrameVariant::WideGetChunk(header) => Some(u32::from(header.chunk_id)),
+            _ => None,
+        })
+        .collect();
+    audit::record(AuditRecord {
+        timestamp_ms: *timestamp_ms,
+        pub_key: hex::encode(pub_key),
+        addr,
+        chunk_ids,
+        bytes_served,
+    });
+}
+
+// The last ticket applied per peer, by timestamp and packet id. Tickets the
+// network duplicated or reordered would otherwise push encoder deadlines and
+// windows once more.
+#[derive(Default)]
+struct AppliedTickets(HashMap<SocketAddr, (u64, u32)>);
+
+impl AppliedTickets {
+    // Records the ticket unless it is the last one applied or older.
+    fn apply(&mut self, peer: SocketAddr, timestamp_ms: u64, packet_id: u32) -> bool {
+        if let Some(&(last_ms, last_id)) = self.0.get(&peer)
+            && (timestamp_ms < last_ms || (timestamp_ms, packet_id) == (last_ms, last_id))
+        {
+            return false;
+        }
+        self.0.insert(peer, (timestamp_ms, packet_id));
+        true
+    }
+
+    fn knows(&self, peer: SocketAddr) -> bool {
+        self.0.contains_key(&peer)
+    }
+
+    fn forget(&mut self, peer: SocketAddr) {
+        self.0.remove(&peer);
+    }
+}
+
+// Tickets stamped further than this from our clock are dropped, unless set
+// otherwise with `SendingSocket::with_max_clock_skew`.
+pub const DEFAULT_MAX_CLOCK_SKEW: Duration = Duration::from_secs(15 * 60);
+// A peer's clock is logged again once its estimate moved by this much.
+const SKEW_LOG_STEP_MS: u64 = 1000;
+
+// How far the clock of every peer is from ours, by the timestamps of its
+// tickets. Tickets are taken up to `max_skew` off, so peers with clocks minutes
+// off are still served while tickets from long ago are not.
+struct TicketClocks {
+    max_skew: Duration,
+    skews: HashMap<SocketAddr, (SkewEstimate, Option<i64>)>,
+}
+
+impl TicketClocks {
+    fn new(max_skew: Duration) -> Self {
+        Self {
+            max_skew,
+            skews: HashMap::new(),
+        }
+    }
+
+    // False if the ticket is too far off our clock `now_ms` to be taken.
+    fn fresh(&mut self, peer: SocketAddr, timestamp_ms: u64, now_ms: u64) -> bool {
+        let offset_ms = offset_ms(now_ms, timestamp_ms);
+        if offset_ms.unsigned_abs() > self.max_skew.as_millis() as u64 {
+            eprintln!(
+                "Ticket of {peer} is {offset_ms} ms off our clock, past the {:?} tolerated.",
+                self.max_skew
+            );
+            return false;
+        }
+        let (skew, logged) = self.skews.entry(peer).or_default();
+        skew.observe(offset_ms);
+        let estimate = skew.offset_ms().unwrap_or_default();
+        if logged.is_none_or(|logged| logged.abs_diff(estimate) >= SKEW_LOG_STEP_MS) {
+            eprintln!("Clock of {peer} is {skew}.");
+            *logged = Some(estimate);
+        }
+        true
+    }
+
+    fn forget(&mut self, peer: SocketAddr) {
+        self.skews.remove(&peer);
+    }
+}
+
+// Fewer packets than this sent since the last ticket say little about loss.
+const LOSS_MIN_SAMPLE: u64 = 16;
+// Adaptive repair on top of what makes up for the estimated loss.
+const REPAIR_MARGIN_PERCENT: u32 = 3;
+const MAX_ADAPTIVE_PERCENT: u32 = 200;
+
+// Loss towards a peer, from the packets its tickets say arrived against those
+// sent to it since the ticket before. Packets in flight count as lost in one
+// sample and as arrived in the next, which the average evens out.
+#[derive(Debug, Clone, Copy, Default)]
+struct LossEstimate {
+    loss: Option<f64>,
+    // The overhead the peer's encoders were last told.
+    announced: Option<u32>,
+}
+
+impl LossEstimate {
+    fn observe(&mut self, sent: u64, received: u64) {
+        if sent < LOSS_MIN_SAMPLE {
+            return;
+        }
+        let sample = 1.0 - (received as f64 / sent as f64).min(1.0);
+        self.loss = Some(
+            self.loss
+                .map_or(sample, |loss| loss + (sample - loss) / 8.0),
+        );
+    }
+
+    // A loss of p takes p / (1 - p) more frames to make up for.
+    fn overhead_percent(&self) -> u32 {
+        let Some(loss) = self.loss else {
+            return ADAPTIVE_START_PERCENT;
+        };
+        let loss = loss.min(0.9);
+        let percent = (100.0 * loss / (1.0 - loss)).round() as u32 + REPAIR_MARGIN_PERCENT;
+        percent.min(MAX_ADAPTIVE_PERCENT)
+    }
+}
+
+// Stretches the interval a peer asked for while it reports CE marks, so the
+// sending rate drops before the bottleneck starts losing packets.
+fn update_backoff<const INFO_LENGTH: usize>(
+    backoff: &mut f64,
+    frames: &[ParsedFrameVariant<INFO_LENGTH>],
+) {
+    let Some(report) = frames.iter().find_map(|frame| match frame {
+        ParsedFrameVariant::Congestion(header) => Some(header),
+        _ => None,
+    }) else {
+        return;
+    };
+    if u32::from(report.ce_packets) > 0 {
+        *backoff = (*backoff * 1.25).min(16.0);
+    } else if u32::from(report.total_packets) > 0 {
+        *backoff = (*backoff * 0.95).max(1.0);
+    }
+}
+
+// Under load, tickets must carry a valid cookie before their signature is checked.
+fn require_cookie<const INFO_LENGTH: usize>(
+    cookies: &mut CookieChecker,
+    socket_addr: SocketAddr,
+    header: &ParsedPacketVariant,
+    frames: &[ParsedFrameVariant<INFO_LENGTH>],
+) -> Result<(), ParseError> {
+    let ParsedPacketVariant::TicketPacket { timestamp_ms, .. } = header else {
+        return Ok(());
+    };
+    frames
+        .iter()
+        .any(|frame| {
+            matches!(frame, ParsedFrameVariant::Cookie(header)
+                if cookies.check_cookie(socket_addr, &header.cookie))
+        })
+        .then_some(())
+        .ok_or(ParseError::CookieRequired(*timestamp_ms))
+}
+
+// Interval between frames of one stream to send at `kbps`.
+pub fn interval_for_kbps(kbps: u32) -> Duration {
+    interval_for_rate(kbps.into(), MTU)
+}
+
+// Saturated at u32::MAX, as rate fields on the wire and in journals are u32.
+pub fn kbps_for_interval(interval: Duration) -> u32 {
+    rate_for_interval(interval, MTU)
+        .try_into()
+        .unwrap_or(u32::MAX)
+}
+
+// The rate a ticket asks for, from whichever of the two frames it carries.
+fn requested_kbps<const INFO_LENGTH: usize>(
+    frames: &[ParsedFrameVariant<INFO_LENGTH>],
+) -> Option<u64> {
+    frames.iter().find_map(|frame| match frame {
+        ParsedFrameVariant::RateLimit(header) => Some(u32::from(header.desired_max_kbps).into()),
+        ParsedFrameVariant::WideRateLimit(header) => Some(header.desired_max_kbps.into()),
+        _ => None,
+    })
+}
+
+fn priority_of<const INFO_LENGTH: usize>(frames: &[ParsedFrameVariant<INFO_LENGTH>]) -> Priority {
+    frames
+        .iter()
+        .find_map(|frame| match frame {
+            ParsedFrameVariant::Priority(header) => Some(header.priority()),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+// None from clients not echoing them.
+fn heard_features<const INFO_LENGTH: usize>(
+    frames: &[ParsedFrameVariant<INFO_LENGTH>],
+) -> Option<u32> {
+    frames.iter().find_map(|frame| match frame {
+        ParsedFrameVariant::FeaturesEcho(echo) => Some(u32::from(echo.flags)),
+        _ => None,
+    })
+}
+
+fn is_encoder_of(peer: SocketAddr) -> impl Fn(&BusAddress) -> bool {
+    move |addr| matches!(addr, BusAddress::FrameEncoder(_, sock_addr) if *sock_addr == peer)
 }
 
 fn build_sending_order<const INFO_LENGTH: usize>(
@@ -29,33 +588,57 @@ fn build_sending_order<const INFO_LENGTH: usize>(
         return None;
     };
     let mut orders = HashMap::new();
-    let mut sending_interval = None;
+    let sending_interval = requested_kbps(&packet.frames).map(|kbps| interval_for_rate(kbps, MTU));
+    let mut stops = vec![];
     for frame in packet.frames {
-        match frame {
-            ParsedFrameVariant::GetChunk(header) => {
-                let chunk_id: u32 = header.chunk_id.into();
-                let next_recieve: u32 = header.next_receive_offset.into();
-                let receive_window: u32 = header.receive_window_frames.into();
-
-                let order = SendingOrder {
-                    chunk_id,
-                    sending_interval,
-                    time_stamp: Instant::now(),
-                    offset_next: next_recieve,
-                    offset_no_more_than: next_recieve + receive_window,
-                    close_now: receive_window == 0,
-                };
-                orders.insert(BusAddress::FrameEncoder(chunk_id, socket_addr), order);
+        let (chunk_id, next_recieve, receive_window, generation) = match frame {
+            ParsedFrameVariant::GetChunk(header) => (
+                ChunkId::from(header.chunk_id),
+                u64::from(u32::from(header.next_receive_offset)),
+                u64::from(u32::from(header.receive_window_frames)),
+                u64::from(header.generation),
+            ),
+            ParsedFrameVariant::WideGetChunk(header) => (
+                ChunkId::from(header.chunk_id),
+                u64::from(header.next_receive_offset),
+                u64::from(header.receive_window_frames),
+                u64::from(header.generation),
+            ),
+            ParsedFrameVariant::StopChunk(header) => {
+                stops.push((ChunkId::from(header.chunk_id), header.reason()));
+                continue;
             }
-            ParsedFrameVariant::RateLimit(header) => {
-                let rate_limit = u32::from(header.desired_max_kbps);
-                sending_interval = Duration::from_millis(8)
-                    .mul_f32((MTU + 20) as f32)
-                    .div_f64(rate_limit as f64)
-                    .into();
-            }
-            _ => {}
-        }
+            _ => continue,
+        };
+        // Encoders count frames in u32 serial arithmetic, see `window`, so wide
+        // offsets wrap and a window stays under half the offset space.
+        let next_recieve = FrameOffset::from_wide(next_recieve);
+        let order = SendingOrder {
+            chunk_id,
+            sending_interval,
+            time_stamp: Instant::now(),
+            offset_next: next_recieve,
+            offset_no_more_than: next_recieve.advance(receive_window),
+            close_now: receive_window == 0,
+            generation,
+        };
+        orders.insert(BusAddress::FrameEncoder(chunk_id, socket_addr), order);
+    }
+    // A stop wins over the GetChunk frame of the same chunk.
+    for (chunk_id, reason) in stops {
+        eprintln!("{socket_addr} stopped chunk {chunk_id}: {reason:?}");
+        orders
+            .entry(BusAddress::FrameEncoder(chunk_id, socket_addr))
+            .and_modify(|order| order.close_now = true)
+            .or_insert_with(|| SendingOrder {
+                chunk_id,
+                sending_interval,
+                time_stamp: Instant::now(),
+                offset_next: FrameOffset::default(),
+                offset_no_more_than: FrameOffset::default(),
+                close_now: true,
+                generation: 0,
+            });
     }
 
     orders.into()
@@ -67,41 +650,772 @@ impl<S: UdpSocketLike, const INFO_LENGTH: usize> SendingSocket<S, INFO_LENGTH> {
         bus_interface: BusInterface<BusAddress, BusMessage<INFO_LENGTH>>,
     ) -> Self {
         Self {
-            socket,
+            socket: Arc::new(socket),
             bus_interface,
+            cookies: CookieChecker::new(),
+            load: LoadMonitor::new(UNDER_LOAD_TICKETS_PER_SEC),
+            rates: HashMap::new(),
+            backoff: HashMap::new(),
+            departures: None,
+            workers: HashMap::new(),
+            unreachable: flume::unbounded(),
+            quotas: None,
+            quota_saved: Instant::now(),
+            quota_keys: HashMap::new(),
+            warmed: HashMap::new(),
+            clock: system_clock(),
+            control: None,
+            encoders: EncoderLru::default(),
+            admission: None,
+            journal: None,
+            journal_saved: Instant::now(),
+            resumed: HashMap::new(),
+            applied: AppliedTickets::default(),
+            clocks: TicketClocks::new(DEFAULT_MAX_CLOCK_SKEW),
+            cover_interval: None,
+            repair: RepairStrategy::Stream,
+            interleave: false,
+            tokens: None,
+            offer: None,
+            link_kbps: None,
+            demands: HashMap::new(),
+            losses: HashMap::new(),
+        }
+    }
+
+    // Also handed to every encoder spawned from here.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.quota_saved = clock.now();
+        self.journal_saved = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    // Peers are reported to `control`, whose rate caps what every peer asks for.
+    pub fn with_control(mut self, control: Arc<ControlState>) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    // Pads every data packet to the MTU and keeps sending each peer packets at
+    // `kbps`, cover packets when it has no data queued, so on-path observers
+    // learn little about chunk sizes and progress. Peers get no more than
+    // `kbps` of data, whatever rate they ask for.
+    pub fn with_cover_traffic(mut self, kbps: u32) -> Self {
+        self.cover_interval = Some(interval_for_kbps(kbps.max(1)));
+        self
+    }
+
+    pub fn with_repair(mut self, repair: RepairStrategy) -> Self {
+        self.repair = repair;
+        self
+    }
+
+    // Sends the chunks streaming to a peer frame by frame in turn, evenly
+    // spaced, instead of as their encoders release them. Smooths the decoder's
+    // load, and chunks complete one after the other rather than in bursts.
+    // Cover traffic, pacing on its own, takes precedence.
+    pub fn with_interleaving(mut self, enabled: bool) -> Self {
+        self.interleave = enabled;
+        self
+    }
+
+    // At most `max_encoders` encoders run at once, across all peers.
+    pub fn with_max_encoders(mut self, max_encoders: usize) -> Self {
+        self.encoders.capacity = Some(max_encoders);
+        self
+    }
+
+    // Closes encoders until one more fits. Ones that already exited are just forgotten.
+    async fn make_room_for_encoder(&mut self) {
+        for addr in self.encoders.evict_for_one() {
+            let BusAddress::FrameEncoder(chunk_id, peer) = addr else {
+                continue;
+            };
+            let order = SendingOrder {
+                chunk_id,
+                sending_interval: None,
+                time_stamp: self.clock.now(),
+                offset_next: FrameOffset::default(),
+                offset_no_more_than: FrameOffset::default(),
+                close_now: true,
+                generation: 0,
+            };
+            if self.bus_interface.send(addr, order).await.is_ok() {
+                eprintln!("Evicted encoder for chunk {chunk_id} of {peer}, too many running");
+            }
+        }
+    }
+
+    // Peers beyond `limits` get a Busy frame and wait in line.
+    pub fn with_admission(mut self, limits: AdmissionLimits) -> Self {
+        self.admission = Some(AdmissionQueue::new(limits));
+        self
+    }
+
+    // True if the peer has to wait, in which case it has been told so.
+    async fn must_wait<const N: usize>(
+        &mut self,
+        peer: SocketAddr,
+        frames: &[ParsedFrameVariant<N>],
+    ) -> bool {
+        let Some(admission) = self.admission.as_mut() else {
+            return false;
+        };
+        let kbps = requested_kbps(frames)
+            .unwrap_or_default()
+            .try_into()
+            .unwrap_or(u32::MAX);
+        let admission = admission.admit(peer, kbps, self.clock.now());
+        if let Some(control) = self.control.as_ref() {
+            control.set_waiting(self.admission.as_ref().unwrap().waiting());
         }
+        let Admission::Queued {
+            position,
+            retry_after,
+        } = admission
+        else {
+            return false;
+        };
+        let busy = BusyFrame::new(retry_after.as_millis() as u32, position as u32);
+        self.send_control(peer, ControlPacket::new().push(busy))
+            .await;
+        true
+    }
+
+    // Sessions are journaled as tickets come in, and those found in the journal
+    // are resumed when `run` starts.
+    pub fn with_journal(mut self, journal: SessionJournal) -> Self {
+        self.journal = Some(journal);
+        self
     }
 
-    pub async fn run<FS>(mut self)
+    // Takes the rate, backoff and admission of every journaled session back up,
+    // and warms the encoders of the chunks they were fetching.
+    fn resume_sessions<FS>(&mut self, prepared: &PreparedEncoders<FS>)
     where
         FS: FrameSender<INFO_LENGTH> + Send + 'static,
     {
-        let mut buffer = [0u8; 65537];
+        let Some(journal) = self.journal.as_ref() else {
+            return;
+        };
+        let now = self.clock.now();
+        let sessions: Vec<_> = journal.sessions().cloned().collect();
+        for session in sessions {
+            if let Some(kbps) = session.rate_kbps {
+                self.rates.insert(session.addr, interval_for_kbps(kbps));
+            }
+            self.backoff.insert(session.addr, session.backoff);
+            if let Some(admission) = self.admission.as_mut() {
+                admission.resume(session.addr, session.rate_kbps.unwrap_or_default(), now);
+            }
+            self.resumed.insert(session.addr, now + RESUME_GRACE);
+            for chunk in session.chunks.iter() {
+                self.warm::<FS, INFO_LENGTH>(chunk.chunk_id, prepared);
+            }
+            eprintln!(
+                "Resumed the session of {}, {} chunks in progress",
+                session.addr,
+                session.chunks.len()
+            );
+        }
+    }
+
+    fn save_journal(&mut self) {
+        let Some(journal) = self.journal.as_mut() else {
+            return;
+        };
+        if self.journal_saved.elapsed() >= Duration::from_secs(5) {
+            if let Err(err) = journal.save() {
+                eprintln!("Failed to save the session journal: {err}");
+            }
+            self.journal_saved = self.clock.now();
+        }
+    }
+
+    pub fn with_quotas(mut self, quotas: QuotaBook) -> Self {
+        self.quotas = Some(quotas);
+        self
+    }
+
+    // Lets in clients presenting a download token from this checker's issuer,
+    // with the token's byte limit as their quota.
+    pub fn with_tokens(mut self, tokens: TokenChecker) -> Self {
+        self.tokens = Some(tokens);
+        self.quotas.get_or_insert_with(QuotaBook::default);
+        self
+    }
+
+    // Bytes served since the previous ticket are charged to the key of the new one.
+    fn charge_quota(&mut self, pub_key: &str, bytes_served: u64) -> bool {
+        let Some(quotas) = self.quotas.as_mut() else {
+            return false;
+        };
+        quotas.charge(pub_key, bytes_served);
+        self.save_quotas(false);
+        self.quotas
+            .as_mut()
+            .is_some_and(|quotas| quotas.exceeded(pub_key))
+    }
+
+    // Bytes served since the peer's last ticket, which no ticket will charge anymore.
+    fn settle_quota(&mut self, addr: SocketAddr) {
+        let Some(pub_key) = self.quota_keys.remove(&addr) else {
+            return;
+        };
+        let bytes_served = self
+            .workers
+            .get(&addr)
+            .map_or(0, |worker| worker.served.bytes.swap(0, Ordering::Relaxed));
+        self.charge_quota(&pub_key, bytes_served);
+    }
+
+    fn save_quotas(&mut self, now: bool) {
+        let Some(quotas) = self.quotas.as_mut() else {
+            return;
+        };
+        if now || self.quota_saved.elapsed() >= Duration::from_secs(5) {
+            if let Err(err) = quotas.save() {
+                eprintln!("Failed to save quota usage: {err}");
+            }
+            self.quota_saved = self.clock.now();
+        }
+    }
+
+    // Peers asking for more than `link_kbps` together get shares of it by the
+    // weight of their priority, see `weighted_shares`, and no more.
+    pub fn with_link_rate(mut self, link_kbps: u64) -> Self {
+        self.link_kbps = Some(link_kbps);
+        self
+    }
+
+    // The interval that keeps `peer` within its share of the link, None if the
+    // link is not shared. Other peers get their new shares with their next tickets.
+    fn fair_interval<const N: usize>(
+        &mut self,
+        peer: SocketAddr,
+        frames: &[ParsedFrameVariant<N>],
+    ) -> Option<Duration> {
+        let link_kbps = self.link_kbps?;
+        let now = self.clock.now();
+        let asked = requested_kbps(frames).unwrap_or(u64::MAX);
+        self.demands.insert(peer, (asked, priority_of(frames), now));
+        self.demands
+            .retain(|_, (_, _, seen)| now - *seen < DEMAND_IDLE);
+        let (peers, demands): (Vec<SocketAddr>, Vec<(u64, u32)>) = self
+            .demands
+            .iter()
+            .map(|(peer, (asked, priority, _))| (*peer, (*asked, priority.weight())))
+            .unzip();
+        let shares = weighted_shares(link_kbps, &demands);
+        let index = peers.iter().position(|other| *other == peer)?;
+        Some(interval_for_rate(shares[index], MTU))
+    }
+
+    // Adapts the repair of the peer's encoders to the loss its ticket reports,
+    // with adaptive repair only.
+    async fn adapt_repair<const N: usize>(
+        &mut self,
+        peer: SocketAddr,
+        frames: &[ParsedFrameVariant<N>],
+    ) {
+        if self.repair != RepairStrategy::Adaptive {
+            return;
+        }
+        let sent = self
+            .workers
+            .get(&peer)
+            .map_or(0, |worker| worker.served.packets.swap(0, Ordering::Relaxed));
+        let Some(received) = frames.iter().find_map(|frame| match frame {
+            ParsedFrameVariant::Congestion(header) => Some(u32::from(header.total_packets)),
+            _ => None,
+        }) else {
+            return;
+        };
+        let estimate = self.losses.entry(peer).or_default();
+        estimate.observe(sent, received.into());
+        let overhead_percent = estimate.overhead_percent();
+        if estimate.announced.replace(overhead_percent) != Some(overhead_percent) {
+            self.bus_interface
+                .broadcast(
+                    is_encoder_of(peer),
+                    PeerEvent::RepairChanged(overhead_percent),
+                )
+                .await;
+        }
+    }
+
+    // What new encoders for `peer` start with.
+    fn repair_for(&self, peer: SocketAddr) -> RepairStrategy {
+        match self.repair {
+            RepairStrategy::Adaptive => RepairStrategy::Proactive {
+                overhead_percent: self
+                    .losses
+                    .get(&peer)
+                    .map_or(ADAPTIVE_START_PERCENT, LossEstimate::overhead_percent),
+            },
+            repair => repair,
+        }
+    }
+
+    // Tickets stamped further than `max_skew` from our clock are dropped.
+    pub fn with_max_clock_skew(mut self, max_skew: Duration) -> Self {
+        self.clocks = TicketClocks::new(max_skew);
+        self
+    }
+
+    // Only enable when the socket stamps departure times (SO_TXTIME), otherwise
+    // frames still leave as soon as the sender timers release them.
+    pub fn with_kernel_pacing(mut self, enabled: bool) -> Self {
+        self.departures = enabled.then(HashMap::new);
+        self
+    }
+
+    // Spreads the frames an encoder released in one timer tick over the tick,
+    // one interval apart.
+    fn departure_delay(&mut self, addr: SocketAddr, chunk_id: ChunkId) -> Duration {
+        if self.interleave {
+            return Duration::ZERO;
+        }
+        let Some(departures) = self.departures.as_mut() else {
+            return Duration::ZERO;
+        };
+        let Some(interval) = self.rates.get(&addr).copied() else {
+            return Duration::ZERO;
+        };
+        let now = self.clock.now();
+        if departures.len() > 4096 {
+            departures.retain(|_, next| *next + Duration::from_secs(1) > now);
+        }
+        let next = departures.entry((addr, chunk_id)).or_insert(now);
+        let departure = (*next).max(now);
+        *next = departure + interval;
+        departure - now
+    }
+
+    fn warm<FS, const N: usize>(&mut self, chunk_id: ChunkId, prepared: &PreparedEncoders<FS>)
+    where
+        FS: FrameSender<N> + Send + 'static,
+    {
+        const WARM_AGAIN_AFTER: Duration = Duration::from_secs(30);
+        let now = self.clock.now();
+        if self.warmed.len() > 1024 {
+            self.warmed.retain(|_, at| now - *at < WARM_AGAIN_AFTER);
+        }
+        if self
+            .warmed
+            .get(&chunk_id)
+            .is_none_or(|at| now - *at >= WARM_AGAIN_AFTER)
+        {
+            self.warmed.insert(chunk_id, now);
+            prepared.prepare::<N>(chunk_id, &self.clock);
+        }
+    }
+
+    async fn send_control(&self, addr: SocketAddr, packet: ControlPacket) {
+        let (packet, _) = match PacketIds::existing(addr) {
+            Some(ids) => packet.build_in(&ids),
+            None => packet.build(),
+        };
+        if let Err(err) = self.socket.send_to(packet.as_slice(), addr).await {
+            eprintln!("Failed to send control packet to {addr}: {err}");
+        }
+    }
+
+    // Hands the packet to the peer's worker, starting one if it has none or it left.
+    fn send_data(&mut self, addr: SocketAddr, packet: BuiltDataPacket, delay: Duration)
+    where
+        S: 'static,
+    {
+        let mut packet = (packet, delay);
+        let interval_ns = self
+            .rates
+            .get(&addr)
+            .map_or(0, |interval| interval.as_nanos() as u64);
+        if let Some(worker) = self.workers.get(&addr) {
+            worker.interval_ns.store(interval_ns, Ordering::Relaxed);
+            match worker.packets.send(packet) {
+                Ok(()) => return,
+                Err(flume::SendError(returned)) => packet = returned,
+            }
+        }
+        let (sender, receiver) = flume::unbounded();
+        let served = self
+            .workers
+            .remove(&addr)
+            .map(|worker| worker.served)
+            .unwrap_or_default();
+        sender.send(packet).ok();
+        let interval_ns = Arc::new(AtomicU64::new(interval_ns));
+        tokio::spawn(accounted(
+            Subsystem::Socket,
+            send_to_peer(
+                self.socket.clone(),
+                addr,
+                receiver,
+                served.clone(),
+                self.unreachable.0.clone(),
+                self.cover_interval.map(CoverTraffic::new),
+                self.interleave
+                    .then(|| Interleaver::new(interval_ns.clone())),
+            ),
+        ));
+        self.workers.insert(
+            addr,
+            PeerWorker {
+                packets: sender,
+                served,
+                interval_ns,
+            },
+        );
+    }
+
+    async fn forget_peer(&mut self, addr: SocketAddr) {
+        self.settle_quota(addr);
+        self.rates.remove(&addr);
+        self.backoff.remove(&addr);
+        self.workers.remove(&addr);
+        self.resumed.remove(&addr);
+        self.applied.forget(addr);
+        self.clocks.forget(addr);
+        self.demands.remove(&addr);
+        self.losses.remove(&addr);
+        PacketIds::forget(addr);
+        if let Some(journal) = self.journal.as_mut() {
+            journal.forget(addr);
+        }
+        if let Some(control) = self.control.as_ref() {
+            control.forget_peer(addr);
+        }
+        self.encoders.remove_peer(addr);
+        if let Some(admission) = self.admission.as_mut() {
+            admission.remove(addr);
+        }
+        if let Some(departures) = self.departures.as_mut() {
+            departures.retain(|(peer, _), _| *peer != addr);
+        }
+        let notified = self
+            .bus_interface
+            .broadcast(is_encoder_of(addr), PeerEvent::Disconnected)
+            .await;
+        eprintln!("Peer {addr} disconnected, {notified} encoders stopped");
+    }
+
+    // Ends only on a socket failing for good, also reported to the bus's supervisor.
+    pub async fn run<FS>(mut self) -> Result<(), SocketFailure>
+    where
+        S: 'static,
+        FS: FrameSender<INFO_LENGTH> + Send + 'static,
+    {
+        accounted(Subsystem::Socket, self.serve::<FS>()).await
+    }
+
+    // Uploads: offers the file to `target` until it sends tickets, then serves
+    // it the chunks it asks for until its tickets stop. Gives up if no ticket
+    // came within `max_wait`.
+    pub async fn push<FS>(
+        mut self,
+        target: SocketAddr,
+        file_id: u64,
+        max_wait: Duration,
+    ) -> Result<(), SessionError>
+    where
+        S: 'static,
+        FS: FrameSender<INFO_LENGTH> + Send + 'static,
+    {
+        self.offer = Some(Offer {
+            target,
+            file_id,
+            max_wait,
+            last_ticket: self.clock.now(),
+            accepted: false,
+        });
+        accounted(Subsystem::Socket, self.serve::<FS>()).await?;
+        match self.offer.as_ref() {
+            Some(offer) if offer.accepted => Ok(()),
+            _ => Err(ServerUnreachable {
+                waited: max_wait,
+                rejected: false,
+            }
+            .into()),
+        }
+    }
+
+    // Makes the offer again until the target sends a ticket. False once the upload is over.
+    async fn offer_again(&mut self) -> bool {
+        let Some(offer) = self.offer.as_ref() else {
+            return true;
+        };
+        let idle = self.clock.now() - offer.last_ticket;
+        if offer.accepted {
+            return idle < UPLOAD_IDLE;
+        }
+        if idle >= offer.max_wait {
+            return false;
+        }
+        let (packet, _) = TicketPacket::new()
+            .set_upload(offer.file_id)
+            .set_timestamp(self.clock.unix_ms())
+            .build_in(&PacketIds::for_peer(offer.target));
+        if let Err(err) = self.socket.send_to(packet.as_slice(), offer.target).await {
+            eprintln!("Failed to offer the upload to {}: {err}", offer.target);
+        }
+        true
+    }
+
+    async fn serve<FS>(&mut self) -> Result<(), SocketFailure>
+    where
+        S: 'static,
+        FS: FrameSender<INFO_LENGTH> + Send + 'static,
+    {
+        let mut buffer = Arc::new(BufferPool::default()).buffer();
+        let mut errors = SocketErrors::default();
+        let prepared = Arc::new(PreparedEncoders::<FS>::default());
+        self.resume_sessions(&prepared);
+        let mut offer_ticks = tokio::time::interval(OFFER_PERIOD);
         loop {
             tokio::select! {
-                Ok((length, sock_addr)) = self.socket.recv_from(&mut buffer) => {
-                    let packet = Bytes::from(Vec::from(&buffer[0..length]));
-                    if let Some(parsed_packet) = parse_packet::<INFO_LENGTH>(packet)
-                        .inspect_err(|err| {dbg!(err);})
-                        .ok().map(
-                        |parsed_packet| build_sending_order(parsed_packet, sock_addr).into_iter().flatten()
+                _ = offer_ticks.tick(), if self.offer.is_some() => {
+                    if !self.offer_again().await {
+                        break;
+                    }
+                },
+
+                received = self.socket.recv_pooled(&mut buffer) => {
+                    let (packet, sock_addr) = match received {
+                        Ok((packet, sock_addr, _)) => {
+                            errors.succeeded();
+                            (packet, sock_addr)
+                        }
+                        // Closed on purpose, e.g. by the other end of a mock socket.
+                        Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                        Err(err) => match errors.failed("receiving", err, self.clock.now()) {
+                            Ok(_) => {
+                                tokio::time::sleep(RETRY_DELAY).await;
+                                continue;
+                            }
+                            Err(failure) => {
+                                eprintln!("Sending socket stopped: {failure}.");
+                                self.bus_interface.get_bus().supervisor().fail(BusAddress::SenderSocket, failure.to_string());
+                                return Err(failure);
+                            }
+                        },
+                    };
+                    let resumed = self.resumed.get(&sock_addr).is_some_and(|until| self.clock.now() < *until);
+                    let under_load = self.load.under_load() && !resumed;
+                    let cookies = &mut self.cookies;
+                    let tokens = self.tokens.as_ref();
+                    let mut granted = None;
+                    let now_ms = self.clock.unix_ms();
+                    let parsed_packet = parse_packet_with_precheck::<INFO_LENGTH>(packet, |header, frames| {
+                        if under_load {
+                            require_cookie(cookies, sock_addr, header, frames)?;
+                        }
+                        granted = tokens.and_then(|tokens| tokens.admit(header, frames, now_ms));
+                        Ok(())
+                    });
+                    if let Ok(packet) = &parsed_packet {
+                        let session_id = PacketIds::existing(sock_addr).map_or(SessionId::default(), |ids| ids.session_id());
+                        packet_log(session_id, packet.get_common_packet_header().packet_id(), 0x19260817);
+                    }
+                    if parsed_packet.is_ok() && let (Some((pub_key, Some(max_bytes))), Some(quotas)) = (granted, self.quotas.as_mut()) {
+                        quotas.set_limit(&pub_key, Quota { daily: None, total: Some(max_bytes) });
+                    }
+
+                    let mut over_quota = false;
+                    let mut waiting = false;
+                    let mut ticket_key = None;
+                    let mut fair = None;
+                    match &parsed_packet {
+                        Err(ParseError::CookieRequired(timestamp_ms)) => {
+                            let cookie = self.cookies.make_cookie(sock_addr);
+                            let (packet, _) = CookieReplyPacket::new(cookie, *timestamp_ms).build();
+                            if let Err(err) = self.socket.send_to(packet.as_slice(), sock_addr).await {
+                                eprintln!("Failed to send a cookie to {sock_addr}: {err}");
+                            }
+                        }
+                        Ok(packet @ ParsedPacket { specific_packet_header: ParsedPacketVariant::TicketPacket { pub_key, timestamp_ms }, .. }) => {
+                            self.load.record();
+                            let packet_id = packet.get_common_packet_header().packet_id();
+                            let greeted = self.applied.knows(sock_addr);
+                            let now_ms = self.clock.unix_ms();
+                            if !self.clocks.fresh(sock_addr, *timestamp_ms, now_ms) {
+                                continue;
+                            }
+                            if !self.applied.apply(sock_addr, *timestamp_ms, packet_id) {
+                                continue;
+                            }
+                            // A peer echoing other features than ours lost the greeting, or had
+                            // it altered on the way, and is greeted again. Tickets are signed,
+                            // so the echo itself can not be.
+                            let downgraded = greeted && heard_features(&packet.frames).is_some_and(|flags| flags != SUPPORTED_FEATURES);
+                            if downgraded {
+                                eprintln!("{sock_addr} did not hear our features, greeting it again.");
+                            }
+                            if !greeted || downgraded {
+                                let greeting = ControlPacket::new()
+                                    .push(FeaturesFrame::new(SUPPORTED_FEATURES))
+                                    .push(ClockFrame::new(*timestamp_ms, now_ms));
+                                self.send_control(sock_addr, greeting).await;
+                            }
+                            if let Some(offer) = self.offer.as_mut() && offer.target == sock_addr {
+                                offer.accepted = true;
+                                offer.last_ticket = self.clock.now();
+                            }
+                            let key = hex::encode(pub_key);
+                            ticket_key = Some(key.clone());
+                            let bytes_served = self.workers.get(&sock_addr).map_or(0, |worker| worker.served.bytes.swap(0, Ordering::Relaxed));
+                            audit_ticket(packet, sock_addr, bytes_served);
+                            if let Some(control) = self.control.as_ref() {
+                                control.record_peer(sock_addr, bytes_served, self.rates.get(&sock_addr).copied().map(kbps_for_interval));
+                                control.record_marking(sock_addr, self.socket.flow_label(sock_addr), self.socket.traffic_class());
+                            }
+                            self.adapt_repair(sock_addr, &packet.frames).await;
+                            over_quota = self.charge_quota(&key, bytes_served);
+                            self.quota_keys.insert(sock_addr, key);
+                            waiting = !over_quota && self.must_wait(sock_addr, &packet.frames).await;
+                            if !over_quota && !waiting {
+                                fair = self.fair_interval(sock_addr, &packet.frames);
+                                for frame in packet.frames.iter() {
+                                    if let ParsedFrameVariant::Prefetch(header) = frame {
+                                        self.warm::<FS, INFO_LENGTH>(ChunkId::from(header.chunk_id), &prepared);
+                                    }
+                                }
+                            }
+                        }
+                        Err(ParseError::Verification(_)) => {
+                            self.load.record();
+                            // Lets a client with several identities move on to the next one.
+                            // Skipped under load, so floods of bad tickets are not answered.
+                            if !under_load {
+                                self.send_control(sock_addr, ControlPacket::new().push(ErrorFrame::new(ChunkId(0), ErrorReason::AuthFailed))).await;
+                            }
+                        }
+                        _ => {}
+                    }
+
+                    // Only peers of signed tickets get an entry, control packets are merely checksummed.
+                    let backoff = match &parsed_packet {
+                        Ok(packet) if ticket_key.is_some() => {
+                            let backoff = self.backoff.entry(sock_addr).or_insert(1.0);
+                            update_backoff(backoff, &packet.frames);
+                            *backoff
+                        }
+                        _ => 1.0,
+                    };
+
+                    if let Some(mut orders) = parsed_packet
+                        .ok().and_then(
+                        |parsed_packet| build_sending_order(parsed_packet, sock_addr)
                     ){
-                        for (addr, order) in parsed_packet.into_iter(){
+                        if waiting {
+                            continue;
+                        }
+                        // Only the closes the ticket asked for, not those of pauses or quotas.
+                        let acks = orders
+                            .values()
+                            .filter(|order| order.close_now)
+                            .fold(ControlPacket::new(), |acks, order| acks.push(StopAckFrame::new(order.chunk_id)));
+                        if !acks.is_empty() {
+                            self.send_control(sock_addr, acks).await;
+                        }
+                        if self.control.as_ref().is_some_and(|control| control.is_paused()) {
+                            // Encoders are closed until resumed, the client keeps its offsets.
+                            for (addr, mut order) in orders.into_iter() {
+                                order.close_now = true;
+                                self.encoders.remove(&addr);
+                                self.bus_interface.send(addr, order).await.ok();
+                            }
+                            continue;
+                        }
+                        if over_quota {
+                            // Running encoders are closed, no new ones are started.
+                            for (addr, mut order) in orders.into_iter() {
+                                order.close_now = true;
+                                let chunk_id = order.chunk_id;
+                                self.encoders.remove(&addr);
+                                self.bus_interface.send(addr, order).await.ok();
+                                self.send_control(sock_addr, ControlPacket::new().push(ErrorFrame::new(chunk_id, ErrorReason::QuotaExceeded))).await;
+                            }
+                            continue;
+                        }
+                        // Bytes of another file version would fail the peer's hashes. The
+                        // plan is served in any epoch, followers learn of the next from it.
+                        let epoch = CHUNK_INDEX.get().map(|index| index.epoch());
+                        let stale: Vec<BusAddress> = orders
+                            .iter()
+                            .filter(|(_, order)| !order.close_now && order.chunk_id != PLAN_CHUNK_ID && epoch.is_some_and(|epoch| epoch != order.generation))
+                            .map(|(addr, _)| addr.clone())
+                            .collect();
+                        for addr in stale {
+                            let Some(mut order) = orders.remove(&addr) else { continue };
+                            order.close_now = true;
+                            let chunk_id = order.chunk_id;
+                            self.encoders.remove(&addr);
+                            self.bus_interface.send(addr, order).await.ok();
+                            self.send_control(sock_addr, ControlPacket::new().push(ErrorFrame::new(chunk_id, ErrorReason::StaleGeneration))).await;
+                        }
+                        let slowest = self.control.as_ref().and_then(|control| control.rate_kbps()).map(interval_for_kbps).max(fair);
+                        for order in orders.values_mut() {
+                            order.sending_interval = match (order.sending_interval.map(|interval| interval.mul_f64(backoff)), slowest) {
+                                (Some(interval), Some(slowest)) => Some(interval.max(slowest)),
+                                (interval, slowest) => interval.or(slowest),
+                            };
+                        }
+                        if let Some(interval) = orders.values().find_map(|order| order.sending_interval)
+                            && self.rates.insert(sock_addr, interval) != Some(interval)
+                        {
+                            let notified = self.bus_interface.broadcast(is_encoder_of(sock_addr), PeerEvent::RateChanged(interval)).await;
+                            eprintln!("Rate of {sock_addr} changed to one frame per {interval:?}, {notified} encoders notified");
+                        }
+
+                        if let (Some(journal), Some(pub_key)) = (self.journal.as_mut(), ticket_key) {
+                            let rate_kbps = self.rates.get(&sock_addr).copied().map(kbps_for_interval);
+                            journal.record_ticket(sock_addr, &pub_key, rate_kbps, backoff, orders.values().map(|order| (order.chunk_id, order.offset_next.0, order.close_now)));
+                            self.save_journal();
+                        }
+
+                        for (addr, order) in orders.into_iter(){
+                            let now = self.clock.now();
+                            match order.close_now {
+                                true => self.encoders.remove(&addr),
+                                false => self.encoders.touch(&addr, now),
+                            }
                             if let Err(order) = self.bus_interface.send(addr.clone(), order).await{
                                 let start_order = order.unwrap();
                                 if start_order.close_now {continue;}
+                                self.encoders.remove(&addr);
+                                self.make_room_for_encoder().await;
                                 eprintln!("Init encoder for chunk {:?}, addr {:?}", start_order.chunk_id, &addr);
                                 let bus = self.bus_interface.get_bus();
-                                super::encoding::spawn::<FS, INFO_LENGTH>(start_order, bus, sock_addr, addr).await;
+                                let chunk_id = start_order.chunk_id;
+                                if let Err(reason) = super::encoding::spawn::<FS, INFO_LENGTH>(start_order, bus, sock_addr, addr.clone(), prepared.clone(), self.clock.clone(), self.cover_interval.map(|_| MTU), self.repair_for(sock_addr)).await {
+                                    eprintln!("Refuse chunk {chunk_id} for {sock_addr}: {reason:?}");
+                                    self.send_control(sock_addr, ControlPacket::new().push(ErrorFrame::new(chunk_id, reason))).await;
+                                    continue;
+                                }
+                                self.encoders.insert(addr, now);
+                                // Clients without prefetch hints most likely want the next chunk in plan order.
+                                self.warm::<FS, INFO_LENGTH>(ChunkId(chunk_id.0.wrapping_add(1)), &prepared);
                             }
                         }
                     }
                 },
 
-                Some((addr, frame)) = self.bus_interface.recv::<(SocketAddr, DataFrame<INFO_LENGTH>)>() => {
-                    let (packet, packet_id) = DataPacket::from(frame).build();
-                    self.socket.send_to(packet.as_slice(), addr).await.ok();
-                    packet_log(packet_id, 0x20250819);
+                Some(message) = self.bus_interface.recv::<BusMessage<INFO_LENGTH>>() => {
+                    let (addr, packet) = match message {
+                        BusMessage::SendingData(data) => data,
+                        BusMessage::SendingControl((addr, frame)) => {
+                            self.send_control(addr, ControlPacket::new().push(frame)).await;
+                            continue;
+                        }
+                        _ => continue,
+                    };
+                    while let Ok(unreachable) = self.unreachable.1.try_recv() {
+                        self.forget_peer(unreachable).await;
+                    }
+                    let delay = self.departure_delay(addr, packet.chunk_id);
+                    self.send_data(addr, packet, delay);
                 },
 
                 else => {
@@ -109,5 +1423,463 @@ impl<S: UdpSocketLike, const INFO_LENGTH: usize> SendingSocket<S, INFO_LENGTH> {
                 }
             }
         }
+        Ok(())
+    }
+}
+
+// Usage charged since the last save would be lost with the server, e.g. on Ctrl-C.
+impl<S: UdpSocketLike, const INFO_LENGTH: usize> Drop for SendingSocket<S, INFO_LENGTH> {
+    fn drop(&mut self) {
+        let peers: Vec<_> = self.quota_keys.keys().copied().collect();
+        for addr in peers {
+            self.settle_quota(addr);
+        }
+        self.save_quotas(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::wire::frames::DataFrame;
+    use crate::util::log::current_timestamp_ms;
+    use bytes::Bytes;
+
+    #[test]
+    fn evict_least_recently_ordered_encoders() {
+        let peer: SocketAddr = "10.0.0.2:7000".parse().unwrap();
+        let other: SocketAddr = "10.0.0.3:7000".parse().unwrap();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut encoders = EncoderLru {
+            capacity: Some(3),
+            ..Default::default()
+        };
+        encoders.insert(BusAddress::FrameEncoder(ChunkId(1), peer), at(0));
+        encoders.insert(BusAddress::FrameEncoder(ChunkId(2), peer), at(1));
+        encoders.insert(BusAddress::FrameEncoder(ChunkId(1), other), at(2));
+        assert!(encoders.evict_for_one().len() == 1);
+        assert!(encoders.last_ordered.len() == 2);
+
+        encoders.insert(BusAddress::FrameEncoder(ChunkId(1), peer), at(3));
+        encoders.touch(&BusAddress::FrameEncoder(ChunkId(2), peer), at(4));
+        assert_eq!(
+            encoders.evict_for_one(),
+            vec![BusAddress::FrameEncoder(ChunkId(1), other)]
+        );
+
+        encoders.remove_peer(peer);
+        assert!(encoders.evict_for_one().is_empty());
+        assert!(encoders.last_ordered.is_empty());
+    }
+
+    #[test]
+    fn tickets_apply_once_and_in_order() {
+        let peer: SocketAddr = "10.0.0.2:7000".parse().unwrap();
+        let other: SocketAddr = "10.0.0.3:7000".parse().unwrap();
+        let mut applied = AppliedTickets::default();
+        assert!(!applied.knows(peer));
+        assert!(applied.apply(peer, 1000, 7));
+        assert!(applied.knows(peer));
+        assert!(!applied.apply(peer, 1000, 7));
+        // Another ticket of the same millisecond.
+        assert!(applied.apply(peer, 1000, 8));
+        assert!(!applied.apply(peer, 999, 9));
+        assert!(applied.apply(other, 999, 9));
+        applied.forget(peer);
+        assert!(!applied.knows(peer));
+        assert!(applied.apply(peer, 999, 9));
+    }
+
+    #[test]
+    fn tickets_are_taken_within_the_skew() {
+        let peer: SocketAddr = "10.0.0.2:7000".parse().unwrap();
+        let mut clocks = TicketClocks::new(Duration::from_secs(600));
+        let now_ms = 1_700_000_000_000;
+        // Five minutes of drift either way are fine, an hour is not.
+        assert!(clocks.fresh(peer, now_ms + 300_000, now_ms));
+        assert!(clocks.fresh(peer, now_ms - 300_000, now_ms));
+        assert!(!clocks.fresh(peer, now_ms - 3_600_000, now_ms));
+        assert!(!clocks.fresh(peer, now_ms + 3_600_000, now_ms));
+        // The earlier sample is the least delayed one, the later only creeps in.
+        assert_eq!(clocks.skews[&peer].0.offset_ms(), Some(-300_000 + 37_500));
+        clocks.forget(peer);
+        assert!(clocks.skews.is_empty());
+    }
+
+    #[tokio::test]
+    async fn duplicated_tickets_apply_once() {
+        use crate::transmission::mock::MockSocket;
+
+        crate::protocol::mock_init();
+        let client: SocketAddr = "127.0.0.1:10000".parse().unwrap();
+        let server: SocketAddr = "127.0.0.1:10001".parse().unwrap();
+        let (client_socket, server_socket) = MockSocket::pair(client, server);
+        let client_socket = client_socket.with_copies(2);
+        let (ticket, _) = TicketPacket::new()
+            .set_timestamp(1_700_000_000_000)
+            .set_get_chunk(ChunkId(3), 0, 100)
+            .build();
+        client_socket.send_to(&ticket, server).await.unwrap();
+
+        let mut applied = AppliedTickets::default();
+        let mut buf = vec![0u8; MTU];
+        let mut outcomes = vec![];
+        for _ in 0..2 {
+            let (length, _) = server_socket.recv_from(&mut buf).await.unwrap();
+            let packet = crate::protocol::wire::encoding::parse_packet::<12>(
+                Bytes::copy_from_slice(&buf[..length]),
+            )
+            .unwrap();
+            let ParsedPacketVariant::TicketPacket { timestamp_ms, .. } =
+                packet.specific_packet_header
+            else {
+                unreachable!()
+            };
+            let packet_id = packet.get_common_packet_header().packet_id();
+            outcomes.push(applied.apply(client, timestamp_ms, packet_id));
+        }
+        assert_eq!(outcomes, vec![true, false]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cover_traffic_fills_the_gaps() {
+        crate::protocol::mock_init();
+        let (sender, packets) = flume::unbounded();
+        let mut cover = CoverTraffic::new(Duration::from_millis(10));
+        let ids = PacketIds::for_peer("127.0.0.1:7234".parse().unwrap());
+        let data = |offset| {
+            let frame = DataFrame::new(ChunkId(4), offset, [0u8; 12], Bytes::from(vec![1u8; 100]));
+            (
+                BuiltDataPacket::new(frame, Some(MTU), &ids),
+                Duration::from_millis(3),
+            )
+        };
+
+        let start = Instant::now();
+        let mut sent = vec![];
+        sender.send(data(0)).unwrap();
+        sender.send(data(1)).unwrap();
+        for _ in 0..4 {
+            let (packet, delay) = cover.next(&packets, &ids).await.unwrap();
+            assert_eq!(packet.len(), MTU);
+            assert_eq!(delay, Duration::ZERO);
+            sent.push(packet.chunk_id);
+        }
+        assert_eq!(
+            sent,
+            vec![ChunkId(4), ChunkId(4), COVER_CHUNK_ID, COVER_CHUNK_ID]
+        );
+        assert_eq!(start.elapsed(), Duration::from_millis(30));
+
+        // Stops once the peer had no data for a while.
+        tokio::time::advance(PEER_WORKER_IDLE).await;
+        assert!(cover.next(&packets, &ids).await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn interleaving_takes_chunks_in_turn() {
+        crate::protocol::mock_init();
+        let (sender, packets) = flume::unbounded();
+        let interval_ns = Arc::new(AtomicU64::new(30_000_000));
+        let mut interleaver = Interleaver::new(interval_ns);
+        let ids = PacketIds::for_peer("127.0.0.1:7235".parse().unwrap());
+        for chunk_id in [1, 1, 1, 2, 2, 3] {
+            let frame =
+                DataFrame::new(ChunkId(chunk_id), 0, [0u8; 12], Bytes::from(vec![1u8; 100]));
+            let packet = BuiltDataPacket::new(frame, None, &ids);
+            sender.send((packet, Duration::from_millis(3))).unwrap();
+        }
+
+        let start = Instant::now();
+        let mut sent = vec![];
+        for _ in 0..6 {
+            let (packet, delay) = interleaver.next(&packets).await.unwrap();
+            assert_eq!(delay, Duration::ZERO);
+            sent.push((packet.chunk_id.0, start.elapsed().as_millis()));
+        }
+        // An interval split between the chunks still queued.
+        assert_eq!(
+            sent,
+            vec![(1, 0), (2, 10), (3, 20), (1, 30), (2, 45), (1, 60)]
+        );
+
+        tokio::time::advance(PEER_WORKER_IDLE).await;
+        assert!(interleaver.next(&packets).await.is_none());
+    }
+
+    #[test]
+    fn stop_chunk_closes_the_encoder() {
+        use crate::protocol::wire::encoding::parse_packet;
+        use crate::protocol::wire::frames::{FEATURE_STOP_CHUNK, StopReason};
+
+        crate::protocol::mock_init();
+        let peer: SocketAddr = "127.0.0.1:10000".parse().unwrap();
+        let (ticket, _) = TicketPacket::new()
+            .set_features(FEATURE_STOP_CHUNK)
+            .set_get_chunk(ChunkId(3), 0, 100)
+            .set_get_chunk(ChunkId(4), 0, 100)
+            .set_stop_chunk(ChunkId(3), StopReason::HashMismatch)
+            .set_stop_chunk(ChunkId(5), StopReason::Aborted)
+            .build();
+        let packet = parse_packet::<12>(Bytes::from(ticket.concat())).unwrap();
+        let orders = build_sending_order(packet, peer).unwrap();
+        let closed = |chunk_id| orders[&BusAddress::FrameEncoder(chunk_id, peer)].close_now;
+        assert!(closed(ChunkId(3)));
+        assert!(!closed(ChunkId(4)));
+        assert!(closed(ChunkId(5)));
+    }
+
+    #[tokio::test]
+    async fn sessions_ending_between_tickets_are_charged() -> std::io::Result<()> {
+        use crate::constants::TRANSMISSION_INFO_LENGTH;
+        use crate::engine::Bus;
+        use crate::protocol::quota::Quota;
+        use crate::transmission::mock::MockSocket;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("quota.toml");
+        let limits = HashMap::from([(
+            String::from("abcd"),
+            Quota {
+                daily: None,
+                total: Some(100),
+            },
+        )]);
+        let (client, server): (SocketAddr, SocketAddr) = (
+            "127.0.0.1:10000".parse().unwrap(),
+            "127.0.0.1:10001".parse().unwrap(),
+        );
+        let (_client_socket, server_socket) = MockSocket::pair(client, server);
+        let bus = Arc::new(Bus::default());
+        let mut sender = SendingSocket::<_, TRANSMISSION_INFO_LENGTH>::new(
+            server_socket,
+            bus.register(BusAddress::SenderSocket).unwrap(),
+        )
+        .with_quotas(QuotaBook::load(limits.clone(), Some(path.clone()))?);
+        let served = Arc::new(Served::default());
+        sender.workers.insert(
+            client,
+            PeerWorker {
+                packets: flume::unbounded().0,
+                served: served.clone(),
+                interval_ns: Arc::default(),
+            },
+        );
+        sender.quota_keys.insert(client, String::from("abcd"));
+
+        // Charged when the peer is forgotten, saved when the sender goes.
+        served.bytes.store(60, Ordering::Relaxed);
+        sender.forget_peer(client).await;
+        assert!(sender.quota_keys.is_empty());
+        drop(sender);
+        let mut book = QuotaBook::load(limits, Some(path))?;
+        assert!(!book.exceeded("abcd"));
+        book.charge("abcd", 40);
+        assert!(book.exceeded("abcd"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn closed_chunks_are_acknowledged() {
+        use crate::constants::TRANSMISSION_INFO_LENGTH;
+        use crate::engine::Bus;
+        use crate::protocol::coding::plain::PlainSender;
+        use crate::protocol::wire::encoding::parse_packet;
+        use crate::protocol::wire::frames::{FEATURE_STOP_CHUNK, StopReason};
+        use crate::transmission::mock::MockSocket;
+
+        crate::protocol::mock_init();
+        let (client, server): (SocketAddr, SocketAddr) = (
+            "127.0.0.1:10000".parse().unwrap(),
+            "127.0.0.1:10001".parse().unwrap(),
+        );
+        let (client_socket, server_socket) = MockSocket::pair(client, server);
+        let bus = Arc::new(Bus::default());
+        let sender = SendingSocket::<_, TRANSMISSION_INFO_LENGTH>::new(
+            server_socket,
+            bus.register(BusAddress::SenderSocket).unwrap(),
+        );
+        let task = tokio::spawn(sender.run::<PlainSender>());
+        let (ticket, _) = TicketPacket::new()
+            .set_timestamp(current_timestamp_ms())
+            .set_features(FEATURE_STOP_CHUNK)
+            .set_get_chunk(ChunkId(3), 0, 0)
+            .set_stop_chunk(ChunkId(5), StopReason::Aborted)
+            .build();
+        client_socket.send_to(&ticket, server).await.unwrap();
+
+        // After the greeting.
+        let mut acked = vec![];
+        let mut buf = vec![0u8; MTU];
+        while acked.is_empty() {
+            let (length, _) = client_socket.recv_from(&mut buf).await.unwrap();
+            let packet =
+                parse_packet::<TRANSMISSION_INFO_LENGTH>(Bytes::copy_from_slice(&buf[..length]))
+                    .unwrap();
+            for frame in packet.frames {
+                if let ParsedFrameVariant::StopAck(ack) = frame {
+                    acked.push(u32::from(ack.chunk_id));
+                }
+            }
+        }
+        acked.sort();
+        assert_eq!(acked, vec![3, 5]);
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn downgraded_features_are_greeted_again() {
+        use crate::constants::TRANSMISSION_INFO_LENGTH;
+        use crate::engine::Bus;
+        use crate::protocol::coding::plain::PlainSender;
+        use crate::protocol::wire::encoding::parse_packet;
+        use crate::protocol::wire::frames::{FEATURE_STOP_CHUNK, StopReason};
+        use crate::transmission::mock::MockSocket;
+
+        crate::protocol::mock_init();
+        let (client, server): (SocketAddr, SocketAddr) = (
+            "127.0.0.1:10000".parse().unwrap(),
+            "127.0.0.1:10001".parse().unwrap(),
+        );
+        let (client_socket, server_socket) = MockSocket::pair(client, server);
+        let bus = Arc::new(Bus::default());
+        let sender = SendingSocket::<_, TRANSMISSION_INFO_LENGTH>::new(
+            server_socket,
+            bus.register(BusAddress::SenderSocket).unwrap(),
+        );
+        let task = tokio::spawn(sender.run::<PlainSender>());
+        let start_ms = current_timestamp_ms();
+        let ticket = |n: u64, echo: u32| {
+            TicketPacket::new()
+                .set_timestamp(start_ms + n)
+                .set_features(FEATURE_STOP_CHUNK)
+                .set_features_echo(echo)
+                .set_stop_chunk(ChunkId(n as u32), StopReason::Aborted)
+                .set_get_chunk(ChunkId(n as u32), 0, 0)
+                .build()
+                .0
+        };
+        // Whether the server greeted the ticket, read up to its StopAck.
+        let mut buf = vec![0u8; MTU];
+        let mut greeted = async || {
+            let mut greeted = false;
+            loop {
+                let (length, _) = client_socket.recv_from(&mut buf).await.unwrap();
+                let packet = parse_packet::<TRANSMISSION_INFO_LENGTH>(Bytes::copy_from_slice(
+                    &buf[..length],
+                ))
+                .unwrap();
+                for frame in packet.frames {
+                    match frame {
+                        ParsedFrameVariant::Features(_) => greeted = true,
+                        ParsedFrameVariant::StopAck(_) => return greeted,
+                        _ => {}
+                    }
+                }
+            }
+        };
+
+        client_socket.send_to(&ticket(1, 0), server).await.unwrap();
+        assert!(greeted().await);
+        // The Features frame was stripped on the way, the client heard none.
+        client_socket.send_to(&ticket(2, 0), server).await.unwrap();
+        assert!(greeted().await);
+        client_socket
+            .send_to(&ticket(3, SUPPORTED_FEATURES), server)
+            .await
+            .unwrap();
+        assert!(!greeted().await);
+
+        // Nor can the echo be altered on the way.
+        let mut tampered = ticket(4, 0xa5a5_a5a5).concat();
+        let at = tampered
+            .windows(4)
+            .position(|window| window == [0xa5; 4])
+            .unwrap();
+        tampered[at..at + 4].copy_from_slice(&SUPPORTED_FEATURES.to_be_bytes());
+        assert!(parse_packet::<TRANSMISSION_INFO_LENGTH>(Bytes::from(tampered)).is_err());
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn link_is_shared_by_priority() {
+        use crate::engine::Bus;
+        use crate::protocol::wire::encoding::parse_packet;
+        use crate::transmission::mock::MockSocket;
+
+        crate::protocol::mock_init();
+        let mirror: SocketAddr = "127.0.0.1:10000".parse().unwrap();
+        let urgent: SocketAddr = "127.0.0.1:10002".parse().unwrap();
+        let (socket, _) = MockSocket::pair("127.0.0.1:10001".parse().unwrap(), mirror);
+        let bus = Arc::new(Bus::default());
+        let mut sender =
+            SendingSocket::<_, 12>::new(socket, bus.register(BusAddress::SenderSocket).unwrap())
+                .with_link_rate(170_000);
+        let frames = |priority| {
+            let (ticket, _) = TicketPacket::new()
+                .set_rate_limit(1_000_000)
+                .set_priority(priority)
+                .set_get_chunk(ChunkId(3), 0, 100)
+                .build();
+            parse_packet::<12>(Bytes::from(ticket.concat()))
+                .unwrap()
+                .frames
+        };
+        let share = |kbps| Some(interval_for_rate(kbps, MTU));
+
+        // Alone, the mirror job gets the whole link.
+        assert_eq!(
+            sender.fair_interval(mirror, &frames(Priority::Background)),
+            share(170_000)
+        );
+        assert_eq!(
+            sender.fair_interval(urgent, &frames(Priority::High)),
+            share(160_000)
+        );
+        assert_eq!(
+            sender.fair_interval(mirror, &frames(Priority::Background)),
+            share(10_000)
+        );
+    }
+
+    #[test]
+    fn repair_follows_the_loss() {
+        use rand::{Rng, SeedableRng, rngs::StdRng};
+
+        let mut rng = StdRng::seed_from_u64(0x1055);
+        let mut ticket = |estimate: &mut LossEstimate, loss: f64| {
+            let received = (0..500).filter(|_| !rng.random_bool(loss)).count();
+            estimate.observe(500, received as u64);
+        };
+        // Random loss, settling at what makes up for it plus the margin.
+        for (loss, settled) in [(0.0, 3), (0.05, 8), (0.2, 28)] {
+            let mut estimate = LossEstimate::default();
+            assert_eq!(estimate.overhead_percent(), ADAPTIVE_START_PERCENT);
+            for _ in 0..200 {
+                ticket(&mut estimate, loss);
+            }
+            let overhead = estimate.overhead_percent();
+            assert!(overhead.abs_diff(settled) <= 2, "{loss}: {overhead}%");
+        }
+
+        // Bursts, half of one ticket in ten lost, average out at 5%.
+        let mut estimate = LossEstimate::default();
+        for round in 0..400 {
+            ticket(&mut estimate, if round % 10 == 0 { 0.5 } else { 0.0 });
+        }
+        let overhead = estimate.overhead_percent();
+        assert!((5..=16).contains(&overhead), "bursts: {overhead}%");
+
+        // A path that cleans up goes back to the margin.
+        for _ in 0..100 {
+            ticket(&mut estimate, 0.0);
+        }
+        assert_eq!(estimate.overhead_percent(), REPAIR_MARGIN_PERCENT);
+
+        // Too few packets to tell change nothing, nor do late ones arriving.
+        estimate.observe(LOSS_MIN_SAMPLE - 1, 0);
+        estimate.observe(100, 120);
+        assert_eq!(estimate.overhead_percent(), REPAIR_MARGIN_PERCENT);
     }
 }
@@ -0,0 +1,174 @@
+use owo_colors::OwoColorize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tokio::time::{Duration, interval};
+
+use super::{BusAddress, BusInterface, BusMessage};
+use crate::util::units::ChunkId;
+
+// How often encoders report, and how often the server logs what they reported.
+pub const PROGRESS_PERIOD: Duration = Duration::from_secs(1);
+const LOG_PERIOD: Duration = Duration::from_secs(5);
+
+// Sent by every encoder to `BusAddress::SenderStats`, and once more when it exits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncoderProgress {
+    pub chunk_id: ChunkId,
+    pub peer: SocketAddr,
+    pub frames_sent: u64,
+    pub bytes_sent: u64,
+    pub max_sent_offset: u32,
+    // The peer's receive window ends here.
+    pub max_frame_offset: u32,
+    // Frames the chunk takes without repair, see `FrameSender::source_frames`.
+    pub source_frames: u32,
+    pub finished: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SenderTotals {
+    pub frames_sent: u64,
+    pub bytes_sent: u64,
+    pub source_frames: u64,
+    pub finished_encoders: u64,
+}
+
+impl SenderTotals {
+    // Frames sent per frame of chunk data, 1 being no repair at all.
+    pub fn efficiency(&self) -> f64 {
+        self.frames_sent as f64 / self.source_frames.max(1) as f64
+    }
+}
+
+pub struct SenderStats<const INFO_LENGTH: usize> {
+    bus_interface: BusInterface<BusAddress, BusMessage<INFO_LENGTH>>,
+    encoders: HashMap<(ChunkId, SocketAddr), EncoderProgress>,
+    // Of encoders that already exited.
+    finished: SenderTotals,
+}
+
+impl<const INFO_LENGTH: usize> SenderStats<INFO_LENGTH> {
+    pub fn new(bus_interface: BusInterface<BusAddress, BusMessage<INFO_LENGTH>>) -> Self {
+        Self {
+            bus_interface,
+            encoders: HashMap::new(),
+            finished: SenderTotals::default(),
+        }
+    }
+
+    fn record(&mut self, progress: EncoderProgress) {
+        let key = (progress.chunk_id, progress.peer);
+        if !progress.finished {
+            self.encoders.insert(key, progress);
+            return;
+        }
+        self.encoders.remove(&key);
+        self.finished.frames_sent += progress.frames_sent;
+        self.finished.bytes_sent += progress.bytes_sent;
+        self.finished.source_frames += progress.source_frames as u64;
+        self.finished.finished_encoders += 1;
+    }
+
+    pub fn totals(&self) -> SenderTotals {
+        self.encoders
+            .values()
+            .fold(self.finished, |totals, progress| SenderTotals {
+                frames_sent: totals.frames_sent + progress.frames_sent,
+                bytes_sent: totals.bytes_sent + progress.bytes_sent,
+                source_frames: totals.source_frames + progress.source_frames as u64,
+                ..totals
+            })
+    }
+
+    fn log(&self) {
+        let totals = self.totals();
+        eprintln!(
+            "Sent {} frames, {} bytes, {:.2} per source frame. {} encoders running, {} finished.",
+            totals.frames_sent.yellow(),
+            totals.bytes_sent.yellow(),
+            totals.efficiency(),
+            self.encoders.len().green(),
+            totals.finished_encoders
+        );
+        let mut encoders: Vec<&EncoderProgress> = self.encoders.values().collect();
+        encoders.sort_by_key(|progress| (progress.chunk_id, progress.peer));
+        for progress in encoders {
+            eprintln!(
+                "  Chunk {} to {}: {} frames sent of {} source, offset {} of window {}",
+                progress.chunk_id.magenta(),
+                progress.peer,
+                progress.frames_sent,
+                progress.source_frames,
+                progress.max_sent_offset,
+                progress.max_frame_offset
+            );
+        }
+    }
+
+    pub async fn run(mut self) {
+        let mut ticker = interval(LOG_PERIOD);
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                Some(message) = self.bus_interface.recv::<BusMessage<INFO_LENGTH>>() => {
+                    if let BusMessage::EncoderProgress(progress) = message {
+                        self.record(progress);
+                    }
+                },
+                _ = ticker.tick() => {
+                    if !self.encoders.is_empty() {
+                        self.log();
+                    }
+                },
+                else => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Bus;
+    use std::sync::Arc;
+
+    #[test]
+    fn totals_include_finished_encoders() {
+        let bus: Arc<Bus<BusAddress, BusMessage<16>>> = Arc::new(Bus::default());
+        let mut stats = SenderStats::new(bus.register(BusAddress::SenderStats).unwrap());
+        let progress = EncoderProgress {
+            chunk_id: ChunkId(1),
+            peer: "10.0.0.2:7000".parse().unwrap(),
+            frames_sent: 10,
+            bytes_sent: 14400,
+            max_sent_offset: 9,
+            max_frame_offset: 8192,
+            source_frames: 8,
+            finished: false,
+        };
+        stats.record(progress);
+        stats.record(EncoderProgress {
+            frames_sent: 20,
+            ..progress
+        });
+        stats.record(EncoderProgress {
+            chunk_id: ChunkId(2),
+            frames_sent: 5,
+            bytes_sent: 7200,
+            finished: true,
+            ..progress
+        });
+
+        assert_eq!(stats.encoders.len(), 1);
+        assert_eq!(
+            stats.totals(),
+            SenderTotals {
+                frames_sent: 25,
+                bytes_sent: 21600,
+                source_frames: 16,
+                finished_encoders: 1,
+            }
+        );
+        assert!((stats.totals().efficiency() - 25.0 / 16.0).abs() < 1e-9);
+    }
+}
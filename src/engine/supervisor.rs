@@ -0,0 +1,221 @@
+use dashmap::DashMap;
+use owo_colors::OwoColorize;
+use std::any::Any;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::{fmt::Debug, future::Future, hash::Hash};
+use tokio::task::JoinHandle;
+
+#[derive(Debug, Clone, Copy)]
+pub enum RestartPolicy {
+    Never,
+    Restart { max_restarts: usize },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TaskHealth {
+    Running,
+    Restarted(usize),
+    Failed(String),
+}
+
+// Owns the engine tasks spawned for bus peers. Finished tasks are forgotten,
+// failed ones are kept so they show up in `Bus::debug`.
+pub struct Supervisor<ADDRESS>
+where
+    ADDRESS: Eq + Hash + Clone + Debug,
+{
+    // Each entry with the generation of the task it is about, as a task may be
+    // spawned at an address another is still finishing at.
+    tasks: Arc<DashMap<ADDRESS, (u64, TaskHealth)>>,
+    generations: Arc<AtomicU64>,
+}
+
+impl<ADDRESS> Default for Supervisor<ADDRESS>
+where
+    ADDRESS: Eq + Hash + Clone + Debug,
+{
+    fn default() -> Self {
+        Self {
+            tasks: Arc::new(DashMap::new()),
+            generations: Arc::default(),
+        }
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| String::from("unknown panic"))
+}
+
+impl<ADDRESS> Supervisor<ADDRESS>
+where
+    ADDRESS: Eq + Hash + Clone + Debug,
+{
+    pub fn health(&self) -> Vec<(ADDRESS, TaskHealth)> {
+        self.tasks
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().1.clone()))
+            .collect()
+    }
+
+    // For tasks not spawned here that still report to the supervisor, such as
+    // the socket loops ending on an error.
+    pub fn fail(&self, address: ADDRESS, reason: String) {
+        let generation = self.generations.fetch_add(1, Relaxed);
+        self.tasks
+            .insert(address, (generation, TaskHealth::Failed(reason)));
+    }
+}
+
+// Updates the entry at `address` only while it is still that of `generation`.
+fn update<ADDRESS: Eq + Hash>(
+    tasks: &DashMap<ADDRESS, (u64, TaskHealth)>,
+    address: &ADDRESS,
+    generation: u64,
+    health: Option<TaskHealth>,
+) {
+    match health {
+        Some(health) => {
+            if let Some(mut entry) = tasks.get_mut(address)
+                && entry.0 == generation
+            {
+                entry.1 = health;
+            }
+        }
+        None => {
+            tasks.remove_if(address, |_, (current, _)| *current == generation);
+        }
+    }
+}
+
+impl<ADDRESS> Supervisor<ADDRESS>
+where
+    ADDRESS: Eq + Hash + Clone + Debug + Send + Sync + 'static,
+{
+    // `make` is called again for every restart. The returned handle yields None
+    // if the task gave up or exhausted its restarts.
+    pub fn spawn<T, Fut>(
+        &self,
+        address: ADDRESS,
+        policy: RestartPolicy,
+        mut make: impl FnMut() -> Fut + Send + 'static,
+    ) -> JoinHandle<Option<T>>
+    where
+        T: Send + 'static,
+        Fut: Future<Output = Option<T>> + Send + 'static,
+    {
+        let tasks = self.tasks.clone();
+        let generation = self.generations.fetch_add(1, Relaxed);
+        tasks.insert(address.clone(), (generation, TaskHealth::Running));
+
+        tokio::spawn(async move {
+            let mut restarts = 0;
+            loop {
+                match tokio::spawn(make()).await {
+                    Ok(output) => {
+                        update(&tasks, &address, generation, None);
+                        return output;
+                    }
+                    // Cancelled on purpose, e.g. as the runtime shuts down.
+                    Err(err) if !err.is_panic() => {
+                        update(&tasks, &address, generation, None);
+                        return None;
+                    }
+                    Err(err) => {
+                        let reason = panic_message(err.into_panic());
+                        eprintln!("Task {:?} {}: {reason}", &address, "panicked".red());
+
+                        match policy {
+                            RestartPolicy::Restart { max_restarts } if restarts < max_restarts => {
+                                restarts += 1;
+                                eprintln!(
+                                    "Task {:?} restarting ({restarts}/{max_restarts})",
+                                    &address
+                                );
+                                update(
+                                    &tasks,
+                                    &address,
+                                    generation,
+                                    Some(TaskHealth::Restarted(restarts)),
+                                );
+                            }
+                            _ => {
+                                update(
+                                    &tasks,
+                                    &address,
+                                    generation,
+                                    Some(TaskHealth::Failed(reason)),
+                                );
+                                return None;
+                            }
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[tokio::test]
+    async fn restart_until_success() {
+        let supervisor: Supervisor<u32> = Supervisor::default();
+        let attempts = Arc::new(AtomicUsize::new(0));
+
+        let counter = attempts.clone();
+        let handle = supervisor.spawn(7, RestartPolicy::Restart { max_restarts: 2 }, move || {
+            let attempt = counter.fetch_add(1, Relaxed);
+            async move {
+                assert!(attempt >= 2, "attempt {attempt} fails");
+                Some(attempt)
+            }
+        });
+
+        assert_eq!(handle.await.unwrap(), Some(2));
+        assert!(supervisor.health().is_empty());
+    }
+
+    #[tokio::test]
+    async fn finishing_task_keeps_the_entry_of_its_successor() {
+        let supervisor: Supervisor<u32> = Supervisor::default();
+        let (finish, finished) = tokio::sync::oneshot::channel::<()>();
+        let mut finished = Some(finished);
+        let first = supervisor.spawn(7, RestartPolicy::Never, move || {
+            let finished = finished.take().unwrap();
+            async move { finished.await.ok() }
+        });
+        let second = supervisor.spawn(7, RestartPolicy::Never, || {
+            std::future::pending::<Option<()>>()
+        });
+
+        finish.send(()).unwrap();
+        assert_eq!(first.await.unwrap(), Some(()));
+        assert_eq!(supervisor.health(), vec![(7, TaskHealth::Running)]);
+        second.abort();
+    }
+
+    #[tokio::test]
+    async fn fail_after_exhausting_restarts() {
+        let supervisor: Supervisor<u32> = Supervisor::default();
+        let handle = supervisor.spawn(7, RestartPolicy::Never, || async {
+            if true {
+                panic!("mmap failed");
+            }
+            Some(())
+        });
+
+        assert_eq!(handle.await.unwrap(), None);
+        assert_eq!(
+            supervisor.health(),
+            vec![(7, TaskHealth::Failed(String::from("mmap failed")))]
+        );
+    }
+}
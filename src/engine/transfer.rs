@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+
+use super::download::{DownloadManager, DownloadProgress, DownloadReport};
+use super::receiving::{DEFAULT_RATE_KBPS, ReceivingSocket, ServerUnreachable, SessionError};
+use super::sending::SendingSocket;
+use super::stats::SenderStats;
+use super::{Bus, BusAddress, BusMessage};
+use crate::constants::TRANSMISSION_INFO_LENGTH;
+use crate::protocol::coding::raptorq_code::{RaptorqReceiver, RaptorqSender};
+use crate::protocol::wire::frames::GrantFrame;
+use crate::transmission::errors::SocketFailure;
+use crate::transmission::real::RealUdpSocket;
+use crate::util::file::{
+    CHUNK_INDEX, ChunkIndex, check_file_exist, check_file_exist_create, chunk_hash, restore_symlink,
+};
+use crate::util::plan::{FileChunk, FileConfig};
+use crate::util::seal::ContentKey;
+
+type TransferBus = Bus<BusAddress, BusMessage<TRANSMISSION_INFO_LENGTH>>;
+
+#[derive(Debug, derive_more::Display, derive_more::Error, derive_more::From)]
+pub enum TransferError {
+    #[display("{_0}")]
+    Io(io::Error),
+    #[display("{_0}")]
+    Unreachable(ServerUnreachable),
+    #[display("{_0}")]
+    Socket(SocketFailure),
+    #[display("cancelled")]
+    Cancelled,
+}
+
+impl From<SessionError> for TransferError {
+    fn from(err: SessionError) -> Self {
+        match err {
+            SessionError::Unreachable(unreachable) => unreachable.into(),
+            SessionError::Socket(failure) => failure.into(),
+        }
+    }
+}
+
+// Stops the transfer it was taken from, from any thread, even before it runs.
+#[derive(Debug, Clone)]
+pub struct CancelHandle(Arc<watch::Sender<bool>>);
+
+impl CancelHandle {
+    fn new() -> Self {
+        Self(Arc::new(watch::Sender::new(false)))
+    }
+
+    pub fn cancel(&self) {
+        self.0.send_replace(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    async fn cancelled(&self) {
+        let mut receiver = self.0.subscribe();
+        receiver.wait_for(|cancelled| *cancelled).await.ok();
+    }
+}
+
+// Stops a task the transfer spawned when the transfer ends or is dropped.
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// Fetches the file of a plan from one server into `destination`, for programs
+// embedding USync. Chunks already there with their planned hash are kept.
+// Signs tickets with the keys of `protocol::init`.
+pub struct Downloader {
+    config: FileConfig,
+    server: SocketAddr,
+    destination: PathBuf,
+    bind: SocketAddr,
+    rate_kbps: u64,
+    max_wait: Duration,
+    retries: usize,
+    content_key: Option<Arc<ContentKey>>,
+    grant: Option<GrantFrame>,
+    cancel: CancelHandle,
+}
+
+impl Downloader {
+    pub fn new(config: FileConfig, server: SocketAddr, destination: &Path) -> Self {
+        Self {
+            config,
+            server,
+            destination: destination.to_path_buf(),
+            bind: "0.0.0.0:0".parse().unwrap(),
+            rate_kbps: DEFAULT_RATE_KBPS,
+            max_wait: Duration::from_secs(60),
+            retries: 2,
+            content_key: None,
+            grant: None,
+            cancel: CancelHandle::new(),
+        }
+    }
+
+    pub fn with_bind(mut self, bind: SocketAddr) -> Self {
+        self.bind = bind;
+        self
+    }
+
+    pub fn with_rate_kbps(mut self, rate_kbps: u64) -> Self {
+        self.rate_kbps = rate_kbps;
+        self
+    }
+
+    // Gives up once the server has been silent or busy this long.
+    pub fn with_max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = max_wait;
+        self
+    }
+
+    pub fn with_retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    // Required to download a sealed file.
+    pub fn with_content_key(mut self, content_key: Arc<ContentKey>) -> Self {
+        self.content_key = Some(content_key);
+        self
+    }
+
+    // Of a download token, whose key must be the one given to `protocol::init`.
+    pub fn with_grant(mut self, grant: GrantFrame) -> Self {
+        self.grant = Some(grant);
+        self
+    }
+
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    fn is_intact(&self, chunk: &FileChunk) -> bool {
+        let hash = match self.content_key.as_ref() {
+            Some(content_key) => content_key.sealed_hash(&self.destination, chunk),
+            None => chunk_hash(&self.destination, chunk.offset, chunk.length),
+        };
+        hash.is_ok_and(|hash| hash == chunk.hash)
+    }
+
+    // `on_progress` is called each time a chunk is written or fails.
+    pub async fn run<F>(self, mut on_progress: F) -> Result<DownloadReport, TransferError>
+    where
+        F: FnMut(&DownloadProgress),
+    {
+        if self.cancel.is_cancelled() {
+            return Err(TransferError::Cancelled);
+        }
+        if self.config.sealed.is_some() && self.content_key.is_none() {
+            return Err(io::Error::other(format!(
+                "{} is sealed and no content key was given",
+                self.config.file_name
+            ))
+            .into());
+        }
+        if let Some(parent) = self.destination.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if let Some(target) = self.config.link_target.as_ref() {
+            restore_symlink(&self.destination, target)?;
+            return Ok(DownloadReport::default());
+        }
+        check_file_exist_create(&self.destination)?;
+        let chunks: Vec<FileChunk> = self
+            .config
+            .chunks
+            .iter()
+            .filter(|chunk| !self.is_intact(chunk))
+            .cloned()
+            .collect();
+        if chunks.is_empty() {
+            return Ok(DownloadReport::default());
+        }
+
+        let bus: Arc<TransferBus> = Arc::new(Bus::default());
+        let socket = RealUdpSocket::bind_with_fallback(self.bind, None, None).await?;
+        let receiver = ReceivingSocket::new(
+            socket,
+            bus.clone().register(BusAddress::ReceiverSocket).unwrap(),
+        )
+        .with_upcoming(chunks.iter().map(|chunk| chunk.chunk_id).collect())
+        .with_rate_kbps(self.rate_kbps)
+        .with_max_wait(self.max_wait);
+        let receiver = match self.grant {
+            Some(grant) => receiver.with_grant(grant),
+            None => receiver,
+        };
+        let mut receiver = AbortOnDrop(tokio::spawn(receiver.run(self.server)));
+
+        let mut download =
+            DownloadManager::new(bus.clone(), &self.destination).with_retries(self.retries);
+        if let Some(content_key) = self.content_key.clone() {
+            download = download.with_content_key(content_key);
+        }
+        let mut progress = download.progress();
+        let download = download.run::<RaptorqReceiver>(chunks);
+        tokio::pin!(download);
+
+        let mut receiver_done = false;
+        loop {
+            tokio::select! {
+                report = &mut download => break Ok(report),
+                Ok(()) = progress.changed() => on_progress(&progress.borrow_and_update()),
+                stopped = &mut receiver.0, if !receiver_done => {
+                    receiver_done = true;
+                    if let Ok(Err(err)) = stopped {
+                        break Err(err.into());
+                    }
+                }
+                _ = self.cancel.cancelled() => break Err(TransferError::Cancelled),
+            }
+        }
+    }
+}
+
+// Serves the file of a plan to any client holding a key of `protocol::init`.
+// Chunks are found through the process-wide `CHUNK_INDEX`, so a process seeds
+// one file.
+pub struct Seeder {
+    config: FileConfig,
+    file: PathBuf,
+    listening: SocketAddr,
+    max_encoders: Option<usize>,
+    cancel: CancelHandle,
+}
+
+impl Seeder {
+    pub fn new(config: FileConfig, file: &Path, listening: SocketAddr) -> Self {
+        Self {
+            config,
+            file: file.to_path_buf(),
+            listening,
+            max_encoders: None,
+            cancel: CancelHandle::new(),
+        }
+    }
+
+    pub fn with_max_encoders(mut self, max_encoders: usize) -> Self {
+        self.max_encoders = Some(max_encoders);
+        self
+    }
+
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    // Serves until cancelled.
+    pub async fn run(self) -> Result<(), TransferError> {
+        check_file_exist(&self.file)?;
+        CHUNK_INDEX
+            .set(
+                ChunkIndex::new(
+                    HashMap::from([(0, OsString::from(&self.file))]),
+                    self.config.chunk_map(),
+                )
+                .with_epoch(self.config.epoch),
+            )
+            .map_err(|_| io::Error::other("this process already seeds a file"))?;
+
+        let bus: Arc<TransferBus> = Arc::new(Bus::default());
+        let socket = RealUdpSocket::bind_with_fallback(self.listening, None, None).await?;
+        let mut sender = SendingSocket::new(
+            socket,
+            bus.clone().register(BusAddress::SenderSocket).unwrap(),
+        );
+        if let Some(max_encoders) = self.max_encoders {
+            sender = sender.with_max_encoders(max_encoders);
+        }
+        let stats = SenderStats::new(bus.clone().register(BusAddress::SenderStats).unwrap());
+        let _stats = AbortOnDrop(tokio::spawn(stats.run()));
+        let mut sender = AbortOnDrop(tokio::spawn(sender.run::<RaptorqSender>()));
+        tokio::select! {
+            _ = self.cancel.cancelled() => Ok(()),
+            Ok(Err(failure)) = &mut sender.0 => Err(failure.into()),
+        }
+    }
+}
@@ -0,0 +1,120 @@
+// Frame offsets are u32 and wrap on very large chunks or long sessions. They
+// are compared in serial number arithmetic (RFC 1982): `a` comes before `b` when
+// `b` is less than 2^31 frames ahead of it, so ordering survives the wrap as
+// long as a window stays under half the offset space.
+
+pub fn serial_lt(a: u32, b: u32) -> bool {
+    a != b && b.wrapping_sub(a) < 1 << 31
+}
+
+pub fn serial_le(a: u32, b: u32) -> bool {
+    a == b || serial_lt(a, b)
+}
+
+// The later of `a` and `b`.
+pub fn serial_max(a: u32, b: u32) -> u32 {
+    if serial_lt(a, b) { b } else { a }
+}
+
+// The frames an encoder may send: from the next one up to, not including, the
+// limit the peer last allowed. Both only move forward.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameWindow {
+    next: u32,
+    limit: u32,
+}
+
+impl FrameWindow {
+    // Starting at `next`, the offset the encoder was seeked to.
+    pub fn new(next: u32, limit: u32) -> Self {
+        Self {
+            next,
+            limit: serial_max(next, limit),
+        }
+    }
+
+    // A limit behind the current one, from a late or repeated order, is ignored.
+    pub fn extend(&mut self, limit: u32) {
+        self.limit = serial_max(self.limit, limit);
+    }
+
+    pub fn can_send(&self) -> bool {
+        serial_lt(self.next, self.limit)
+    }
+
+    // Records frame `offset` as sent. Offsets behind the next one change nothing.
+    pub fn sent(&mut self, offset: u32) {
+        if serial_le(self.next, offset) {
+            self.next = offset.wrapping_add(1);
+        }
+    }
+
+    pub fn next(&self) -> u32 {
+        self.next
+    }
+
+    pub fn limit(&self) -> u32 {
+        self.limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serial_order_survives_the_wrap() {
+        assert!(serial_lt(1, 2));
+        assert!(!serial_lt(2, 1));
+        assert!(!serial_lt(5, 5));
+        assert!(serial_le(5, 5));
+        assert!(serial_lt(u32::MAX, 0));
+        assert!(serial_lt(u32::MAX - 10, 10));
+        assert!(!serial_lt(10, u32::MAX - 10));
+        assert_eq!(serial_max(u32::MAX - 1, 3), 3);
+        assert_eq!(serial_max(3, u32::MAX - 1), 3);
+    }
+
+    #[test]
+    fn window_sends_up_to_the_limit() {
+        let mut window = FrameWindow::new(10, 13);
+        let mut sent = vec![];
+        while window.can_send() {
+            sent.push(window.next());
+            window.sent(window.next());
+        }
+        assert_eq!(sent, vec![10, 11, 12]);
+
+        // A repeated or older order does not move the limit.
+        window.extend(13);
+        window.extend(11);
+        assert!(!window.can_send());
+        window.extend(14);
+        assert!(window.can_send());
+    }
+
+    #[test]
+    fn window_crosses_u32_max() {
+        let mut window = FrameWindow::new(u32::MAX - 1, 2);
+        let mut sent = vec![];
+        while window.can_send() {
+            sent.push(window.next());
+            window.sent(window.next());
+        }
+        assert_eq!(sent, vec![u32::MAX - 1, u32::MAX, 0, 1]);
+        assert_eq!(window.next(), 2);
+
+        // Frames sent out of order never move the window back.
+        window.sent(u32::MAX);
+        assert_eq!(window.next(), 2);
+        window.extend(u32::MAX);
+        assert_eq!(window.limit(), 2);
+    }
+
+    #[test]
+    fn limit_behind_the_start_is_empty() {
+        let window = FrameWindow::new(100, 50);
+        assert!(!window.can_send());
+        assert_eq!(window.limit(), 100);
+    }
+}
@@ -1,8 +1,29 @@
 #![allow(dead_code)]
 #![warn(unused_imports)]
 
+// Declares modules internal to the crate. The binaries, examples and tests of
+// this repository reach into them with the `internals` feature, embedders use
+// `prelude` instead: these change without notice.
+macro_rules! internal_mods {
+    ($($(#[$attr:meta])* $name:ident;)*) => {$(
+        $(#[$attr])*
+        #[cfg(feature = "internals")]
+        #[doc(hidden)]
+        pub mod $name;
+        $(#[$attr])*
+        #[cfg(not(feature = "internals"))]
+        pub(crate) mod $name;
+    )*};
+}
+
 pub mod constants;
-pub mod engine;
-pub mod protocol;
-pub mod transmission;
-pub mod util;
+pub mod prelude;
+
+internal_mods! {
+    #[cfg(feature = "engine")]
+    engine;
+    #[cfg(feature = "protocol")]
+    protocol;
+    transmission;
+    util;
+}
@@ -0,0 +1,27 @@
+// The supported API for embedders: planning a file, downloading and seeding
+// it, and the codec and socket traits to plug in. Kept stable across minor
+// versions, the rest of the crate is there for the binaries and may change.
+// `tests/prelude.rs` pins these items down.
+
+pub use crate::util::plan::{FileChunk, FileConfig, PlanBuilder};
+pub use crate::util::units::{ByteOffset, ChunkId};
+
+#[cfg(feature = "protocol")]
+pub use crate::protocol::coding::{FrameReceiver, FrameSender};
+#[cfg(feature = "protocol")]
+pub use crate::protocol::init;
+
+#[cfg(feature = "raptorq")]
+pub use crate::protocol::coding::raptorq_code::{RaptorqReceiver, RaptorqSender};
+
+#[cfg(feature = "runtime")]
+pub use crate::transmission::{DynSocket, Ecn, UdpSocketLike};
+#[cfg(feature = "runtime")]
+pub use crate::util::plan::plan_file;
+#[cfg(feature = "runtime")]
+pub use crate::util::seal::ContentKey;
+
+#[cfg(feature = "engine")]
+pub use crate::engine::download::{DownloadProgress, DownloadReport};
+#[cfg(feature = "engine")]
+pub use crate::engine::transfer::{CancelHandle, Downloader, Seeder, TransferError};
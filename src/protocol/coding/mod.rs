@@ -1,7 +1,11 @@
 pub trait FrameSender<const TRANSMISSION_INFO_LENGTH: usize> {
     fn init(chunk_data: impl AsRef<[u8]>, next_id: u32) -> Self;
+    // Continue from `next_id`, for encoders initialized before the peer's offset was known.
+    fn seek(&mut self, next_id: u32);
     fn next_frame(&mut self) -> (u32, Vec<u8>);
     fn get_trasmission_info(&self) -> [u8; TRANSMISSION_INFO_LENGTH];
+    // Frames carrying as much as the chunk itself, the least a receiver needs.
+    fn source_frames(&self) -> u32;
 }
 
 pub trait FrameReceiver<const TRANSMISSION_INFO_LENGTH: usize>: Sized {
@@ -10,4 +14,6 @@ pub trait FrameReceiver<const TRANSMISSION_INFO_LENGTH: usize>: Sized {
     fn expected_frame_id(&self) -> u32;
 }
 
+pub mod plain;
+#[cfg(feature = "raptorq")]
 pub mod raptorq_code;
@@ -0,0 +1,133 @@
+use super::{FrameReceiver, FrameSender};
+use crate::constants::{DEFAULT_FRAME_LEN, TRANSMISSION_INFO_LENGTH};
+
+// Chunks sent as they are, frame after frame and round again, without repair.
+// A receiver needs every frame, so this is for tests and debug builds, where
+// RaptorQ is slow, over links that lose little. The transmission info is the
+// chunk length, u64, and the frame length, u32, both little endian.
+
+pub struct PlainSender {
+    data: Vec<u8>,
+    frame_len: usize,
+    next_id: u32,
+}
+
+fn frame_count(length: usize, frame_len: usize) -> u32 {
+    length.div_ceil(frame_len).max(1) as u32
+}
+
+impl FrameSender<TRANSMISSION_INFO_LENGTH> for PlainSender {
+    fn init(chunk_data: impl AsRef<[u8]>, next_id: u32) -> Self {
+        Self {
+            data: chunk_data.as_ref().to_vec(),
+            frame_len: DEFAULT_FRAME_LEN,
+            next_id,
+        }
+    }
+
+    fn seek(&mut self, next_id: u32) {
+        self.next_id = next_id;
+    }
+
+    fn next_frame(&mut self) -> (u32, Vec<u8>) {
+        let frame_id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let start = (frame_id % self.source_frames()) as usize * self.frame_len;
+        let end = (start + self.frame_len).min(self.data.len());
+        (frame_id, self.data[start..end].to_vec())
+    }
+
+    fn get_trasmission_info(&self) -> [u8; TRANSMISSION_INFO_LENGTH] {
+        let mut info = [0u8; TRANSMISSION_INFO_LENGTH];
+        info[..8].copy_from_slice(&(self.data.len() as u64).to_le_bytes());
+        info[8..].copy_from_slice(&(self.frame_len as u32).to_le_bytes());
+        info
+    }
+
+    fn source_frames(&self) -> u32 {
+        frame_count(self.data.len(), self.frame_len)
+    }
+}
+
+pub struct PlainReceiver {
+    data: Vec<u8>,
+    frame_len: usize,
+    received: Vec<bool>,
+    missing: usize,
+    expected_frame_id: u32,
+}
+
+impl FrameReceiver<TRANSMISSION_INFO_LENGTH> for PlainReceiver {
+    fn try_init(frame: &[u8; TRANSMISSION_INFO_LENGTH]) -> Option<Self> {
+        let length = u64::from_le_bytes(frame[..8].try_into().unwrap());
+        let frame_len = u32::from_le_bytes(frame[8..].try_into().unwrap()) as usize;
+        let length = usize::try_from(length).ok()?;
+        if frame_len == 0 || length.div_ceil(frame_len) > u32::MAX as usize {
+            return None;
+        }
+        let frames = frame_count(length, frame_len) as usize;
+        Some(Self {
+            data: vec![0; length],
+            frame_len,
+            received: vec![false; frames],
+            missing: frames,
+            expected_frame_id: 0,
+        })
+    }
+
+    // Frames of the wrong length are dropped.
+    fn update(&mut self, frame_id: u32, frame: &[u8]) -> Option<Vec<u8>> {
+        self.expected_frame_id = self.expected_frame_id.max(frame_id.wrapping_add(1));
+        let index = frame_id as usize % self.received.len();
+        let start = index * self.frame_len;
+        let end = (start + self.frame_len).min(self.data.len());
+        if frame.len() != end - start {
+            return None;
+        }
+        if !self.received[index] {
+            self.data[start..end].copy_from_slice(frame);
+            self.received[index] = true;
+            self.missing -= 1;
+        }
+        (self.missing == 0).then(|| self.data.clone())
+    }
+
+    fn expected_frame_id(&self) -> u32 {
+        self.expected_frame_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_frame_once_restores_the_chunk() {
+        let data: Vec<u8> = (0..10 * DEFAULT_FRAME_LEN + 7).map(|i| i as u8).collect();
+        let mut sender = PlainSender::init(&data, 0);
+        assert_eq!(sender.source_frames(), 11);
+        let mut receiver = PlainReceiver::try_init(&sender.get_trasmission_info()).unwrap();
+        // Every third frame of the first round is lost, and comes again in the second.
+        let restored = loop {
+            let (frame_id, frame) = sender.next_frame();
+            assert!(frame_id < 22, "Take too long!");
+            if frame_id < 11 && frame_id % 3 == 0 {
+                continue;
+            }
+            if let Some(restored) = receiver.update(frame_id, &frame) {
+                break restored;
+            }
+        };
+        assert_eq!(restored, data);
+        assert_eq!(receiver.expected_frame_id(), 21);
+    }
+
+    #[test]
+    fn empty_chunks_and_bad_info() {
+        let mut sender = PlainSender::init(Vec::new(), 5);
+        let mut receiver = PlainReceiver::try_init(&sender.get_trasmission_info()).unwrap();
+        let (frame_id, frame) = sender.next_frame();
+        assert_eq!(receiver.update(frame_id, &frame), Some(vec![]));
+        assert!(PlainReceiver::try_init(&[0xff; TRANSMISSION_INFO_LENGTH]).is_none());
+    }
+}
@@ -5,6 +5,114 @@ use crate::protocol::coding::FrameReceiver;
 use raptorq::{Decoder, Encoder, EncodingPacket, ObjectTransmissionInformation};
 
 use std::collections::VecDeque;
+use std::sync::RwLock;
+
+// How chunks are cut into RaptorQ source blocks and sub-blocks. Larger blocks
+// need fewer repair symbols but more decoder memory and time per block, which
+// matters for 32 MiB chunks. Receivers read them from the transmission info,
+// so only the sender picks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RaptorqParams {
+    // Blocks as large as a decoder working memory of this many bytes allows,
+    // the raptorq crate's default being 10 MiB.
+    DecoderMemory(u64),
+    // As given, or as by default if RaptorQ can not cut a chunk that way.
+    Fixed {
+        source_blocks: u8,
+        sub_blocks: u16,
+        alignment: u8,
+    },
+}
+
+const DEFAULT_DECODER_MEMORY: u64 = 10 * 1024 * 1024;
+// The most symbols a source block takes (K'max of RFC 6330).
+const MAX_BLOCK_SYMBOLS: u64 = 56403;
+
+impl Default for RaptorqParams {
+    fn default() -> Self {
+        RaptorqParams::DecoderMemory(DEFAULT_DECODER_MEMORY)
+    }
+}
+
+impl RaptorqParams {
+    pub fn transmission_info(
+        &self,
+        transfer_length: u64,
+        symbol_size: u16,
+    ) -> ObjectTransmissionInformation {
+        match *self {
+            RaptorqParams::DecoderMemory(bytes) => {
+                ObjectTransmissionInformation::generate_encoding_parameters(
+                    transfer_length,
+                    symbol_size,
+                    bytes,
+                )
+            }
+            RaptorqParams::Fixed {
+                source_blocks,
+                sub_blocks,
+                alignment,
+            } => {
+                let symbols = transfer_length.div_ceil(symbol_size as u64);
+                let fits = source_blocks > 0
+                    && sub_blocks > 0
+                    && alignment > 0
+                    && symbol_size.is_multiple_of(alignment as u16)
+                    && sub_blocks as u64 * alignment as u64 <= symbol_size as u64
+                    && symbols.div_ceil(source_blocks as u64) <= MAX_BLOCK_SYMBOLS;
+                if !fits {
+                    return RaptorqParams::default()
+                        .transmission_info(transfer_length, symbol_size);
+                }
+                ObjectTransmissionInformation::new(
+                    transfer_length,
+                    symbol_size,
+                    source_blocks,
+                    sub_blocks,
+                    alignment,
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum RaptorqPreset {
+    #[default]
+    Default,
+    // More sub-blocks, for decoders with 2 MiB of working memory.
+    LowMemory,
+    // 8 source blocks per chunk, each decoded faster than one large block, at
+    // slightly more frames needed.
+    SmallBlocks,
+}
+
+impl RaptorqPreset {
+    pub fn params(self) -> RaptorqParams {
+        match self {
+            RaptorqPreset::Default => RaptorqParams::default(),
+            RaptorqPreset::LowMemory => RaptorqParams::DecoderMemory(2 * 1024 * 1024),
+            RaptorqPreset::SmallBlocks => RaptorqParams::Fixed {
+                source_blocks: 8,
+                sub_blocks: 1,
+                alignment: 8,
+            },
+        }
+    }
+}
+
+static PARAMS: RwLock<Option<RaptorqParams>> = RwLock::new(None);
+
+// For every encoder initialized from here on. Set before serving, as encoders
+// of a chunk restarted with other parameters send other frames.
+pub fn set_params(params: RaptorqParams) {
+    *PARAMS.write().unwrap() = Some(params);
+}
+
+pub fn params() -> RaptorqParams {
+    PARAMS.read().unwrap().unwrap_or_default()
+}
 
 pub struct RaptorqSender {
     encoder: Encoder,
@@ -13,13 +121,9 @@ pub struct RaptorqSender {
     next_fetch_id: usize,
 }
 
-impl FrameSender<RAPTORQ_TRANSMISSION_INFO_LENGTH> for RaptorqSender {
-    fn init(chunk_data: impl AsRef<[u8]>, next_id: u32) -> Self {
-        let chunk_data: &[u8] = chunk_data.as_ref();
-        let config = ObjectTransmissionInformation::with_defaults(
-            chunk_data.len() as u64,
-            DEFAULT_FRAME_LEN as u16,
-        );
+impl RaptorqSender {
+    pub fn with_params(chunk_data: &[u8], next_id: u32, params: RaptorqParams) -> Self {
+        let config = params.transmission_info(chunk_data.len() as u64, DEFAULT_FRAME_LEN as u16);
         let encoder = Encoder::new(chunk_data, config);
         let next_fetch_id = next_id as usize / encoder.get_block_encoders().len();
         RaptorqSender {
@@ -29,6 +133,17 @@ impl FrameSender<RAPTORQ_TRANSMISSION_INFO_LENGTH> for RaptorqSender {
             next_fetch_id,
         }
     }
+}
+
+impl FrameSender<RAPTORQ_TRANSMISSION_INFO_LENGTH> for RaptorqSender {
+    fn init(chunk_data: impl AsRef<[u8]>, next_id: u32) -> Self {
+        Self::with_params(chunk_data.as_ref(), next_id, params())
+    }
+
+    fn seek(&mut self, next_id: u32) {
+        self.cache.clear();
+        self.next_fetch_id = next_id as usize / self.encoder.get_block_encoders().len();
+    }
 
     fn next_frame(&mut self) -> (u32, Vec<u8>) {
         const BURST: usize = 16;
@@ -58,6 +173,12 @@ impl FrameSender<RAPTORQ_TRANSMISSION_INFO_LENGTH> for RaptorqSender {
     fn get_trasmission_info(&self) -> [u8; RAPTORQ_TRANSMISSION_INFO_LENGTH] {
         self.encoder.get_config().serialize()
     }
+
+    fn source_frames(&self) -> u32 {
+        self.config
+            .transfer_length()
+            .div_ceil(self.config.symbol_size() as u64) as u32
+    }
 }
 
 pub struct RaptorqReceiver {
@@ -87,12 +208,13 @@ impl FrameReceiver<RAPTORQ_TRANSMISSION_INFO_LENGTH> for RaptorqReceiver {
 #[cfg(test)]
 mod test {
     const CHUNK_SIZE: usize = 1048576;
-    use crate::constants::MTU;
+    use crate::constants::{DEFAULT_FRAME_LEN, MTU};
     use crate::protocol::coding::{
         FrameReceiver, FrameSender,
-        raptorq_code::{RaptorqReceiver, RaptorqSender},
+        raptorq_code::{RaptorqParams, RaptorqPreset, RaptorqReceiver, RaptorqSender},
     };
     use crate::util::generate_random;
+    use raptorq::ObjectTransmissionInformation;
 
     #[test]
     fn get_gen_frames() {
@@ -107,6 +229,80 @@ mod test {
         }
     }
 
+    #[test]
+    fn source_frames_cover_the_chunk() {
+        let data = generate_random(CHUNK_SIZE);
+        let sender = RaptorqSender::init(&data, 0);
+        assert_eq!(
+            sender.source_frames() as usize,
+            CHUNK_SIZE.div_ceil(DEFAULT_FRAME_LEN)
+        );
+    }
+
+    #[test]
+    fn parameters_travel_in_the_transmission_info() {
+        let data = generate_random(CHUNK_SIZE);
+        let params = RaptorqParams::Fixed {
+            source_blocks: 3,
+            sub_blocks: 2,
+            alignment: 8,
+        };
+        let mut encoder = RaptorqSender::with_params(&data, 0, params);
+        let info = encoder.get_trasmission_info();
+        let config = ObjectTransmissionInformation::deserialize(&info);
+        assert_eq!((config.source_blocks(), config.sub_blocks()), (3, 2));
+
+        // The receiver needs nothing but the transmission info.
+        let mut decoder = RaptorqReceiver::try_init(&info).unwrap();
+        let restored = loop {
+            let (frame_id, frame) = encoder.next_frame();
+            assert!(frame_id < 2000, "Take too long!");
+            if frame_id % 7 != 0
+                && let Some(restored) = decoder.update(frame_id, &frame)
+            {
+                break restored;
+            }
+        };
+        assert_eq!(data, restored);
+    }
+
+    #[test]
+    fn presets_cut_chunks_their_way() {
+        let length = crate::constants::CHUNK_SIZE as u64;
+        let info = |preset: RaptorqPreset| {
+            preset
+                .params()
+                .transmission_info(length, DEFAULT_FRAME_LEN as u16)
+        };
+        assert_eq!(info(RaptorqPreset::SmallBlocks).source_blocks(), 8);
+        assert!(
+            info(RaptorqPreset::LowMemory).sub_blocks() > info(RaptorqPreset::Default).sub_blocks()
+        );
+        // A cut RaptorQ can not encode falls back to the default: a GiB is too
+        // many symbols for a single block.
+        let single_block = RaptorqParams::Fixed {
+            source_blocks: 1,
+            sub_blocks: 1,
+            alignment: 8,
+        };
+        assert_eq!(
+            single_block.transmission_info(1 << 30, DEFAULT_FRAME_LEN as u16),
+            RaptorqParams::default().transmission_info(1 << 30, DEFAULT_FRAME_LEN as u16)
+        );
+    }
+
+    #[test]
+    fn seek_matches_init() {
+        let data = generate_random(CHUNK_SIZE);
+        let mut fresh = RaptorqSender::init(&data, 96);
+        let mut seeked = RaptorqSender::init(&data, 0);
+        seeked.next_frame();
+        seeked.seek(96);
+        for _ in 0..40 {
+            assert_eq!(fresh.next_frame(), seeked.next_frame());
+        }
+    }
+
     #[test]
     fn decoding() {
         let data = generate_random(CHUNK_SIZE);
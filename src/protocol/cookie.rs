@@ -0,0 +1,147 @@
+use std::net::{IpAddr, SocketAddr};
+use tokio::time::{Duration, Instant};
+
+use crate::constants::COOKIE_LENGTH;
+use crate::util::generate_random;
+
+// Same lifetime as WireGuard's cookie secret.
+const SECRET_ROTATE_AFTER: Duration = Duration::from_secs(120);
+const LOAD_WINDOW: Duration = Duration::from_secs(1);
+
+// Stateless cookies handed out to peers while the server is under load.
+// A cookie is a MAC over the peer's address with a secret only the server knows,
+// so echoing it back proves the peer can receive on the address it claims.
+pub struct CookieChecker {
+    secret: [u8; 32],
+    previous_secret: Option<[u8; 32]>,
+    secret_born: Instant,
+}
+
+impl Default for CookieChecker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn new_secret() -> [u8; 32] {
+    generate_random(32).try_into().unwrap()
+}
+
+fn mac(secret: &[u8; 32], addr: SocketAddr) -> [u8; COOKIE_LENGTH] {
+    let mut hasher = blake3::Hasher::new_keyed(secret);
+    match addr.ip() {
+        IpAddr::V4(ip) => hasher.update(&ip.octets()),
+        IpAddr::V6(ip) => hasher.update(&ip.octets()),
+    };
+    hasher.update(&addr.port().to_be_bytes());
+
+    let mut cookie = [0u8; COOKIE_LENGTH];
+    hasher.finalize_xof().fill(&mut cookie);
+    cookie
+}
+
+impl CookieChecker {
+    pub fn new() -> Self {
+        Self {
+            secret: new_secret(),
+            previous_secret: None,
+            secret_born: Instant::now(),
+        }
+    }
+
+    fn rotate(&mut self) {
+        if self.secret_born.elapsed() >= SECRET_ROTATE_AFTER {
+            self.previous_secret = Some(std::mem::replace(&mut self.secret, new_secret()));
+            self.secret_born = Instant::now();
+        }
+    }
+
+    pub fn make_cookie(&mut self, addr: SocketAddr) -> [u8; COOKIE_LENGTH] {
+        self.rotate();
+        mac(&self.secret, addr)
+    }
+
+    // Cookies minted with the previous secret are still honored, so a rotation
+    // does not bounce every peer at once.
+    pub fn check_cookie(&mut self, addr: SocketAddr, cookie: &[u8; COOKIE_LENGTH]) -> bool {
+        self.rotate();
+        mac(&self.secret, addr) == *cookie
+            || self
+                .previous_secret
+                .is_some_and(|secret| mac(&secret, addr) == *cookie)
+    }
+}
+
+// Counts expensive signature verifications within a sliding one-second window.
+pub struct LoadMonitor {
+    threshold: usize,
+    window_start: Instant,
+    count: usize,
+}
+
+impl LoadMonitor {
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            threshold,
+            window_start: Instant::now(),
+            count: 0,
+        }
+    }
+
+    pub fn record(&mut self) {
+        self.count += 1;
+    }
+
+    pub fn under_load(&mut self) -> bool {
+        if self.window_start.elapsed() >= LOAD_WINDOW {
+            self.window_start = Instant::now();
+            self.count = 0;
+        }
+        self.count >= self.threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cookie_bound_to_addr() {
+        let mut checker = CookieChecker::new();
+        let addr1: SocketAddr = "127.0.0.1:10000".parse().unwrap();
+        let addr2: SocketAddr = "127.0.0.1:10001".parse().unwrap();
+
+        let cookie = checker.make_cookie(addr1);
+        assert!(checker.check_cookie(addr1, &cookie));
+        assert!(!checker.check_cookie(addr2, &cookie));
+
+        let other = CookieChecker::new().make_cookie(addr1);
+        assert!(!checker.check_cookie(addr1, &other));
+    }
+
+    #[test]
+    fn cookie_survives_one_rotation() {
+        let mut checker = CookieChecker::new();
+        let addr: SocketAddr = "[::1]:7234".parse().unwrap();
+        let cookie = checker.make_cookie(addr);
+
+        checker.secret_born -= SECRET_ROTATE_AFTER;
+        assert!(checker.check_cookie(addr, &cookie));
+
+        checker.secret_born -= SECRET_ROTATE_AFTER;
+        assert!(!checker.check_cookie(addr, &cookie));
+    }
+
+    #[test]
+    fn load_monitor_threshold() {
+        let mut monitor = LoadMonitor::new(3);
+        for _ in 0..3 {
+            assert!(!monitor.under_load());
+            monitor.record();
+        }
+        assert!(monitor.under_load());
+
+        monitor.window_start -= LOAD_WINDOW;
+        assert!(!monitor.under_load());
+    }
+}
@@ -3,8 +3,11 @@ use ed25519_dalek::ed25519::signature::Signer;
 use ed25519_dalek::{PUBLIC_KEY_LENGTH, Signature, SigningKey, VerifyingKey};
 use log::warn;
 
-use std::collections::HashSet;
-use std::sync::OnceLock;
+use crate::util::log::current_timestamp_ms;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{OnceLock, RwLock};
 
 pub static KEY_RING: OnceLock<KeyRing> = OnceLock::new();
 
@@ -14,13 +17,19 @@ pub fn mock_init() {
     }
     const PRIKEY: &str = "fd9d88daa555f6bad0bbece8e0e4fffef190723e16aa9dfe0d18c8e4ff7a6eda";
     const PUBKEY: &str = "4ae6629e09372dd96196f35c032fd1c5da3dfe01ca40ecf8b268d78d741e9d1c";
-    init(vec![String::from(PUBKEY)], Some(String::from(PRIKEY)));
+    init(vec![String::from(PUBKEY)], vec![String::from(PRIKEY)]);
 }
 
 #[derive(Debug, Default)]
 pub struct KeyRing {
     pub public_key_rings: HashSet<VerifyingKey>,
-    private_key: Option<SigningKey>,
+    // Identities of a client, tickets are signed with the active one.
+    private_keys: Vec<SigningKey>,
+    active: AtomicUsize,
+    // Where rotating started, every identity has been tried once back there.
+    first: AtomicUsize,
+    // Keys of download tokens, accepted until the unix ms they expire.
+    granted: RwLock<HashMap<VerifyingKey, u64>>,
 }
 
 fn prase_key(key: &String) -> Option<[u8; KEY_LEN]> {
@@ -30,7 +39,7 @@ fn prase_key(key: &String) -> Option<[u8; KEY_LEN]> {
 }
 
 impl KeyRing {
-    pub fn new(public_keys: Vec<String>, private_key: Option<String>) -> Self {
+    pub fn new(public_keys: Vec<String>, private_keys: Vec<String>) -> Self {
         let public_key_rings = public_keys
             .iter()
             .map(|key| {
@@ -42,42 +51,134 @@ impl KeyRing {
             })
             .collect();
 
-        let private_key = private_key.as_ref().map(|key| {
-            prase_key(key)
-                .as_ref()
-                .map(SigningKey::from_bytes)
-                .unwrap_or_else(|| panic!("{key} is not a 256-bit Hex number."))
-        });
+        let private_keys = private_keys
+            .iter()
+            .map(|key| {
+                prase_key(key)
+                    .as_ref()
+                    .map(SigningKey::from_bytes)
+                    .unwrap_or_else(|| panic!("{key} is not a 256-bit Hex number."))
+            })
+            .collect();
         Self {
             public_key_rings,
-            private_key,
+            private_keys,
+            active: AtomicUsize::new(0),
+            first: AtomicUsize::new(0),
+            granted: RwLock::default(),
         }
     }
     pub fn add_public_key(mut self, key: VerifyingKey) -> Self {
         self.public_key_rings.insert(key);
         self
     }
+    pub fn grant(&self, key: VerifyingKey, expires_ms: u64) {
+        let now_ms = current_timestamp_ms();
+        let mut granted = self.granted.write().unwrap();
+        granted.retain(|_, expires_ms| *expires_ms > now_ms);
+        granted.insert(key, expires_ms);
+    }
+
+    // Authorized, or granted by a download token that has not expired.
+    pub fn accepts(&self, key: &VerifyingKey) -> bool {
+        self.public_key_rings.contains(key)
+            || self
+                .granted
+                .read()
+                .unwrap()
+                .get(key)
+                .is_some_and(|expires_ms| current_timestamp_ms() < *expires_ms)
+    }
+
     pub fn set_private_key(mut self, key: SigningKey) -> Self {
-        self.private_key = Some(key);
+        self.private_keys = vec![key];
+        self.active = AtomicUsize::new(0);
+        self.first = AtomicUsize::new(0);
         self
     }
+
+    fn private_key(&self) -> Option<&SigningKey> {
+        self.private_keys.get(self.active.load(Ordering::Relaxed))
+    }
+
     pub fn sign_with_private_key(&self, content: &[u8]) -> Option<Signature> {
-        self.private_key.as_ref().map(|key| key.sign(content))
+        self.private_key().map(|key| key.sign(content))
     }
 
     pub fn derive_public_key(&self) -> Option<[u8; PUBLIC_KEY_LENGTH]> {
-        self.private_key
-            .as_ref()
-            .map(|key| key.verifying_key().to_bytes())
+        self.private_key().map(|key| key.verifying_key().to_bytes())
+    }
+
+    pub fn private_key_count(&self) -> usize {
+        self.private_keys.len()
+    }
+
+    pub fn select_private_key(&self, index: usize) {
+        let index = index % self.private_keys.len().max(1);
+        self.active.store(index, Ordering::Relaxed);
+        self.first.store(index, Ordering::Relaxed);
+    }
+
+    // Moves on to the next identity, wrapping around, false once every one
+    // has been tried.
+    pub fn rotate_private_key(&self) -> bool {
+        let next = (self.active.load(Ordering::Relaxed) + 1) % self.private_keys.len().max(1);
+        if next == self.first.load(Ordering::Relaxed) {
+            return false;
+        }
+        self.active.store(next, Ordering::Relaxed);
+        true
     }
 }
 
 // Panic on second call!
-pub fn init(public_keys: Vec<String>, private_key: Option<String>) {
+pub fn init(public_keys: Vec<String>, private_keys: Vec<String>) {
     if KEY_RING
-        .set(KeyRing::new(public_keys, private_key))
+        .set(KeyRing::new(public_keys, private_keys))
         .is_err()
     {
         warn!("Second initialization!")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotate_through_identities() {
+        let keys = (0..3)
+            .map(|i| hex::encode([i as u8 + 1; 32]))
+            .collect::<Vec<_>>();
+        let key_ring = KeyRing::new(vec![], keys);
+        let first = key_ring.derive_public_key();
+
+        assert!(key_ring.rotate_private_key());
+        assert_ne!(key_ring.derive_public_key(), first);
+        assert!(key_ring.rotate_private_key());
+        assert!(!key_ring.rotate_private_key());
+
+        key_ring.select_private_key(3);
+        assert_eq!(key_ring.derive_public_key(), first);
+    }
+
+    #[test]
+    fn rotate_from_the_middle_wraps_around() {
+        let keys = (0..3)
+            .map(|i| hex::encode([i as u8 + 1; 32]))
+            .collect::<Vec<_>>();
+        let key_ring = KeyRing::new(vec![], keys);
+        let first = key_ring.derive_public_key();
+        key_ring.select_private_key(1);
+        let middle = key_ring.derive_public_key();
+
+        let mut tried = vec![middle];
+        while key_ring.rotate_private_key() {
+            tried.push(key_ring.derive_public_key());
+        }
+        assert_eq!(tried.len(), 3);
+        assert!(tried.contains(&first));
+        assert_eq!(key_ring.derive_public_key(), tried[2]);
+        assert!(!key_ring.rotate_private_key());
+    }
+}
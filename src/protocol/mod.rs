@@ -1,6 +1,12 @@
 pub mod coding;
+#[cfg(feature = "runtime")]
+pub(crate) mod cookie;
 
 mod key_ring;
+pub mod quota;
+pub mod token;
 pub mod wire;
 
-pub use key_ring::{init, mock_init};
+pub use key_ring::{KEY_RING, init};
+#[cfg(any(test, feature = "internals"))]
+pub use key_ring::mock_init;
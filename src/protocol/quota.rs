@@ -0,0 +1,183 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{self, Error};
+use std::path::PathBuf;
+
+use crate::util::log::current_timestamp_ms;
+use crate::util::plan::parse_size;
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+// Byte limits of one public key, unlimited if None. Days are UTC days.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub daily: Option<u64>,
+    pub total: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Usage {
+    total: u64,
+    day: u64,
+    today: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct UsageFile {
+    keys: HashMap<String, Usage>,
+}
+
+// A line of the authorized key file: the hex key, optionally followed by
+// `daily=SIZE` and/or `total=SIZE`, where SIZE is as `parse_size` takes it.
+pub fn parse_key_line(line: &str) -> Result<(String, Quota), String> {
+    let mut fields = line.split_whitespace();
+    let key = fields.next().ok_or("Empty key line")?.to_ascii_lowercase();
+    let mut quota = Quota::default();
+    for field in fields {
+        let (name, size) = field
+            .split_once('=')
+            .ok_or_else(|| format!("Expected NAME=SIZE, got {field}"))?;
+        let size = parse_size(size).map_err(|err| format!("Invalid size {size}, {err}"))?;
+        match name {
+            "daily" => quota.daily = Some(size),
+            "total" => quota.total = Some(size),
+            _ => return Err(format!("Unknown quota {name}")),
+        }
+    }
+    Ok((key, quota))
+}
+
+// Usage of every key with a quota, kept in a TOML file so restarts do not reset it.
+#[derive(Default)]
+pub struct QuotaBook {
+    limits: HashMap<String, Quota>,
+    usage: HashMap<String, Usage>,
+    path: Option<PathBuf>,
+    dirty: bool,
+}
+
+impl QuotaBook {
+    pub fn load(limits: HashMap<String, Quota>, path: Option<PathBuf>) -> io::Result<Self> {
+        let usage = match &path {
+            Some(path) if path.exists() => {
+                toml::from_str::<UsageFile>(&std::fs::read_to_string(path)?)
+                    .map_err(Error::other)?
+                    .keys
+            }
+            _ => HashMap::new(),
+        };
+        Ok(Self {
+            limits,
+            usage,
+            path,
+            dirty: false,
+        })
+    }
+
+    fn usage_today(&mut self, key: &str, now_ms: u64) -> &mut Usage {
+        let usage = self.usage.entry(key.to_string()).or_default();
+        if usage.day != now_ms / DAY_MS {
+            usage.day = now_ms / DAY_MS;
+            usage.today = 0;
+        }
+        usage
+    }
+
+    // For keys learned after loading, e.g. of download tokens.
+    pub fn set_limit(&mut self, key: &str, quota: Quota) {
+        self.limits.insert(key.to_string(), quota);
+    }
+
+    pub fn charge(&mut self, key: &str, bytes: u64) {
+        if bytes == 0 || !self.limits.contains_key(key) {
+            return;
+        }
+        let usage = self.usage_today(key, current_timestamp_ms());
+        usage.total += bytes;
+        usage.today += bytes;
+        self.dirty = true;
+    }
+
+    pub fn exceeded(&mut self, key: &str) -> bool {
+        let Some(quota) = self.limits.get(key).copied() else {
+            return false;
+        };
+        let usage = *self.usage_today(key, current_timestamp_ms());
+        quota.daily.is_some_and(|daily| usage.today >= daily)
+            || quota.total.is_some_and(|total| usage.total >= total)
+    }
+
+    // Writes the usage out if it changed since the last save.
+    pub fn save(&mut self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+        let file = UsageFile {
+            keys: self.usage.clone(),
+        };
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, toml::to_string(&file).map_err(Error::other)?)?;
+        std::fs::rename(tmp, path)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn parse_key_lines() {
+        assert_eq!(
+            parse_key_line("abcd"),
+            Ok((String::from("abcd"), Quota::default()))
+        );
+        assert_eq!(
+            parse_key_line("abcd daily=10G total=1024"),
+            Ok((
+                String::from("abcd"),
+                Quota {
+                    daily: Some(10 << 30),
+                    total: Some(1024)
+                }
+            ))
+        );
+        // Sizes read as on the command line.
+        assert_eq!(
+            parse_key_line("abcd daily=10g").map(|(_, quota)| quota.daily),
+            Ok(Some(10 << 30))
+        );
+        assert!(parse_key_line("abcd weekly=1G").is_err());
+        assert!(parse_key_line("abcd daily=1X").is_err());
+    }
+
+    #[test]
+    fn quota_persists() -> io::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("quota.toml");
+        let limits = HashMap::from([(
+            String::from("abcd"),
+            Quota {
+                daily: None,
+                total: Some(100),
+            },
+        )]);
+
+        let mut book = QuotaBook::load(limits.clone(), Some(path.clone()))?;
+        book.charge("abcd", 60);
+        book.charge("other", 1000);
+        assert!(!book.exceeded("abcd"));
+        assert!(!book.exceeded("other"));
+        book.save()?;
+
+        let mut book = QuotaBook::load(limits, Some(path))?;
+        book.charge("abcd", 40);
+        assert!(book.exceeded("abcd"));
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use ed25519_dalek::{SECRET_KEY_LENGTH, Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use zerocopy::{FromBytes, IntoBytes};
+
+use super::KEY_RING;
+use super::wire::frames::{GrantFrame, ParsedFrameVariant};
+use super::wire::packets::ParsedPacketVariant;
+
+const TOKEN_PREFIX: &str = "usync-token-";
+
+// Names the file of a plan, so a token only opens the file it was minted for.
+pub fn file_id(file_name: &str) -> u64 {
+    let hash = blake3::hash(file_name.as_bytes());
+    u64::from_be_bytes(hash.as_bytes()[..8].try_into().unwrap())
+}
+
+fn signed_content(key: &VerifyingKey, grant: &GrantFrame) -> Vec<u8> {
+    let mut content = key.to_bytes().to_vec();
+    content.extend_from_slice(grant.file_id.as_bytes());
+    content.extend_from_slice(grant.expires_ms.as_bytes());
+    content.extend_from_slice(grant.max_bytes.as_bytes());
+    content
+}
+
+// Lets whoever holds it download one file for a while, without a key in the
+// server's authorized key file. A token carries a fresh private key for the
+// client to sign its tickets with, and the server's grant for that key, sent
+// along in every ticket.
+pub struct DownloadToken {
+    pub key: SigningKey,
+    pub grant: GrantFrame,
+}
+
+impl DownloadToken {
+    // `issuer` is the server's token key. No more than `max_bytes` are served
+    // for the token if given.
+    pub fn mint(
+        issuer: &SigningKey,
+        file_id: u64,
+        expires_ms: u64,
+        max_bytes: Option<u64>,
+    ) -> Self {
+        let key = SigningKey::from_bytes(&rand::random());
+        let mut grant = GrantFrame {
+            file_id: file_id.into(),
+            expires_ms: expires_ms.into(),
+            max_bytes: max_bytes.unwrap_or_default().into(),
+            signature: [0; 64],
+        };
+        grant.signature = issuer
+            .sign(&signed_content(&key.verifying_key(), &grant))
+            .to_bytes();
+        Self { key, grant }
+    }
+
+    pub fn encode(&self) -> String {
+        let mut bytes = self.key.to_bytes().to_vec();
+        bytes.extend_from_slice(self.grant.as_bytes());
+        format!("{TOKEN_PREFIX}{}", URL_SAFE_NO_PAD.encode(bytes))
+    }
+
+    pub fn decode(token: &str) -> Option<Self> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(token.trim().strip_prefix(TOKEN_PREFIX)?)
+            .ok()?;
+        let (key, grant) = bytes.split_at_checked(SECRET_KEY_LENGTH)?;
+        Some(Self {
+            key: SigningKey::from_bytes(key.try_into().ok()?),
+            grant: GrantFrame::read_from_bytes(grant).ok()?,
+        })
+    }
+
+    pub fn max_bytes(&self) -> Option<u64> {
+        Some(self.grant.max_bytes.get()).filter(|max_bytes| *max_bytes > 0)
+    }
+}
+
+// Lets the keys of valid tokens for one file in, on the server.
+#[derive(Clone)]
+pub struct TokenChecker {
+    issuer: VerifyingKey,
+    file_id: u64,
+}
+
+impl TokenChecker {
+    pub fn new(issuer: VerifyingKey, file_id: u64) -> Self {
+        Self { issuer, file_id }
+    }
+
+    // Whether `grant` was signed by the issuer for `key`, for this file, and has not expired.
+    pub fn check(&self, key: &VerifyingKey, grant: &GrantFrame, now_ms: u64) -> bool {
+        grant.file_id.get() == self.file_id
+            && now_ms < grant.expires_ms.get()
+            && self
+                .issuer
+                .verify(
+                    &signed_content(key, grant),
+                    &Signature::from_bytes(&grant.signature),
+                )
+                .is_ok()
+    }
+
+    // Adds the key of a ticket carrying a valid grant to the key ring until the
+    // grant expires. Returns the hex key and its byte limit, if any. Called before
+    // the ticket is verified, which then checks the ticket was signed by that key.
+    pub fn admit<const INFO_LENGTH: usize>(
+        &self,
+        header: &ParsedPacketVariant,
+        frames: &[ParsedFrameVariant<INFO_LENGTH>],
+        now_ms: u64,
+    ) -> Option<(String, Option<u64>)> {
+        let ParsedPacketVariant::TicketPacket { pub_key, .. } = header else {
+            return None;
+        };
+        let grant = frames.iter().find_map(|frame| match frame {
+            ParsedFrameVariant::Grant(grant) => Some(grant),
+            _ => None,
+        })?;
+        let key = VerifyingKey::try_from(pub_key.as_ref()).ok()?;
+        if !self.check(&key, grant, now_ms) {
+            return None;
+        }
+        KEY_RING.get()?.grant(key, grant.expires_ms.get());
+        let max_bytes = Some(grant.max_bytes.get()).filter(|max_bytes| *max_bytes > 0);
+        Some((hex::encode(pub_key), max_bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_open_one_file_until_they_expire() {
+        let issuer = SigningKey::from_bytes(&[7; 32]);
+        let checker = TokenChecker::new(issuer.verifying_key(), file_id("movie.mkv"));
+        let token = DownloadToken::mint(&issuer, file_id("movie.mkv"), 2_000, Some(1 << 20));
+
+        let token = DownloadToken::decode(&token.encode()).unwrap();
+        let key = token.key.verifying_key();
+        assert_eq!(token.max_bytes(), Some(1 << 20));
+        assert!(checker.check(&key, &token.grant, 1_999));
+        assert!(!checker.check(&key, &token.grant, 2_000));
+
+        // Minted for another file, by another issuer, or handed to another key.
+        let other_file = DownloadToken::mint(&issuer, file_id("other.iso"), 2_000, None);
+        assert!(!checker.check(&other_file.key.verifying_key(), &other_file.grant, 0));
+        let forged = DownloadToken::mint(
+            &SigningKey::from_bytes(&[8; 32]),
+            file_id("movie.mkv"),
+            2_000,
+            None,
+        );
+        assert!(!checker.check(&forged.key.verifying_key(), &forged.grant, 0));
+        assert!(!checker.check(&forged.key.verifying_key(), &token.grant, 0));
+
+        let mut extended = token.grant;
+        extended.expires_ms = 9_000.into();
+        assert!(!checker.check(&key, &extended, 2_500));
+        assert!(DownloadToken::decode("usync-token-garbage").is_none());
+    }
+}
@@ -1,13 +1,16 @@
 use bytes::{Buf, Bytes, BytesMut};
 
-use crate::constants::VERSION;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::constants::{MTU, VERSION};
 use crate::protocol::key_ring::KEY_RING;
 
 use crate::protocol::wire::{
-    BuiltFrame, CommonFrameHeader, CommonPacketHeader, Frame, FrameType, Packet, PacketType,
-    ParsedFrameVariant, ParsedPacketVariant, SpecificFrameHeader, verify::PacketVerificationError,
+    BuiltFrame, CommonFrameHeader, CommonPacketHeader, Frame, FrameType, Packet, PacketIds,
+    PacketType, ParsedFrameVariant, ParsedPacketVariant, SpecificFrameHeader,
+    frames::is_ignorable_frame_type, verify::PacketVerificationError,
 };
-use crate::util::log::packet_log;
 
 use zerocopy::{FromBytes, Immutable, IntoBytes, TryFromBytes, Unaligned};
 
@@ -19,7 +22,17 @@ pub trait RawParts: IntoBytes + FromBytes + Unaligned + Sized + Immutable {
 impl<T> RawParts for T where T: IntoBytes + FromBytes + Unaligned + Immutable {}
 
 pub(crate) trait PacketExt: Packet {
+    // Numbered outside any session, for packets of no peer in particular.
     fn build(self) -> (Vec<Bytes>, u32) {
+        self.build_with_id(super::new_packet_id())
+    }
+
+    // Numbered in the session of `ids`.
+    fn build_in(self, ids: &PacketIds) -> (Vec<Bytes>, u32) {
+        self.build_with_id(ids.next_id())
+    }
+
+    fn build_with_id(self, packet_id: u32) -> (Vec<Bytes>, u32) {
         let header_length = (
             CommonPacketHeader::raw_len(),
             <Self as Packet>::Header::raw_len(),
@@ -46,9 +59,19 @@ pub(crate) trait PacketExt: Packet {
             packet_type: packet_type.into(),
             header_length: ((header_length.0 + header_length.1) as u16).into(),
             body_length: (body_length as u16).into(),
-            packet_id: super::new_packet_id().into(),
+            packet_id: packet_id.into(),
         };
         let packet_id = packet_header.packet_id;
+        // Layouts are checked against the MTU when compiling, see `layout`, a
+        // packet still outgrows it with too many frames.
+        debug_assert!(
+            header_length.0
+                + header_length.1
+                + body_length
+                + Self::PACKET_VERIFICATION_TYPE.signature_len()
+                <= MTU,
+            "{packet_type:?} packet of {body_length} bytes of frames exceeds the MTU"
+        );
 
         let mut common_header = BytesMut::with_capacity(header_length.0);
         common_header.extend_from_slice(packet_header.as_bytes());
@@ -71,6 +94,10 @@ pub(super) trait FrameExt: Frame {
         CommonFrameHeader::raw_len() + <Self as Frame>::Header::raw_len()
     }
 
+    fn wire_len(&self) -> usize {
+        self.total_header_len() + self.body_len()
+    }
+
     fn build(self) -> BuiltFrame {
         let header_length = self.total_header_len();
 
@@ -119,10 +146,60 @@ pub enum ParseError {
     Verification(PacketVerificationError),
     FailedToParsePacketHeader,
     FailedToParseFrame,
+    CookieRequired(u64), // Echoes the ticket timestamp
+}
+
+// How packets off the spec in harmless ways are taken.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ParseMode {
+    // Any inconsistency rejects the packet.
+    #[default]
+    Strict,
+    // Bytes past the verification field, frames of unknown type and a zero
+    // frame length, taken as the end of the frames, are let through and
+    // counted. For debugging against other implementations.
+    Lenient,
+}
+
+static LENIENT: AtomicBool = AtomicBool::new(false);
+static TRAILING_BYTES: AtomicU64 = AtomicU64::new(0);
+static UNKNOWN_FRAMES: AtomicU64 = AtomicU64::new(0);
+static EMPTY_FRAMES: AtomicU64 = AtomicU64::new(0);
+
+// For every packet parsed from here on, on any thread.
+pub fn set_parse_mode(mode: ParseMode) {
+    LENIENT.store(mode == ParseMode::Lenient, Ordering::Relaxed);
+}
+
+pub fn parse_mode() -> ParseMode {
+    if LENIENT.load(Ordering::Relaxed) {
+        ParseMode::Lenient
+    } else {
+        ParseMode::Strict
+    }
+}
+
+// What lenient parsing let through so far, all zero in strict mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ToleratedCounts {
+    // Packets with bytes past their verification field.
+    pub trailing_bytes: u64,
+    pub unknown_frames: u64,
+    pub empty_frames: u64,
+}
+
+pub fn tolerated() -> ToleratedCounts {
+    ToleratedCounts {
+        trailing_bytes: TRAILING_BYTES.load(Ordering::Relaxed),
+        unknown_frames: UNKNOWN_FRAMES.load(Ordering::Relaxed),
+        empty_frames: EMPTY_FRAMES.load(Ordering::Relaxed),
+    }
 }
 
 fn parse_frame<const INFO_LENGTH: usize>(
     mut remained_body: Bytes,
+    mode: ParseMode,
 ) -> Result<Vec<ParsedFrameVariant<INFO_LENGTH>>, ParseError> {
     let mut frames = vec![];
 
@@ -132,16 +209,30 @@ fn parse_frame<const INFO_LENGTH: usize>(
                 .map_err(|_| ParseError::BodyTooshort)?;
         let frame_type = common_frame_header.frame_type;
         let frame_length = u16::from(common_frame_header.frame_length) as usize;
+        if frame_length == 0 && mode == ParseMode::Lenient {
+            EMPTY_FRAMES.fetch_add(1, Ordering::Relaxed);
+            break;
+        }
 
-        let current_frame = if frame_length < CommonFrameHeader::raw_len() {
-            eprintln!("Insane frame length");
-            return Err(ParseError::BodyTooshort);
-        } else {
-            &remained_body[CommonFrameHeader::raw_len()..frame_length]
+        let current_frame =
+            if frame_length < CommonFrameHeader::raw_len() || frame_length > remained_body.len() {
+                eprintln!("Insane frame length");
+                return Err(ParseError::BodyTooshort);
+            } else {
+                &remained_body[CommonFrameHeader::raw_len()..frame_length]
+            };
+
+        let Ok(known_type) = FrameType::try_from(frame_type) else {
+            if !is_ignorable_frame_type(frame_type) {
+                if mode == ParseMode::Strict {
+                    return Err(ParseError::UnsupportedFrameType(frame_type));
+                }
+                UNKNOWN_FRAMES.fetch_add(1, Ordering::Relaxed);
+            }
+            remained_body.advance(frame_length);
+            continue;
         };
-
-        let current_frame = FrameType::try_from(frame_type)
-            .map_err(|_| ParseError::UnsupportedFrameType(frame_type))?
+        let current_frame = known_type
             .try_parse(remained_body.slice_ref(current_frame))
             .ok_or(ParseError::UnsupportedFrameType(frame_type))?;
 
@@ -154,6 +245,29 @@ fn parse_frame<const INFO_LENGTH: usize>(
 
 pub fn parse_packet<const INFO_LENGTH: usize>(
     packet: Bytes,
+) -> Result<ParsedPacket<INFO_LENGTH>, ParseError> {
+    parse_packet_with_precheck(packet, |_, _| Ok(()))
+}
+
+// `precheck` runs on the parsed header and frames before the packet is verified,
+// so cheap rejections never pay for a signature check.
+pub fn parse_packet_with_precheck<const INFO_LENGTH: usize>(
+    packet: Bytes,
+    precheck: impl FnOnce(
+        &ParsedPacketVariant,
+        &[ParsedFrameVariant<INFO_LENGTH>],
+    ) -> Result<(), ParseError>,
+) -> Result<ParsedPacket<INFO_LENGTH>, ParseError> {
+    parse_packet_in_mode(packet, parse_mode(), precheck)
+}
+
+fn parse_packet_in_mode<const INFO_LENGTH: usize>(
+    packet: Bytes,
+    mode: ParseMode,
+    precheck: impl FnOnce(
+        &ParsedPacketVariant,
+        &[ParsedFrameVariant<INFO_LENGTH>],
+    ) -> Result<(), ParseError>,
 ) -> Result<ParsedPacket<INFO_LENGTH>, ParseError> {
     let (common_packet_header, _) = CommonPacketHeader::try_ref_from_prefix(packet.as_bytes())
         .map_err(|_| ParseError::PacketTooShort)?;
@@ -171,9 +285,6 @@ pub fn parse_packet<const INFO_LENGTH: usize>(
         &packet[header_length + body_length..]
     };
 
-    // LOG here!
-    packet_log(u32::from(common_packet_header.packet_id), 0x19260817);
-
     let specific_packet_header = if header_length < CommonPacketHeader::raw_len() {
         eprintln!("Insane packet header length");
         return Err(ParseError::InconsistentFields);
@@ -186,6 +297,21 @@ pub fn parse_packet<const INFO_LENGTH: usize>(
         .try_parse::<INFO_LENGTH>(packet.slice_ref(specific_packet_header))
         .ok_or(ParseError::FailedToParsePacketHeader)?;
 
+    let remained_body = packet.slice_ref(&packet[header_length..header_length + body_length]);
+
+    let frames = parse_frame(remained_body, mode)?;
+
+    let signature_len = packet_variant.verify_type().signature_len();
+    let verification_field = match mode {
+        ParseMode::Lenient if verification_field.len() > signature_len => {
+            TRAILING_BYTES.fetch_add(1, Ordering::Relaxed);
+            &verification_field[..signature_len]
+        }
+        _ => verification_field,
+    };
+
+    precheck(&packet_variant, &frames)?;
+
     KEY_RING
         .get()
         .unwrap()
@@ -197,9 +323,6 @@ pub fn parse_packet<const INFO_LENGTH: usize>(
         )
         .map_err(ParseError::Verification)?;
 
-    let remained_body = packet.slice_ref(&packet[header_length..header_length + body_length]);
-
-    let frames = parse_frame(remained_body)?;
     Ok(ParsedPacket {
         pkt: packet,
         specific_packet_header: packet_variant,
@@ -216,12 +339,13 @@ mod tests {
     use crate::protocol::key_ring::mock_init;
     use crate::protocol::wire::frames::{GetChunkFrameHeader, ParsedFrameVariant};
     use crate::util::log::current_timestamp_ms;
+    use crate::util::units::ChunkId;
     use bytes::BytesMut;
 
     fn build_into_bytes(vec: Vec<Bytes>) -> Bytes {
         let mut total_packet = BytesMut::new();
         for item in vec.iter() {
-            total_packet.extend_from_slice(&item);
+            total_packet.extend_from_slice(item);
         }
         total_packet.freeze()
     }
@@ -233,7 +357,7 @@ mod tests {
         use crate::protocol::wire::packets::DataPacket;
         let mock_data: Vec<u8> = vec![88; DEFAULT_FRAME_LEN];
         let data_packet = DataPacket::new(
-            19260817,
+            ChunkId(19260817),
             85213,
             [7u8; TRANSMISSION_INFO_LENGTH],
             mock_data.clone(),
@@ -249,12 +373,13 @@ mod tests {
         );
 
         assert!(total_packet.len() <= MTU);
+        let offset = crate::protocol::wire::DATA_CHUNK_ID_OFFSET;
+        assert_eq!(total_packet[offset..offset + 4], 19260817u32.to_be_bytes());
 
-        let parsed_packet =
-            parse_packet::<TRANSMISSION_INFO_LENGTH>(Bytes::from(total_packet)).unwrap();
+        let parsed_packet = parse_packet::<TRANSMISSION_INFO_LENGTH>(total_packet).unwrap();
 
         if let ParsedFrameVariant::Data(data_frame) = &parsed_packet.frames[0] {
-            assert_eq!(19260817, data_frame.chunk_id);
+            assert_eq!(ChunkId(19260817), data_frame.chunk_id);
             assert_eq!(85213, data_frame.frame_offset);
 
             assert_eq!(mock_data, data_frame.data);
@@ -263,6 +388,47 @@ mod tests {
         }
     }
 
+    #[test]
+    fn padded_data_packets_share_one_size() {
+        mock_init();
+        use crate::protocol::wire::packets::DataPacket;
+
+        let packets = [
+            DataPacket::new(
+                ChunkId(1),
+                0,
+                [7u8; TRANSMISSION_INFO_LENGTH],
+                vec![1; DEFAULT_FRAME_LEN],
+            ),
+            DataPacket::new(ChunkId(2), 9, [7u8; TRANSMISSION_INFO_LENGTH], vec![2; 100]),
+            DataPacket::cover(),
+        ];
+        for packet in packets {
+            let packet = packet.padded_to(MTU);
+            assert_eq!(packet.wire_len(), MTU);
+            let built = build_into_bytes(packet.build().0);
+            assert_eq!(built.len(), MTU);
+            let parsed = parse_packet::<TRANSMISSION_INFO_LENGTH>(built).unwrap();
+            assert!(matches!(
+                parsed.frames.last(),
+                Some(ParsedFrameVariant::Padding)
+            ));
+            if let Some(ParsedFrameVariant::Data(frame)) = parsed.frames.first() {
+                assert!(
+                    frame
+                        .data
+                        .iter()
+                        .all(|byte| *byte as u32 == frame.chunk_id.0)
+                );
+            }
+        }
+
+        // Too close to the size for a padding frame.
+        let packet = DataPacket::new(ChunkId(3), 0, [7u8; TRANSMISSION_INFO_LENGTH], vec![3; 100]);
+        let unpadded = packet.wire_len();
+        assert_eq!(packet.padded_to(unpadded + 2).wire_len(), unpadded);
+    }
+
     #[test]
     fn build_parse_ticket_packet() {
         mock_init();
@@ -272,16 +438,18 @@ mod tests {
 
         let packet = TicketPacket::new()
             .set_rate_limit(80000)
-            .set_get_chunk(8, 75, 400) // Should be shadowed!
-            .set_get_chunk(17, 2334, 800)
-            .set_get_chunk(8, 234, 600)
+            .set_congestion(3, 1000)
+            .set_prefetch([ChunkId(21), ChunkId(22)])
+            .set_get_chunk(ChunkId(8), 75, 400) // Should be shadowed!
+            .set_get_chunk(ChunkId(17), 2334, 800)
+            .set_get_chunk(ChunkId(8), 234, 600)
+            .set_generation(7)
             .build();
 
         let total_packet = build_into_bytes(packet.0);
         assert!(total_packet.len() <= MTU);
 
-        let parsed_packet =
-            parse_packet::<TRANSMISSION_INFO_LENGTH>(Bytes::from(total_packet)).unwrap();
+        let parsed_packet = parse_packet::<TRANSMISSION_INFO_LENGTH>(total_packet).unwrap();
 
         let current_time = current_timestamp_ms();
 
@@ -303,6 +471,8 @@ mod tests {
         expected.insert(8, (234, 600));
         expected.insert(17, (2334, 800));
         let mut rate_limit = None;
+        let mut congestion = None;
+        let mut prefetch = vec![];
 
         for frame in parsed_packet.frames {
             match frame {
@@ -313,14 +483,23 @@ mod tests {
                             .is_none()
                     )
                 }
+                ParsedFrameVariant::Prefetch(header) => prefetch.push(u32::from(header.chunk_id)),
+                ParsedFrameVariant::Congestion(header) => {
+                    congestion = Some((
+                        u32::from(header.ce_packets),
+                        u32::from(header.total_packets),
+                    ))
+                }
                 ParsedFrameVariant::GetChunk(GetChunkFrameHeader {
                     chunk_id,
                     next_receive_offset,
                     receive_window_frames,
+                    generation,
                 }) => {
                     let expected_entry = expected.remove(&u32::from(chunk_id)).unwrap();
                     assert_eq!(expected_entry.0, u32::from(next_receive_offset));
                     assert_eq!(expected_entry.1, u32::from(receive_window_frames));
+                    assert_eq!(u64::from(generation), 7);
                 }
                 _ => unreachable!(),
             }
@@ -328,5 +507,328 @@ mod tests {
 
         assert_eq!(expected.len(), 0);
         assert_eq!(rate_limit, Some(80000));
+        assert_eq!(congestion, Some((3, 1000)));
+        assert_eq!(prefetch, vec![21, 22]);
+    }
+
+    #[test]
+    fn oversized_tickets_split() {
+        mock_init();
+        use crate::protocol::wire::layout::TICKET_CHUNKS;
+        use crate::protocol::wire::packets::TicketPacket;
+
+        let chunks = 2 * TICKET_CHUNKS + 1;
+        let ticket = (0..chunks as u32)
+            .fold(TicketPacket::new(), |ticket, chunk_id| {
+                ticket.set_get_chunk(ChunkId(chunk_id), chunk_id, 100)
+            })
+            .set_rate_limit(80000)
+            .set_congestion(3, 1000)
+            .set_generation(7);
+        let tickets = ticket.split();
+        assert_eq!(tickets.len(), 3);
+
+        let mut requested = vec![];
+        for (i, ticket) in tickets.into_iter().enumerate() {
+            let total_packet = build_into_bytes(ticket.build().0);
+            assert!(total_packet.len() <= MTU);
+            let parsed_packet = parse_packet::<TRANSMISSION_INFO_LENGTH>(total_packet).unwrap();
+            let (mut rate_limit, mut congestion) = (None, None);
+            for frame in parsed_packet.frames {
+                match frame {
+                    ParsedFrameVariant::RateLimit(header) => {
+                        rate_limit = Some(u32::from(header.desired_max_kbps))
+                    }
+                    ParsedFrameVariant::Congestion(_) => congestion = Some(i),
+                    ParsedFrameVariant::GetChunk(header) => {
+                        assert_eq!(u64::from(header.generation), 7);
+                        requested.push(u32::from(header.chunk_id));
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            assert_eq!(rate_limit, Some(80000));
+            assert_eq!(congestion, (i == 0).then_some(0));
+        }
+        requested.sort();
+        assert_eq!(requested, (0..chunks as u32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn wide_rate_needs_the_feature() {
+        mock_init();
+        use crate::protocol::wire::frames::FEATURE_WIDE_FIELDS;
+        use crate::protocol::wire::packets::TicketPacket;
+
+        let requested = |packet: TicketPacket| {
+            let parsed =
+                parse_packet::<TRANSMISSION_INFO_LENGTH>(build_into_bytes(packet.build().0))
+                    .unwrap();
+            parsed.frames.iter().find_map(|frame| match frame {
+                ParsedFrameVariant::RateLimit(header) => {
+                    Some((false, u64::from(u32::from(header.desired_max_kbps))))
+                }
+                ParsedFrameVariant::WideRateLimit(header) => {
+                    Some((true, u64::from(header.desired_max_kbps)))
+                }
+                _ => None,
+            })
+        };
+
+        let fast = 6_000_000_000;
+        assert_eq!(
+            requested(TicketPacket::new().set_rate_limit(fast)),
+            Some((false, u32::MAX.into()))
+        );
+        assert_eq!(
+            requested(
+                TicketPacket::new()
+                    .set_rate_limit(fast)
+                    .set_features(FEATURE_WIDE_FIELDS)
+            ),
+            Some((true, fast))
+        );
+        // Rates that fit go narrow, which every server reads.
+        assert_eq!(
+            requested(
+                TicketPacket::new()
+                    .set_rate_limit(80000)
+                    .set_features(FEATURE_WIDE_FIELDS)
+            ),
+            Some((false, 80000))
+        );
+    }
+
+    #[test]
+    fn cookie_precheck_before_verification() {
+        mock_init();
+        use crate::protocol::wire::packets::{CookieReplyPacket, TicketPacket};
+
+        let cookie = [0x5a; COOKIE_LENGTH];
+        let reply = build_into_bytes(CookieReplyPacket::new(cookie, 1234).build().0);
+        let parsed_reply = parse_packet::<TRANSMISSION_INFO_LENGTH>(reply).unwrap();
+        assert!(matches!(
+            parsed_reply.specific_packet_header,
+            ParsedPacketVariant::CookieReplyPacket {
+                cookie: c,
+                echo_timestamp_ms: 1234
+            } if c == cookie
+        ));
+
+        let has_cookie = |frames: &[ParsedFrameVariant<TRANSMISSION_INFO_LENGTH>]| {
+            frames
+                .iter()
+                .any(|frame| matches!(frame, ParsedFrameVariant::Cookie(header) if header.cookie == cookie))
+        };
+
+        let without_cookie = build_into_bytes(
+            TicketPacket::new()
+                .set_get_chunk(ChunkId(1), 0, 8)
+                .build()
+                .0,
+        );
+        let err =
+            parse_packet_with_precheck::<TRANSMISSION_INFO_LENGTH>(without_cookie, |_, frames| {
+                has_cookie(frames)
+                    .then_some(())
+                    .ok_or(ParseError::CookieRequired(0))
+            })
+            .unwrap_err();
+        assert!(matches!(err, ParseError::CookieRequired(0)));
+
+        let with_cookie = build_into_bytes(
+            TicketPacket::new()
+                .set_cookie(Some(cookie))
+                .set_get_chunk(ChunkId(1), 0, 8)
+                .build()
+                .0,
+        );
+        parse_packet_with_precheck::<TRANSMISSION_INFO_LENGTH>(with_cookie, |_, frames| {
+            has_cookie(frames)
+                .then_some(())
+                .ok_or(ParseError::CookieRequired(0))
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn seeded_tickets_repeat() {
+        mock_init();
+        use crate::protocol::wire::packets::TicketPacket;
+        use crate::protocol::wire::seed_packet_ids;
+
+        let build = || {
+            seed_packet_ids(42);
+            (0..2)
+                .map(|_| {
+                    let packet = TicketPacket::new()
+                        .set_timestamp(1_700_000_000_000)
+                        .set_get_chunk(ChunkId(9), 1, 100)
+                        .set_get_chunk(ChunkId(3), 2, 200)
+                        .build();
+                    build_into_bytes(packet.0)
+                })
+                .collect::<Vec<_>>()
+        };
+        let first = build();
+        assert_eq!(first, build());
+        assert_ne!(first[0], first[1]);
+    }
+
+    #[test]
+    fn seeded_jitter_repeats() {
+        use crate::protocol::wire::{random_factor, seed_packet_ids};
+
+        let draw = || {
+            seed_packet_ids(42);
+            (0..4)
+                .map(|_| random_factor(0.75..1.25))
+                .collect::<Vec<_>>()
+        };
+        let first = draw();
+        assert_eq!(first, draw());
+        assert!(first.iter().all(|factor| (0.75..1.25).contains(factor)));
+    }
+
+    #[test]
+    fn sessions_number_packets_apart() {
+        mock_init();
+        use crate::protocol::wire::packets::TicketPacket;
+        use crate::protocol::wire::{PacketIds, seed_packet_ids};
+
+        seed_packet_ids(7);
+        let peer = "10.0.0.2:7000".parse().unwrap();
+        let other = "10.0.0.3:7000".parse().unwrap();
+        let ids = PacketIds::for_peer(peer);
+        assert!(PacketIds::existing(other).is_none());
+        assert_ne!(ids.session_id(), PacketIds::for_peer(other).session_id());
+        assert_eq!(
+            ids.session_id(),
+            PacketIds::existing(peer).unwrap().session_id()
+        );
+
+        let build = || TicketPacket::new().set_timestamp(0).build_in(&ids).1;
+        let first = build();
+        assert_eq!(build(), first.wrapping_add(1));
+
+        PacketIds::forget(peer);
+        assert_ne!(PacketIds::for_peer(peer).session_id(), ids.session_id());
+    }
+
+    #[test]
+    fn build_parse_control_packet() {
+        mock_init();
+        use crate::protocol::wire::frames::{ErrorFrame, ErrorReason};
+        use crate::protocol::wire::packets::ControlPacket;
+
+        let packet = ControlPacket::new()
+            .push(ErrorFrame::new(ChunkId(3), ErrorReason::UnknownChunk))
+            .push(ErrorFrame::new(ChunkId(4), ErrorReason::Other(0xee)))
+            .build();
+        let parsed_packet =
+            parse_packet::<TRANSMISSION_INFO_LENGTH>(build_into_bytes(packet.0)).unwrap();
+
+        let errors: Vec<_> = parsed_packet
+            .frames
+            .iter()
+            .map(|frame| match frame {
+                ParsedFrameVariant::Error(header) => (u32::from(header.chunk_id), header.reason()),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (3, ErrorReason::UnknownChunk),
+                (4, ErrorReason::Other(0xee))
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_frames_skipped_only_if_ignorable() {
+        use crate::protocol::wire::frames::{FRAME_TYPE_IGNORABLE, is_experimental_frame_type};
+
+        let body = |frame_type: u8| {
+            // The unknown frame with two bytes of body, then a padding frame.
+            Bytes::from(vec![
+                frame_type,
+                0,
+                5,
+                0xaa,
+                0xbb,
+                FrameType::Padding.into(),
+                0,
+                3,
+            ])
+        };
+        let experimental = 0x73;
+        assert!(is_experimental_frame_type(experimental));
+        assert!(is_experimental_frame_type(
+            experimental | FRAME_TYPE_IGNORABLE
+        ));
+        assert!(!is_experimental_frame_type(FrameType::Data.into()));
+
+        let frames =
+            parse_frame::<16>(body(experimental | FRAME_TYPE_IGNORABLE), ParseMode::Strict)
+                .unwrap();
+        assert!(matches!(frames[..], [ParsedFrameVariant::Padding]));
+        assert!(matches!(
+            parse_frame::<16>(body(experimental), ParseMode::Strict),
+            Err(ParseError::UnsupportedFrameType(0x73))
+        ));
+        // A frame this build knows is still rejected when malformed.
+        assert!(
+            parse_frame::<16>(
+                Bytes::from(vec![FrameType::RateLimit.into(), 0, 4, 1]),
+                ParseMode::Strict
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn lenient_mode_tolerates_and_counts() {
+        mock_init();
+        use crate::protocol::wire::frames::{ErrorFrame, ErrorReason};
+        use crate::protocol::wire::packets::ControlPacket;
+
+        let before = tolerated();
+        let mut packet = BytesMut::from(
+            &build_into_bytes(
+                ControlPacket::new()
+                    .push(ErrorFrame::new(ChunkId(3), ErrorReason::UnknownChunk))
+                    .build()
+                    .0,
+            )[..],
+        );
+        packet.extend_from_slice(&[0; 4]);
+        let packet = packet.freeze();
+        let no_precheck = |_: &ParsedPacketVariant, _: &[ParsedFrameVariant<16>]| Ok(());
+        assert!(parse_packet_in_mode(packet.clone(), ParseMode::Strict, no_precheck).is_err());
+        let parsed = parse_packet_in_mode(packet, ParseMode::Lenient, no_precheck).unwrap();
+        assert!(matches!(parsed.frames[..], [ParsedFrameVariant::Error(_)]));
+
+        // An unknown frame without the ignorable bit, then a zero-filled tail.
+        let body = Bytes::from(vec![
+            0x73,
+            0,
+            4,
+            0xaa,
+            FrameType::Padding.into(),
+            0,
+            3,
+            0,
+            0,
+            0,
+        ]);
+        assert!(parse_frame::<16>(body.clone(), ParseMode::Strict).is_err());
+        let frames = parse_frame::<16>(body, ParseMode::Lenient).unwrap();
+        assert!(matches!(frames[..], [ParsedFrameVariant::Padding]));
+
+        let after = tolerated();
+        assert!(after.trailing_bytes > before.trailing_bytes);
+        assert!(after.unknown_frames > before.unknown_frames);
+        assert!(after.empty_frames > before.empty_frames);
     }
 }
@@ -1,11 +1,32 @@
-use crate::constants::TRANSMISSION_INFO_LENGTH;
+use crate::constants::{COOKIE_LENGTH, MTU, TRANSMISSION_INFO_LENGTH};
 use bytes::Bytes;
-use num_enum::{IntoPrimitive, TryFromPrimitive};
+use ed25519_dalek::SIGNATURE_LENGTH;
+use num_enum::{FromPrimitive, IntoPrimitive, TryFromPrimitive};
 use std::fmt;
-use zerocopy::byteorder::{BigEndian, U32};
+use std::ops::RangeInclusive;
+use zerocopy::byteorder::{BigEndian, U32, U64};
 use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned};
 
+use super::layout::wire_struct;
 use super::{Frame, SpecificFrameHeader};
+use crate::util::units::ChunkId;
+
+// A parser that does not know a frame type with this bit set skips the frame,
+// while an unknown type without it fails the packet. Extensions the peer may
+// safely miss set it, those it must understand do not.
+pub const FRAME_TYPE_IGNORABLE: u8 = 0x80;
+
+// Frame types left to experiments and vendor extensions, with or without the
+// ignorable bit. Never assigned here, so they cannot clash with a later release.
+pub const EXPERIMENTAL_FRAME_TYPES: RangeInclusive<u8> = 0x70..=0x7f;
+
+pub fn is_ignorable_frame_type(frame_type: u8) -> bool {
+    frame_type & FRAME_TYPE_IGNORABLE != 0
+}
+
+pub fn is_experimental_frame_type(frame_type: u8) -> bool {
+    EXPERIMENTAL_FRAME_TYPES.contains(&(frame_type & !FRAME_TYPE_IGNORABLE))
+}
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
@@ -13,6 +34,26 @@ pub enum FrameType {
     Data = 0x01,
     GetChunk = 0x02,
     RateLimit = 0x03,
+    Cookie = 0x04,
+    Error = 0x05,
+    Congestion = 0x06,
+    Prefetch = 0x07,
+    Busy = 0x08,
+    Padding = 0x09,
+    Grant = 0x0a,
+    Upload = 0x0b,
+    Features = 0x0c,
+    WideRateLimit = 0x0d,
+    WideGetChunk = 0x0e,
+    StopChunk = 0x0f,
+    // Ignorable, a server without priorities serves every transfer alike.
+    Priority = 0x90,
+    // Ignorable, a client without it only learns nothing about the server's clock.
+    Clock = 0x91,
+    // Ignorable, a client without it reports stopped chunks for a few tickets.
+    StopAck = 0x92,
+    // Ignorable, a server without it can not tell a Features frame stripped on the way.
+    FeaturesEcho = 0x93,
 }
 
 impl FrameType {
@@ -24,6 +65,22 @@ impl FrameType {
             FrameType::Data => DataFrame::<TRANSMISSION_INFO_LENGTH>::try_parse(data),
             FrameType::GetChunk => GetChunkFrame::try_parse(data),
             FrameType::RateLimit => RateLimitFrame::try_parse(data),
+            FrameType::Cookie => CookieFrame::try_parse(data),
+            FrameType::Error => ErrorFrame::try_parse(data),
+            FrameType::Congestion => CongestionFrame::try_parse(data),
+            FrameType::Prefetch => PrefetchFrame::try_parse(data),
+            FrameType::Busy => BusyFrame::try_parse(data),
+            FrameType::Padding => PaddingFrame::try_parse(data),
+            FrameType::Grant => GrantFrame::try_parse(data),
+            FrameType::Upload => UploadFrame::try_parse(data),
+            FrameType::Features => FeaturesFrame::try_parse(data),
+            FrameType::WideRateLimit => WideRateLimitFrame::try_parse(data),
+            FrameType::WideGetChunk => WideGetChunkFrame::try_parse(data),
+            FrameType::StopChunk => StopChunkFrame::try_parse(data),
+            FrameType::Priority => PriorityFrame::try_parse(data),
+            FrameType::Clock => ClockFrame::try_parse(data),
+            FrameType::StopAck => StopAckFrame::try_parse(data),
+            FrameType::FeaturesEcho => FeaturesEchoFrame::try_parse(data),
         }
     }
 }
@@ -33,14 +90,32 @@ pub enum ParsedFrameVariant<const INFO_LENGTH: usize> {
     Data(ParsedDataFrame<INFO_LENGTH>),
     GetChunk(GetChunkFrameHeader),
     RateLimit(RateLimitFrameHeader),
+    Cookie(CookieFrameHeader),
+    Error(ErrorFrameHeader),
+    Congestion(CongestionFrameHeader),
+    Prefetch(PrefetchFrameHeader),
+    Busy(BusyFrameHeader),
+    Padding,
+    Grant(GrantFrameHeader),
+    Upload(UploadFrameHeader),
+    Features(FeaturesFrameHeader),
+    WideRateLimit(WideRateLimitFrameHeader),
+    WideGetChunk(WideGetChunkFrameHeader),
+    StopChunk(StopChunkFrameHeader),
+    Priority(PriorityFrameHeader),
+    Clock(ClockFrameHeader),
+    StopAck(StopAckFrameHeader),
+    FeaturesEcho(FeaturesEchoFrameHeader),
 }
 
-#[repr(C)]
-#[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
-pub struct DataFrameHeader<const INFO_LENGTH: usize> {
-    pub chunk_id: U32<BigEndian>,
-    pub frame_offset: U32<BigEndian>,
-    pub transmission_info: [u8; INFO_LENGTH],
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
+    pub struct DataFrameHeader<const INFO_LENGTH: usize> {
+        pub chunk_id: U32<BigEndian>,
+        pub frame_offset: U32<BigEndian>,
+        pub transmission_info: [u8; INFO_LENGTH],
+    }
 }
 
 impl<const INFO_LENGTH: usize> SpecificFrameHeader for DataFrameHeader<INFO_LENGTH> {
@@ -55,7 +130,7 @@ pub struct DataFrame<const INFO_LENGTH: usize> {
 }
 
 pub struct ParsedDataFrame<const INFO_LENGTH: usize> {
-    pub chunk_id: u32,
+    pub chunk_id: ChunkId,
     pub frame_offset: u32,
     pub transmission_info: [u8; INFO_LENGTH],
     pub data: Bytes,
@@ -99,7 +174,7 @@ impl<const INFO_LENGTH: usize> fmt::Debug for ParsedDataFrame<INFO_LENGTH> {
 
 impl<const INFO_LENGTH: usize> DataFrame<INFO_LENGTH> {
     pub fn new(
-        chunk_id: u32,
+        chunk_id: ChunkId,
         frame_offset: u32,
         transmission_info: [u8; INFO_LENGTH],
         data: Bytes,
@@ -113,6 +188,10 @@ impl<const INFO_LENGTH: usize> DataFrame<INFO_LENGTH> {
             data,
         }
     }
+
+    pub fn chunk_id(&self) -> ChunkId {
+        self.header.chunk_id.into()
+    }
 }
 
 impl<const INFO_LEN: usize> Frame for DataFrame<INFO_LEN> {
@@ -141,12 +220,16 @@ impl<const INFO_LEN: usize> Frame for DataFrame<INFO_LEN> {
     }
 }
 
-#[repr(C)]
-#[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
-pub struct GetChunkFrameHeader {
-    pub chunk_id: U32<BigEndian>,
-    pub next_receive_offset: U32<BigEndian>,
-    pub receive_window_frames: U32<BigEndian>, // 0 means send no more!
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
+    pub struct GetChunkFrameHeader {
+        pub chunk_id: U32<BigEndian>,
+        pub next_receive_offset: U32<BigEndian>,
+        pub receive_window_frames: U32<BigEndian>, // 0 means send no more!
+        // The plan epoch the client hashes chunks against.
+        pub generation: U64<BigEndian>,
+    }
 }
 
 impl SpecificFrameHeader for GetChunkFrameHeader {
@@ -170,10 +253,12 @@ impl Frame for GetChunkFrame {
     }
 }
 
-#[repr(C)]
-#[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
-pub struct RateLimitFrameHeader {
-    pub desired_max_kbps: U32<BigEndian>,
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
+    pub struct RateLimitFrameHeader {
+        pub desired_max_kbps: U32<BigEndian>,
+    }
 }
 
 impl SpecificFrameHeader for RateLimitFrameHeader {
@@ -197,3 +282,662 @@ impl Frame for RateLimitFrame {
             .then_some(ParsedFrameVariant::RateLimit(header))
     }
 }
+
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug, Clone, Copy)]
+    pub struct CookieFrameHeader {
+        pub cookie: [u8; COOKIE_LENGTH],
+    }
+}
+
+impl SpecificFrameHeader for CookieFrameHeader {
+    fn get_frame_type(&self) -> FrameType {
+        FrameType::Cookie
+    }
+}
+
+pub type CookieFrame = CookieFrameHeader;
+impl Frame for CookieFrame {
+    type Header = CookieFrameHeader;
+    fn header(&self) -> &Self::Header {
+        self
+    }
+    fn try_parse<const INFO_LENGTH: usize>(data: Bytes) -> Option<ParsedFrameVariant<INFO_LENGTH>> {
+        let (header, remain) = CookieFrameHeader::read_from_prefix(data.as_bytes()).ok()?;
+
+        remain
+            .is_empty()
+            .then_some(ParsedFrameVariant::Cookie(header))
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntoPrimitive, FromPrimitive)]
+pub enum ErrorReason {
+    UnknownChunk = 0x01,
+    ChunkUnavailable = 0x02,
+    EncoderFailed = 0x03,
+    QuotaExceeded = 0x04,
+    // The ticket failed verification, `chunk_id` is meaningless.
+    AuthFailed = 0x05,
+    // The server's file changed since the plan of the GetChunk frame.
+    StaleGeneration = 0x06,
+    #[num_enum(catch_all)]
+    Other(u8),
+}
+
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
+    pub struct ErrorFrameHeader {
+        pub chunk_id: U32<BigEndian>,
+        pub reason: u8,
+    }
+}
+
+impl SpecificFrameHeader for ErrorFrameHeader {
+    fn get_frame_type(&self) -> FrameType {
+        FrameType::Error
+    }
+}
+
+pub type ErrorFrame = ErrorFrameHeader;
+impl ErrorFrame {
+    pub fn new(chunk_id: ChunkId, reason: ErrorReason) -> Self {
+        Self {
+            chunk_id: chunk_id.into(),
+            reason: reason.into(),
+        }
+    }
+
+    pub fn reason(&self) -> ErrorReason {
+        ErrorReason::from(self.reason)
+    }
+}
+
+impl Frame for ErrorFrame {
+    type Header = ErrorFrameHeader;
+    fn header(&self) -> &Self::Header {
+        self
+    }
+    fn try_parse<const INFO_LENGTH: usize>(data: Bytes) -> Option<ParsedFrameVariant<INFO_LENGTH>> {
+        let (header, remain) = ErrorFrameHeader::read_from_prefix(data.as_bytes()).ok()?;
+
+        remain
+            .is_empty()
+            .then_some(ParsedFrameVariant::Error(header))
+    }
+}
+
+// ECN feedback from the receiver: packets marked CE out of all received since the last ticket.
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
+    pub struct CongestionFrameHeader {
+        pub ce_packets: U32<BigEndian>,
+        pub total_packets: U32<BigEndian>,
+    }
+}
+
+impl SpecificFrameHeader for CongestionFrameHeader {
+    fn get_frame_type(&self) -> FrameType {
+        FrameType::Congestion
+    }
+}
+
+pub type CongestionFrame = CongestionFrameHeader;
+impl Frame for CongestionFrame {
+    type Header = CongestionFrameHeader;
+    fn header(&self) -> &Self::Header {
+        self
+    }
+    fn try_parse<const INFO_LENGTH: usize>(data: Bytes) -> Option<ParsedFrameVariant<INFO_LENGTH>> {
+        let (header, remain) = CongestionFrameHeader::read_from_prefix(data.as_bytes()).ok()?;
+
+        remain
+            .is_empty()
+            .then_some(ParsedFrameVariant::Congestion(header))
+    }
+}
+
+// A chunk the receiver is about to ask for, so the sender can get it ready.
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
+    pub struct PrefetchFrameHeader {
+        pub chunk_id: U32<BigEndian>,
+    }
+}
+
+impl SpecificFrameHeader for PrefetchFrameHeader {
+    fn get_frame_type(&self) -> FrameType {
+        FrameType::Prefetch
+    }
+}
+
+pub type PrefetchFrame = PrefetchFrameHeader;
+impl Frame for PrefetchFrame {
+    type Header = PrefetchFrameHeader;
+    fn header(&self) -> &Self::Header {
+        self
+    }
+    fn try_parse<const INFO_LENGTH: usize>(data: Bytes) -> Option<ParsedFrameVariant<INFO_LENGTH>> {
+        let (header, remain) = PrefetchFrameHeader::read_from_prefix(data.as_bytes()).ok()?;
+
+        remain
+            .is_empty()
+            .then_some(ParsedFrameVariant::Prefetch(header))
+    }
+}
+
+// The server is at capacity and queued the session instead of serving it.
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
+    pub struct BusyFrameHeader {
+        pub retry_after_ms: U32<BigEndian>,
+        // 1 for the next session to be admitted.
+        pub queue_position: U32<BigEndian>,
+    }
+}
+
+impl SpecificFrameHeader for BusyFrameHeader {
+    fn get_frame_type(&self) -> FrameType {
+        FrameType::Busy
+    }
+}
+
+pub type BusyFrame = BusyFrameHeader;
+impl BusyFrame {
+    pub fn new(retry_after_ms: u32, queue_position: u32) -> Self {
+        Self {
+            retry_after_ms: retry_after_ms.into(),
+            queue_position: queue_position.into(),
+        }
+    }
+}
+
+impl Frame for BusyFrame {
+    type Header = BusyFrameHeader;
+    fn header(&self) -> &Self::Header {
+        self
+    }
+    fn try_parse<const INFO_LENGTH: usize>(data: Bytes) -> Option<ParsedFrameVariant<INFO_LENGTH>> {
+        let (header, remain) = BusyFrameHeader::read_from_prefix(data.as_bytes()).ok()?;
+
+        remain
+            .is_empty()
+            .then_some(ParsedFrameVariant::Busy(header))
+    }
+}
+
+// Zeroes that only make a packet longer, ignored by the receiver.
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
+    pub struct PaddingFrameHeader {}
+}
+
+impl SpecificFrameHeader for PaddingFrameHeader {
+    fn get_frame_type(&self) -> FrameType {
+        FrameType::Padding
+    }
+}
+
+static ZEROES: [u8; MTU] = [0; MTU];
+
+pub struct PaddingFrame {
+    header: PaddingFrameHeader,
+    length: usize,
+}
+
+impl PaddingFrame {
+    // `length` bytes of zeroes after the frame header, at most an MTU.
+    pub fn new(length: usize) -> Self {
+        Self {
+            header: PaddingFrameHeader {},
+            length: length.min(MTU),
+        }
+    }
+}
+
+impl Frame for PaddingFrame {
+    type Header = PaddingFrameHeader;
+    fn header(&self) -> &Self::Header {
+        &self.header
+    }
+    fn body_len(&self) -> usize {
+        self.length
+    }
+    fn take_body(self) -> Option<Bytes> {
+        Some(Bytes::from_static(&ZEROES[..self.length]))
+    }
+    fn try_parse<const INFO_LENGTH: usize>(
+        _data: Bytes,
+    ) -> Option<ParsedFrameVariant<INFO_LENGTH>> {
+        Some(ParsedFrameVariant::Padding)
+    }
+}
+
+// A download token's proof that the server let the ticket's key in, see
+// `protocol::token`.
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug, Clone, Copy)]
+    pub struct GrantFrameHeader {
+        pub file_id: U64<BigEndian>,
+        pub expires_ms: U64<BigEndian>,
+        // 0 for no limit.
+        pub max_bytes: U64<BigEndian>,
+        // By the server's token key, over the ticket's key and the fields above.
+        pub signature: [u8; SIGNATURE_LENGTH],
+    }
+}
+
+impl SpecificFrameHeader for GrantFrameHeader {
+    fn get_frame_type(&self) -> FrameType {
+        FrameType::Grant
+    }
+}
+
+pub type GrantFrame = GrantFrameHeader;
+impl Frame for GrantFrame {
+    type Header = GrantFrameHeader;
+    fn header(&self) -> &Self::Header {
+        self
+    }
+    fn try_parse<const INFO_LENGTH: usize>(data: Bytes) -> Option<ParsedFrameVariant<INFO_LENGTH>> {
+        let (header, remain) = GrantFrameHeader::read_from_prefix(data.as_bytes()).ok()?;
+
+        remain
+            .is_empty()
+            .then_some(ParsedFrameVariant::Grant(header))
+    }
+}
+
+// Offers the file to the receiver of the ticket, which then sends tickets for
+// its chunks back, for uploads.
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
+    pub struct UploadFrameHeader {
+        pub file_id: U64<BigEndian>,
+    }
+}
+
+impl SpecificFrameHeader for UploadFrameHeader {
+    fn get_frame_type(&self) -> FrameType {
+        FrameType::Upload
+    }
+}
+
+pub type UploadFrame = UploadFrameHeader;
+impl Frame for UploadFrame {
+    type Header = UploadFrameHeader;
+    fn header(&self) -> &Self::Header {
+        self
+    }
+    fn try_parse<const INFO_LENGTH: usize>(data: Bytes) -> Option<ParsedFrameVariant<INFO_LENGTH>> {
+        let (header, remain) = UploadFrameHeader::read_from_prefix(data.as_bytes()).ok()?;
+
+        remain
+            .is_empty()
+            .then_some(ParsedFrameVariant::Upload(header))
+    }
+}
+
+// Frames with 64-bit rates and offsets, for rates past u32::MAX kbps and chunks
+// past 2^32 frames. Only sent to a peer that listed this in its Features frame.
+pub const FEATURE_WIDE_FIELDS: u32 = 1 << 0;
+// StopChunk frames, sent along the GetChunk frame closing the window.
+pub const FEATURE_STOP_CHUNK: u32 = 1 << 1;
+// A StopAck frame for every chunk a ticket closes, so the client can stop
+// reporting it.
+pub const FEATURE_STOP_ACK: u32 = 1 << 2;
+// Every feature this build understands.
+pub const SUPPORTED_FEATURES: u32 = FEATURE_WIDE_FIELDS | FEATURE_STOP_CHUNK | FEATURE_STOP_ACK;
+
+// The server's features, sent to a peer with its first applied ticket. Servers
+// from before it send none, so a client assumes no features until it arrives.
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
+    pub struct FeaturesFrameHeader {
+        pub flags: U32<BigEndian>,
+    }
+}
+
+impl SpecificFrameHeader for FeaturesFrameHeader {
+    fn get_frame_type(&self) -> FrameType {
+        FrameType::Features
+    }
+}
+
+pub type FeaturesFrame = FeaturesFrameHeader;
+impl FeaturesFrame {
+    pub fn new(flags: u32) -> Self {
+        Self {
+            flags: flags.into(),
+        }
+    }
+}
+
+impl Frame for FeaturesFrame {
+    type Header = FeaturesFrameHeader;
+    fn header(&self) -> &Self::Header {
+        self
+    }
+    fn try_parse<const INFO_LENGTH: usize>(data: Bytes) -> Option<ParsedFrameVariant<INFO_LENGTH>> {
+        let (header, remain) = FeaturesFrameHeader::read_from_prefix(data.as_bytes()).ok()?;
+
+        remain
+            .is_empty()
+            .then_some(ParsedFrameVariant::Features(header))
+    }
+}
+
+// The features a client heard from the server, 0 before any, in its signed
+// tickets. Control packets only carry a checksum, so an on-path attacker may
+// strip or alter the Features frame, but not what the client says it heard.
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug, Clone, Copy)]
+    pub struct FeaturesEchoFrameHeader {
+        pub flags: U32<BigEndian>,
+    }
+}
+
+impl SpecificFrameHeader for FeaturesEchoFrameHeader {
+    fn get_frame_type(&self) -> FrameType {
+        FrameType::FeaturesEcho
+    }
+}
+
+pub type FeaturesEchoFrame = FeaturesEchoFrameHeader;
+impl FeaturesEchoFrame {
+    pub fn new(flags: u32) -> Self {
+        Self {
+            flags: flags.into(),
+        }
+    }
+}
+
+impl Frame for FeaturesEchoFrame {
+    type Header = FeaturesEchoFrameHeader;
+    fn header(&self) -> &Self::Header {
+        self
+    }
+    fn try_parse<const INFO_LENGTH: usize>(data: Bytes) -> Option<ParsedFrameVariant<INFO_LENGTH>> {
+        let (header, remain) = FeaturesEchoFrameHeader::read_from_prefix(data.as_bytes()).ok()?;
+
+        remain
+            .is_empty()
+            .then_some(ParsedFrameVariant::FeaturesEcho(header))
+    }
+}
+
+// RateLimit for rates past u32::MAX kbps.
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
+    pub struct WideRateLimitFrameHeader {
+        pub desired_max_kbps: U64<BigEndian>,
+    }
+}
+
+impl SpecificFrameHeader for WideRateLimitFrameHeader {
+    fn get_frame_type(&self) -> FrameType {
+        FrameType::WideRateLimit
+    }
+}
+
+pub type WideRateLimitFrame = WideRateLimitFrameHeader;
+impl Frame for WideRateLimitFrame {
+    type Header = WideRateLimitFrameHeader;
+    fn header(&self) -> &Self::Header {
+        self
+    }
+    fn try_parse<const INFO_LENGTH: usize>(data: Bytes) -> Option<ParsedFrameVariant<INFO_LENGTH>> {
+        let (header, remain) = WideRateLimitFrameHeader::read_from_prefix(data.as_bytes()).ok()?;
+
+        remain
+            .is_empty()
+            .then_some(ParsedFrameVariant::WideRateLimit(header))
+    }
+}
+
+// GetChunk for chunks past 2^32 frames.
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
+    pub struct WideGetChunkFrameHeader {
+        pub chunk_id: U32<BigEndian>,
+        pub next_receive_offset: U64<BigEndian>,
+        pub receive_window_frames: U64<BigEndian>, // 0 means send no more!
+        pub generation: U64<BigEndian>,
+    }
+}
+
+impl SpecificFrameHeader for WideGetChunkFrameHeader {
+    fn get_frame_type(&self) -> FrameType {
+        FrameType::WideGetChunk
+    }
+}
+
+pub type WideGetChunkFrame = WideGetChunkFrameHeader;
+impl Frame for WideGetChunkFrame {
+    type Header = WideGetChunkFrameHeader;
+    fn header(&self) -> &Self::Header {
+        self
+    }
+    fn try_parse<const INFO_LENGTH: usize>(data: Bytes) -> Option<ParsedFrameVariant<INFO_LENGTH>> {
+        let (header, remain) = WideGetChunkFrameHeader::read_from_prefix(data.as_bytes()).ok()?;
+
+        remain
+            .is_empty()
+            .then_some(ParsedFrameVariant::WideGetChunk(header))
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntoPrimitive, FromPrimitive)]
+pub enum StopReason {
+    Finished = 0x01,
+    // Given up on or fetched elsewhere.
+    Aborted = 0x02,
+    // The decoded chunk did not match its planned hash.
+    HashMismatch = 0x03,
+    #[num_enum(catch_all)]
+    Other(u8),
+}
+
+// Tells the server to close the encoder of a chunk at once, and why.
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
+    pub struct StopChunkFrameHeader {
+        pub chunk_id: U32<BigEndian>,
+        pub reason: u8,
+    }
+}
+
+impl SpecificFrameHeader for StopChunkFrameHeader {
+    fn get_frame_type(&self) -> FrameType {
+        FrameType::StopChunk
+    }
+}
+
+pub type StopChunkFrame = StopChunkFrameHeader;
+impl StopChunkFrame {
+    pub fn new(chunk_id: ChunkId, reason: StopReason) -> Self {
+        Self {
+            chunk_id: chunk_id.into(),
+            reason: reason.into(),
+        }
+    }
+
+    pub fn reason(&self) -> StopReason {
+        StopReason::from(self.reason)
+    }
+}
+
+impl Frame for StopChunkFrame {
+    type Header = StopChunkFrameHeader;
+    fn header(&self) -> &Self::Header {
+        self
+    }
+    fn try_parse<const INFO_LENGTH: usize>(data: Bytes) -> Option<ParsedFrameVariant<INFO_LENGTH>> {
+        let (header, remain) = StopChunkFrameHeader::read_from_prefix(data.as_bytes()).ok()?;
+
+        remain
+            .is_empty()
+            .then_some(ParsedFrameVariant::StopChunk(header))
+    }
+}
+
+// How urgent a transfer is. The client starts chunks and splits its rate
+// between transfers by weight, and so does a server with a link rate between
+// sessions. Unknown classes count as normal.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, IntoPrimitive, FromPrimitive)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Priority {
+    Background = 0x00,
+    #[default]
+    Normal = 0x01,
+    High = 0x02,
+}
+
+impl Priority {
+    // Shares of a busy link go by these, so a high priority transfer gets four
+    // times the rate of a normal one and sixteen times that of a background one.
+    pub fn weight(self) -> u32 {
+        match self {
+            Priority::Background => 1,
+            Priority::Normal => 4,
+            Priority::High => 16,
+        }
+    }
+}
+
+// The priority of the ticket's transfer. Tickets without one are normal.
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
+    pub struct PriorityFrameHeader {
+        pub class: u8,
+    }
+}
+
+impl SpecificFrameHeader for PriorityFrameHeader {
+    fn get_frame_type(&self) -> FrameType {
+        FrameType::Priority
+    }
+}
+
+pub type PriorityFrame = PriorityFrameHeader;
+impl PriorityFrame {
+    pub fn new(priority: Priority) -> Self {
+        Self {
+            class: priority.into(),
+        }
+    }
+
+    pub fn priority(&self) -> Priority {
+        Priority::from(self.class)
+    }
+}
+
+impl Frame for PriorityFrame {
+    type Header = PriorityFrameHeader;
+    fn header(&self) -> &Self::Header {
+        self
+    }
+    fn try_parse<const INFO_LENGTH: usize>(data: Bytes) -> Option<ParsedFrameVariant<INFO_LENGTH>> {
+        let (header, remain) = PriorityFrameHeader::read_from_prefix(data.as_bytes()).ok()?;
+
+        remain
+            .is_empty()
+            .then_some(ParsedFrameVariant::Priority(header))
+    }
+}
+
+// The server's wall clock when it answered a ticket, and the timestamp of that
+// ticket, so the client can tell how far apart their clocks are.
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
+    pub struct ClockFrameHeader {
+        pub echo_timestamp_ms: U64<BigEndian>,
+        pub timestamp_ms: U64<BigEndian>,
+    }
+}
+
+impl SpecificFrameHeader for ClockFrameHeader {
+    fn get_frame_type(&self) -> FrameType {
+        FrameType::Clock
+    }
+}
+
+pub type ClockFrame = ClockFrameHeader;
+impl ClockFrame {
+    pub fn new(echo_timestamp_ms: u64, timestamp_ms: u64) -> Self {
+        Self {
+            echo_timestamp_ms: echo_timestamp_ms.into(),
+            timestamp_ms: timestamp_ms.into(),
+        }
+    }
+}
+
+impl Frame for ClockFrame {
+    type Header = ClockFrameHeader;
+    fn header(&self) -> &Self::Header {
+        self
+    }
+    fn try_parse<const INFO_LENGTH: usize>(data: Bytes) -> Option<ParsedFrameVariant<INFO_LENGTH>> {
+        let (header, remain) = ClockFrameHeader::read_from_prefix(data.as_bytes()).ok()?;
+
+        remain
+            .is_empty()
+            .then_some(ParsedFrameVariant::Clock(header))
+    }
+}
+
+// A chunk the server closed as a ticket asked, in reply to that ticket.
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
+    pub struct StopAckFrameHeader {
+        pub chunk_id: U32<BigEndian>,
+    }
+}
+
+impl SpecificFrameHeader for StopAckFrameHeader {
+    fn get_frame_type(&self) -> FrameType {
+        FrameType::StopAck
+    }
+}
+
+pub type StopAckFrame = StopAckFrameHeader;
+impl StopAckFrame {
+    pub fn new(chunk_id: ChunkId) -> Self {
+        Self {
+            chunk_id: chunk_id.into(),
+        }
+    }
+}
+
+impl Frame for StopAckFrame {
+    type Header = StopAckFrameHeader;
+    fn header(&self) -> &Self::Header {
+        self
+    }
+    fn try_parse<const INFO_LENGTH: usize>(data: Bytes) -> Option<ParsedFrameVariant<INFO_LENGTH>> {
+        let (header, remain) = StopAckFrameHeader::read_from_prefix(data.as_bytes()).ok()?;
+
+        remain
+            .is_empty()
+            .then_some(ParsedFrameVariant::StopAck(header))
+    }
+}
@@ -0,0 +1,309 @@
+use serde::Serialize;
+use zerocopy::byteorder::{BigEndian, U16, U32, U64};
+use zerocopy::{FromZeros, IntoBytes};
+
+use super::frames::{
+    BusyFrame, ClockFrame, CongestionFrame, CookieFrame, DataFrame, ErrorFrame, FeaturesEchoFrame,
+    FeaturesFrame, GetChunkFrame, GrantFrame, PaddingFrame, PrefetchFrame, PriorityFrame,
+    RateLimitFrame, StopAckFrame, StopChunkFrame, UploadFrame, WideGetChunkFrame,
+    WideRateLimitFrame,
+};
+use super::packets::{CookieReplyPacket, DataPacket, MAX_PREFETCH, TicketPacket};
+use super::{
+    CommonFrameHeader, CommonPacketHeader, Frame, Packet, SpecificFrameHeader,
+    packets::ControlPacket, verify::PacketVerifyType,
+};
+use crate::constants::{DEFAULT_FRAME_LEN, MTU, TRANSMISSION_INFO_LENGTH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldEncoding {
+    BigEndian,
+    Byte,
+    Bytes,
+}
+
+// Encoding of every type a wire header field may have.
+pub trait WireType {
+    const ENCODING: FieldEncoding;
+}
+
+impl WireType for u8 {
+    const ENCODING: FieldEncoding = FieldEncoding::Byte;
+}
+impl WireType for U16<BigEndian> {
+    const ENCODING: FieldEncoding = FieldEncoding::BigEndian;
+}
+impl WireType for U32<BigEndian> {
+    const ENCODING: FieldEncoding = FieldEncoding::BigEndian;
+}
+impl WireType for U64<BigEndian> {
+    const ENCODING: FieldEncoding = FieldEncoding::BigEndian;
+}
+impl<const N: usize> WireType for [u8; N] {
+    const ENCODING: FieldEncoding = FieldEncoding::Bytes;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct WireField {
+    pub name: &'static str,
+    pub size: usize,
+    pub encoding: FieldEncoding,
+}
+
+pub trait WireLayout {
+    const FIELDS: &'static [WireField];
+}
+
+// Declares a wire header struct and records its fields, in order, for `describe`.
+// Field types must implement `WireType`.
+macro_rules! wire_struct {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident $(<const $param:ident: usize>)? {
+            $($field_vis:vis $field:ident: $ty:ty),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name $(<const $param: usize>)? {
+            $($field_vis $field: $ty),*
+        }
+
+        impl $(<const $param: usize>)? $crate::protocol::wire::layout::WireLayout for $name $(<$param>)? {
+            const FIELDS: &'static [$crate::protocol::wire::layout::WireField] = &[$(
+                $crate::protocol::wire::layout::WireField {
+                    name: stringify!($field),
+                    size: std::mem::size_of::<$ty>(),
+                    encoding: <$ty as $crate::protocol::wire::layout::WireType>::ENCODING,
+                }
+            ),*];
+        }
+    };
+}
+pub(crate) use wire_struct;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlacedField {
+    pub name: &'static str,
+    pub offset: usize,
+    pub size: usize,
+    pub encoding: FieldEncoding,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StructLayout {
+    pub name: String,
+    // The packet or frame type byte selecting this header, if any.
+    pub type_code: Option<u8>,
+    // How the packet is verified, for packet headers.
+    pub verification: Option<&'static str>,
+    pub size: usize,
+    pub fields: Vec<PlacedField>,
+}
+
+fn layout_of<T: WireLayout + IntoBytes>(
+    name: &str,
+    type_code: Option<u8>,
+    verification: Option<&'static str>,
+) -> StructLayout {
+    let mut offset = 0;
+    let fields = T::FIELDS
+        .iter()
+        .map(|field| {
+            let placed = PlacedField {
+                name: field.name,
+                offset,
+                size: field.size,
+                encoding: field.encoding,
+            };
+            offset += field.size;
+            placed
+        })
+        .collect();
+    StructLayout {
+        name: name.to_string(),
+        type_code,
+        verification,
+        size: std::mem::size_of::<T>(),
+        fields,
+    }
+}
+
+fn verification_of(verify_type: PacketVerifyType) -> &'static str {
+    match verify_type {
+        PacketVerifyType::CRC64 => "CRC-64/ECMA-182 of all preceding bytes, 8 bytes big endian",
+        PacketVerifyType::Ed25519 => {
+            "Ed25519 signature of all preceding bytes by `pubkey`, 64 bytes"
+        }
+    }
+}
+
+fn packet<P>(name: &str) -> StructLayout
+where
+    P: Packet,
+    P::Header: WireLayout,
+{
+    layout_of::<P::Header>(
+        name,
+        Some(P::PACKET_TYPE.into()),
+        Some(verification_of(P::PACKET_VERIFICATION_TYPE)),
+    )
+}
+
+fn frame<F>(name: &str) -> StructLayout
+where
+    F: Frame,
+    F::Header: WireLayout + FromZeros,
+{
+    let frame_type = F::Header::new_zeroed().get_frame_type();
+    layout_of::<F::Header>(name, Some(frame_type.into()), None)
+}
+
+// Every header on the wire. A packet is the common packet header, the packet
+// header selected by `packet_type`, its frames, then the verification field.
+// A frame is the common frame header, the frame header selected by `frame_type`,
+// then any body up to `frame_length`.
+pub fn describe() -> Vec<StructLayout> {
+    vec![
+        layout_of::<CommonPacketHeader>("CommonPacketHeader", None, None),
+        packet::<DataPacket<TRANSMISSION_INFO_LENGTH>>("DataPacketHeader"),
+        packet::<TicketPacket>("TicketPacketHeader"),
+        packet::<CookieReplyPacket>("CookieReplyPacketHeader"),
+        packet::<ControlPacket>("ControlPacketHeader"),
+        layout_of::<CommonFrameHeader>("CommonFrameHeader", None, None),
+        frame::<DataFrame<TRANSMISSION_INFO_LENGTH>>("DataFrameHeader"),
+        frame::<GetChunkFrame>("GetChunkFrameHeader"),
+        frame::<RateLimitFrame>("RateLimitFrameHeader"),
+        frame::<CookieFrame>("CookieFrameHeader"),
+        frame::<ErrorFrame>("ErrorFrameHeader"),
+        frame::<CongestionFrame>("CongestionFrameHeader"),
+        frame::<PrefetchFrame>("PrefetchFrameHeader"),
+        frame::<BusyFrame>("BusyFrameHeader"),
+        frame::<PaddingFrame>("PaddingFrameHeader"),
+        frame::<GrantFrame>("GrantFrameHeader"),
+        frame::<UploadFrame>("UploadFrameHeader"),
+        frame::<FeaturesFrame>("FeaturesFrameHeader"),
+        frame::<FeaturesEchoFrame>("FeaturesEchoFrameHeader"),
+        frame::<WideRateLimitFrame>("WideRateLimitFrameHeader"),
+        frame::<WideGetChunkFrame>("WideGetChunkFrameHeader"),
+        frame::<StopChunkFrame>("StopChunkFrameHeader"),
+        frame::<PriorityFrame>("PriorityFrameHeader"),
+        frame::<ClockFrame>("ClockFrameHeader"),
+        frame::<StopAckFrame>("StopAckFrameHeader"),
+    ]
+}
+
+// Bytes a packet takes besides its frames.
+pub const fn packet_overhead<P: Packet>() -> usize {
+    size_of::<CommonPacketHeader>()
+        + size_of::<P::Header>()
+        + P::PACKET_VERIFICATION_TYPE.signature_len()
+}
+
+// Bytes a frame takes besides its body.
+pub const fn frame_overhead<F: Frame>() -> usize {
+    size_of::<CommonFrameHeader>() + size_of::<F::Header>()
+}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b { a } else { b }
+}
+
+// A ticket with every optional frame, before its GetChunk and StopChunk frames.
+const TICKET_FIXED: usize = packet_overhead::<TicketPacket>()
+    + max(
+        frame_overhead::<RateLimitFrame>(),
+        frame_overhead::<WideRateLimitFrame>(),
+    )
+    + frame_overhead::<PriorityFrame>()
+    + frame_overhead::<FeaturesEchoFrame>()
+    + frame_overhead::<CookieFrame>()
+    + frame_overhead::<CongestionFrame>()
+    + frame_overhead::<GrantFrame>()
+    + frame_overhead::<UploadFrame>()
+    + MAX_PREFETCH * frame_overhead::<PrefetchFrame>();
+
+// Chunks one ticket can report within an MTU, each with a GetChunk and a StopChunk frame.
+pub const TICKET_CHUNKS: usize =
+    (MTU - TICKET_FIXED) / (frame_overhead::<GetChunkFrame>() + frame_overhead::<StopChunkFrame>());
+
+// Packets must not outgrow the MTU when a header changes, or they fragment.
+const _: () = {
+    assert!(
+        packet_overhead::<DataPacket<TRANSMISSION_INFO_LENGTH>>()
+            + frame_overhead::<DataFrame<TRANSMISSION_INFO_LENGTH>>()
+            + DEFAULT_FRAME_LEN
+            <= MTU
+    );
+    // A client downloading 8 chunks at once keeps finished ones in the next 3 tickets.
+    assert!(TICKET_FIXED <= MTU && TICKET_CHUNKS >= 32);
+    assert!(packet_overhead::<CookieReplyPacket>() <= MTU);
+    // The reply to a first ticket, the largest control packet.
+    assert!(
+        packet_overhead::<ControlPacket>()
+            + frame_overhead::<FeaturesFrame>()
+            + frame_overhead::<ClockFrame>()
+            <= MTU
+    );
+    // The acknowledgements of the fullest ticket.
+    assert!(
+        packet_overhead::<ControlPacket>() + TICKET_CHUNKS * frame_overhead::<StopAckFrame>()
+            <= MTU
+    );
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_cover_every_header() {
+        for layout in describe() {
+            let covered = layout.fields.last().map(|f| f.offset + f.size);
+            assert_eq!(covered.unwrap_or(0), layout.size, "{}", layout.name);
+        }
+        let common = &describe()[0];
+        assert_eq!(common.size, 10);
+        assert_eq!(
+            common.fields[4],
+            PlacedField {
+                name: "packet_id",
+                offset: 6,
+                size: 4,
+                encoding: FieldEncoding::BigEndian,
+            }
+        );
+    }
+
+    #[test]
+    fn fullest_ticket_fits_the_mtu() {
+        use super::super::encoding::PacketExt;
+        use super::super::frames::{FEATURE_STOP_CHUNK, FEATURE_WIDE_FIELDS, Priority, StopReason};
+        use crate::util::units::ChunkId;
+        use zerocopy::FromZeros;
+
+        crate::protocol::mock_init();
+        let ticket = (0..TICKET_CHUNKS as u32).map(ChunkId).fold(
+            TicketPacket::new()
+                .set_features(FEATURE_WIDE_FIELDS | FEATURE_STOP_CHUNK)
+                .set_rate_limit(u64::MAX)
+                .set_priority(Priority::High)
+                .set_features_echo(u32::MAX)
+                .set_cookie(Some([1; 16]))
+                .set_congestion(1, 2)
+                .set_grant(Some(GrantFrame::new_zeroed()))
+                .set_upload(7)
+                .set_prefetch((0..MAX_PREFETCH as u32).map(ChunkId)),
+            |ticket, chunk_id| {
+                ticket
+                    .set_get_chunk(chunk_id, 0, 100)
+                    .set_stop_chunk(chunk_id, StopReason::Aborted)
+            },
+        );
+        let length: usize = ticket.build().0.iter().map(|part| part.len()).sum();
+        assert!(length <= MTU, "{length}");
+        assert!(
+            length + frame_overhead::<GetChunkFrame>() + frame_overhead::<StopChunkFrame>() > MTU
+        );
+    }
+}
@@ -2,45 +2,180 @@ use crate::protocol::wire::encoding::RawParts;
 use crate::protocol::wire::frames::{FrameType, ParsedFrameVariant};
 use crate::protocol::wire::packets::{PacketType, ParsedPacketVariant};
 use crate::protocol::wire::verify::PacketVerifyType;
+use crate::util::units::SessionId;
 
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::ops::Range;
 use std::sync::atomic::{AtomicU32, Ordering::Relaxed};
+use std::sync::{Arc, LazyLock, Mutex};
 
 use bytes::Bytes;
+use layout::wire_struct;
+use rand::{Rng, SeedableRng, rngs::StdRng};
 
 use zerocopy::byteorder::{BigEndian, U16, U32};
 use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned};
 
 pub mod encoding;
 pub mod frames;
+pub mod layout;
 pub mod packets;
 pub mod verify;
 
-static ID_COUNTER: AtomicU32 = AtomicU32::new(0);
+// Numbers packets built outside any session, from a random start so the ids
+// of one run do not line up with those of the last.
+static ID_COUNTER: LazyLock<AtomicU32> = LazyLock::new(|| AtomicU32::new(rand::random()));
+
+static SESSIONS: LazyLock<Mutex<HashMap<SocketAddr, Arc<PacketIds>>>> =
+    LazyLock::new(Mutex::default);
+
+// Replaces the random draws of this thread, sessions included.
+struct Seeded {
+    rng: StdRng,
+    next_id: u32,
+    sessions: HashMap<SocketAddr, Arc<PacketIds>>,
+}
+
+thread_local! {
+    static SEEDED: RefCell<Option<Seeded>> = const { RefCell::new(None) };
+}
+
+// Packets built on this thread from now on are numbered from a start drawn
+// from `seed`, so simulations on a current-thread runtime repeat exactly.
+// Sessions started before are forgotten.
+pub fn seed_packet_ids(seed: u64) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let next_id = rng.random();
+    SEEDED.set(Some(Seeded {
+        rng,
+        next_id,
+        sessions: HashMap::new(),
+    }));
+}
+
 fn new_packet_id() -> u32 {
-    ID_COUNTER.fetch_add(1, Relaxed)
+    SEEDED.with_borrow_mut(|seeded| match seeded {
+        Some(seeded) => {
+            let id = seeded.next_id;
+            seeded.next_id = id.wrapping_add(1);
+            id
+        }
+        None => ID_COUNTER.fetch_add(1, Relaxed),
+    })
 }
 
-#[repr(C)]
-#[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
-pub struct CommonPacketHeader {
-    version: u8,
-    packet_type: u8,
-    header_length: U16<BigEndian>,
-    body_length: U16<BigEndian>,
-    packet_id: U32<BigEndian>,
+// A factor in `range`, e.g. to jitter a delay, drawn as packet ids are so a
+// seeded thread repeats it too.
+pub fn random_factor(range: Range<f64>) -> f64 {
+    SEEDED.with_borrow_mut(|seeded| match seeded {
+        Some(seeded) => seeded.rng.random_range(range),
+        None => rand::random_range(range),
+    })
+}
+
+// The ids of the packets sent to one peer. Both the session id and the first
+// packet id are drawn at random, so ids of different sessions and of restarts
+// do not collide and logs of one session can be told apart.
+#[derive(Debug)]
+pub struct PacketIds {
+    session_id: SessionId,
+    next_id: AtomicU32,
+}
+
+impl PacketIds {
+    fn drawn_from(rng: &mut impl Rng) -> Arc<Self> {
+        Arc::new(Self {
+            session_id: SessionId(rng.random()),
+            next_id: AtomicU32::new(rng.random()),
+        })
+    }
+
+    // The session with `peer`, started by the first call.
+    pub fn for_peer(peer: SocketAddr) -> Arc<Self> {
+        SEEDED.with_borrow_mut(|seeded| match seeded {
+            Some(seeded) => seeded
+                .sessions
+                .entry(peer)
+                .or_insert_with(|| Self::drawn_from(&mut seeded.rng))
+                .clone(),
+            None => SESSIONS
+                .lock()
+                .unwrap()
+                .entry(peer)
+                .or_insert_with(|| Self::drawn_from(&mut rand::rng()))
+                .clone(),
+        })
+    }
+
+    // The session with `peer` if there is one, for packets to peers that may
+    // not be worth a session.
+    pub fn existing(peer: SocketAddr) -> Option<Arc<Self>> {
+        SEEDED.with_borrow(|seeded| match seeded {
+            Some(seeded) => seeded.sessions.get(&peer).cloned(),
+            None => SESSIONS.lock().unwrap().get(&peer).cloned(),
+        })
+    }
+
+    // Ends the session with `peer`, the next packet to it starts another.
+    pub fn forget(peer: SocketAddr) {
+        SEEDED.with_borrow_mut(|seeded| match seeded {
+            Some(seeded) => {
+                seeded.sessions.remove(&peer);
+            }
+            None => {
+                SESSIONS.lock().unwrap().remove(&peer);
+            }
+        });
+    }
+
+    pub fn session_id(&self) -> SessionId {
+        self.session_id
+    }
+
+    fn next_id(&self) -> u32 {
+        self.next_id.fetch_add(1, Relaxed)
+    }
+}
+
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
+    pub struct CommonPacketHeader {
+        version: u8,
+        packet_type: u8,
+        header_length: U16<BigEndian>,
+        body_length: U16<BigEndian>,
+        packet_id: U32<BigEndian>,
+    }
+}
+
+impl CommonPacketHeader {
+    pub fn packet_id(&self) -> u32 {
+        self.packet_id.get()
+    }
 }
 
 pub trait SpecificPacketHeader: RawParts {
     fn get_packet_type(&self) -> PacketType;
 }
 
-#[repr(C)]
-#[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
-struct CommonFrameHeader {
-    frame_type: u8,
-    frame_length: U16<BigEndian>,
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Debug)]
+    struct CommonFrameHeader {
+        frame_type: u8,
+        frame_length: U16<BigEndian>,
+    }
 }
 
+// Where the chunk id of a data packet starts, for steering packets before they
+// are parsed.
+pub const DATA_CHUNK_ID_OFFSET: usize = std::mem::size_of::<CommonPacketHeader>()
+    + std::mem::size_of::<packets::DataPacketHeader>()
+    + std::mem::size_of::<CommonFrameHeader>();
+
 pub trait SpecificFrameHeader: RawParts {
     fn get_frame_type(&self) -> FrameType;
 }
@@ -1,14 +1,21 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
 
-use super::encoding::FrameExt;
-use super::frames::DataFrame;
+use super::encoding::{FrameExt, RawParts};
+use super::frames::{DataFrame, PaddingFrame};
+use super::layout::{TICKET_CHUNKS, wire_struct};
 use super::verify::PacketVerificationData;
-use super::{Packet, SpecificPacketHeader};
-use crate::constants::PUB_KEY_LENGTH;
+use super::{CommonPacketHeader, Packet, SpecificPacketHeader};
+use crate::constants::{COOKIE_LENGTH, PUB_KEY_LENGTH};
 use crate::protocol::key_ring::KEY_RING;
-use crate::protocol::wire::frames::{GetChunkFrame, RateLimitFrame};
+use crate::protocol::wire::frames::{
+    CongestionFrame, CookieFrame, FEATURE_STOP_CHUNK, FEATURE_WIDE_FIELDS, FeaturesEchoFrame,
+    GetChunkFrame, GrantFrame, PrefetchFrame, Priority, PriorityFrame, RateLimitFrame,
+    StopChunkFrame, StopReason, UploadFrame, WideRateLimitFrame,
+};
 use crate::protocol::wire::verify::PacketVerifyType;
 use crate::util::log::current_timestamp_ms;
+use crate::util::units::ChunkId;
 
 use bytes::{Buf, Bytes};
 use ed25519_dalek::PUBLIC_KEY_LENGTH;
@@ -16,6 +23,14 @@ use num_enum::{IntoPrimitive, TryFromPrimitive};
 
 use zerocopy::{BigEndian, FromBytes, Immutable, IntoBytes, KnownLayout, U64, Unaligned};
 
+// Packet types left to experiments and vendor extensions, never assigned here.
+// Unlike frames, an unknown packet is always rejected, as its verification is
+// unknown too, so both peers must agree on these beforehand.
+pub const EXPERIMENTAL_PACKET_TYPES: RangeInclusive<u8> = 0xf0..=0xff;
+
+// Prefetch hints a ticket carries at most, see `layout::TICKET_CHUNKS`.
+pub const MAX_PREFETCH: usize = 8;
+
 #[repr(u8)]
 #[derive(
     Debug, Clone, Copy, PartialEq, Eq, IntoPrimitive, TryFromPrimitive, Unaligned, Immutable,
@@ -24,6 +39,8 @@ use zerocopy::{BigEndian, FromBytes, Immutable, IntoBytes, KnownLayout, U64, Una
 pub enum PacketType {
     Data = 0b1000_0001,
     Ticket = 0b0100_0001,
+    CookieReply = 0b1000_0010,
+    Control = 0b1000_0011,
 }
 
 impl PacketType {
@@ -34,24 +51,46 @@ impl PacketType {
         match &self {
             PacketType::Data => DataPacket::<INFO_LENGTH>::try_parse(data),
             PacketType::Ticket => TicketPacket::try_parse(data),
+            PacketType::CookieReply => CookieReplyPacket::try_parse(data),
+            PacketType::Control => ControlPacket::try_parse(data),
         }
     }
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)] // Named after the packets.
 pub enum ParsedPacketVariant {
     DataPacket(),
-    TicketPacket { pub_key: Bytes, timestamp_ms: u64 },
+    ControlPacket(),
+    TicketPacket {
+        pub_key: Bytes,
+        timestamp_ms: u64,
+    },
+    CookieReplyPacket {
+        cookie: [u8; COOKIE_LENGTH],
+        echo_timestamp_ms: u64,
+    },
 }
 
 impl ParsedPacketVariant {
+    pub fn verify_type(&self) -> PacketVerifyType {
+        match self {
+            ParsedPacketVariant::DataPacket()
+            | ParsedPacketVariant::ControlPacket()
+            | ParsedPacketVariant::CookieReplyPacket { .. } => PacketVerifyType::CRC64,
+            ParsedPacketVariant::TicketPacket { .. } => PacketVerifyType::Ed25519,
+        }
+    }
+
     pub fn build_verification_data<'a>(
         &'a self,
         pkt: &'a [u8],
         verification_field: &'a [u8],
     ) -> PacketVerificationData<'a> {
         match self {
-            ParsedPacketVariant::DataPacket() => PacketVerificationData::CRC64 {
+            ParsedPacketVariant::DataPacket()
+            | ParsedPacketVariant::ControlPacket()
+            | ParsedPacketVariant::CookieReplyPacket { .. } => PacketVerificationData::CRC64 {
                 pkt,
                 crc64: verification_field,
             },
@@ -64,9 +103,11 @@ impl ParsedPacketVariant {
     }
 }
 
-#[repr(C)]
-#[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout)]
-pub struct DataPacketHeader {}
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout)]
+    pub struct DataPacketHeader {}
+}
 
 impl SpecificPacketHeader for DataPacketHeader {
     fn get_packet_type(&self) -> PacketType {
@@ -76,29 +117,59 @@ impl SpecificPacketHeader for DataPacketHeader {
 
 pub struct DataPacket<const INFO_LENGTH: usize> {
     header: DataPacketHeader,
-    data: DataFrame<INFO_LENGTH>, // DataFrame<12> for raptorq
+    data: Option<DataFrame<INFO_LENGTH>>, // DataFrame<12> for raptorq
+    padding: Option<PaddingFrame>,
 }
 
 impl<const INFO_LENGTH: usize> From<DataFrame<INFO_LENGTH>> for DataPacket<INFO_LENGTH> {
     fn from(data: DataFrame<INFO_LENGTH>) -> Self {
         Self {
             header: DataPacketHeader {},
-            data,
+            data: Some(data),
+            padding: None,
         }
     }
 }
 
 impl<const INFO_LENGTH: usize> DataPacket<INFO_LENGTH> {
     pub fn new(
-        chunk_id: u32,
+        chunk_id: ChunkId,
         offset: u32,
         transmission_info: [u8; INFO_LENGTH],
         data: Vec<u8>,
     ) -> Self {
+        DataFrame::new(chunk_id, offset, transmission_info, Bytes::from(data)).into()
+    }
+
+    // A packet without data, sent as cover traffic. Pad it to the size of the
+    // data packets it hides among.
+    pub fn cover() -> Self {
         Self {
             header: DataPacketHeader {},
-            data: DataFrame::new(chunk_id, offset, transmission_info, Bytes::from(data)),
+            data: None,
+            padding: None,
+        }
+    }
+
+    // Length of the built packet.
+    pub fn wire_len(&self) -> usize {
+        CommonPacketHeader::raw_len()
+            + DataPacketHeader::raw_len()
+            + self.data.as_ref().map_or(0, FrameExt::wire_len)
+            + self.padding.as_ref().map_or(0, FrameExt::wire_len)
+            + Self::PACKET_VERIFICATION_TYPE.signature_len()
+    }
+
+    // Pads the packet to `size` bytes. One already longer, or too close to
+    // `size` to fit a padding frame, is left unpadded.
+    pub fn padded_to(mut self, size: usize) -> Self {
+        self.padding = None;
+        let padding_header = PaddingFrame::new(0).wire_len();
+        let missing = size.saturating_sub(self.wire_len());
+        if missing >= padding_header {
+            self.padding = Some(PaddingFrame::new(missing - padding_header));
         }
+        self
     }
 }
 
@@ -110,20 +181,24 @@ impl<const INFO_LENGTH: usize> Packet for DataPacket<INFO_LENGTH> {
     fn get_header(&self) -> &Self::Header {
         &self.header
     }
+    // The data frame goes first, where receivers steering by chunk id look for it.
     fn get_body(self) -> impl Iterator<Item = super::BuiltFrame> {
-        let built = self.data.build();
-        std::iter::once(built)
+        let data = self.data.map(|frame| frame.build()).into_iter();
+        let padding = self.padding.map(|frame| frame.build()).into_iter();
+        data.chain(padding)
     }
     fn try_parse(data: Bytes) -> Option<ParsedPacketVariant> {
         (data.is_empty()).then_some(ParsedPacketVariant::DataPacket())
     }
 }
 
-#[repr(C)]
-#[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout)]
-pub struct TicketPacketHeader {
-    pub pubkey: [u8; PUBLIC_KEY_LENGTH],
-    pub timestamp_ms: U64<BigEndian>,
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout, Clone, Copy)]
+    pub struct TicketPacketHeader {
+        pub pubkey: [u8; PUBLIC_KEY_LENGTH],
+        pub timestamp_ms: U64<BigEndian>,
+    }
 }
 
 impl SpecificPacketHeader for TicketPacketHeader {
@@ -134,8 +209,20 @@ impl SpecificPacketHeader for TicketPacketHeader {
 
 pub struct TicketPacket {
     header: TicketPacketHeader,
-    rate_limit: Option<RateLimitFrame>,
-    get_chunk: HashMap<u32, GetChunkFrame>,
+    rate_kbps: Option<u64>,
+    priority: Priority,
+    // Those the server listed, picking which frames carry the fields above.
+    features: u32,
+    echo: Option<FeaturesEchoFrame>,
+    cookie: Option<CookieFrame>,
+    congestion: Option<CongestionFrame>,
+    grant: Option<GrantFrame>,
+    upload: Option<UploadFrame>,
+    prefetch: Vec<PrefetchFrame>,
+    // Ordered, so the same ticket always builds the same bytes.
+    get_chunk: BTreeMap<ChunkId, GetChunkFrame>,
+    stop_chunk: BTreeMap<ChunkId, StopChunkFrame>,
+    generation: u64,
 }
 
 impl Default for TicketPacket {
@@ -155,20 +242,96 @@ impl TicketPacket {
                 pubkey,
                 timestamp_ms: current_timestamp_ms().into(),
             },
-            rate_limit: None,
-            get_chunk: HashMap::new(),
+            rate_kbps: None,
+            priority: Priority::Normal,
+            features: 0,
+            echo: None,
+            cookie: None,
+            congestion: None,
+            grant: None,
+            upload: None,
+            prefetch: vec![],
+            get_chunk: BTreeMap::new(),
+            stop_chunk: BTreeMap::new(),
+            generation: 0,
         }
     }
-    pub fn set_rate_limit(mut self, rate_kpbs: u32) -> Self {
-        self.rate_limit = Some(RateLimitFrame {
-            desired_max_kbps: rate_kpbs.into(),
+    // Past u32::MAX kbps the rate needs the wide frame, otherwise it is capped.
+    pub fn set_rate_limit(mut self, rate_kbps: u64) -> Self {
+        self.rate_kbps = Some(rate_kbps);
+        self
+    }
+
+    // Normal transfers send no Priority frame.
+    pub fn set_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn set_features(mut self, features: u32) -> Self {
+        self.features = features;
+        self
+    }
+
+    // The flags of the server's Features frame as heard, unmasked, see
+    // `FeaturesEchoFrame`.
+    pub fn set_features_echo(mut self, flags: u32) -> Self {
+        self.echo = Some(FeaturesEchoFrame::new(flags));
+        self
+    }
+
+    pub fn set_cookie(mut self, cookie: Option<[u8; COOKIE_LENGTH]>) -> Self {
+        self.cookie = cookie.map(|cookie| CookieFrame { cookie });
+        self
+    }
+
+    pub fn set_congestion(mut self, ce_packets: u32, total_packets: u32) -> Self {
+        self.congestion = Some(CongestionFrame {
+            ce_packets: ce_packets.into(),
+            total_packets: total_packets.into(),
         });
         self
     }
 
+    // Lets a key minted with a download token in, instead of an authorized one.
+    pub fn set_grant(mut self, grant: Option<GrantFrame>) -> Self {
+        self.grant = grant;
+        self
+    }
+
+    // Offers the file to the receiver of the ticket instead of asking for chunks.
+    pub fn set_upload(mut self, file_id: u64) -> Self {
+        self.upload = Some(UploadFrame {
+            file_id: file_id.into(),
+        });
+        self
+    }
+
+    pub fn set_timestamp(mut self, timestamp_ms: u64) -> Self {
+        self.header.timestamp_ms = timestamp_ms.into();
+        self
+    }
+
+    pub fn set_prefetch(mut self, chunk_ids: impl IntoIterator<Item = ChunkId>) -> Self {
+        self.prefetch = chunk_ids
+            .into_iter()
+            .take(MAX_PREFETCH)
+            .map(|chunk_id| PrefetchFrame {
+                chunk_id: chunk_id.into(),
+            })
+            .collect();
+        self
+    }
+
+    // Stamped on every GetChunk frame of the ticket.
+    pub fn set_generation(mut self, generation: u64) -> Self {
+        self.generation = generation;
+        self
+    }
+
     pub fn set_get_chunk(
         mut self,
-        chunk_id: u32,
+        chunk_id: ChunkId,
         next_received_offset: u32,
         receive_window: u32,
     ) -> Self {
@@ -178,10 +341,56 @@ impl TicketPacket {
                 chunk_id: chunk_id.into(),
                 next_receive_offset: next_received_offset.into(),
                 receive_window_frames: receive_window.into(),
+                generation: 0.into(),
             },
         );
         self
     }
+
+    // Dropped unless the server lists StopChunk frames among its features, so
+    // the GetChunk frame closing the window has to be set as well.
+    pub fn set_stop_chunk(mut self, chunk_id: ChunkId, reason: StopReason) -> Self {
+        self.stop_chunk
+            .insert(chunk_id, StopChunkFrame::new(chunk_id, reason));
+        self
+    }
+
+    // A ticket of more chunks than fit an MTU, see `layout::TICKET_CHUNKS`, as
+    // several. Each repeats what authenticates and paces it, the congestion,
+    // upload and prefetch frames go with the first only.
+    pub fn split(mut self) -> Vec<Self> {
+        let mut tickets = vec![];
+        loop {
+            let mut chunk_ids: Vec<ChunkId> = self
+                .get_chunk
+                .keys()
+                .chain(self.stop_chunk.keys())
+                .copied()
+                .collect();
+            chunk_ids.sort();
+            chunk_ids.dedup();
+            let Some(first_left) = chunk_ids.get(TICKET_CHUNKS) else {
+                tickets.push(self);
+                return tickets;
+            };
+            let next = Self {
+                header: self.header,
+                rate_kbps: self.rate_kbps,
+                priority: self.priority,
+                features: self.features,
+                echo: self.echo,
+                cookie: self.cookie,
+                congestion: None,
+                grant: self.grant,
+                upload: None,
+                prefetch: vec![],
+                get_chunk: self.get_chunk.split_off(first_left),
+                stop_chunk: self.stop_chunk.split_off(first_left),
+                generation: self.generation,
+            };
+            tickets.push(std::mem::replace(&mut self, next));
+        }
+    }
 }
 
 impl Packet for TicketPacket {
@@ -193,14 +402,60 @@ impl Packet for TicketPacket {
         &self.header
     }
     fn get_body(self) -> impl Iterator<Item = super::BuiltFrame> {
+        let wide = self.features & FEATURE_WIDE_FIELDS != 0;
+        let can_stop = self.features & FEATURE_STOP_CHUNK != 0;
         let rate_limit = self
-            .rate_limit
-            .map(|rate_limit| rate_limit.build())
+            .rate_kbps
+            .map(|kbps| match u32::try_from(kbps) {
+                Ok(kbps) => RateLimitFrame {
+                    desired_max_kbps: kbps.into(),
+                }
+                .build(),
+                Err(_) if wide => WideRateLimitFrame {
+                    desired_max_kbps: kbps.into(),
+                }
+                .build(),
+                Err(_) => RateLimitFrame {
+                    desired_max_kbps: u32::MAX.into(),
+                }
+                .build(),
+            })
+            .into_iter();
+        let priority = (self.priority != Priority::Normal)
+            .then(|| PriorityFrame::new(self.priority).build())
             .into_iter();
 
-        let get_packets = self.get_chunk.into_values().map(|frame| frame.build());
+        let echo = self.echo.map(|echo| echo.build()).into_iter();
+        let cookie = self.cookie.map(|cookie| cookie.build()).into_iter();
+        let congestion = self
+            .congestion
+            .map(|congestion| congestion.build())
+            .into_iter();
 
-        rate_limit.chain(get_packets)
+        let grant = self.grant.map(|grant| grant.build()).into_iter();
+        let upload = self.upload.map(|upload| upload.build()).into_iter();
+        let prefetch = self.prefetch.into_iter().map(|frame| frame.build());
+        let stop_chunk = self
+            .stop_chunk
+            .into_values()
+            .filter(move |_| can_stop)
+            .map(|frame| frame.build());
+        let generation = self.generation;
+        let get_packets = self.get_chunk.into_values().map(move |mut frame| {
+            frame.generation = generation.into();
+            frame.build()
+        });
+
+        rate_limit
+            .chain(priority)
+            .chain(echo)
+            .chain(cookie)
+            .chain(congestion)
+            .chain(grant)
+            .chain(upload)
+            .chain(prefetch)
+            .chain(get_packets)
+            .chain(stop_chunk)
     }
     fn try_parse(data: Bytes) -> Option<ParsedPacketVariant> {
         let (pub_key, mut remain): (&[u8], &[u8]) =
@@ -216,3 +471,114 @@ impl Packet for TicketPacket {
             })
     }
 }
+
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout)]
+    pub struct CookieReplyPacketHeader {
+        pub cookie: [u8; COOKIE_LENGTH],
+        pub echo_timestamp_ms: U64<BigEndian>,
+    }
+}
+
+impl SpecificPacketHeader for CookieReplyPacketHeader {
+    fn get_packet_type(&self) -> PacketType {
+        PacketType::CookieReply
+    }
+}
+
+// Sent by a loaded server instead of honoring a ticket; the client echoes the
+// cookie in its following tickets.
+pub struct CookieReplyPacket {
+    header: CookieReplyPacketHeader,
+}
+
+impl CookieReplyPacket {
+    pub fn new(cookie: [u8; COOKIE_LENGTH], echo_timestamp_ms: u64) -> Self {
+        Self {
+            header: CookieReplyPacketHeader {
+                cookie,
+                echo_timestamp_ms: echo_timestamp_ms.into(),
+            },
+        }
+    }
+}
+
+impl Packet for CookieReplyPacket {
+    type Header = CookieReplyPacketHeader;
+    const PACKET_TYPE: PacketType = PacketType::CookieReply;
+    const PACKET_VERIFICATION_TYPE: PacketVerifyType = PacketVerifyType::CRC64;
+
+    fn get_header(&self) -> &Self::Header {
+        &self.header
+    }
+    fn get_body(self) -> impl Iterator<Item = super::BuiltFrame> {
+        std::iter::empty()
+    }
+    fn try_parse(data: Bytes) -> Option<ParsedPacketVariant> {
+        let header = CookieReplyPacketHeader::read_from_bytes(data.as_bytes()).ok()?;
+        ParsedPacketVariant::CookieReplyPacket {
+            cookie: header.cookie,
+            echo_timestamp_ms: header.echo_timestamp_ms.into(),
+        }
+        .into()
+    }
+}
+
+wire_struct! {
+    #[repr(C)]
+    #[derive(IntoBytes, FromBytes, Unaligned, Immutable, KnownLayout)]
+    pub struct ControlPacketHeader {}
+}
+
+impl SpecificPacketHeader for ControlPacketHeader {
+    fn get_packet_type(&self) -> PacketType {
+        PacketType::Control
+    }
+}
+
+// Control frames from the server back to the client, e.g. errors about a chunk.
+pub struct ControlPacket {
+    header: ControlPacketHeader,
+    frames: Vec<super::BuiltFrame>,
+}
+
+impl Default for ControlPacket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ControlPacket {
+    pub fn new() -> Self {
+        Self {
+            header: ControlPacketHeader {},
+            frames: vec![],
+        }
+    }
+
+    pub fn push<F: super::Frame>(mut self, frame: F) -> Self {
+        self.frames.push(frame.build());
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+}
+
+impl Packet for ControlPacket {
+    type Header = ControlPacketHeader;
+    const PACKET_TYPE: PacketType = PacketType::Control;
+    const PACKET_VERIFICATION_TYPE: PacketVerifyType = PacketVerifyType::CRC64;
+
+    fn get_header(&self) -> &Self::Header {
+        &self.header
+    }
+    fn get_body(self) -> impl Iterator<Item = super::BuiltFrame> {
+        self.frames.into_iter()
+    }
+    fn try_parse(data: Bytes) -> Option<ParsedPacketVariant> {
+        (data.is_empty()).then_some(ParsedPacketVariant::ControlPacket())
+    }
+}
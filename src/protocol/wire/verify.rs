@@ -46,6 +46,16 @@ pub enum PacketVerifyType {
     Ed25519,
 }
 
+impl PacketVerifyType {
+    // Bytes the verification field adds to the end of a packet.
+    pub const fn signature_len(&self) -> usize {
+        match self {
+            Self::CRC64 => std::mem::size_of::<u64>(),
+            Self::Ed25519 => ed25519_dalek::SIGNATURE_LENGTH,
+        }
+    }
+}
+
 impl<'a> PacketVerificationData<'a> {
     pub fn pkt_len(&self) -> usize {
         match self {
@@ -118,7 +128,7 @@ impl KeyRing {
     fn parse_and_check_key(&self, pub_key: &[u8]) -> Result<VerifyingKey, PacketVerificationError> {
         let key = VerifyingKey::try_from(pub_key)
             .map_err(|_| PacketVerificationError::IncorrectLength)?;
-        if !self.public_key_rings.contains(&key) {
+        if !self.accepts(&key) {
             return Err(PacketVerificationError::UnknownPublicKey);
         }
         Ok(key)
@@ -180,8 +190,8 @@ mod tests {
         let exported_verifying_key = hex::encode(verifying_key.as_bytes());
         dbg!(&exported_signing_key, &exported_verifying_key);
 
-        let server_keyring = KeyRing::new(vec![exported_verifying_key], None);
-        let clietn_keyring = KeyRing::new(vec![], Some(exported_signing_key));
+        let server_keyring = KeyRing::new(vec![exported_verifying_key], vec![]);
+        let clietn_keyring = KeyRing::new(vec![], vec![exported_signing_key]);
         (server_keyring, clietn_keyring)
     }
 
@@ -237,7 +247,7 @@ mod tests {
         let derived_public_key = client.derive_public_key().unwrap();
 
         dbg!(hex::encode_upper(&signature));
-        dbg!(hex::encode_upper(&derived_public_key));
+        dbg!(hex::encode_upper(derived_public_key));
 
         let whole_packet = pkt_slices
             .into_iter()
@@ -255,7 +265,7 @@ mod tests {
 
         server.verify(whole_packet.clone()).unwrap();
 
-        KeyRing::new(vec![], None)
+        KeyRing::new(vec![], vec![])
             .verify(whole_packet)
             .expect_err("Should fail when no pubkey");
     }
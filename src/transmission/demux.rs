@@ -0,0 +1,175 @@
+use super::errors::{RETRY_DELAY, SocketErrors};
+use super::pool::{BufferPool, PooledBuffer};
+use super::{Ecn, UdpSocketLike};
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+
+type Peers = Arc<Mutex<HashMap<SocketAddr, flume::Sender<(Bytes, SocketAddr, Ecn)>>>>;
+
+// One socket shared by the receivers of several peers, e.g. one per plan when
+// downloading from several servers at once. A task reads it and hands every
+// packet to the receiver of the peer it came from, packets of other peers are
+// dropped. Sends go straight out.
+pub struct SharedSocket<S> {
+    socket: Arc<S>,
+    peers: Peers,
+    reader: JoinHandle<()>,
+}
+
+impl<S: UdpSocketLike + 'static> SharedSocket<S> {
+    pub fn new(socket: S) -> Self {
+        let socket = Arc::new(socket);
+        let peers = Peers::default();
+        let reader = tokio::spawn(Self::read(socket.clone(), peers.clone()));
+        Self {
+            socket,
+            peers,
+            reader,
+        }
+    }
+
+    async fn read(socket: Arc<S>, peers: Peers) {
+        let mut buffer = Arc::new(BufferPool::default()).buffer();
+        let mut errors = SocketErrors::default();
+        loop {
+            let (data, from, ecn) = match socket.recv_pooled(&mut buffer).await {
+                Ok(received) => {
+                    errors.succeeded();
+                    received
+                }
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                Err(err) => {
+                    match errors.failed("receiving on the shared socket", err, Instant::now()) {
+                        Ok(_) => {
+                            tokio::time::sleep(RETRY_DELAY).await;
+                            continue;
+                        }
+                        Err(failure) => {
+                            eprintln!("{failure}, closing it.");
+                            break;
+                        }
+                    }
+                }
+            };
+            let mut peers = peers.lock().unwrap();
+            if let Some(peer) = peers.get(&from)
+                && peer.send((data, from, ecn)).is_err()
+            {
+                peers.remove(&from);
+            }
+        }
+        // Receivers see the end of the socket as their own.
+        peers.lock().unwrap().clear();
+    }
+
+    // The socket as seen by the receiver of `peer`. A later one for the same
+    // peer takes its packets over.
+    pub fn for_peer(&self, peer: SocketAddr) -> PeerSocket<S> {
+        let (sender, receiver) = flume::unbounded();
+        self.peers.lock().unwrap().insert(peer, sender);
+        PeerSocket {
+            socket: self.socket.clone(),
+            receiver,
+        }
+    }
+}
+
+impl<S> Drop for SharedSocket<S> {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+pub struct PeerSocket<S> {
+    socket: Arc<S>,
+    receiver: flume::Receiver<(Bytes, SocketAddr, Ecn)>,
+}
+
+impl<S> PeerSocket<S> {
+    async fn next(&self) -> std::io::Result<(Bytes, SocketAddr, Ecn)> {
+        self.receiver
+            .recv_async()
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::UnexpectedEof, "shared socket closed"))
+    }
+}
+
+#[async_trait]
+impl<S: UdpSocketLike> UdpSocketLike for PeerSocket<S> {
+    async fn send_to(&self, bufs: &[Bytes], target: SocketAddr) -> std::io::Result<usize> {
+        self.socket.send_to(bufs, target).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        let (length, from, _) = self.recv_from_ecn(buf).await?;
+        Ok((length, from))
+    }
+
+    async fn send_to_after(
+        &self,
+        bufs: &[Bytes],
+        target: SocketAddr,
+        delay: Duration,
+    ) -> std::io::Result<usize> {
+        self.socket.send_to_after(bufs, target, delay).await
+    }
+
+    fn flow_label(&self, peer: SocketAddr) -> Option<u32> {
+        self.socket.flow_label(peer)
+    }
+
+    fn traffic_class(&self) -> Option<u8> {
+        self.socket.traffic_class()
+    }
+
+    async fn recv_from_ecn(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr, Ecn)> {
+        let (data, from, ecn) = self.next().await?;
+        let length = data.len().min(buf.len());
+        buf[..length].copy_from_slice(&data[..length]);
+        Ok((length, from, ecn))
+    }
+
+    // Packets are already cut off the shared socket's buffers.
+    async fn recv_pooled(
+        &self,
+        _buffer: &mut PooledBuffer,
+    ) -> std::io::Result<(Bytes, SocketAddr, Ecn)> {
+        self.next().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transmission::mock::MockSocket;
+
+    #[tokio::test]
+    async fn packets_go_to_their_peer() {
+        let local: SocketAddr = "127.0.0.1:10000".parse().unwrap();
+        let (a, b, stranger): (SocketAddr, SocketAddr, SocketAddr) = (
+            "127.0.0.1:10001".parse().unwrap(),
+            "127.0.0.1:10002".parse().unwrap(),
+            "127.0.0.1:10003".parse().unwrap(),
+        );
+        // Mock sockets report the address a packet was sent to as its source.
+        let (shared, remote) = MockSocket::pair(local, a);
+        let shared = SharedSocket::new(shared);
+        let (to_a, to_b) = (shared.for_peer(a), shared.for_peer(b));
+
+        for (payload, peer) in [("stray", stranger), ("for b", b), ("for a", a)] {
+            remote.send_to(&[Bytes::from(payload)], peer).await.unwrap();
+        }
+        let mut buf = vec![0u8; 16];
+        let (length, from) = to_a.recv_from(&mut buf).await.unwrap();
+        assert_eq!((&buf[..length], from), (&b"for a"[..], a));
+        let (length, from) = to_b.recv_from(&mut buf).await.unwrap();
+        assert_eq!((&buf[..length], from), (&b"for b"[..], b));
+        assert!(to_a.receiver.is_empty() && to_b.receiver.is_empty());
+    }
+}
@@ -0,0 +1,217 @@
+use bytes::Bytes;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use tokio::time::{Duration, Instant};
+
+use crate::constants::MTU;
+
+// What an engine loop does about a failed socket call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketErrorKind {
+    // Retried: the call was interrupted or would block, or the network or the
+    // peer was briefly unreachable, which UDP sockets report on later calls.
+    Transient,
+    // Only the packet at hand is lost, e.g. one too large for the path.
+    Packet,
+    // The socket is closed or unusable, retrying gets nowhere.
+    Fatal,
+}
+
+pub fn classify(err: &io::Error) -> SocketErrorKind {
+    match err.kind() {
+        ErrorKind::Interrupted
+        | ErrorKind::WouldBlock
+        | ErrorKind::TimedOut
+        | ErrorKind::ConnectionRefused
+        | ErrorKind::ConnectionReset
+        | ErrorKind::HostUnreachable
+        | ErrorKind::NetworkUnreachable
+        | ErrorKind::NetworkDown
+        | ErrorKind::AddrNotAvailable
+        // Firewall rules dropping packets on the way out.
+        | ErrorKind::PermissionDenied => SocketErrorKind::Transient,
+        ErrorKind::InvalidInput => SocketErrorKind::Packet,
+        _ => match err.raw_os_error() {
+            Some(libc::ENOBUFS) => SocketErrorKind::Transient,
+            Some(libc::EMSGSIZE) => SocketErrorKind::Packet,
+            _ => SocketErrorKind::Fatal,
+        },
+    }
+}
+
+// A packet refused before it reaches the socket for being over the MTU: the
+// IP layer would fragment it, and peers drop packets past the MTU anyway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketTooLarge {
+    pub length: usize,
+    pub mtu: usize,
+}
+
+impl fmt::Display for PacketTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "packet of {} bytes over the MTU of {}",
+            self.length, self.mtu
+        )
+    }
+}
+
+impl std::error::Error for PacketTooLarge {}
+
+// Loses the packet only, as `InvalidInput` does, see `classify`.
+impl From<PacketTooLarge> for io::Error {
+    fn from(err: PacketTooLarge) -> Self {
+        io::Error::new(ErrorKind::InvalidInput, err)
+    }
+}
+
+// The length of the packet in `bufs`, Err with `PacketTooLarge` past the MTU.
+pub fn check_size(bufs: &[Bytes]) -> io::Result<usize> {
+    let length = bufs.iter().map(Bytes::len).sum();
+    if length > MTU {
+        return Err(PacketTooLarge { length, mtu: MTU }.into());
+    }
+    Ok(length)
+}
+
+// Transient errors coming back for this long without a call succeeding count
+// as fatal, e.g. those of an interface that went away for good.
+pub const MAX_ERROR_BURST: Duration = Duration::from_secs(30);
+
+// Loops sleep this long after a transient error, so they do not spin on one.
+pub const RETRY_DELAY: Duration = Duration::from_millis(10);
+
+// The error a loop ended on, and what it was doing.
+#[derive(Debug)]
+pub struct SocketFailure {
+    pub context: &'static str,
+    pub source: io::Error,
+}
+
+impl fmt::Display for SocketFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed: {}", self.context, self.source)
+    }
+}
+
+impl std::error::Error for SocketFailure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+// Errors of one kind of call of a loop, e.g. its receives. The first of a
+// burst and those losing a packet are logged.
+#[derive(Debug, Default)]
+pub struct SocketErrors {
+    burst_since: Option<Instant>,
+}
+
+impl SocketErrors {
+    pub fn succeeded(&mut self) {
+        self.burst_since = None;
+    }
+
+    // Err once the loop has to give up.
+    pub fn failed(
+        &mut self,
+        context: &'static str,
+        err: io::Error,
+        now: Instant,
+    ) -> Result<SocketErrorKind, SocketFailure> {
+        let kind = classify(&err);
+        let failure = SocketFailure {
+            context,
+            source: err,
+        };
+        match kind {
+            SocketErrorKind::Fatal => return Err(failure),
+            SocketErrorKind::Packet => eprintln!("{failure}, packet dropped."),
+            SocketErrorKind::Transient => match self.burst_since {
+                None => {
+                    eprintln!("{failure}, retrying.");
+                    self.burst_since = Some(now);
+                }
+                Some(since) if now - since >= MAX_ERROR_BURST => return Err(failure),
+                Some(_) => {}
+            },
+        }
+        Ok(kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_by_kind() {
+        for (err, kind) in [
+            (ErrorKind::Interrupted.into(), SocketErrorKind::Transient),
+            (ErrorKind::WouldBlock.into(), SocketErrorKind::Transient),
+            (
+                io::Error::from_raw_os_error(libc::ENETUNREACH),
+                SocketErrorKind::Transient,
+            ),
+            (
+                io::Error::from_raw_os_error(libc::ENOBUFS),
+                SocketErrorKind::Transient,
+            ),
+            (
+                io::Error::from_raw_os_error(libc::EMSGSIZE),
+                SocketErrorKind::Packet,
+            ),
+            (
+                io::Error::from_raw_os_error(libc::EBADF),
+                SocketErrorKind::Fatal,
+            ),
+            (ErrorKind::UnexpectedEof.into(), SocketErrorKind::Fatal),
+        ] {
+            assert_eq!(classify(&err), kind, "{err}");
+        }
+    }
+
+    #[test]
+    fn oversized_packets_are_refused() {
+        let packet = [Bytes::from(vec![0; MTU - 10]), Bytes::from(vec![0; 10])];
+        assert_eq!(check_size(&packet).unwrap(), MTU);
+        let err = check_size(&[Bytes::from(vec![0; MTU + 1])]).unwrap_err();
+        assert_eq!(classify(&err), SocketErrorKind::Packet);
+        let too_large = err.get_ref().unwrap().downcast_ref::<PacketTooLarge>();
+        assert_eq!(
+            too_large,
+            Some(&PacketTooLarge {
+                length: MTU + 1,
+                mtu: MTU
+            })
+        );
+    }
+
+    #[test]
+    fn long_bursts_are_fatal() {
+        let mut errors = SocketErrors::default();
+        let start = Instant::now();
+        let unreachable = || io::Error::from_raw_os_error(libc::ENETUNREACH);
+        for seconds in [0, 10, 29] {
+            let now = start + Duration::from_secs(seconds);
+            assert!(errors.failed("sending", unreachable(), now).is_ok());
+        }
+        // A success ends the burst.
+        errors.succeeded();
+        let later = start + Duration::from_secs(40);
+        assert!(errors.failed("sending", unreachable(), later).is_ok());
+        let failure = errors
+            .failed("sending", unreachable(), later + MAX_ERROR_BURST)
+            .unwrap_err();
+        assert!(
+            failure.to_string().starts_with("sending failed"),
+            "{failure}"
+        );
+        assert!(
+            errors
+                .failed("receiving", ErrorKind::UnexpectedEof.into(), later)
+                .is_err()
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use super::UdpSocketLike;
+use super::errors::check_size;
 use async_trait::async_trait;
 use bytes::Bytes;
 use flume::{Receiver, Sender};
@@ -9,6 +10,8 @@ pub struct MockSocket {
     sender: Sender<(Bytes, SocketAddr)>,
     receiver: Receiver<(Bytes, SocketAddr)>,
     local_addr: SocketAddr,
+    // Times every packet is delivered, above 1 to test duplicate handling.
+    copies: usize,
 }
 
 impl MockSocket {
@@ -21,22 +24,30 @@ impl MockSocket {
             sender: tx1,
             receiver: rx2,
             local_addr: addr1,
+            copies: 1,
         };
         let socket2 = MockSocket {
             sender: tx2,
             receiver: rx1,
             local_addr: addr2,
+            copies: 1,
         };
 
         (socket1, socket2)
     }
+
+    // Every packet sent is delivered `copies` times, as a duplicating network would.
+    pub fn with_copies(mut self, copies: usize) -> Self {
+        self.copies = copies.max(1);
+        self
+    }
 }
 
 #[async_trait]
 impl UdpSocketLike for MockSocket {
     async fn send_to(&self, bufs: &[Bytes], target: SocketAddr) -> std::io::Result<usize> {
         // Splice the parts together to simulate the payload of a UDP packet.
-        let total_len: usize = bufs.iter().map(|b| b.len()).sum();
+        let total_len = check_size(bufs)?;
         let combined = if bufs.len() == 1 {
             bufs[0].clone()
         } else {
@@ -46,15 +57,17 @@ impl UdpSocketLike for MockSocket {
             }
             Bytes::from(v)
         };
-        self.sender
-            .send_async((combined, target))
-            .await
-            .map_err(|e| {
-                std::io::Error::new(
-                    std::io::ErrorKind::BrokenPipe,
-                    format!("channel closed: {e}"),
-                )
-            })?;
+        for _ in 0..self.copies {
+            self.sender
+                .send_async((combined.clone(), target))
+                .await
+                .map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::BrokenPipe,
+                        format!("channel closed: {e}"),
+                    )
+                })?;
+        }
         Ok(total_len)
     }
 
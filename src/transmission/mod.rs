@@ -1,11 +1,143 @@
+// Only `Transport` is there without the runtime, for plans to name it.
+#[cfg(feature = "runtime")]
+pub mod demux;
+#[cfg(feature = "runtime")]
+pub mod errors;
+#[cfg(feature = "runtime")]
 pub mod mock;
+#[cfg(feature = "runtime")]
+pub mod multipath;
+#[cfg(feature = "runtime")]
+pub mod pool;
+#[cfg(feature = "runtime")]
 pub mod real;
+#[cfg(feature = "runtime")]
+pub mod tcp;
 
+#[cfg(feature = "runtime")]
 use bytes::Bytes;
+#[cfg(feature = "runtime")]
+use pool::PooledBuffer;
+#[cfg(feature = "runtime")]
 use std::net::SocketAddr;
+#[cfg(feature = "runtime")]
+use std::time::Duration;
 
+// Picked at runtime by the client and the server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    #[default]
+    Udp,
+    // Datagrams over TCP connections, see `tcp::TcpDatagramSocket`.
+    Tcp,
+}
+
+// A transport chosen at runtime, engines take it like any other socket.
+#[cfg(feature = "runtime")]
+pub type DynSocket = Box<dyn UdpSocketLike>;
+
+// The ECN codepoint, the two low bits of the IP TOS / traffic class byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ecn {
+    NotEct,
+    Ect1,
+    Ect0,
+    Ce,
+}
+
+impl Ecn {
+    pub fn from_tos(tos: u8) -> Self {
+        match tos & 0b11 {
+            0b00 => Ecn::NotEct,
+            0b01 => Ecn::Ect1,
+            0b10 => Ecn::Ect0,
+            _ => Ecn::Ce,
+        }
+    }
+}
+
+#[cfg(feature = "runtime")]
 #[async_trait::async_trait]
 pub trait UdpSocketLike: Send + Sync {
     async fn send_to(&self, bufs: &[Bytes], target: SocketAddr) -> std::io::Result<usize>;
     async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)>;
+
+    // Hands the packet over now, to leave the host `delay` later. Sockets without
+    // kernel pacing send right away and leave pacing to the sender timers.
+    async fn send_to_after(
+        &self,
+        bufs: &[Bytes],
+        target: SocketAddr,
+        _delay: Duration,
+    ) -> std::io::Result<usize> {
+        self.send_to(bufs, target).await
+    }
+
+    // Label of the packets sent to `peer`, None if they go unlabelled.
+    fn flow_label(&self, _peer: SocketAddr) -> Option<u32> {
+        None
+    }
+
+    // TOS byte or IPv6 traffic class of outgoing packets, None if unknown.
+    fn traffic_class(&self) -> Option<u8> {
+        None
+    }
+
+    // Sockets that cannot see the TOS byte report every packet as not ECN capable.
+    async fn recv_from_ecn(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr, Ecn)> {
+        let (length, addr) = self.recv_from(buf).await?;
+        Ok((length, addr, Ecn::NotEct))
+    }
+
+    // Receives into a pooled buffer and cuts the packet off it, so receive loops
+    // neither allocate nor copy per packet.
+    async fn recv_pooled(
+        &self,
+        buffer: &mut PooledBuffer,
+    ) -> std::io::Result<(Bytes, SocketAddr, Ecn)> {
+        let (length, addr, ecn) = self.recv_from_ecn(buffer.space()).await?;
+        Ok((buffer.cut(length), addr, ecn))
+    }
+}
+
+#[cfg(feature = "runtime")]
+#[async_trait::async_trait]
+impl<T: UdpSocketLike + ?Sized> UdpSocketLike for Box<T> {
+    async fn send_to(&self, bufs: &[Bytes], target: SocketAddr) -> std::io::Result<usize> {
+        (**self).send_to(bufs, target).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        (**self).recv_from(buf).await
+    }
+
+    async fn send_to_after(
+        &self,
+        bufs: &[Bytes],
+        target: SocketAddr,
+        delay: Duration,
+    ) -> std::io::Result<usize> {
+        (**self).send_to_after(bufs, target, delay).await
+    }
+
+    fn flow_label(&self, peer: SocketAddr) -> Option<u32> {
+        (**self).flow_label(peer)
+    }
+
+    fn traffic_class(&self) -> Option<u8> {
+        (**self).traffic_class()
+    }
+
+    async fn recv_from_ecn(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr, Ecn)> {
+        (**self).recv_from_ecn(buf).await
+    }
+
+    async fn recv_pooled(
+        &self,
+        buffer: &mut PooledBuffer,
+    ) -> std::io::Result<(Bytes, SocketAddr, Ecn)> {
+        (**self).recv_pooled(buffer).await
+    }
 }
@@ -0,0 +1,416 @@
+use super::errors::{RETRY_DELAY, SocketErrors, SocketFailure};
+use super::{Ecn, UdpSocketLike, pool::BufferPool};
+use crate::protocol::wire::encoding::{ParsedPacket, parse_packet};
+use crate::util::units::ChunkId;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::task::JoinSet;
+use tokio::time::{Duration, Instant};
+
+// A path that got no data for this long while it has chunks to deliver is given up on.
+const PATH_SILENCE: Duration = Duration::from_secs(3);
+// Chunks leave a path losing more than this for one losing less than half as much.
+const HEAVY_LOSS: f64 = 0.3;
+// Taken for paths that delivered no first frame yet.
+const UNMEASURED_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PathStats {
+    // Smoothed time from asking for a chunk to its first frame, so it includes
+    // the server setting up the encoder.
+    pub delay: Option<Duration>,
+    // Smoothed share of frames missing between the offsets received.
+    pub loss: f64,
+    pub frames: u64,
+    pub bytes: u64,
+    pub chunks: usize,
+    last_data: Option<Instant>,
+    // Frames expected and received since the loss was last updated.
+    expected: u64,
+    received: u64,
+}
+
+impl PathStats {
+    fn cost(&self) -> f64 {
+        self.delay.unwrap_or(UNMEASURED_DELAY).as_secs_f64() * (1.0 + 10.0 * self.loss)
+    }
+
+    fn alive(&self, now: Instant) -> bool {
+        self.last_data
+            .is_some_and(|last_data| now - last_data < PATH_SILENCE)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ChunkOnPath {
+    path: usize,
+    asked: Instant,
+    last_offset: Option<u32>,
+}
+
+// One packet, with the index of the path it came in on. `packet` is None if
+// it failed to parse.
+#[derive(Debug)]
+pub struct Received<const INFO_LENGTH: usize> {
+    pub path: usize,
+    pub packet: Option<ParsedPacket<INFO_LENGTH>>,
+    pub length: usize,
+    pub from: SocketAddr,
+    pub ecn: Ecn,
+}
+
+// Packets of every path, read and parsed by a task per socket until dropped.
+// A socket failing for good ends with its failure, one closed with nothing.
+pub struct Incoming<const INFO_LENGTH: usize> {
+    receiver: flume::Receiver<Result<Received<INFO_LENGTH>, SocketFailure>>,
+    _tasks: JoinSet<()>,
+}
+
+impl<const INFO_LENGTH: usize> Incoming<INFO_LENGTH> {
+    pub async fn recv(&self) -> Option<Result<Received<INFO_LENGTH>, SocketFailure>> {
+        self.receiver.recv_async().await.ok()
+    }
+}
+
+// Several sockets to the same server, e.g. one per interface, each a path of
+// its own. Every chunk is fetched over one path, picked by how fast and how
+// lossy the paths have been, and moved to another one if its path goes quiet
+// or loses far more than the others.
+//
+// A path may be received on by several sockets sharing its address, see
+// `RealUdpSocket::bind_sharded`, each read and parsed on a task of its own.
+// Packets go out over the first one.
+pub struct PathManager<S> {
+    sockets: Vec<Vec<Arc<S>>>,
+    pool: Arc<BufferPool>,
+    stats: Vec<PathStats>,
+    chunks: HashMap<ChunkId, ChunkOnPath>,
+}
+
+impl<S: UdpSocketLike + 'static> PathManager<S> {
+    pub fn new(sockets: Vec<S>) -> Self {
+        Self::sharded(sockets.into_iter().map(|socket| vec![socket]).collect())
+    }
+
+    // One list of sockets per path.
+    pub fn sharded(paths: Vec<Vec<S>>) -> Self {
+        assert!(
+            !paths.is_empty() && paths.iter().all(|shards| !shards.is_empty()),
+            "A path manager needs at least one path, each with a socket"
+        );
+        Self {
+            stats: vec![PathStats::default(); paths.len()],
+            sockets: paths
+                .into_iter()
+                .map(|shards| shards.into_iter().map(Arc::new).collect())
+                .collect(),
+            pool: Arc::new(BufferPool::default()),
+            chunks: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.sockets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sockets.is_empty()
+    }
+
+    pub fn socket(&self, path: usize) -> &S {
+        &self.sockets[path][0]
+    }
+
+    pub async fn send_to(
+        &self,
+        path: usize,
+        bufs: &[Bytes],
+        target: SocketAddr,
+    ) -> std::io::Result<usize> {
+        self.sockets[path][0].send_to(bufs, target).await
+    }
+
+    pub fn incoming<const INFO_LENGTH: usize>(&self) -> Incoming<INFO_LENGTH> {
+        let (sender, receiver) = flume::unbounded();
+        let mut tasks = JoinSet::new();
+        let shards = self
+            .sockets
+            .iter()
+            .enumerate()
+            .flat_map(|(path, shards)| shards.iter().map(move |socket| (path, socket)));
+        for (path, socket) in shards {
+            let (socket, sender) = (socket.clone(), sender.clone());
+            let mut buffer = self.pool.buffer();
+            tasks.spawn(async move {
+                let mut errors = SocketErrors::default();
+                loop {
+                    let (data, from, ecn) = match socket.recv_pooled(&mut buffer).await {
+                        Ok(received) => {
+                            errors.succeeded();
+                            received
+                        }
+                        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return,
+                        Err(err) => match errors.failed("receiving", err, Instant::now()) {
+                            Ok(_) => {
+                                tokio::time::sleep(RETRY_DELAY).await;
+                                continue;
+                            }
+                            Err(failure) => {
+                                sender.send(Err(failure)).ok();
+                                return;
+                            }
+                        },
+                    };
+                    let length = data.len();
+                    if sender
+                        .send(Ok(Received {
+                            path,
+                            packet: parse_packet(data).ok(),
+                            length,
+                            from,
+                            ecn,
+                        }))
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+            });
+        }
+        Incoming {
+            receiver,
+            _tasks: tasks,
+        }
+    }
+
+    // The path of a chunk about to be fetched: the one that would cost least
+    // with one more chunk on it. A chunk fetched again keeps its path.
+    pub fn assign(&mut self, chunk_id: ChunkId, now: Instant) -> usize {
+        if let Some(chunk) = self.chunks.get_mut(&chunk_id) {
+            (chunk.asked, chunk.last_offset) = (now, None);
+            return chunk.path;
+        }
+        let path = (0..self.len())
+            .min_by(|a, b| {
+                let load =
+                    |path: usize| self.stats[path].cost() * (self.stats[path].chunks + 1) as f64;
+                load(*a).total_cmp(&load(*b))
+            })
+            .unwrap_or(0);
+        self.stats[path].chunks += 1;
+        self.chunks.insert(
+            chunk_id,
+            ChunkOnPath {
+                path,
+                asked: now,
+                last_offset: None,
+            },
+        );
+        path
+    }
+
+    pub fn path_of(&self, chunk_id: ChunkId) -> usize {
+        self.chunks.get(&chunk_id).map_or(0, |chunk| chunk.path)
+    }
+
+    pub fn finish(&mut self, chunk_id: ChunkId) {
+        if let Some(chunk) = self.chunks.remove(&chunk_id) {
+            self.stats[chunk.path].chunks -= 1;
+        }
+    }
+
+    pub fn record_frame(
+        &mut self,
+        path: usize,
+        chunk_id: ChunkId,
+        offset: u32,
+        length: usize,
+        now: Instant,
+    ) {
+        let stats = &mut self.stats[path];
+        stats.frames += 1;
+        stats.bytes += length as u64;
+        stats.last_data = Some(now);
+        stats.received += 1;
+        let Some(chunk) = self
+            .chunks
+            .get_mut(&chunk_id)
+            .filter(|chunk| chunk.path == path)
+        else {
+            stats.expected += 1;
+            return;
+        };
+        match chunk.last_offset {
+            Some(last) if offset > last => stats.expected += (offset - last) as u64,
+            // Reordered, filling a gap already expected.
+            Some(_) => {}
+            None => {
+                stats.expected += 1;
+                let sample = now - chunk.asked;
+                stats.delay = Some(match stats.delay {
+                    Some(delay) => (delay * 7 + sample) / 8,
+                    None => sample,
+                });
+            }
+        }
+        chunk.last_offset = Some(chunk.last_offset.map_or(offset, |last| last.max(offset)));
+    }
+
+    // Called once a ticket period. Returns the chunks moved, as (chunk_id, from, to).
+    pub fn rebalance(&mut self, now: Instant) -> Vec<(ChunkId, usize, usize)> {
+        for stats in self.stats.iter_mut() {
+            if stats.expected > 0 {
+                let loss = 1.0 - (stats.received as f64 / stats.expected as f64).min(1.0);
+                stats.loss = (stats.loss * 3.0 + loss) / 4.0;
+                (stats.expected, stats.received) = (0, 0);
+            }
+        }
+        if self.len() < 2 {
+            return vec![];
+        }
+
+        let mut moves = vec![];
+        for from in 0..self.len() {
+            let stats = self.stats[from];
+            let oldest_ask = self
+                .chunks
+                .values()
+                .filter(|chunk| chunk.path == from)
+                .map(|chunk| chunk.asked)
+                .min();
+            let Some(oldest_ask) = oldest_ask else {
+                continue;
+            };
+            let stalled = !stats.alive(now) && now - oldest_ask >= PATH_SILENCE;
+            let Some(to) = (0..self.len())
+                .filter(|to| *to != from && self.stats[*to].alive(now))
+                .filter(|to| {
+                    stalled || (stats.loss > HEAVY_LOSS && self.stats[*to].loss < stats.loss / 2.0)
+                })
+                .min_by(|a, b| self.stats[*a].cost().total_cmp(&self.stats[*b].cost()))
+            else {
+                continue;
+            };
+            for (chunk_id, chunk) in self
+                .chunks
+                .iter_mut()
+                .filter(|(_, chunk)| chunk.path == from)
+            {
+                *chunk = ChunkOnPath {
+                    path: to,
+                    asked: now,
+                    last_offset: None,
+                };
+                moves.push((*chunk_id, from, to));
+            }
+        }
+        for (_, from, to) in moves.iter() {
+            self.stats[*from].chunks -= 1;
+            self.stats[*to].chunks += 1;
+        }
+        moves.sort_unstable();
+        moves
+    }
+
+    pub fn stats(&self) -> Vec<PathStats> {
+        self.stats.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::mock_init;
+    use crate::protocol::wire::encoding::PacketExt;
+    use crate::protocol::wire::{frames::ParsedFrameVariant, packets::DataPacket};
+    use crate::transmission::mock::MockSocket;
+
+    fn paths() -> (PathManager<MockSocket>, Vec<MockSocket>) {
+        let server: SocketAddr = "10.0.0.1:7000".parse().unwrap();
+        let (a, server_a) = MockSocket::pair("10.0.1.2:7000".parse().unwrap(), server);
+        let (b, server_b) = MockSocket::pair("10.0.2.2:7000".parse().unwrap(), server);
+        (PathManager::new(vec![a, b]), vec![server_a, server_b])
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn chunks_spread_and_leave_a_quiet_path() {
+        let (mut paths, _) = paths();
+        let start = Instant::now();
+        assert_eq!(paths.assign(ChunkId(1), start), 0);
+        assert_eq!(paths.assign(ChunkId(2), start), 1);
+
+        // Path 1 is slower to deliver, so it gets fewer chunks.
+        let later = start + Duration::from_millis(400);
+        paths.record_frame(0, ChunkId(1), 0, 1200, start + Duration::from_millis(20));
+        paths.record_frame(1, ChunkId(2), 0, 1200, later);
+        assert_eq!(paths.assign(ChunkId(3), later), 0);
+        assert_eq!(paths.assign(ChunkId(4), later), 0);
+        assert_eq!(paths.assign(ChunkId(1), later), 0);
+
+        // Path 0 goes quiet, its chunks move to path 1.
+        let quiet = later + PATH_SILENCE;
+        paths.record_frame(1, ChunkId(2), 1, 1200, quiet);
+        assert_eq!(
+            paths.rebalance(quiet),
+            vec![(ChunkId(1), 0, 1), (ChunkId(3), 0, 1), (ChunkId(4), 0, 1)]
+        );
+        assert_eq!(paths.path_of(ChunkId(3)), 1);
+        assert_eq!(paths.stats()[1].chunks, 4);
+        paths.finish(ChunkId(3));
+        assert_eq!(paths.stats()[1].chunks, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lossy_path_is_measured_and_left() {
+        let (mut paths, _) = paths();
+        let now = Instant::now();
+        paths.assign(ChunkId(1), now);
+        paths.assign(ChunkId(2), now);
+        let (mut offset, mut moves) = (0, vec![]);
+        while moves.is_empty() && offset < 1000 {
+            for _ in 0..25 {
+                paths.record_frame(0, ChunkId(1), offset, 1200, now);
+                // Only every fourth frame makes it over path 1.
+                paths.record_frame(1, ChunkId(2), offset * 4, 1200, now);
+                offset += 1;
+            }
+            moves = paths.rebalance(now);
+        }
+        assert!(paths.stats()[1].loss > HEAVY_LOSS);
+        assert!(paths.stats()[0].loss < 0.05);
+        assert_eq!(moves, vec![(ChunkId(2), 1, 0)]);
+    }
+
+    #[tokio::test]
+    async fn incoming_tags_packets_with_their_path() {
+        mock_init();
+        let (paths, servers) = paths();
+        let incoming = paths.incoming::<4>();
+        let client: SocketAddr = "10.0.0.2:7000".parse().unwrap();
+        for (path, server) in servers.iter().enumerate() {
+            let (packet, _) = DataPacket::new(ChunkId(path as u32), 0, [0; 4], vec![1; 16]).build();
+            server.send_to(&packet, client).await.unwrap();
+        }
+        servers[0]
+            .send_to(&[Bytes::from_static(b"garbage")], client)
+            .await
+            .unwrap();
+        let mut received = vec![];
+        for _ in 0..3 {
+            let packet = incoming.recv().await.unwrap().unwrap();
+            let chunk_id = packet.packet.map(|packet| match &packet.frames[0] {
+                ParsedFrameVariant::Data(frame) => frame.chunk_id,
+                _ => unreachable!(),
+            });
+            received.push((packet.path, chunk_id));
+        }
+        received.sort();
+        assert_eq!(
+            received,
+            vec![(0, None), (0, Some(ChunkId(0))), (1, Some(ChunkId(1)))]
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use bytes::{Bytes, BytesMut};
+use std::sync::{Arc, Mutex};
+
+// Room for the largest UDP payload, and a byte more to notice truncation.
+pub const MAX_DATAGRAM: usize = 65537;
+const BLOCK_SIZE: usize = 1 << 20;
+const MAX_FREE_BLOCKS: usize = 16;
+
+// Receive buffers handed out in blocks. Packets are cut off the front of a
+// block, so one allocation serves many packets; a used up block goes back to
+// the free list and is reused once the packets cut from it are dropped.
+pub struct BufferPool {
+    free: Mutex<Vec<BytesMut>>,
+    block_size: usize,
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(BLOCK_SIZE)
+    }
+}
+
+impl BufferPool {
+    pub fn new(block_size: usize) -> Self {
+        Self {
+            free: Mutex::new(vec![]),
+            block_size: block_size.max(MAX_DATAGRAM),
+        }
+    }
+
+    pub fn buffer(self: &Arc<Self>) -> PooledBuffer {
+        PooledBuffer {
+            block: self.take(),
+            pool: self.clone(),
+        }
+    }
+
+    // A block with room for a datagram, all of it initialized.
+    fn take(&self) -> BytesMut {
+        let mut block = self
+            .free
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| BytesMut::with_capacity(self.block_size));
+        if block.capacity() < MAX_DATAGRAM {
+            // Gets the whole allocation back if nothing cut from it is alive.
+            block.clear();
+            block.reserve(self.block_size);
+        }
+        // Only zeroes what the last packets took away.
+        block.resize(MAX_DATAGRAM, 0);
+        block
+    }
+
+    fn put(&self, block: BytesMut) {
+        let mut free = self.free.lock().unwrap();
+        if free.len() < MAX_FREE_BLOCKS {
+            free.push(block);
+        }
+    }
+
+    pub fn free_blocks(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+}
+
+// The buffer one receive loop reads into.
+pub struct PooledBuffer {
+    block: BytesMut,
+    pool: Arc<BufferPool>,
+}
+
+impl PooledBuffer {
+    pub fn space(&mut self) -> &mut [u8] {
+        &mut self.block[..MAX_DATAGRAM]
+    }
+
+    // The `length` bytes received into `space`, without copying them.
+    pub fn cut(&mut self, length: usize) -> Bytes {
+        let packet = self.block.split_to(length.min(MAX_DATAGRAM)).freeze();
+        if self.block.capacity() < MAX_DATAGRAM {
+            let used = std::mem::replace(&mut self.block, self.pool.take());
+            self.pool.put(used);
+        } else {
+            self.block.resize(MAX_DATAGRAM, 0);
+        }
+        packet
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.block));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_share_blocks() {
+        let pool = Arc::new(BufferPool::new(4 * MAX_DATAGRAM));
+        let mut buffer = pool.buffer();
+        let mut packets = vec![];
+        for i in 0..100u8 {
+            buffer.space()[..1400].fill(i);
+            packets.push(buffer.cut(1400));
+        }
+        assert!(packets.iter().enumerate().all(|(i, packet)| {
+            packet.len() == 1400 && packet.iter().all(|byte| *byte == i as u8)
+        }));
+        // 140 000 bytes fit the first block next to a free datagram.
+        let first = packets[0].as_ptr() as usize;
+        assert_eq!(packets[99].as_ptr() as usize - first, 99 * 1400);
+
+        for _ in 0..200 {
+            buffer.cut(MAX_DATAGRAM);
+        }
+        assert!(pool.free_blocks() <= MAX_FREE_BLOCKS);
+        drop(buffer);
+        assert!(pool.free_blocks() >= 1);
+    }
+}
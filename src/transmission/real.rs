@@ -1,18 +1,46 @@
 use bytes::Bytes;
+use dashmap::DashMap;
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
-use std::io::IoSlice;
-use std::net::SocketAddr;
+use std::io::{Error, ErrorKind, IoSlice};
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::ops::RangeInclusive;
+use std::time::Duration;
 use tokio::net::UdpSocket as TokioUdpSocket;
 
-use super::UdpSocketLike;
+use super::errors::check_size;
+use super::{Ecn, UdpSocketLike};
 
 pub struct RealUdpSocket {
     innner_raw: Socket,
     inner_tokio: TokioUdpSocket,
+    txtime: Option<TxTimeClock>,
+    // Label of every IPv6 peer sent to, 0 where none could be leased.
+    flow_labels: Option<DashMap<SocketAddr, u32>>,
+}
+
+// The ECN codepoint, left to `enable_ecn`.
+const ECN_MASK: u8 = 0b11;
+
+// Clock of the departure timestamps handed to the kernel with SO_TXTIME.
+// The ETF qdisc is usually configured with TAI, fq with the monotonic clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum TxTimeClock {
+    Tai,
+    Monotonic,
 }
 
 impl RealUdpSocket {
     pub async fn bind(addr: SocketAddr) -> std::io::Result<Self> {
+        Self::bind_with(addr, None).await
+    }
+
+    // `interface` pins the socket to a NIC with SO_BINDTODEVICE, e.g. on multihomed hosts.
+    pub async fn bind_with(addr: SocketAddr, interface: Option<&str>) -> std::io::Result<Self> {
+        Self::open(addr, interface, false)
+    }
+
+    fn open(addr: SocketAddr, interface: Option<&str>, reuse_port: bool) -> std::io::Result<Self> {
         let domain = match addr {
             SocketAddr::V4(_) => Domain::IPV4,
             SocketAddr::V6(_) => Domain::IPV6,
@@ -20,7 +48,13 @@ impl RealUdpSocket {
         let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
 
         socket.set_reuse_address(true)?;
+        if reuse_port {
+            set_reuse_port(&socket)?;
+        }
         socket.set_nonblocking(true)?;
+        if let Some(interface) = interface {
+            bind_device(&socket, interface)?;
+        }
 
         socket.bind(&addr.into())?;
         let std_socket = socket.try_clone()?.into();
@@ -29,25 +63,592 @@ impl RealUdpSocket {
         Ok(Self {
             inner_tokio: tokio_socket,
             innner_raw: socket,
+            txtime: None,
+            flow_labels: None,
         })
     }
+
+    // Lets the kernel enforce pacing: `send_to_after` then stamps each packet
+    // with its departure time instead of sending it right away.
+    pub fn enable_txtime(&mut self, clock: TxTimeClock) -> std::io::Result<()> {
+        set_txtime(&self.innner_raw, clock)?;
+        self.txtime = Some(clock);
+        Ok(())
+    }
+
+    // On EADDRINUSE or EACCES, tries every port of `fallback_ports` in turn.
+    // The final error explains the likely cause.
+    pub async fn bind_with_fallback(
+        addr: SocketAddr,
+        interface: Option<&str>,
+        fallback_ports: Option<RangeInclusive<u16>>,
+    ) -> std::io::Result<Self> {
+        Self::open_with_fallback(addr, interface, fallback_ports, false)
+    }
+
+    fn open_with_fallback(
+        addr: SocketAddr,
+        interface: Option<&str>,
+        fallback_ports: Option<RangeInclusive<u16>>,
+        reuse_port: bool,
+    ) -> std::io::Result<Self> {
+        let mut last_err = match Self::open(addr, interface, reuse_port) {
+            Ok(socket) => return Ok(socket),
+            Err(err) => err,
+        };
+        let mut last_addr = addr;
+        for port in fallback_ports.into_iter().flatten() {
+            if !matches!(
+                last_err.kind(),
+                ErrorKind::AddrInUse | ErrorKind::PermissionDenied
+            ) {
+                break;
+            }
+            last_addr.set_port(port);
+            match Self::open(last_addr, interface, reuse_port) {
+                Ok(socket) => return Ok(socket),
+                Err(err) => last_err = err,
+            }
+        }
+        Err(Error::new(
+            last_err.kind(),
+            format!(
+                "Failed to bind {last_addr}: {last_err}. {}",
+                bind_error_hint(&last_err)
+            ),
+        ))
+    }
+
+    // `shards` sockets sharing one address with SO_REUSEPORT, the first one
+    // bound like `bind_with_fallback`. Data packets are steered to the socket
+    // of their chunk id modulo `shards`, everything else to the first one.
+    pub async fn bind_sharded(
+        addr: SocketAddr,
+        interface: Option<&str>,
+        fallback_ports: Option<RangeInclusive<u16>>,
+        shards: usize,
+    ) -> std::io::Result<Vec<Self>> {
+        let first = Self::open_with_fallback(addr, interface, fallback_ports, true)?;
+        let local_addr = first.local_addr()?;
+        let mut sockets = vec![first];
+        for _ in 1..shards {
+            sockets.push(Self::open(local_addr, interface, true)?);
+        }
+        if shards > 1 {
+            steer_chunks(&sockets[0].innner_raw, shards as u32)?;
+        }
+        Ok(sockets)
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.inner_tokio.local_addr()
+    }
+
+    // Marks every outgoing packet ECT(0) and asks the kernel for the TOS byte of
+    // incoming ones, see `recv_from_ecn`.
+    pub fn enable_ecn(&self) -> std::io::Result<()> {
+        const ECT0: u8 = 0b10;
+        let class = self.traffic_class()? & !ECN_MASK;
+        self.set_tos(class | ECT0)?;
+        match self.local_addr()? {
+            SocketAddr::V4(_) => self.innner_raw.set_recv_tos_v4(true),
+            SocketAddr::V6(_) => self.innner_raw.set_recv_tclass_v6(true),
+        }
+    }
+
+    // The TOS byte, or IPv6 traffic class, of outgoing packets. The DSCP bits of
+    // `class` are used, the ECN bits stay as `enable_ecn` set them.
+    pub fn set_traffic_class(&self, class: u8) -> std::io::Result<()> {
+        let ecn = self.traffic_class()? & ECN_MASK;
+        self.set_tos((class & !ECN_MASK) | ecn)
+    }
+
+    pub fn traffic_class(&self) -> std::io::Result<u8> {
+        let tos = match self.local_addr()? {
+            SocketAddr::V4(_) => self.innner_raw.tos_v4()?,
+            SocketAddr::V6(_) => self.innner_raw.tclass_v6()?,
+        };
+        Ok(tos as u8)
+    }
+
+    fn set_tos(&self, tos: u8) -> std::io::Result<()> {
+        match self.local_addr()? {
+            SocketAddr::V4(_) => self.innner_raw.set_tos_v4(tos.into()),
+            SocketAddr::V6(_) => self.innner_raw.set_tclass_v6(tos.into()),
+        }
+    }
+
+    // Sends to every IPv6 peer with a flow label of its own that stays the same
+    // for the session, so ECMP routers keep the flow on one path. IPv4 sockets
+    // are refused, IPv4-mapped peers are sent to unlabelled.
+    pub fn enable_flow_labels(&mut self) -> std::io::Result<()> {
+        if self.local_addr()?.is_ipv4() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "Flow labels need an IPv6 socket",
+            ));
+        }
+        set_flowinfo_send(&self.innner_raw)?;
+        self.flow_labels = Some(DashMap::new());
+        Ok(())
+    }
+
+    // The address `target` is sent to, carrying its flow label. Labels are
+    // leased from the kernel on first use.
+    fn labelled(&self, target: SocketAddr) -> SockAddr {
+        let (Some(labels), SocketAddr::V6(v6)) = (self.flow_labels.as_ref(), target) else {
+            return SockAddr::from(target);
+        };
+        if v6.ip().to_ipv4_mapped().is_some() {
+            return SockAddr::from(target);
+        }
+        let label = *labels.entry(target).or_insert_with(|| {
+            let label = flow_label_for(self.local_addr().unwrap_or(target), target);
+            match lease_flow_label(&self.innner_raw, *v6.ip(), label) {
+                Ok(()) => label,
+                Err(err) => {
+                    eprintln!("No flow label for {target}: {err}");
+                    0
+                }
+            }
+        });
+        // The kernel takes sin6_flowinfo in network byte order, which std passes on as is.
+        SockAddr::from(SocketAddrV6::new(
+            *v6.ip(),
+            v6.port(),
+            label.to_be(),
+            v6.scope_id(),
+        ))
+    }
+}
+
+// A nonzero label from the session's addresses, the same every time. Kept below
+// 0x80000, which Linux may reserve for leased labels.
+fn flow_label_for(local: SocketAddr, peer: SocketAddr) -> u32 {
+    let hash = blake3::hash(format!("{local} {peer}").as_bytes());
+    let label = u32::from_be_bytes(hash.as_bytes()[..4].try_into().unwrap()) & 0x7ffff;
+    label.max(1)
+}
+
+#[cfg(target_os = "linux")]
+fn set_flowinfo_send(socket: &Socket) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let enable: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_FLOWINFO_SEND,
+            (&enable as *const libc::c_int).cast(),
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_flowinfo_send(_socket: &Socket) -> std::io::Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "Setting flow labels is only supported on Linux",
+    ))
+}
+
+// struct in6_flowlabel_req from linux/in6.h, which libc does not have.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct FlowLabelRequest {
+    dst: libc::in6_addr,
+    label: u32,
+    action: u8,
+    share: u8,
+    flags: u16,
+    expires: u16,
+    linger: u16,
+    pad: u32,
+}
+
+// Linux only sends labels the socket holds a lease on.
+#[cfg(target_os = "linux")]
+fn lease_flow_label(socket: &Socket, dst: Ipv6Addr, label: u32) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    const IPV6_FL_A_GET: u8 = 0;
+    const IPV6_FL_S_EXCL: u8 = 1;
+    const IPV6_FL_F_CREATE: u16 = 1;
+
+    let request = FlowLabelRequest {
+        dst: libc::in6_addr {
+            s6_addr: dst.octets(),
+        },
+        label: label.to_be(),
+        action: IPV6_FL_A_GET,
+        share: IPV6_FL_S_EXCL,
+        flags: IPV6_FL_F_CREATE,
+        expires: 0,
+        linger: 0,
+        pad: 0,
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_FLOWLABEL_MGR,
+            (&request as *const FlowLabelRequest).cast(),
+            size_of::<FlowLabelRequest>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lease_flow_label(_socket: &Socket, _dst: Ipv6Addr, _label: u32) -> std::io::Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "Setting flow labels is only supported on Linux",
+    ))
+}
+
+// A recvmsg that also returns the TOS byte (or IPv6 traffic class) from the control data.
+#[cfg(target_os = "linux")]
+fn recv_with_tos(
+    fd: std::os::fd::RawFd,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr, u8)> {
+    use socket2::SockAddrStorage;
+    use std::mem::{size_of_val, zeroed};
+
+    let mut addr = SockAddrStorage::zeroed();
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    // u64 keeps the control buffer aligned for cmsghdr.
+    let mut control = [0u64; 8];
+    let mut msg: libc::msghdr = unsafe { zeroed() };
+    msg.msg_name =
+        unsafe { addr.view_as::<libc::sockaddr_storage>() as *mut libc::sockaddr_storage }.cast();
+    msg.msg_namelen = addr.size_of();
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = size_of_val(&control) as _;
+
+    let length = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+    if length < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let mut tos = 0;
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while let Some(header) = unsafe { cmsg.as_ref() } {
+        let data = unsafe { libc::CMSG_DATA(cmsg) };
+        match (header.cmsg_level, header.cmsg_type) {
+            // A single byte for IPv4, an int for IPv6.
+            (libc::IPPROTO_IP, libc::IP_TOS) => tos = unsafe { *data },
+            (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                tos = unsafe { data.cast::<libc::c_int>().read_unaligned() } as u8
+            }
+            _ => {}
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+
+    let addr = unsafe { SockAddr::new(addr, msg.msg_namelen) };
+    let addr = addr
+        .as_socket()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Not an IP source address"))?;
+    Ok((length as usize, addr, tos))
+}
+
+#[cfg(unix)]
+fn set_reuse_port(socket: &Socket) -> std::io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_socket: &Socket) -> std::io::Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
+
+// A classic BPF program picking the socket of a reuseport group: the chunk id
+// modulo `shards` for data packets, the first socket for anything else.
+#[cfg(target_os = "linux")]
+fn steer_chunks(socket: &Socket, shards: u32) -> std::io::Result<()> {
+    use crate::protocol::wire::{DATA_CHUNK_ID_OFFSET, packets::PacketType};
+    use std::os::fd::AsRawFd;
+
+    let op = |code: u32, jt: u8, jf: u8, k: u32| libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    };
+    let mut program = [
+        op(libc::BPF_LD | libc::BPF_B | libc::BPF_ABS, 0, 0, 1),
+        op(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            0,
+            3,
+            u8::from(PacketType::Data) as u32,
+        ),
+        op(
+            libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+            0,
+            0,
+            DATA_CHUNK_ID_OFFSET as u32,
+        ),
+        op(libc::BPF_ALU | libc::BPF_MOD | libc::BPF_K, 0, 0, shards),
+        op(libc::BPF_RET | libc::BPF_A, 0, 0, 0),
+        op(libc::BPF_RET | libc::BPF_K, 0, 0, 0),
+    ];
+    let fprog = libc::sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_mut_ptr(),
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_REUSEPORT_CBPF,
+            &fprog as *const libc::sock_fprog as *const libc::c_void,
+            std::mem::size_of::<libc::sock_fprog>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn steer_chunks(_socket: &Socket, _shards: u32) -> std::io::Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "Steering packets to sockets is not supported on this platform",
+    ))
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &Socket, interface: &str) -> std::io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_socket: &Socket, _interface: &str) -> std::io::Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "Binding to an interface is not supported on this platform",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn clock_id(clock: TxTimeClock) -> libc::clockid_t {
+    match clock {
+        TxTimeClock::Tai => libc::CLOCK_TAI,
+        TxTimeClock::Monotonic => libc::CLOCK_MONOTONIC,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_txtime(socket: &Socket, clock: TxTimeClock) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let config = libc::sock_txtime {
+        clockid: clock_id(clock),
+        flags: 0,
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TXTIME,
+            (&config as *const libc::sock_txtime).cast(),
+            size_of::<libc::sock_txtime>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_txtime(_socket: &Socket, _clock: TxTimeClock) -> std::io::Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "SO_TXTIME is only available on Linux",
+    ))
+}
+
+// Sends with an SCM_TXTIME control message, departing `delay` from now on `clock`.
+#[cfg(target_os = "linux")]
+fn send_at(
+    socket: &Socket,
+    bufs: &[IoSlice],
+    target: &SockAddr,
+    clock: TxTimeClock,
+    delay: Duration,
+) -> std::io::Result<usize> {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(clock_id(clock), &mut now) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    let txtime = now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64 + delay.as_nanos() as u64;
+
+    // u64 keeps the control buffer aligned for cmsghdr.
+    let mut control = [0u64; 4];
+    let space = unsafe { libc::CMSG_SPACE(size_of::<u64>() as u32) } as usize;
+    let header = control.as_mut_ptr().cast::<libc::cmsghdr>();
+    unsafe {
+        (*header).cmsg_level = libc::SOL_SOCKET;
+        (*header).cmsg_type = libc::SCM_TXTIME;
+        (*header).cmsg_len = libc::CMSG_LEN(size_of::<u64>() as u32) as _;
+        libc::CMSG_DATA(header)
+            .cast::<u64>()
+            .write_unaligned(txtime);
+    }
+    let control = unsafe { std::slice::from_raw_parts(control.as_ptr().cast::<u8>(), space) };
+
+    let msg = socket2::MsgHdr::new()
+        .with_addr(target)
+        .with_buffers(bufs)
+        .with_control(control);
+    socket.sendmsg(&msg, 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_at(
+    socket: &Socket,
+    bufs: &[IoSlice],
+    target: &SockAddr,
+    _clock: TxTimeClock,
+    _delay: Duration,
+) -> std::io::Result<usize> {
+    socket.send_to_vectored(bufs, target)
+}
+
+fn bind_error_hint(err: &Error) -> &'static str {
+    match err.kind() {
+        ErrorKind::PermissionDenied => {
+            "Ports below 1024 need root or CAP_NET_BIND_SERVICE, use a higher port or grant the capability."
+        }
+        ErrorKind::AddrInUse => {
+            "Another process holds this port (see `ss -ulpn`), pick another one or pass --port-range."
+        }
+        ErrorKind::AddrNotAvailable => "The address is not assigned to any local interface.",
+        _ => "Check the address and the local network configuration.",
+    }
+}
+
+// Parses `START-END`, as taken by --port-range.
+pub fn parse_port_range(range: &str) -> Result<RangeInclusive<u16>, String> {
+    let (start, end) = range
+        .split_once('-')
+        .ok_or_else(|| format!("Expected START-END, got {range}"))?;
+    let start: u16 = start.trim().parse().map_err(|err| format!("{err}"))?;
+    let end: u16 = end.trim().parse().map_err(|err| format!("{err}"))?;
+    if start > end {
+        return Err(format!("Empty port range {range}"));
+    }
+    Ok(start..=end)
+}
+
+// Parses a traffic class byte, as taken by --traffic-class: a number such as
+// `0x28`, or the name of a DSCP such as `ef`, `af41` or `cs1`.
+pub fn parse_traffic_class(class: &str) -> Result<u8, String> {
+    let class = class.trim().to_ascii_lowercase();
+    let number = |digits: &str| digits.parse::<u8>().map_err(|err| format!("{err}"));
+    let dscp = if class == "ef" {
+        46
+    } else if let Some(digits) = class.strip_prefix("cs") {
+        match number(digits)? {
+            selector @ 0..=7 => selector << 3,
+            _ => return Err(format!("No class selector {class}")),
+        }
+    } else if let Some(digits) = class.strip_prefix("af")
+        && let [class_digit @ b'1'..=b'4', drop @ b'1'..=b'3'] = digits.as_bytes()
+    {
+        ((class_digit - b'0') << 3) | ((drop - b'0') << 1)
+    } else if let Some(hex) = class.strip_prefix("0x") {
+        return u8::from_str_radix(hex, 16).map_err(|err| format!("{err}"));
+    } else {
+        return number(&class).map_err(|err| format!("{err}, expected a number or a DSCP name"));
+    };
+    Ok(dscp << 2)
 }
 
 #[async_trait::async_trait]
 impl UdpSocketLike for RealUdpSocket {
     async fn send_to(&self, bufs: &[Bytes], target: SocketAddr) -> std::io::Result<usize> {
+        check_size(bufs)?;
         let io_slice = bufs
             .iter()
             .map(|slice| IoSlice::new(slice))
             .collect::<Vec<_>>();
 
         self.innner_raw
-            .send_to_vectored(io_slice.as_slice(), &SockAddr::from(target))
+            .send_to_vectored(io_slice.as_slice(), &self.labelled(target))
+    }
+
+    async fn send_to_after(
+        &self,
+        bufs: &[Bytes],
+        target: SocketAddr,
+        delay: Duration,
+    ) -> std::io::Result<usize> {
+        let Some(clock) = self.txtime else {
+            return self.send_to(bufs, target).await;
+        };
+        check_size(bufs)?;
+        let io_slice = bufs
+            .iter()
+            .map(|slice| IoSlice::new(slice))
+            .collect::<Vec<_>>();
+        send_at(
+            &self.innner_raw,
+            io_slice.as_slice(),
+            &self.labelled(target),
+            clock,
+            delay,
+        )
     }
 
     async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
         self.inner_tokio.recv_from(buf).await
     }
+
+    fn flow_label(&self, peer: SocketAddr) -> Option<u32> {
+        let label = *self.flow_labels.as_ref()?.get(&peer)?;
+        (label != 0).then_some(label)
+    }
+
+    fn traffic_class(&self) -> Option<u8> {
+        RealUdpSocket::traffic_class(self).ok()
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn recv_from_ecn(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr, Ecn)> {
+        use std::os::fd::AsRawFd;
+        let fd = self.inner_tokio.as_raw_fd();
+        let (length, addr, tos) = self
+            .inner_tokio
+            .async_io(tokio::io::Interest::READABLE, || recv_with_tos(fd, buf))
+            .await?;
+        Ok((length, addr, Ecn::from_tos(tos)))
+    }
 }
 
 #[cfg(test)]
@@ -85,4 +686,154 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_ecn_marks_arrive() -> std::io::Result<()> {
+        let receiver = RealUdpSocket::bind("127.0.0.1:0".parse().unwrap()).await?;
+        let sender = RealUdpSocket::bind("127.0.0.1:0".parse().unwrap()).await?;
+        receiver.enable_ecn()?;
+        sender.enable_ecn()?;
+
+        let data = vec![Bytes::from_static(b"ECN")];
+        sender.send_to(&data, receiver.local_addr()?).await?;
+
+        let mut buf = vec![0u8; 1024];
+        let (len, from, ecn) = receiver.recv_from_ecn(&mut buf).await?;
+        assert_eq!(&buf[..len], b"ECN");
+        assert_eq!(from, sender.local_addr()?);
+        if cfg!(target_os = "linux") {
+            assert_eq!(ecn, Ecn::Ect0);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_traffic_class_and_flow_labels() -> std::io::Result<()> {
+        let Ok(receiver) = RealUdpSocket::bind("[::1]:0".parse().unwrap()).await else {
+            eprintln!("No IPv6 loopback here, skipped.");
+            return Ok(());
+        };
+        let mut sender = RealUdpSocket::bind("[::1]:0".parse().unwrap()).await?;
+        sender.set_traffic_class(parse_traffic_class("af41").unwrap())?;
+        sender.enable_ecn()?;
+        assert_eq!(sender.traffic_class()?, 0x8a);
+        if let Err(err) = sender.enable_flow_labels() {
+            eprintln!("Flow labels unsupported here, skipped: {err}");
+            return Ok(());
+        }
+
+        let target = receiver.local_addr()?;
+        let data = vec![Bytes::from_static(b"Labelled")];
+        sender.send_to(&data, target).await?;
+        let mut buf = vec![0u8; 1024];
+        let (len, _) = receiver.recv_from(&mut buf).await?;
+        assert_eq!(&buf[..len], b"Labelled");
+        let label = UdpSocketLike::flow_label(&sender, target);
+        assert!(label.is_some_and(|label| label > 0 && label < 0x80000));
+        sender.send_to(&data, target).await?;
+        assert_eq!(UdpSocketLike::flow_label(&sender, target), label);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_traffic_class() {
+        assert_eq!(parse_traffic_class("EF"), Ok(0xb8));
+        assert_eq!(parse_traffic_class("cs1"), Ok(0x20));
+        assert_eq!(parse_traffic_class("af11"), Ok(0x28));
+        assert_eq!(parse_traffic_class("0x28"), Ok(0x28));
+        assert_eq!(parse_traffic_class("184"), Ok(0xb8));
+        assert!(parse_traffic_class("af51").is_err());
+        assert!(parse_traffic_class("cs8").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_txtime_send() -> std::io::Result<()> {
+        let receiver = RealUdpSocket::bind("127.0.0.1:0".parse().unwrap()).await?;
+        let mut sender = RealUdpSocket::bind("127.0.0.1:0".parse().unwrap()).await?;
+        if let Err(err) = sender.enable_txtime(TxTimeClock::Monotonic) {
+            eprintln!("SO_TXTIME unsupported here, skipped: {err}");
+            return Ok(());
+        }
+
+        let data = vec![Bytes::from_static(b"Paced")];
+        sender
+            .send_to_after(&data, receiver.local_addr()?, Duration::from_millis(1))
+            .await?;
+
+        let mut buf = vec![0u8; 1024];
+        let (len, _) = receiver.recv_from(&mut buf).await?;
+        assert_eq!(&buf[..len], b"Paced");
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_port_range() {
+        assert_eq!(parse_port_range("7000-7010"), Ok(7000..=7010));
+        assert_eq!(parse_port_range("7000 - 7000"), Ok(7000..=7000));
+        assert!(parse_port_range("7010-7000").is_err());
+        assert!(parse_port_range("7000").is_err());
+        assert!(parse_port_range("7000-70000").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind_reports_chosen_port() -> std::io::Result<()> {
+        let socket =
+            RealUdpSocket::bind_with_fallback("127.0.0.1:0".parse().unwrap(), None, None).await?;
+        assert_ne!(socket.local_addr()?.port(), 0);
+
+        let err = RealUdpSocket::bind_with_fallback("192.0.2.1:40003".parse().unwrap(), None, None)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::AddrNotAvailable);
+        assert!(err.to_string().contains("not assigned"));
+
+        let socket = RealUdpSocket::bind_with("127.0.0.1:0".parse().unwrap(), Some("lo")).await?;
+        assert!(socket.local_addr()?.ip().is_loopback());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sharded_sockets_split_chunks() -> std::io::Result<()> {
+        use crate::protocol::wire::{DATA_CHUNK_ID_OFFSET, packets::PacketType};
+
+        let shards =
+            RealUdpSocket::bind_sharded("127.0.0.1:0".parse().unwrap(), None, None, 2).await?;
+        let addr = shards[0].local_addr()?;
+        assert_eq!(shards[1].local_addr()?, addr);
+        let sender = RealUdpSocket::bind("127.0.0.1:0".parse().unwrap()).await?;
+        let packet = |packet_type: PacketType, chunk_id: u32| {
+            let mut packet = vec![0u8; 64];
+            packet[1] = packet_type.into();
+            packet[DATA_CHUNK_ID_OFFSET..DATA_CHUNK_ID_OFFSET + 4]
+                .copy_from_slice(&chunk_id.to_be_bytes());
+            Bytes::from(packet)
+        };
+        for chunk_id in [3, 4, 5] {
+            sender
+                .send_to(&[packet(PacketType::Data, chunk_id)], addr)
+                .await?;
+        }
+        sender
+            .send_to(&[packet(PacketType::Control, 7)], addr)
+            .await?;
+
+        let mut buf = vec![0u8; 1024];
+        let chunk_of = |buf: &[u8]| {
+            u32::from_be_bytes(
+                buf[DATA_CHUNK_ID_OFFSET..DATA_CHUNK_ID_OFFSET + 4]
+                    .try_into()
+                    .unwrap(),
+            )
+        };
+        shards[1].recv_from(&mut buf).await?;
+        assert_eq!(chunk_of(&buf), 3);
+        shards[1].recv_from(&mut buf).await?;
+        assert_eq!(chunk_of(&buf), 5);
+        for expected in [4, 7] {
+            shards[0].recv_from(&mut buf).await?;
+            assert_eq!(chunk_of(&buf), expected);
+        }
+        Ok(())
+    }
 }
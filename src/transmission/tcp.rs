@@ -0,0 +1,197 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use dashmap::DashMap;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+
+use super::{Ecn, UdpSocketLike, pool::PooledBuffer};
+
+type Connections = DashMap<SocketAddr, Arc<Mutex<OwnedWriteHalf>>>;
+
+// Datagrams over TCP, for networks that drop UDP. Each datagram is sent with a
+// 2 byte big endian length in front. Peers connect on their first send, and
+// each side accepts connections on the address it is bound to, so the server
+// answers over the connection a client opened.
+pub struct TcpDatagramSocket {
+    local_addr: SocketAddr,
+    connections: Arc<Connections>,
+    incoming: flume::Receiver<(Bytes, SocketAddr)>,
+    sender: flume::Sender<(Bytes, SocketAddr)>,
+}
+
+impl TcpDatagramSocket {
+    pub async fn bind(addr: SocketAddr) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let (sender, incoming) = flume::unbounded();
+        let connections = Arc::new(Connections::new());
+        tokio::spawn({
+            let (connections, sender) = (connections.clone(), sender.clone());
+            async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, peer)) => {
+                            Self::attach(&connections, &sender, stream, peer);
+                        }
+                        Err(err) => eprintln!("Failed to accept a TCP connection: {err}"),
+                    }
+                }
+            }
+        });
+        Ok(Self {
+            local_addr,
+            connections,
+            incoming,
+            sender,
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    // A connection replaces any earlier one to the same peer.
+    fn attach(
+        connections: &Arc<Connections>,
+        sender: &flume::Sender<(Bytes, SocketAddr)>,
+        stream: TcpStream,
+        peer: SocketAddr,
+    ) -> Arc<Mutex<OwnedWriteHalf>> {
+        stream.set_nodelay(true).ok();
+        let (reader, writer) = stream.into_split();
+        let writer = Arc::new(Mutex::new(writer));
+        connections.insert(peer, writer.clone());
+        tokio::spawn(read_datagrams(
+            reader,
+            peer,
+            sender.clone(),
+            connections.clone(),
+            writer.clone(),
+        ));
+        writer
+    }
+
+    async fn connection(&self, peer: SocketAddr) -> std::io::Result<Arc<Mutex<OwnedWriteHalf>>> {
+        if let Some(writer) = self.connections.get(&peer) {
+            return Ok(writer.clone());
+        }
+        let stream = TcpStream::connect(peer).await?;
+        Ok(Self::attach(&self.connections, &self.sender, stream, peer))
+    }
+}
+
+async fn read_datagrams(
+    mut reader: OwnedReadHalf,
+    peer: SocketAddr,
+    sender: flume::Sender<(Bytes, SocketAddr)>,
+    connections: Arc<Connections>,
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+) {
+    while let Ok(length) = reader.read_u16().await {
+        let mut datagram = vec![0u8; length as usize];
+        if reader.read_exact(&mut datagram).await.is_err()
+            || sender.send((Bytes::from(datagram), peer)).is_err()
+        {
+            break;
+        }
+    }
+    // Unless it was replaced by a newer connection meanwhile.
+    connections.remove_if(&peer, |_, current| Arc::ptr_eq(current, &writer));
+}
+
+#[async_trait]
+impl UdpSocketLike for TcpDatagramSocket {
+    async fn send_to(&self, bufs: &[Bytes], target: SocketAddr) -> std::io::Result<usize> {
+        let length: usize = bufs.iter().map(|buf| buf.len()).sum();
+        let Ok(prefix) = u16::try_from(length) else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("A datagram of {length} bytes does not fit a length prefix"),
+            ));
+        };
+        let mut datagram = Vec::with_capacity(2 + length);
+        datagram.extend_from_slice(&prefix.to_be_bytes());
+        for buf in bufs {
+            datagram.extend_from_slice(buf);
+        }
+        let writer = self.connection(target).await?;
+        let result = writer.lock().await.write_all(&datagram).await;
+        if let Err(err) = result {
+            self.connections
+                .remove_if(&target, |_, current| Arc::ptr_eq(current, &writer));
+            return Err(err);
+        }
+        Ok(length)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        let (datagram, from) = self
+            .incoming
+            .recv_async()
+            .await
+            .map_err(|err| Error::new(ErrorKind::UnexpectedEof, err))?;
+        let length = datagram.len().min(buf.len());
+        buf[..length].copy_from_slice(&datagram[..length]);
+        Ok((length, from))
+    }
+
+    // Datagrams arrive whole already, no need for the pool.
+    async fn recv_pooled(
+        &self,
+        _buffer: &mut PooledBuffer,
+    ) -> std::io::Result<(Bytes, SocketAddr, Ecn)> {
+        let (datagram, from) = self
+            .incoming
+            .recv_async()
+            .await
+            .map_err(|err| Error::new(ErrorKind::UnexpectedEof, err))?;
+        Ok((datagram, from, Ecn::NotEct))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn datagrams_keep_their_boundaries() -> std::io::Result<()> {
+        let server = TcpDatagramSocket::bind("127.0.0.1:0".parse().unwrap()).await?;
+        let client = TcpDatagramSocket::bind("127.0.0.1:0".parse().unwrap()).await?;
+        let server_addr = server.local_addr()?;
+
+        client
+            .send_to(
+                &[Bytes::from_static(b"Hello, "), Bytes::from_static(b"TCP!")],
+                server_addr,
+            )
+            .await?;
+        client
+            .send_to(&[Bytes::from_static(b"again")], server_addr)
+            .await?;
+        let mut buf = vec![0u8; 1024];
+        let (length, from) = server.recv_from(&mut buf).await?;
+        assert_eq!(&buf[..length], b"Hello, TCP!");
+        let (length, _) = server.recv_from(&mut buf).await?;
+        assert_eq!(&buf[..length], b"again");
+
+        // Answered over the connection the client opened.
+        server
+            .send_to(&[Bytes::from_static(b"reply")], from)
+            .await?;
+        let (length, reply_from) = client.recv_from(&mut buf).await?;
+        assert_eq!(&buf[..length], b"reply");
+        assert_eq!(reply_from, server_addr);
+        assert!(
+            client
+                .send_to(&[Bytes::from(vec![0u8; 70000])], server_addr)
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+use ed25519_dalek::{SECRET_KEY_LENGTH, Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use flume::{Receiver, Sender, unbounded};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+// One accepted ticket. `bytes_served` counts what was sent to `addr` since its previous ticket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub timestamp_ms: u64,
+    pub pub_key: String, // hex
+    pub addr: SocketAddr,
+    pub chunk_ids: Vec<u32>,
+    pub bytes_served: u64,
+}
+
+impl AuditRecord {
+    fn to_line(&self) -> String {
+        let chunk_ids = self
+            .chunk_ids
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            "{}\t{}\t{}\t{}\t{}",
+            self.timestamp_ms, self.pub_key, self.addr, chunk_ids, self.bytes_served
+        )
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let mut fields = line.split('\t');
+        let timestamp_ms = fields.next()?.parse().ok()?;
+        let pub_key = fields.next()?.to_string();
+        let addr = fields.next()?.parse().ok()?;
+        let chunk_ids = match fields.next()? {
+            "" => vec![],
+            ids => ids
+                .split(',')
+                .map(|id| id.parse().ok())
+                .collect::<Option<_>>()?,
+        };
+        let bytes_served = fields.next()?.parse().ok()?;
+        fields.next().is_none().then_some(Self {
+            timestamp_ms,
+            pub_key,
+            addr,
+            chunk_ids,
+            bytes_served,
+        })
+    }
+}
+
+// Each line is a record followed by a signature over the previous line's
+// signature and the record, so editing or dropping a line breaks every later one.
+fn sign_line(key: &SigningKey, previous: &Signature, line: &str) -> Signature {
+    key.sign(&[previous.to_bytes().as_slice(), line.as_bytes()].concat())
+}
+
+fn genesis() -> Signature {
+    Signature::from_bytes(&[0u8; 64])
+}
+
+fn split_signature(line: &str) -> Option<(&str, Signature)> {
+    let (record, signature) = line.rsplit_once('\t')?;
+    let mut bytes = [0u8; 64];
+    hex::decode_to_slice(signature, &mut bytes).ok()?;
+    Some((record, Signature::from_bytes(&bytes)))
+}
+
+pub fn parse_signing_key(key: &str) -> Option<SigningKey> {
+    let mut bytes = [0u8; SECRET_KEY_LENGTH];
+    hex::decode_to_slice(key.trim(), &mut bytes).ok()?;
+    Some(SigningKey::from_bytes(&bytes))
+}
+
+pub struct AuditLog {
+    file: File,
+    key: SigningKey,
+    previous: Signature,
+}
+
+impl AuditLog {
+    // Appends to an existing log, continuing its signature chain.
+    pub fn open<P: AsRef<Path>>(path: P, key: SigningKey) -> io::Result<Self> {
+        let path = path.as_ref();
+        let previous = match File::open(path) {
+            Ok(file) => match BufReader::new(file).lines().last().transpose()? {
+                Some(line) => {
+                    split_signature(&line)
+                        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Corrupted audit log"))?
+                        .1
+                }
+                None => genesis(),
+            },
+            Err(err) if err.kind() == ErrorKind::NotFound => genesis(),
+            Err(err) => return Err(err),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file,
+            key,
+            previous,
+        })
+    }
+
+    pub fn append(&mut self, record: &AuditRecord) -> io::Result<()> {
+        let line = record.to_line();
+        let signature = sign_line(&self.key, &self.previous, &line);
+        writeln!(self.file, "{line}\t{}", hex::encode(signature.to_bytes()))?;
+        self.previous = signature;
+        Ok(())
+    }
+}
+
+// Reads every record back. With a key, the chain is checked and reading fails
+// at the first line that does not verify.
+pub fn read_audit_log<P: AsRef<Path>>(
+    path: P,
+    key: Option<&VerifyingKey>,
+) -> io::Result<Vec<AuditRecord>> {
+    let mut records = vec![];
+    let mut previous = genesis();
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid audit line {}", number + 1),
+            )
+        };
+        let (record, signature) = split_signature(&line).ok_or_else(invalid)?;
+        if let Some(key) = key {
+            key.verify(
+                &[previous.to_bytes().as_slice(), record.as_bytes()].concat(),
+                &signature,
+            )
+            .map_err(|_| invalid())?;
+        }
+        records.push(AuditRecord::from_line(record).ok_or_else(invalid)?);
+        previous = signature;
+    }
+    Ok(records)
+}
+
+static AUDITOR: OnceLock<Sender<AuditRecord>> = OnceLock::new();
+
+pub fn record(record: AuditRecord) {
+    if let Some(auditor) = AUDITOR.get() {
+        let _ = auditor.send(record);
+    }
+}
+
+fn audit_writer(rx: Receiver<AuditRecord>, mut log: AuditLog) {
+    while let Ok(record) = rx.recv() {
+        if let Err(err) = log.append(&record) {
+            eprintln!("Failed to write audit log: {err}");
+        }
+    }
+}
+
+pub fn init(path: PathBuf, key: SigningKey) -> io::Result<()> {
+    let log = AuditLog::open(path, key)?;
+    let (auditor_tx, auditor_rx) = unbounded();
+    std::thread::spawn(move || audit_writer(auditor_rx, log));
+    let _ = AUDITOR.set(auditor_tx);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn sample(timestamp_ms: u64, chunk_ids: Vec<u32>) -> AuditRecord {
+        AuditRecord {
+            timestamp_ms,
+            pub_key: String::from(
+                "4ae6629e09372dd96196f35c032fd1c5da3dfe01ca40ecf8b268d78d741e9d1c",
+            ),
+            addr: "127.0.0.1:7000".parse().unwrap(),
+            chunk_ids,
+            bytes_served: timestamp_ms * 10,
+        }
+    }
+
+    #[test]
+    fn chain_survives_reopen_and_detects_edits() -> io::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("audit.log");
+        let key =
+            parse_signing_key("fd9d88daa555f6bad0bbece8e0e4fffef190723e16aa9dfe0d18c8e4ff7a6eda")
+                .unwrap();
+
+        AuditLog::open(&path, key.clone())?.append(&sample(1, vec![3, 4]))?;
+        AuditLog::open(&path, key.clone())?.append(&sample(2, vec![]))?;
+
+        let records = read_audit_log(&path, Some(&key.verifying_key()))?;
+        assert_eq!(records, vec![sample(1, vec![3, 4]), sample(2, vec![])]);
+
+        let tampered = std::fs::read_to_string(&path)?.replacen("\t10\t", "\t0\t", 1);
+        std::fs::write(&path, tampered)?;
+        assert!(read_audit_log(&path, Some(&key.verifying_key())).is_err());
+        assert_eq!(read_audit_log(&path, None)?.len(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+use std::io::{ErrorKind, Read, Result};
+
+// Content-defined chunking after FastCDC: a boundary is cut where a rolling gear
+// hash of the last bytes matches a mask, so boundaries move along with
+// insertions and deletions and the chunks around them keep their hashes.
+
+// Part of the plan format, changing it moves every boundary.
+const GEAR: [u64; 256] = gear_table(0x5553_796e_6343_4443);
+
+const fn gear_table(mut seed: u64) -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        // splitmix64
+        seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cdc {
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+}
+
+impl Cdc {
+    // Chunks average about `avg_size` bytes, between a quarter and four times that.
+    pub fn new(avg_size: usize) -> Self {
+        let avg_size = avg_size.max(64);
+        Self {
+            min_size: avg_size / 4,
+            avg_size,
+            max_size: avg_size * 4,
+        }
+    }
+
+    pub fn with_min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size.min(self.avg_size);
+        self
+    }
+
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size.max(self.avg_size);
+        self
+    }
+
+    // Normalized chunking: a stricter mask before the average size and a looser
+    // one after it pull chunk sizes towards the average.
+    fn masks(&self) -> (u64, u64) {
+        let bits = self.avg_size.ilog2();
+        let mask = |bits: u32| !0u64 << (64 - bits.clamp(1, 63));
+        (mask(bits + 1), mask(bits - 1))
+    }
+
+    // Length of the chunk at the start of `data`, which holds the rest of the
+    // file or at least `max_size` bytes of it.
+    pub fn cut(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min_size {
+            return data.len();
+        }
+        let (strict, loose) = self.masks();
+        let end = data.len().min(self.max_size);
+        let normal = self.avg_size.min(end);
+        let mut hash = 0u64;
+        for (i, byte) in data.iter().enumerate().take(end).skip(self.min_size) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            let mask = if i < normal { strict } else { loose };
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+
+    // Reads `reader` to its end, handing every chunk to `visit` with its offset.
+    // An empty input still makes one, empty, chunk.
+    pub fn split<R: Read>(&self, mut reader: R, mut visit: impl FnMut(u64, &[u8])) -> Result<()> {
+        let mut buffer = vec![0u8; 2 * self.max_size];
+        let (mut start, mut filled) = (0, 0);
+        let mut offset = 0u64;
+        let mut eof = false;
+        loop {
+            // Keep at least `max_size` bytes ahead of the cut unless the input ran out.
+            if !eof && filled - start < self.max_size {
+                buffer.copy_within(start..filled, 0);
+                (start, filled) = (0, filled - start);
+                while !eof && filled < buffer.len() {
+                    match reader.read(&mut buffer[filled..]) {
+                        Ok(0) => eof = true,
+                        Ok(read) => filled += read,
+                        Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                        Err(err) => return Err(err),
+                    }
+                }
+            }
+            let length = self.cut(&buffer[start..filled]);
+            if length == 0 && offset > 0 {
+                return Ok(());
+            }
+            visit(offset, &buffer[start..start + length]);
+            if length == 0 {
+                return Ok(());
+            }
+            start += length;
+            offset += length as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+    use std::collections::HashSet;
+
+    fn chunks(cdc: &Cdc, data: &[u8]) -> Vec<(u64, Vec<u8>)> {
+        let mut chunks = vec![];
+        cdc.split(data, |offset, chunk| chunks.push((offset, chunk.to_vec())))
+            .unwrap();
+        chunks
+    }
+
+    #[test]
+    fn boundaries_survive_insertions() {
+        let mut rng = StdRng::seed_from_u64(3902);
+        let data: Vec<u8> = (0..1 << 20).map(|_| rng.random()).collect();
+        let cdc = Cdc::new(8192);
+
+        let before = chunks(&cdc, &data);
+        let mut covered = 0;
+        for (i, (offset, chunk)) in before.iter().enumerate() {
+            assert_eq!(*offset, covered);
+            assert!(chunk.len() <= cdc.max_size);
+            assert!(chunk.len() >= cdc.min_size || i == before.len() - 1);
+            covered += chunk.len() as u64;
+        }
+        assert_eq!(covered, data.len() as u64);
+        let average = data.len() / before.len();
+        assert!((4096..16384).contains(&average), "{average}");
+
+        let mut edited = data.clone();
+        edited.splice(500_000..500_000, [0x42; 100]);
+        edited.drain(100_000..100_010);
+        let after = chunks(&cdc, &edited);
+        let hashes: HashSet<_> = before
+            .iter()
+            .map(|(_, chunk)| blake3::hash(chunk))
+            .collect();
+        let kept = after
+            .iter()
+            .filter(|(_, chunk)| hashes.contains(&blake3::hash(chunk)))
+            .count();
+        // Only the chunks around the two edits change.
+        assert!(kept + 6 >= after.len(), "{kept} of {}", after.len());
+
+        assert_eq!(chunks(&cdc, &[]), vec![(0, vec![])]);
+    }
+}
@@ -0,0 +1,165 @@
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, Ordering};
+use tokio::time::Instant;
+
+use super::log::current_timestamp_ms;
+
+// Where the engine reads time from. Monotonic time comes from tokio, so it
+// already follows a paused runtime; wall time is what has to be injected.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn unix_ms(&self) -> u64;
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_ms(&self) -> u64 {
+        current_timestamp_ms()
+    }
+}
+
+pub fn system_clock() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+// Wall time starting at a fixed epoch and advancing with tokio's clock, so runs
+// under `tokio::time::pause` see the same timestamps every time.
+pub struct SimulatedClock {
+    epoch_ms: u64,
+    start: Instant,
+}
+
+impl SimulatedClock {
+    pub fn new(epoch_ms: u64) -> Self {
+        Self {
+            epoch_ms,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn shared(epoch_ms: u64) -> SharedClock {
+        Arc::new(Self::new(epoch_ms))
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_ms(&self) -> u64 {
+        self.epoch_ms + (Instant::now() - self.start).as_millis() as u64
+    }
+}
+
+// A simulated clock whose wall time can be stepped, as NTP or someone setting
+// the time would, while monotonic time goes on. A suspended machine is
+// `tokio::time::advance` with nothing polled meanwhile.
+pub struct AdjustableClock {
+    simulated: SimulatedClock,
+    step_ms: AtomicI64,
+}
+
+impl AdjustableClock {
+    pub fn new(epoch_ms: u64) -> Self {
+        Self {
+            simulated: SimulatedClock::new(epoch_ms),
+            step_ms: AtomicI64::new(0),
+        }
+    }
+
+    // Moves wall time by `step_ms`, back if negative.
+    pub fn step_wall(&self, step_ms: i64) {
+        self.step_ms.fetch_add(step_ms, Ordering::Relaxed);
+    }
+}
+
+impl Clock for AdjustableClock {
+    fn now(&self) -> Instant {
+        self.simulated.now()
+    }
+
+    fn unix_ms(&self) -> u64 {
+        self.simulated
+            .unix_ms()
+            .saturating_add_signed(self.step_ms.load(Ordering::Relaxed))
+    }
+}
+
+// How far a peer's clock is behind ours, in ms, from timestamps it sent: our
+// time on receipt minus its timestamp. Each sample also carries the network
+// delay, so the smallest is kept, let creep up so the estimate follows drift.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SkewEstimate(Option<i64>);
+
+impl SkewEstimate {
+    pub fn observe(&mut self, offset_ms: i64) {
+        self.0 = Some(match self.0 {
+            Some(current) if offset_ms > current => current + (offset_ms - current) / 16,
+            _ => offset_ms,
+        });
+    }
+
+    pub fn offset_ms(&self) -> Option<i64> {
+        self.0
+    }
+}
+
+// Our wall time minus the remote one, both in ms since the epoch.
+pub fn offset_ms(local_ms: u64, remote_ms: u64) -> i64 {
+    local_ms.wrapping_sub(remote_ms) as i64
+}
+
+impl fmt::Display for SkewEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            None => write!(f, "unknown"),
+            Some(offset_ms) if offset_ms >= 0 => write!(f, "{offset_ms} ms behind"),
+            Some(offset_ms) => write!(f, "{} ms ahead", offset_ms.unsigned_abs()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn simulated_clock_follows_paused_time() {
+        let clock = SimulatedClock::new(1_000_000);
+        let start = clock.now();
+        assert_eq!(clock.unix_ms(), 1_000_000);
+
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(clock.unix_ms(), 1_001_500);
+        assert_eq!(clock.now() - start, Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn skew_keeps_the_least_delayed_sample() {
+        let mut skew = SkewEstimate::default();
+        assert_eq!(skew.to_string(), "unknown");
+        // A peer 3 minutes ahead, with 40 to 200 ms of delay.
+        for delay in [200, 40, 120] {
+            skew.observe(offset_ms(1_000_000 + delay, 1_180_000));
+        }
+        assert_eq!(skew.offset_ms(), Some(-179_960 + 5));
+        assert_eq!(skew.to_string(), "179955 ms ahead");
+        // Drift back is followed slowly, a late sample must not move it much.
+        skew.observe(offset_ms(1_000_000, 1_179_000));
+        assert_eq!(skew.offset_ms(), Some(-179_955 + 59));
+
+        let mut behind = SkewEstimate::default();
+        behind.observe(offset_ms(1_000_000, 900_000));
+        assert_eq!(behind.to_string(), "100000 ms behind");
+    }
+}
@@ -0,0 +1,129 @@
+use serde::Serialize;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::task::{Context, Poll};
+
+// Optional CPU accounting by subsystem, to tell a transfer held back by the
+// network from one held back by RaptorQ. The CPU time of the thread polling a
+// task is read around every poll and added to the task's subsystem. Off unless
+// enabled, as reading the thread clock costs a syscall per poll.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    Encoder,
+    Decoder,
+    // Tasks reading and writing the socket, parsing and building packets.
+    Socket,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SPENT_NS: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn read_clock(clock: libc::clockid_t) -> u64 {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Can not fail for these clocks, and 0 only loses one sample if it did.
+    if unsafe { libc::clock_gettime(clock, &mut time) } != 0 {
+        return 0;
+    }
+    time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
+
+fn thread_ns() -> u64 {
+    read_clock(libc::CLOCK_THREAD_CPUTIME_ID)
+}
+
+fn add(subsystem: Subsystem, ns: u64) {
+    SPENT_NS[subsystem as usize].fetch_add(ns, Ordering::Relaxed);
+}
+
+// Runs blocking work, e.g. inside `spawn_blocking`, on the account of `subsystem`.
+pub fn measure<T>(subsystem: Subsystem, work: impl FnOnce() -> T) -> T {
+    if !is_enabled() {
+        return work();
+    }
+    let start = thread_ns();
+    let result = work();
+    add(subsystem, thread_ns().saturating_sub(start));
+    result
+}
+
+pub struct Accounted<F> {
+    subsystem: Subsystem,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for Accounted<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let subsystem = self.subsystem;
+        measure(subsystem, || self.future.as_mut().poll(cx))
+    }
+}
+
+pub fn accounted<F: Future>(subsystem: Subsystem, future: F) -> Accounted<F> {
+    Accounted {
+        subsystem,
+        future: Box::pin(future),
+    }
+}
+
+// CPU time in ms. The process total also has what no subsystem accounts for,
+// the runtime itself, the bus and the control socket among others.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CpuReport {
+    pub encoder_ms: u64,
+    pub decoder_ms: u64,
+    pub socket_ms: u64,
+    pub process_ms: u64,
+}
+
+// None when accounting is off.
+pub fn report() -> Option<CpuReport> {
+    let ms =
+        |subsystem: Subsystem| SPENT_NS[subsystem as usize].load(Ordering::Relaxed) / 1_000_000;
+    is_enabled().then(|| CpuReport {
+        encoder_ms: ms(Subsystem::Encoder),
+        decoder_ms: ms(Subsystem::Decoder),
+        socket_ms: ms(Subsystem::Socket),
+        process_ms: read_clock(libc::CLOCK_PROCESS_CPUTIME_ID) / 1_000_000,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spin(rounds: u64) -> u64 {
+        (0..rounds).fold(0u64, |hash, round| {
+            std::hint::black_box(hash.rotate_left(5) ^ round)
+        })
+    }
+
+    #[tokio::test]
+    async fn work_lands_on_its_subsystem() {
+        // Off, nothing is counted.
+        measure(Subsystem::Decoder, || spin(1_000_000));
+        assert_eq!(report(), None);
+
+        enable();
+        let before = report().unwrap();
+        measure(Subsystem::Decoder, || spin(20_000_000));
+        accounted(Subsystem::Decoder, async { spin(20_000_000) }).await;
+        let after = report().unwrap();
+        assert!(after.decoder_ms > before.decoder_ms, "{before:?} {after:?}");
+        assert!(after.process_ms >= after.decoder_ms - before.decoder_ms);
+    }
+}
@@ -0,0 +1,110 @@
+use std::process::ExitCode;
+
+use super::i18n;
+use crate::engine::receiving::SessionError;
+
+// Exit codes the binaries share, for wrappers and CI to branch on the kind of
+// failure instead of parsing stderr. 0 is success, 1 any failure not listed
+// here and 2 a usage error, as clap exits with. Codes never change meaning,
+// new kinds get new codes.
+//
+// Errors are tagged with their kind as anyhow context, e.g.
+// `.context(Failure::BadPlan)`, which `exit_code` finds under any later context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    // Chunks, or an audit log, failed their hash or signature check.
+    Verification,
+    // The peer stayed silent or busy for longer than allowed.
+    Timeout,
+    // Keys or tokens were refused, by us or by the peer.
+    Auth,
+    // The file, the destination or a state file could not be read or written.
+    Disk,
+    // A plan could not be read, parsed, or does not check out.
+    BadPlan,
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(i18n::failure(*self))
+    }
+}
+
+impl Failure {
+    pub const ALL: [Failure; 5] = [
+        Failure::Verification,
+        Failure::Timeout,
+        Failure::Auth,
+        Failure::Disk,
+        Failure::BadPlan,
+    ];
+
+    pub fn code(self) -> u8 {
+        match self {
+            Failure::Verification => 3,
+            Failure::Timeout => 4,
+            Failure::Auth => 5,
+            Failure::Disk => 6,
+            Failure::BadPlan => 7,
+        }
+    }
+
+    // Of a child process that exited with `code`, e.g. the client run by `usync get`.
+    pub fn from_code(code: i32) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|failure| i32::from(failure.code()) == code)
+    }
+
+    // Socket failures are no timeout, and not listed.
+    pub fn of_session(err: &SessionError) -> Option<Self> {
+        match err {
+            SessionError::Unreachable(unreachable) if unreachable.rejected => Some(Failure::Auth),
+            SessionError::Unreachable(_) => Some(Failure::Timeout),
+            SessionError::Socket(_) => None,
+        }
+    }
+}
+
+// `err` tagged with `failure`, if it is of a listed kind.
+pub fn tag(err: anyhow::Error, failure: Option<Failure>) -> anyhow::Error {
+    match failure {
+        Some(failure) => err.context(failure),
+        None => err,
+    }
+}
+
+// What `main` returns: the error printed as anyhow would, and the code of the
+// kind it was tagged with.
+pub fn exit_code(result: anyhow::Result<()>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}: {err:?}", i18n::error());
+            let failure = err.downcast_ref::<Failure>();
+            ExitCode::from(failure.map_or(1, |failure| failure.code()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{Context, anyhow};
+
+    #[test]
+    fn failures_are_found_under_later_context() {
+        let codes: Vec<u8> = Failure::ALL.iter().map(|failure| failure.code()).collect();
+        assert_eq!(codes, vec![3, 4, 5, 6, 7]);
+        assert_eq!(Failure::from_code(6), Some(Failure::Disk));
+        assert_eq!(Failure::from_code(1), None);
+
+        let err = Err::<(), _>(anyhow!("no such file"))
+            .context(Failure::BadPlan)
+            .context("loading plan.toml")
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<Failure>(), Some(&Failure::BadPlan));
+        assert_eq!(exit_code(Err(err)), ExitCode::from(7));
+        assert_eq!(exit_code(Err(anyhow!("untagged"))), ExitCode::from(1));
+    }
+}
@@ -1,23 +1,183 @@
-use memmap2::{Mmap, MmapOptions};
+use dashmap::DashSet;
+use memmap2::{Advice, Mmap, MmapOptions};
 use std::collections::HashMap;
-use std::ffi::OsString;
-use std::fs::{File, OpenOptions};
+use std::ffi::{CString, OsString};
+use std::fs::{File, OpenOptions, Permissions};
 use std::io::{Error, ErrorKind, Result};
-use std::os::unix::fs::FileExt;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt, PermissionsExt};
 use std::path::Path;
-use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, UNIX_EPOCH};
+
+use super::plan::FileMetadata;
+use super::store::ChunkStore;
+use super::units::{ByteOffset, ChunkId};
 
 pub struct ChunkIndex {
     pub files: HashMap<usize, OsString>,
-    pub chunks: HashMap<u32, (usize, u64, usize)>, // (file, offset, length)
+    // Replaced as a whole when a watched file is planned again.
+    chunks: RwLock<HashMap<ChunkId, (usize, u64, usize)>>, // (file, offset, length)
+    unavailable: DashSet<ChunkId>,
+    lock_pages: bool,
+    store: ChunkStore,
+    epoch: AtomicU64,
+    // Planned hashes, checked once per chunk before it is served when
+    // `verify` is set, see `verify_chunk`.
+    verify: bool,
+    expected: RwLock<HashMap<ChunkId, String>>,
+    verified: DashSet<ChunkId>,
 }
 
 impl ChunkIndex {
-    pub fn get(&self, index: u32) -> Option<(&OsString, u64, usize)> {
-        self.chunks.get(&index).and_then(|(file, offset, length)| {
-            self.files.get(file).map(|file| (file, *offset, *length))
+    pub fn new(
+        files: HashMap<usize, OsString>,
+        chunks: HashMap<ChunkId, (usize, u64, usize)>,
+    ) -> Self {
+        Self {
+            files,
+            chunks: RwLock::new(chunks),
+            unavailable: DashSet::new(),
+            lock_pages: false,
+            store: ChunkStore::default(),
+            epoch: AtomicU64::new(0),
+            verify: false,
+            expected: RwLock::default(),
+            verified: DashSet::new(),
+        }
+    }
+
+    // The plan epoch the chunks belong to.
+    pub fn with_epoch(self, epoch: u64) -> Self {
+        self.epoch.store(epoch, Ordering::Relaxed);
+        self
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::Relaxed)
+    }
+
+    // Takes on the chunks of a new plan and their hashes, all of them available
+    // and to be verified again. The epoch changes before the lock is released,
+    // so a chunk looked up and then checked against `epoch()` is never from a
+    // newer plan than that.
+    pub fn replace_chunks(
+        &self,
+        epoch: u64,
+        chunks: HashMap<ChunkId, (usize, u64, usize)>,
+        hashes: HashMap<ChunkId, String>,
+    ) {
+        let mut current = self.chunks.write().unwrap();
+        *current = chunks;
+        *self.expected.write().unwrap() = hashes;
+        self.unavailable.clear();
+        self.verified.clear();
+        self.epoch.store(epoch, Ordering::Relaxed);
+    }
+
+    // While the file is being changed no plan matches what it holds.
+    pub fn mark_changing(&self) {
+        self.epoch.store(u64::MAX, Ordering::Relaxed);
+    }
+
+    // Pin the pages of chunks while their encoders are being built.
+    pub fn with_lock_pages(mut self, lock_pages: bool) -> Self {
+        self.lock_pages = lock_pages;
+        self
+    }
+
+    pub fn lock_pages(&self) -> bool {
+        self.lock_pages
+    }
+
+    // Hashes every chunk against `hashes` as it is loaded to be served, until
+    // it passed once, so a file changed since planning is not served as if it
+    // were the planned one. Chunks without a hash there are served unchecked.
+    pub fn with_verification(mut self, hashes: HashMap<ChunkId, String>) -> Self {
+        self.verify = true;
+        self.expected = RwLock::new(hashes);
+        self
+    }
+
+    // False, and the chunk marked unavailable, if `data` loaded for it is not
+    // what was planned.
+    pub fn verify_chunk(&self, index: ChunkId, data: &[u8]) -> bool {
+        if !self.verify || self.verified.contains(&index) {
+            return true;
+        }
+        let expected = self.expected.read().unwrap();
+        let Some(hash) = expected.get(&index) else {
+            return true;
+        };
+        if hex::encode(blake3::hash(data).as_bytes()) != *hash {
+            self.mark_unavailable(index);
+            return false;
+        }
+        self.verified.insert(index);
+        true
+    }
+
+    pub fn with_store(mut self, store: ChunkStore) -> Self {
+        self.store = store;
+        self
+    }
+
+    pub fn store(&self) -> ChunkStore {
+        self.store
+    }
+
+    pub fn get(&self, index: ChunkId) -> Option<(OsString, u64, usize)> {
+        let chunks = self.chunks.read().unwrap();
+        chunks.get(&index).and_then(|(file, offset, length)| {
+            self.files
+                .get(file)
+                .map(|file| (file.clone(), *offset, *length))
         })
     }
+
+    pub fn is_available(&self, index: ChunkId) -> bool {
+        !self.unavailable.contains(&index)
+    }
+
+    // Returns false if it was already unavailable.
+    pub fn mark_unavailable(&self, index: ChunkId) -> bool {
+        self.unavailable.insert(index)
+    }
+
+    // Chunks no longer fully backed by their file (e.g. it shrank after planning)
+    // are marked unavailable and returned.
+    pub fn validate_lengths(&self) -> Vec<(ChunkId, Error)> {
+        let mut stale = vec![];
+        for (&index, (file, offset, length)) in self.chunks.read().unwrap().iter() {
+            let result = self
+                .files
+                .get(file)
+                .ok_or(Error::new(ErrorKind::NotFound, "Unknown file"))
+                .and_then(std::fs::metadata)
+                .and_then(|metadata| {
+                    let end = ByteOffset(*offset).saturating_end(*length);
+                    if end.0 > metadata.len() {
+                        Err(Error::new(
+                            ErrorKind::UnexpectedEof,
+                            format!(
+                                "Chunk [{offset}..{end}) exceeds file size ({})",
+                                metadata.len()
+                            ),
+                        ))
+                    } else {
+                        Ok(())
+                    }
+                });
+            if let Err(err) = result {
+                self.mark_unavailable(index);
+                stale.push((index, err));
+            }
+        }
+        stale.sort_by_key(|(index, _)| *index);
+        stale
+    }
 }
 
 pub static CHUNK_INDEX: OnceLock<ChunkIndex> = OnceLock::new();
@@ -52,6 +212,62 @@ pub fn check_file_exist_create<P: AsRef<Path>>(path: P) -> Result<bool> {
     Ok(false)
 }
 
+// Follows symbolic links.
+pub fn read_metadata<P: AsRef<Path>>(path: P) -> Result<FileMetadata> {
+    let metadata = std::fs::metadata(path)?;
+    Ok(FileMetadata {
+        mode: metadata.permissions().mode() & 0o7777,
+        mtime_secs: metadata.mtime(),
+        mtime_nanos: metadata.mtime_nsec() as u32,
+    })
+}
+
+// The mtime goes last, any later write to the file moves it again.
+pub fn apply_metadata<P: AsRef<Path>>(
+    path: P,
+    metadata: &FileMetadata,
+    permissions: bool,
+    times: bool,
+) -> Result<()> {
+    let path = path.as_ref();
+    if permissions {
+        std::fs::set_permissions(path, Permissions::from_mode(metadata.mode))?;
+    }
+    if times {
+        let since_epoch = Duration::new(metadata.mtime_secs.unsigned_abs(), metadata.mtime_nanos);
+        let mtime = if metadata.mtime_secs >= 0 {
+            UNIX_EPOCH + since_epoch
+        } else {
+            UNIX_EPOCH - since_epoch
+        };
+        // Opened for writing, which a read-only mode just applied may forbid.
+        File::options()
+            .append(true)
+            .open(path)
+            .or_else(|_| File::open(path))?
+            .set_modified(mtime)?;
+    }
+    Ok(())
+}
+
+// Leaves an existing link alone if it already points at `target`.
+pub fn restore_symlink<P: AsRef<Path>>(path: P, target: &Path) -> Result<bool> {
+    let path = path.as_ref();
+    match std::fs::read_link(path) {
+        Ok(existing) if existing == target => return Ok(false),
+        Ok(_) => std::fs::remove_file(path)?,
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(_) => {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} exists and is not a symbolic link", path.display()),
+            ));
+        }
+    }
+    std::os::unix::fs::symlink(target, path)?;
+    Ok(true)
+}
+
 pub fn check_file_exist<P: AsRef<Path>>(path: P) -> Result<()> {
     let path = path.as_ref();
     if path.exists() {
@@ -69,7 +285,7 @@ pub fn mmap_segment<P: AsRef<Path>>(path: P, offset: u64, length: usize) -> Resu
     let metadata = file.metadata()?;
     let file_size = metadata.len();
     let page_size = page_size::get() as u64;
-    if offset % page_size != 0 {
+    if !offset.is_multiple_of(page_size) {
         return Err(Error::new(ErrorKind::InvalidInput, "Unaligned offset!"));
     }
 
@@ -85,10 +301,69 @@ pub fn mmap_segment<P: AsRef<Path>>(path: P, offset: u64, length: usize) -> Resu
     }
 
     let mmap = unsafe { MmapOptions::new().offset(offset).len(length).map(&file)? };
+    // Every reader walks the chunk front to back, let the kernel read ahead aggressively.
+    mmap.advise(Advice::Sequential)?;
 
     Ok(mmap)
 }
 
+// Starts reading the whole segment in the background, so a cold chunk does not
+// stall its reader on one page fault after another. With `lock`, the pages are
+// also faulted in and pinned until the mapping is dropped.
+pub fn prefetch_segment(mmap: &Mmap, lock: bool) -> Result<()> {
+    mmap.advise(Advice::WillNeed)?;
+    if lock {
+        mmap.lock()?;
+    }
+    Ok(())
+}
+
+// Content-defined chunks start anywhere, those off a page boundary cannot be
+// mapped and are read into memory instead.
+pub fn read_segment<P: AsRef<Path>>(path: P, offset: u64, length: usize) -> Result<Vec<u8>> {
+    let mut data = vec![0u8; length];
+    File::open(path)?.read_exact_at(&mut data, offset)?;
+    Ok(data)
+}
+
+// Hex encoded blake3 hash, the form used in plan files.
+pub fn chunk_hash<P: AsRef<Path>>(path: P, offset: u64, length: usize) -> Result<String> {
+    if !offset.is_multiple_of(page_size::get() as u64) {
+        let chunk = read_segment(path, offset, length)?;
+        return Ok(hex::encode(blake3::hash(&chunk).as_bytes()));
+    }
+    let chunk = mmap_segment(path, offset, length)?;
+    Ok(hex::encode(blake3::hash(&chunk).as_bytes()))
+}
+
+// Bytes an unprivileged user can still write on the file system holding `path`.
+pub fn available_space<P: AsRef<Path>>(path: P) -> Result<u64> {
+    let path = CString::new(path.as_ref().as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    #[allow(clippy::unnecessary_cast)] // Narrower than u64 on some targets.
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+// Whether the file lives on a spinning disk, None if the kernel does not say,
+// e.g. on network or virtual file systems.
+pub fn is_rotational<P: AsRef<Path>>(path: P) -> Option<bool> {
+    let dev = std::fs::metadata(path).ok()?.dev();
+    let device = std::fs::canonicalize(format!(
+        "/sys/dev/block/{}:{}",
+        libc::major(dev),
+        libc::minor(dev)
+    ))
+    .ok()?;
+    // A partition has the queue of the disk it is on.
+    let queue = |dir: &Path| std::fs::read_to_string(dir.join("queue/rotational")).ok();
+    let rotational = queue(&device).or_else(|| queue(device.parent()?))?;
+    Some(rotational.trim() == "1")
+}
+
 pub fn create_sparse_file<P: AsRef<Path>>(path: P, length: u64) -> Result<()> {
     let file = OpenOptions::new()
         .write(true)
@@ -112,7 +387,6 @@ pub fn write_at<P: AsRef<Path>>(path: P, offset: u64, data: &[u8]) -> Result<()>
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::os::unix::fs::MetadataExt;
     use tempfile::tempdir;
 
     #[test]
@@ -152,6 +426,7 @@ mod tests {
         // Check content
         {
             let mmap1 = mmap_segment(&file_path, 0, block_size)?;
+            prefetch_segment(&mmap1, false)?;
             let slice1 = &mmap1[0..block_size];
             assert!(slice1.iter().all(|&b| b == 0x88));
         }
@@ -165,4 +440,118 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_available_space() -> Result<()> {
+        let dir = tempdir()?;
+        assert!(available_space(dir.path())? > 0);
+        assert!(available_space(dir.path().join("missing")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_lengths() -> Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("short.bin");
+        create_sparse_file(&file_path, 3 * 4096)?;
+
+        let index = ChunkIndex::new(
+            HashMap::from([(0, file_path.clone().into_os_string())]),
+            HashMap::from([
+                (ChunkId(0), (0, 0, 4096)),
+                (ChunkId(1), (0, 4096, 8192)),
+                (ChunkId(2), (0, 8192, 8192)), // Beyond the end
+                (ChunkId(3), (1, 0, 4096)),    // Unknown file
+            ]),
+        );
+
+        let stale: Vec<ChunkId> = index
+            .validate_lengths()
+            .into_iter()
+            .map(|(chunk, _)| chunk)
+            .collect();
+        assert_eq!(stale, vec![ChunkId(2), ChunkId(3)]);
+        assert!(index.is_available(ChunkId(1)));
+        assert!(!index.is_available(ChunkId(2)));
+
+        assert_eq!(
+            chunk_hash(&file_path, 0, 4096)?,
+            hex::encode(blake3::hash(&[0u8; 4096]).as_bytes())
+        );
+        assert_eq!(
+            chunk_hash(&file_path, 100, 4096)?,
+            hex::encode(blake3::hash(&[0u8; 4096]).as_bytes())
+        );
+        assert!(chunk_hash(&file_path, 100, 3 * 4096).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_chunk() {
+        let zeros = hex::encode(blake3::hash(&[0u8; 16]).as_bytes());
+        let chunks = HashMap::from([(ChunkId(0), (0, 0, 16)), (ChunkId(1), (0, 16, 16))]);
+        let index = ChunkIndex::new(HashMap::new(), chunks.clone());
+        // Off, anything goes.
+        assert!(index.verify_chunk(ChunkId(0), &[1u8; 16]));
+
+        let hashes = HashMap::from([(ChunkId(0), zeros.clone()), (ChunkId(1), zeros.clone())]);
+        let index =
+            ChunkIndex::new(HashMap::new(), chunks.clone()).with_verification(hashes.clone());
+        assert!(index.verify_chunk(ChunkId(0), &[0u8; 16]));
+        // Passed once, not hashed again.
+        assert!(index.verify_chunk(ChunkId(0), &[1u8; 16]));
+        assert!(!index.verify_chunk(ChunkId(1), &[1u8; 16]));
+        assert!(!index.is_available(ChunkId(1)));
+        // Unplanned chunks, like those of the plan file, are served unchecked.
+        assert!(index.verify_chunk(ChunkId(2), &[1u8; 16]));
+
+        // A new plan is verified from scratch.
+        index.replace_chunks(1, chunks, hashes);
+        assert!(index.is_available(ChunkId(1)));
+        assert!(!index.verify_chunk(ChunkId(0), &[1u8; 16]));
+    }
+
+    #[test]
+    fn test_restore_metadata_and_links() -> Result<()> {
+        let dir = tempdir()?;
+        let source = dir.path().join("source.bin");
+        let copy = dir.path().join("copy.bin");
+        create_sparse_file(&source, 4096)?;
+        create_sparse_file(&copy, 4096)?;
+        std::fs::set_permissions(&source, Permissions::from_mode(0o640))?;
+        File::options()
+            .write(true)
+            .open(&source)?
+            .set_modified(UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789))?;
+
+        let metadata = read_metadata(&source)?;
+        assert_eq!(metadata.mode, 0o640);
+        apply_metadata(&copy, &metadata, false, true)?;
+        assert_eq!(read_metadata(&copy)?.mtime_secs, 1_600_000_000);
+        assert_ne!(read_metadata(&copy)?.mode, 0o640);
+        apply_metadata(
+            &copy,
+            &FileMetadata {
+                mode: 0o444,
+                ..metadata
+            },
+            true,
+            true,
+        )?;
+        assert_eq!(
+            read_metadata(&copy)?,
+            FileMetadata {
+                mode: 0o444,
+                ..metadata
+            }
+        );
+
+        let link = dir.path().join("link");
+        assert!(restore_symlink(&link, Path::new("source.bin"))?);
+        assert!(!restore_symlink(&link, Path::new("source.bin"))?);
+        assert!(restore_symlink(&link, Path::new("copy.bin"))?);
+        assert_eq!(std::fs::read_link(&link)?, Path::new("copy.bin"));
+        assert!(restore_symlink(&copy, Path::new("source.bin")).is_err());
+        Ok(())
+    }
 }
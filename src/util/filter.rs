@@ -0,0 +1,249 @@
+use std::fs;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+
+// A gitignore-style pattern. Without a `/` other than a trailing one it
+// matches names at any depth, otherwise paths from the walked root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    pattern: String,
+    anchored: bool,
+    dir_only: bool,
+    include: bool,
+}
+
+impl Rule {
+    // A leading `!` turns the rule around.
+    fn parse(line: &str, include: bool) -> Option<Self> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (line, include) = match line.strip_prefix('!') {
+            Some(line) => (line, !include),
+            None => (line, include),
+        };
+        let (line, dir_only) = match line.strip_suffix('/') {
+            Some(line) => (line, true),
+            None => (line, false),
+        };
+        let anchored = line.contains('/');
+        Some(Self {
+            pattern: line.trim_start_matches('/').to_string(),
+            anchored,
+            dir_only,
+            include,
+        })
+    }
+
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let text = if self.anchored {
+            path
+        } else {
+            path.rsplit('/').next().unwrap_or(path)
+        };
+        glob(self.pattern.as_bytes(), text.as_bytes())
+    }
+}
+
+// `*` and `?` stop at `/`, `**` does not, `[a-z]` and `[!a]` are classes.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', b'/', rest @ ..] => {
+            glob(rest, text)
+                || text
+                    .iter()
+                    .enumerate()
+                    .any(|(i, c)| *c == b'/' && glob(rest, &text[i + 1..]))
+        }
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|i| glob(rest, &text[i..])),
+        [b'*', rest @ ..] => {
+            let limit = text.iter().position(|c| *c == b'/').unwrap_or(text.len());
+            (0..=limit).any(|i| glob(rest, &text[i..]))
+        }
+        [b'?', rest @ ..] => matches!(text, [c, ..] if *c != b'/') && glob(rest, &text[1..]),
+        [b'[', class @ ..] => match (text.first(), class_end(class)) {
+            (Some(c), Some(end)) => {
+                *c != b'/'
+                    && class_matches(&class[..end], *c)
+                    && glob(&class[end + 1..], &text[1..])
+            }
+            (Some(c), None) => *c == b'[' && glob(class, &text[1..]),
+            (None, _) => false,
+        },
+        [b'\\', c, rest @ ..] | [c, rest @ ..] => text.first() == Some(c) && glob(rest, &text[1..]),
+    }
+}
+
+// Index of the `]` closing a class, which may itself start with `]`.
+fn class_end(class: &[u8]) -> Option<usize> {
+    let skip = match class {
+        [b'!' | b'^', b']', ..] => 2,
+        [b'!' | b'^', ..] | [b']', ..] => 1,
+        _ => 0,
+    };
+    class[skip..]
+        .iter()
+        .position(|c| *c == b']')
+        .map(|end| end + skip)
+}
+
+fn class_matches(class: &[u8], c: u8) -> bool {
+    let (class, negated) = match class {
+        [b'!' | b'^', class @ ..] => (class, true),
+        _ => (class, false),
+    };
+    let mut found = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == b'-' {
+            found |= (class[i]..=class[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            found |= class[i] == c;
+            i += 1;
+        }
+    }
+    found != negated
+}
+
+// Decides which files under a directory are planned. The last matching rule
+// wins; files no rule matches take the decision of their closest matched
+// directory, and are left out only if there are include rules.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathFilter {
+    rules: Vec<Rule>,
+}
+
+impl PathFilter {
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        self.rules.extend(Rule::parse(pattern, false));
+        self
+    }
+
+    pub fn include(mut self, pattern: &str) -> Self {
+        self.rules.extend(Rule::parse(pattern, true));
+        self
+    }
+
+    // One pattern a line, in .gitignore syntax, each one an include.
+    pub fn include_from<P: AsRef<Path>>(mut self, path: P) -> Result<Self> {
+        for line in fs::read_to_string(path)?.lines() {
+            self = self.include(line);
+        }
+        Ok(self)
+    }
+
+    fn decide(&self, path: &str, is_dir: bool) -> Option<bool> {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(path, is_dir))
+            .map(|rule| rule.include)
+    }
+
+    fn has_includes(&self) -> bool {
+        self.rules.iter().any(|rule| rule.include)
+    }
+
+    // Regular files and symbolic links below `root`, relative to it and sorted.
+    // Excluded directories are not descended into, links to directories neither.
+    pub fn walk<P: AsRef<Path>>(&self, root: P) -> Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        self.walk_into(root.as_ref(), Path::new(""), None, &mut files)?;
+        files.sort();
+        Ok(files)
+    }
+
+    fn walk_into(
+        &self,
+        root: &Path,
+        dir: &Path,
+        inherited: Option<bool>,
+        files: &mut Vec<PathBuf>,
+    ) -> Result<()> {
+        for entry in fs::read_dir(root.join(dir))? {
+            let entry = entry?;
+            let relative = dir.join(entry.file_name());
+            let Some(path) = relative.to_str() else {
+                eprintln!("Skipped {}, not valid UTF-8.", relative.display());
+                continue;
+            };
+            let file_type = entry.file_type()?;
+            let decision = self.decide(path, file_type.is_dir()).or(inherited);
+            if file_type.is_dir() {
+                if decision != Some(false) {
+                    self.walk_into(root, &relative, decision, files)?;
+                }
+            } else if decision.unwrap_or(!self.has_includes()) {
+                files.push(relative);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn globs() {
+        assert!(glob(b"*.o", b"main.o"));
+        assert!(!glob(b"*.o", b"src/main.o"));
+        assert!(glob(b"**/*.o", b"main.o"));
+        assert!(glob(b"**/*.o", b"src/lib/main.o"));
+        assert!(glob(b"target/**", b"target/debug/usync"));
+        assert!(glob(b"a/**/b", b"a/b"));
+        assert!(glob(b"a/**/b", b"a/x/y/b"));
+        assert!(glob(b"file?.[ch]", b"file1.c"));
+        assert!(!glob(b"file?.[!ch]", b"file1.c"));
+        assert!(glob(b"[a-c]x", b"bx"));
+        assert!(glob(b"\\*", b"*"));
+        assert!(!glob(b"\\*", b"a"));
+    }
+
+    #[test]
+    fn walk_with_filters() -> Result<()> {
+        let dir = tempdir()?;
+        for file in [
+            "Cargo.toml",
+            "src/main.rs",
+            "src/main.o",
+            "target/debug/usync",
+            "docs/target/index.md",
+            ".cache/blob",
+        ] {
+            let path = dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, b"")?;
+        }
+        let paths = |filter: &PathFilter| -> Result<Vec<String>> {
+            Ok(filter
+                .walk(dir.path())?
+                .into_iter()
+                .map(|path| path.to_str().unwrap().to_string())
+                .collect())
+        };
+
+        let filter = PathFilter::default()
+            .exclude("/target/")
+            .exclude("*.o")
+            .exclude(".cache");
+        assert_eq!(
+            paths(&filter)?,
+            ["Cargo.toml", "docs/target/index.md", "src/main.rs"]
+        );
+
+        let includes = dir.path().join("includes");
+        fs::write(&includes, "# sources only\nsrc/\n!*.o\n")?;
+        let filter = PathFilter::default().include_from(&includes)?;
+        assert_eq!(paths(&filter)?, ["src/main.rs"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,365 @@
+use std::fmt::Display;
+use std::sync::OnceLock;
+
+use super::exit::Failure;
+
+// The messages the binaries print about what they are doing, in English or
+// Chinese. The language is that of `USYNC_LANG`, else of the locale, read once.
+// Errors of the engine and debug output stay in English, as they are what
+// gets searched for and reported.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lang {
+    En,
+    Zh,
+}
+
+static LANG: OnceLock<Lang> = OnceLock::new();
+
+pub fn lang() -> Lang {
+    *LANG.get_or_init(|| from_env(|var| std::env::var(var).ok()))
+}
+
+// The first of these set decides, as for gettext: `zh`, `zh_CN.UTF-8` and the
+// like are Chinese, anything else English.
+fn from_env(get: impl Fn(&str) -> Option<String>) -> Lang {
+    ["USYNC_LANG", "LC_ALL", "LC_MESSAGES", "LANG"]
+        .into_iter()
+        .find_map(|var| get(var).filter(|value| !value.is_empty()))
+        .map_or(Lang::En, |value| {
+            match value.to_ascii_lowercase().starts_with("zh") {
+                true => Lang::Zh,
+                false => Lang::En,
+            }
+        })
+}
+
+fn pick(en: impl FnOnce() -> String, zh: impl FnOnce() -> String) -> String {
+    match lang() {
+        Lang::En => en(),
+        Lang::Zh => zh(),
+    }
+}
+
+fn label(en: &'static str, zh: &'static str) -> &'static str {
+    match lang() {
+        Lang::En => en,
+        Lang::Zh => zh,
+    }
+}
+
+pub fn error() -> &'static str {
+    label("Error", "错误")
+}
+
+fn failure_in(lang: Lang, failure: Failure) -> &'static str {
+    match (lang, failure) {
+        (Lang::En, Failure::Verification) => "verification failed",
+        (Lang::En, Failure::Timeout) => "network timeout",
+        (Lang::En, Failure::Auth) => "authentication rejected",
+        (Lang::En, Failure::Disk) => "disk error",
+        (Lang::En, Failure::BadPlan) => "bad plan",
+        (Lang::Zh, Failure::Verification) => "校验失败",
+        (Lang::Zh, Failure::Timeout) => "网络超时",
+        (Lang::Zh, Failure::Auth) => "认证被拒绝",
+        (Lang::Zh, Failure::Disk) => "磁盘错误",
+        (Lang::Zh, Failure::BadPlan) => "计划文件无效",
+    }
+}
+
+pub fn failure(failure: Failure) -> &'static str {
+    failure_in(lang(), failure)
+}
+
+pub fn downloading_file(path: impl Display) -> String {
+    pick(
+        || format!("Downloading file: {path}"),
+        || format!("正在下载文件：{path}"),
+    )
+}
+
+pub fn already_exists(path: impl Display) -> String {
+    pick(
+        || format!("{path} already exists."),
+        || format!("{path} 已存在。"),
+    )
+}
+
+pub fn created_empty(path: impl Display) -> String {
+    pick(
+        || format!("Created {path} successfully as an empty file."),
+        || format!("已创建空文件 {path}。"),
+    )
+}
+
+pub fn fetching_plan(server: impl Display) -> String {
+    pick(
+        || format!("Fetching the plan from {server}."),
+        || format!("正在从 {server} 获取计划文件。"),
+    )
+}
+
+pub fn linked(path: impl Display, target: impl Display) -> String {
+    pick(
+        || format!("Linked {path} to {target}."),
+        || format!("已将 {path} 链接到 {target}。"),
+    )
+}
+
+pub fn already_linked(path: impl Display, target: impl Display) -> String {
+    pick(
+        || format!("{path} already links to {target}."),
+        || format!("{path} 已链接到 {target}。"),
+    )
+}
+
+pub fn offering(file: impl Display, server: impl Display) -> String {
+    pick(
+        || format!("Offering {file} to {server}."),
+        || format!("正在向 {server} 提供 {file}。"),
+    )
+}
+
+pub fn upload_finished() -> String {
+    pick(|| "Upload finished.".into(), || "上传完成。".into())
+}
+
+pub fn control_socket(path: impl Display) -> String {
+    pick(
+        || format!("Control socket at {path}."),
+        || format!("控制套接字位于 {path}。"),
+    )
+}
+
+pub fn epoch_published(epoch: impl Display) -> String {
+    pick(
+        || format!("Plan epoch {epoch} published, looking for changed chunks."),
+        || format!("计划第 {epoch} 版已发布，正在查找有变化的分块。"),
+    )
+}
+
+pub fn chunks_to_receive(missing: usize, total: usize) -> String {
+    pick(
+        || format!("{missing} / {total} chunks to receive."),
+        || format!("待接收分块 {missing} / {total}。"),
+    )
+}
+
+pub fn waiting_for_upload(file: impl Display) -> String {
+    pick(
+        || format!("Waiting for an upload of {file}."),
+        || format!("正在等待 {file} 的上传。"),
+    )
+}
+
+pub fn receiving(file: impl Display, peer: impl Display) -> String {
+    pick(
+        || format!("Receiving {file} from {peer}."),
+        || format!("正在从 {peer} 接收 {file}。"),
+    )
+}
+
+pub fn published_epoch(
+    epoch: impl Display,
+    file: impl Display,
+    changed: impl Display,
+    total: usize,
+) -> String {
+    pick(
+        || format!("Published epoch {epoch} of {file}, {changed} / {total} chunks changed."),
+        || format!("已发布 {file} 的第 {epoch} 版，{changed} / {total} 个分块有变化。"),
+    )
+}
+
+pub fn listening(addr: impl Display, transport: &str, port: u16) -> String {
+    pick(
+        || {
+            format!(
+                "Listening on {addr}, make sure the firewall lets {transport} port {port} through."
+            )
+        },
+        || format!("正在监听 {addr}，请确认防火墙放行 {transport} 端口 {port}。"),
+    )
+}
+
+pub fn http_gateway(addr: impl Display) -> String {
+    pick(
+        || format!("HTTP gateway on {addr}."),
+        || format!("HTTP 网关位于 {addr}。"),
+    )
+}
+
+pub fn resuming_sessions(count: usize) -> String {
+    pick(
+        || format!("Resuming {count} journaled sessions."),
+        || format!("正在恢复日志中的 {count} 个会话。"),
+    )
+}
+
+pub fn new_content_key(path: impl Display) -> String {
+    pick(
+        || format!("Wrote a new content key to {path}."),
+        || format!("已将新的内容密钥写入 {path}。"),
+    )
+}
+
+pub fn planned_files(count: usize) -> String {
+    pick(
+        || format!("Planned {count} files."),
+        || format!("已为 {count} 个文件生成计划。"),
+    )
+}
+
+pub fn chunks_in_file(count: usize, file: impl Display) -> String {
+    pick(
+        || format!("{count} chunks in total for file {file}."),
+        || format!("文件 {file} 共有 {count} 个分块。"),
+    )
+}
+
+pub fn chunks_selected(count: impl Display) -> String {
+    pick(
+        || format!("{count} of them selected."),
+        || format!("其中选中 {count} 个。"),
+    )
+}
+
+pub fn chunks_unchanged(count: impl Display) -> String {
+    pick(
+        || format!("{count} chunks unchanged since last verified, not checked again."),
+        || format!("{count} 个分块自上次验证后未变，不再检查。"),
+    )
+}
+
+pub fn chunks_checked(done: usize, total: usize) -> String {
+    pick(
+        || format!(">>> Checked {done} / {total} chunks"),
+        || format!(">>> 已检查分块 {done} / {total}"),
+    )
+}
+
+pub fn chunks_passed(count: impl Display) -> String {
+    pick(
+        || format!("{count} of them passed their hash check."),
+        || format!("其中 {count} 个通过了哈希校验。"),
+    )
+}
+
+pub fn need_to_download(
+    chunks: impl Display,
+    selected: impl Display,
+    size: impl Display,
+    selected_size: impl Display,
+) -> String {
+    pick(
+        || {
+            format!(
+                "Need to download {chunks} / {selected} chunks which sized {size} / {selected_size}."
+            )
+        },
+        || format!("需要下载分块 {chunks} / {selected}，大小 {size} / {selected_size}。"),
+    )
+}
+
+pub fn bound(addr: impl Display) -> String {
+    pick(
+        || format!("Bound to {addr}."),
+        || format!("已绑定 {addr}。"),
+    )
+}
+
+pub fn bound_over_tcp(addr: impl Display) -> String {
+    pick(
+        || format!("Bound to {addr} over TCP."),
+        || format!("已通过 TCP 绑定 {addr}。"),
+    )
+}
+
+pub fn bound_on(addr: impl Display, interface: impl Display) -> String {
+    pick(
+        || format!("Bound to {addr} on {interface}."),
+        || format!("已在 {interface} 上绑定 {addr}。"),
+    )
+}
+
+pub fn receiving_on_sockets(count: usize) -> String {
+    pick(
+        || format!("Receiving on {count} sockets."),
+        || format!("正在通过 {count} 个套接字接收。"),
+    )
+}
+
+pub fn chunks_available(available: usize, total: usize) -> String {
+    pick(
+        || format!("{available} / {total} chunks available."),
+        || format!("可用分块 {available} / {total}。"),
+    )
+}
+
+pub fn served_to_key(key: impl Display, tickets: usize, bytes: u64) -> String {
+    pick(
+        || format!("{key}: {tickets} tickets, {bytes} bytes served."),
+        || format!("{key}：{tickets} 张票据，已发送 {bytes} 字节。"),
+    )
+}
+
+// Short labels `usync` puts before what they are about.
+pub fn trying() -> &'static str {
+    label("trying", "尝试")
+}
+
+pub fn conflict() -> &'static str {
+    label("conflict", "冲突")
+}
+
+pub fn push() -> &'static str {
+    label("push", "推送")
+}
+
+pub fn pull() -> &'static str {
+    label("pull", "拉取")
+}
+
+pub fn verified() -> &'static str {
+    label("Verified", "已验证")
+}
+
+pub fn records(count: usize) -> String {
+    pick(
+        || format!("{count} records."),
+        || format!("{count} 条记录。"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn language_follows_the_environment() {
+        let env = |vars: &[(&str, &str)]| {
+            let vars: HashMap<String, String> = vars
+                .iter()
+                .map(|(var, value)| (var.to_string(), value.to_string()))
+                .collect();
+            from_env(move |var| vars.get(var).cloned())
+        };
+        assert_eq!(env(&[]), Lang::En);
+        assert_eq!(env(&[("LANG", "zh_CN.UTF-8")]), Lang::Zh);
+        assert_eq!(env(&[("LANG", "zh_CN.UTF-8"), ("LC_ALL", "C")]), Lang::En);
+        assert_eq!(env(&[("LC_ALL", ""), ("LANG", "zh_TW")]), Lang::Zh);
+        assert_eq!(env(&[("LANG", "en_US"), ("USYNC_LANG", "zh")]), Lang::Zh);
+
+        // Every kind of failure reads differently in either language.
+        for lang in [Lang::En, Lang::Zh] {
+            let mut texts: Vec<&str> = Failure::ALL
+                .iter()
+                .map(|failure| failure_in(lang, *failure))
+                .collect();
+            texts.sort();
+            texts.dedup();
+            assert_eq!(texts.len(), Failure::ALL.len());
+        }
+    }
+}
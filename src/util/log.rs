@@ -8,37 +8,56 @@ use std::{
 
 use zerocopy::IntoBytes;
 
-use std::{
-    path::PathBuf,
-    time::{SystemTime, UNIX_EPOCH},
-};
+use super::units::SessionId;
+
+use std::path::PathBuf;
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+// A system clock set before 1970 reads as the epoch instead of panicking.
 pub fn current_timestamp_ms() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards")
+        .unwrap_or_default()
         .as_millis() as u64
 }
 
+// The browser has no system clock for `SystemTime` to read.
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+pub fn current_timestamp_ms() -> u64 {
+    js_sys::Date::now() as u64
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
 fn current_timestamp_ns() -> u64 {
     (SystemTime::now()
         .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards")
+        .unwrap_or_default()
         .as_nanos()
         & 0xFFFF_FFFF_FFFF_FFFF) as u64
 }
 
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+fn current_timestamp_ns() -> u64 {
+    current_timestamp_ms() * 1_000_000
+}
+
 struct PacketLog {
     time_ns: u64,
+    // Of the peer the packet went to or came from, 0 for none.
+    session_id: SessionId,
     pkt_number: u32,
     magic: u32,
 }
 
 static LOGGER: OnceLock<Sender<PacketLog>> = OnceLock::new();
 
-pub fn packet_log(pkt_number: u32, magic: u32) {
+pub fn packet_log(session_id: SessionId, pkt_number: u32, magic: u32) {
     if let Some(logger) = LOGGER.get() {
         let log = PacketLog {
             time_ns: current_timestamp_ns(),
+            session_id,
             pkt_number,
             magic,
         };
@@ -57,8 +76,9 @@ fn log_writer(rx: Receiver<PacketLog>, log_file: PathBuf) -> io::Result<()> {
     }
 
     while let Ok(log) = rx.recv() {
-        let mut writer = BytesMut::with_capacity(16).writer();
+        let mut writer = BytesMut::with_capacity(20).writer();
         writer.write_all(log.time_ns.as_bytes())?;
+        writer.write_all(log.session_id.as_bytes())?;
         writer.write_all(log.pkt_number.as_bytes())?;
         writer.write_all(log.magic.as_bytes())?;
         file.write_all(writer.get_ref().as_bytes())?;
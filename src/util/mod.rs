@@ -1,31 +1,60 @@
-pub mod file;
-pub mod plan;
-pub mod timer;
-pub mod timer_logger;
-
-pub mod log;
+internal_mods! {
+    audit;
+    cdc;
+    #[cfg(feature = "runtime")]
+    clock;
+    #[cfg(feature = "runtime")]
+    cpu;
+    #[cfg(feature = "cli")]
+    exit;
+    #[cfg(feature = "runtime")]
+    file;
+    filter;
+    #[cfg(feature = "cli")]
+    i18n;
+    plan;
+    #[cfg(feature = "runtime")]
+    seal;
+    #[cfg(feature = "runtime")]
+    store;
+    #[cfg(feature = "engine")]
+    summary;
+    sync;
+    #[cfg(feature = "engine")]
+    telemetry;
+    units;
+    uri;
+    #[cfg(feature = "runtime")]
+    verified;
+    log;
+}
+pub(crate) mod pacing;
+#[cfg(feature = "runtime")]
+pub(crate) mod timer;
 
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+#[cfg(feature = "runtime")]
+use clock::{Clock, SystemClock};
+#[cfg(feature = "runtime")]
+use std::time::Duration;
+#[cfg(feature = "runtime")]
 use tokio::time::Instant;
 
+#[cfg(feature = "runtime")]
 pub fn unix_ms_to_tokio_instant(unix_ms: u64) -> Instant {
-    // Current wall-clock time
-    let now_unix_ms = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_millis() as u64;
-
-    let now_instant = Instant::now();
-
-    if unix_ms >= now_unix_ms {
-        // Future timestamp: add the difference
-        let diff = unix_ms - now_unix_ms;
-        now_instant + Duration::from_millis(diff)
-    } else {
-        // Past timestamp: subtract the difference
-        let diff = now_unix_ms - unix_ms;
-        now_instant - Duration::from_millis(diff)
+    unix_ms_to_instant(&SystemClock, unix_ms)
+}
+
+// The Instant a wall clock time is at by `clock`. Wall time may be stepped or
+// jump across a suspend while Instants do not, so convert right before use and
+// never keep the result across an await. Times an Instant can not hold are now.
+#[cfg(feature = "runtime")]
+pub fn unix_ms_to_instant(clock: &dyn Clock, unix_ms: u64) -> Instant {
+    let (now_ms, now) = (clock.unix_ms(), clock.now());
+    match unix_ms.checked_sub(now_ms) {
+        Some(ahead_ms) => now.checked_add(Duration::from_millis(ahead_ms)),
+        None => now.checked_sub(Duration::from_millis(now_ms - unix_ms)),
     }
+    .unwrap_or(now)
 }
 
 pub trait Compare: Ord + Clone {
@@ -51,3 +80,32 @@ pub fn generate_random(size: usize) -> Vec<u8> {
     }
     data
 }
+
+#[cfg(all(test, feature = "runtime"))]
+mod tests {
+    use super::*;
+    use clock::AdjustableClock;
+
+    #[tokio::test(start_paused = true)]
+    async fn wall_clock_steps_move_instants_without_panicking() {
+        let clock = AdjustableClock::new(1_700_000_000_000);
+        let now = clock.now();
+        assert_eq!(
+            unix_ms_to_instant(&clock, 1_700_000_001_000),
+            now + Duration::from_secs(1)
+        );
+        // Set back an hour, the same wall time is an hour further away.
+        clock.step_wall(-3_600_000);
+        assert_eq!(
+            unix_ms_to_instant(&clock, 1_700_000_001_000),
+            now + Duration::from_secs(3601)
+        );
+        assert_eq!(
+            unix_ms_to_instant(&clock, 1_699_996_399_000),
+            now - Duration::from_secs(1)
+        );
+        // Out of range either way.
+        unix_ms_to_instant(&clock, 0);
+        unix_ms_to_instant(&clock, u64::MAX);
+    }
+}
@@ -0,0 +1,135 @@
+use std::time::Duration;
+
+// Rates are in kbps, 1000 bits per second, and count every frame as
+// `frame_len` bytes plus this much for the IP header.
+pub const HEADER_OVERHEAD: usize = 20;
+
+fn frame_bits(frame_len: usize) -> u128 {
+    8 * (frame_len + HEADER_OVERHEAD) as u128
+}
+
+// Time between frames of `frame_len` bytes for a stream to go at `kbps`,
+// rounded to the nearest nanosecond. Rates up to u64 kbps are taken, as 40 Gbps
+// links are past u32 kbps. 0 kbps goes as 1 kbps, and no interval is below 1ns.
+pub fn interval_for_rate(kbps: u64, frame_len: usize) -> Duration {
+    let kbps = kbps.max(1) as u128;
+    // bits / (kbps * 1000) seconds, in nanoseconds.
+    let nanos = (frame_bits(frame_len) * 1_000_000 + kbps / 2) / kbps;
+    Duration::from_nanos(nanos.clamp(1, u64::MAX as u128) as u64)
+}
+
+// The rate of a stream sending a frame of `frame_len` bytes every `interval`,
+// rounded to the nearest kbps. Undoes `interval_for_rate` up to its rounding.
+pub fn rate_for_interval(interval: Duration, frame_len: usize) -> u64 {
+    let nanos = interval.as_nanos().max(1);
+    let kbps = (frame_bits(frame_len) * 1_000_000 + nanos / 2) / nanos;
+    kbps.min(u64::MAX as u128) as u64
+}
+
+// Splits `total_kbps` between streams asking for `(kbps, weight)` each by
+// weighted max-min fairness, the rates weighted fair queuing settles at: a
+// stream asking less than its weighted share gets what it asks, and what it
+// leaves is split among the others by weight. Streams of weight 0 get nothing.
+pub fn weighted_shares(total_kbps: u64, demands: &[(u64, u32)]) -> Vec<u64> {
+    let mut shares = vec![0; demands.len()];
+    let mut open: Vec<usize> = (0..demands.len())
+        .filter(|&index| demands[index].1 > 0)
+        .collect();
+    let mut left = total_kbps as u128;
+    while !open.is_empty() {
+        let weights: u128 = open.iter().map(|&index| demands[index].1 as u128).sum();
+        let (satisfied, rest): (Vec<usize>, Vec<usize>) = open.iter().partition(|&&index| {
+            let (kbps, weight) = demands[index];
+            kbps as u128 * weights <= left * weight as u128
+        });
+        if satisfied.is_empty() {
+            for index in rest {
+                shares[index] = (left * demands[index].1 as u128 / weights) as u64;
+            }
+            break;
+        }
+        for index in satisfied {
+            shares[index] = demands[index].0;
+            left -= demands[index].0 as u128;
+        }
+        open = rest;
+    }
+    shares
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    const FRAME_LEN: usize = 1490;
+
+    #[test]
+    fn known_rates() {
+        // 1510 bytes at 12080 kbps take a millisecond.
+        assert_eq!(
+            interval_for_rate(12_080, FRAME_LEN),
+            Duration::from_millis(1)
+        );
+        assert_eq!(
+            rate_for_interval(Duration::from_millis(1), FRAME_LEN),
+            12_080
+        );
+        // 40 Gbps, past u32 kbps at 4.29 Tbps it still works.
+        assert_eq!(
+            interval_for_rate(40_000_000, FRAME_LEN),
+            Duration::from_nanos(302)
+        );
+        assert_eq!(
+            interval_for_rate(u64::MAX, FRAME_LEN),
+            Duration::from_nanos(1)
+        );
+        assert_eq!(
+            interval_for_rate(0, FRAME_LEN),
+            interval_for_rate(1, FRAME_LEN)
+        );
+    }
+
+    #[test]
+    fn shares_go_by_weight() {
+        assert_eq!(
+            weighted_shares(170, &[(1000, 16), (1000, 1)]),
+            vec![160, 10]
+        );
+        // What a stream does not ask for goes to the others.
+        assert_eq!(
+            weighted_shares(100, &[(10, 1), (1000, 1), (1000, 2)]),
+            vec![10, 30, 60]
+        );
+        assert_eq!(weighted_shares(100, &[(20, 1), (30, 4)]), vec![20, 30]);
+        assert_eq!(weighted_shares(100, &[(u64::MAX, 0)]), vec![0]);
+    }
+
+    #[test]
+    fn faster_rates_never_wait_longer() {
+        let mut rng = StdRng::seed_from_u64(0x9ace);
+        for _ in 0..10_000 {
+            let frame_len = rng.random_range(1..9000);
+            let slow = rng.random_range(1..100_000_000u64);
+            let fast = rng.random_range(slow..=100_000_000u64);
+            assert!(interval_for_rate(fast, frame_len) <= interval_for_rate(slow, frame_len));
+        }
+    }
+
+    #[test]
+    fn rate_survives_a_round_trip() {
+        let mut rng = StdRng::seed_from_u64(0x7a7e);
+        for _ in 0..10_000 {
+            let frame_len = rng.random_range(100..9000);
+            let kbps = rng.random_range(1..100_000_000u64);
+            let back = rate_for_interval(interval_for_rate(kbps, frame_len), frame_len);
+            // The interval is rounded to a nanosecond, relatively more at high rates.
+            let interval_ns = interval_for_rate(kbps, frame_len).as_nanos() as f64;
+            let tolerance = (kbps as f64 / interval_ns).ceil() as u64 + 1;
+            assert!(
+                back.abs_diff(kbps) <= tolerance,
+                "{kbps} kbps came back as {back}"
+            );
+        }
+    }
+}
@@ -1,52 +1,500 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+#[cfg(feature = "runtime")]
+use std::io;
+use std::net::SocketAddr;
+use std::ops::{Range, RangeInclusive};
+#[cfg(feature = "runtime")]
+use std::path::Path;
+use std::path::{Component, PathBuf};
 
-use crate::constants::{CHUNK_SIZE, DEFAULT_PAGE_SIZE};
+#[cfg(feature = "runtime")]
+use super::cdc::Cdc;
+#[cfg(feature = "runtime")]
+use super::file::read_metadata;
+#[cfg(feature = "runtime")]
+use super::store::ChunkStore;
+use super::units::{ByteOffset, ChunkId};
+use crate::constants::{CHUNK_SIZE, DEFAULT_PAGE_SIZE, MTU};
+use crate::transmission::Transport;
+
+// The only erasure code there is, named in download hints.
+pub const CODEC: &str = "raptorq";
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileChunk {
-    pub chunk_id: usize,
+    pub chunk_id: ChunkId,
     pub hash: String,
     pub offset: u64,
     pub length: usize,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl FileChunk {
+    // Exclusive, saturating: a chunk running past u64::MAX is no valid chunk,
+    // which `FileConfig::check_chunks` tells.
+    pub fn end(&self) -> u64 {
+        ByteOffset(self.offset).saturating_end(self.length).0
+    }
+}
+
+// Of the source file, restored by the client once every chunk is written.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileMetadata {
+    // Permission bits, including setuid, setgid and sticky.
+    pub mode: u32,
+    pub mtime_secs: i64,
+    pub mtime_nanos: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileConfig {
     pub file_name: String,
     pub total_length: u64,
     pub total_hash: String,
+    // Counts how often a watched file was planned again.
+    #[serde(default)]
+    pub epoch: u64,
     pub chunks: Vec<FileChunk>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<FileMetadata>,
+    // Set for a symbolic link, which has no chunks and is recreated pointing here.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_target: Option<PathBuf>,
+    // Set when the served file holds the chunks sealed with a content key, see `seal`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<SealedFile>,
+    // How to fetch the file, so the client needs no more than the plan.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hints: Option<DownloadHints>,
+    // The average chunk size of a plan cut by content, see `cdc`, so a changed
+    // file is cut the same way again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cdc: Option<usize>,
 }
 
-//output an iterator over (start_offset, length)
-pub fn make_plan(file_length: u64) -> impl Iterator<Item = (u64, usize)> {
-    let full_chunks = file_length / CHUNK_SIZE as u64;
-    let full_chunks_used = full_chunks.checked_sub(1).unwrap_or_default();
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SealedFile {
+    pub cipher: String,
+    // Of the file the client ends up with, the other lengths, offsets and
+    // hashes of the plan are those of the sealed file.
+    pub plain_length: u64,
+}
 
-    let tail_1_offset = full_chunks_used * CHUNK_SIZE as u64;
+// Suggestions of whoever published the plan. The client's own flags take
+// precedence, except for the codec and the MTU, which it cannot change.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct DownloadHints {
+    // Tried in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub servers: Vec<SocketAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_kbps: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transport: Option<Transport>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codec: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<usize>,
+}
 
-    let remain_bytes = file_length - tail_1_offset;
-    let remain_pages = remain_bytes / DEFAULT_PAGE_SIZE as u64;
+impl DownloadHints {
+    // Records the codec and MTU of this build along with the given hints.
+    pub fn new(
+        servers: Vec<SocketAddr>,
+        rate_kbps: Option<u32>,
+        transport: Option<Transport>,
+    ) -> Self {
+        Self {
+            servers,
+            rate_kbps,
+            transport,
+            codec: Some(CODEC.to_string()),
+            mtu: Some(MTU),
+        }
+    }
+
+    // Whether this build can talk to servers sending as the plan says.
+    pub fn check(&self) -> Result<(), String> {
+        if let Some(codec) = self.codec.as_ref()
+            && codec != CODEC
+        {
+            return Err(format!(
+                "The plan asks for codec {codec}, only {CODEC} is built in"
+            ));
+        }
+        if let Some(mtu) = self.mtu
+            && mtu != MTU
+        {
+            return Err(format!(
+                "The plan asks for an MTU of {mtu}, this build uses {MTU}"
+            ));
+        }
+        Ok(())
+    }
+}
 
-    let tail_1_len = if remain_bytes > CHUNK_SIZE as u64 {
-        remain_pages.div_ceil(2) * DEFAULT_PAGE_SIZE as u64
+impl FileConfig {
+    // Of the file the client ends up with.
+    pub fn plain_length(&self) -> u64 {
+        self.sealed
+            .as_ref()
+            .map_or(self.total_length, |sealed| sealed.plain_length)
+    }
+
+    // Plans are read from files and servers, so their numbers are checked
+    // before any arithmetic trusts them.
+    pub fn check_chunks(&self) -> Result<(), String> {
+        for chunk in self.chunks.iter() {
+            match ByteOffset(chunk.offset).checked_end(chunk.length) {
+                Some(end) if end.0 <= self.total_length => {}
+                _ => {
+                    return Err(format!(
+                        "Chunk {} runs past the end of the file",
+                        chunk.chunk_id
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Clients and servers join `file_name` to a folder of theirs, it must stay in it.
+    pub fn check_file_name(&self) -> Result<(), String> {
+        check_file_name(&self.file_name)
+    }
+
+    // (file index, offset, length) of every chunk, as the chunk index takes them.
+    pub fn chunk_map(&self) -> HashMap<ChunkId, (usize, u64, usize)> {
+        self.chunks
+            .iter()
+            .map(|chunk| (chunk.chunk_id, (0usize, chunk.offset, chunk.length)))
+            .collect()
+    }
+
+    // The planned hash of every chunk, for the chunk index to verify them.
+    pub fn chunk_hashes(&self) -> HashMap<ChunkId, String> {
+        self.chunks
+            .iter()
+            .map(|chunk| (chunk.chunk_id, chunk.hash.clone()))
+            .collect()
+    }
+
+    // Chunks with an id in `ids` or holding any byte of `ranges`, in plan order.
+    pub fn select(
+        &self,
+        ids: &[RangeInclusive<ChunkId>],
+        ranges: &[Range<u64>],
+    ) -> Result<Vec<&FileChunk>, String> {
+        let last_id = self.chunks.iter().map(|chunk| chunk.chunk_id).max();
+        if let Some(unknown) = ids
+            .iter()
+            .find(|ids| last_id.is_none_or(|last_id| *ids.end() > last_id))
+        {
+            return Err(format!("No chunk {} in the plan", unknown.end()));
+        }
+        if let Some(outside) = ranges.iter().find(|range| range.start >= self.total_length) {
+            return Err(format!(
+                "Range starting at {} is past the end of the file",
+                outside.start
+            ));
+        }
+        Ok(self
+            .chunks
+            .iter()
+            .filter(|chunk| {
+                let end = chunk.end();
+                ids.iter().any(|ids| ids.contains(&chunk.chunk_id))
+                    || ranges
+                        .iter()
+                        .any(|range| range.start < end && chunk.offset < range.end)
+            })
+            .collect())
+    }
+}
+
+// Chunks `known` has the hash of are only read for the total hash.
+#[cfg(feature = "runtime")]
+fn hash_chunks(
+    path: &Path,
+    boundaries: Vec<(u64, usize)>,
+    known: impl Fn(u64, usize) -> Option<String>,
+) -> io::Result<(String, Vec<FileChunk>)> {
+    let mut total_hasher = blake3::Hasher::new();
+    let mut chunks = vec![];
+    for (index, (offset, length)) in boundaries.into_iter().enumerate() {
+        let chunk_id =
+            ChunkId::from_index(index).ok_or_else(|| io::Error::other("Too many chunks"))?;
+        let data = ChunkStore::Mmap.load(path, offset, length, false)?;
+        total_hasher.update(data.as_ref());
+        let hash = known(offset, length)
+            .unwrap_or_else(|| hex::encode(blake3::hash(data.as_ref()).as_bytes()));
+        chunks.push(FileChunk {
+            chunk_id,
+            hash,
+            offset,
+            length,
+        });
+    }
+    Ok((hex::encode(total_hasher.finalize().as_bytes()), chunks))
+}
+
+// Plans `path` as cut by `builder`, named by its file name. The planner binary
+// covers links, sealing and content-defined chunks besides.
+#[cfg(feature = "runtime")]
+pub fn plan_file(path: &Path, builder: &PlanBuilder) -> io::Result<FileConfig> {
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| io::Error::other("File name is not valid UTF-8."))?
+        .to_string();
+    let total_length = std::fs::metadata(path)?.len();
+    let (total_hash, chunks) = hash_chunks(path, builder.plan(total_length), |_, _| None)?;
+    Ok(FileConfig {
+        file_name,
+        total_length,
+        total_hash,
+        epoch: 0,
+        chunks,
+        metadata: Some(read_metadata(path)?),
+        link_target: None,
+        sealed: None,
+        hints: None,
+        cdc: None,
+    })
+}
+
+// Plans `path` again after it changed, as the next epoch, cut the way `old` was.
+// Plans cut by content are cut by content again, so chunks around a change keep
+// their hashes. Fixed chunks keep their boundaries while the length stays the
+// same, otherwise the file is cut anew in chunks the size of the old first one.
+// `changed` are the byte ranges written since `old`, where known: chunks where
+// `old` had one, clear of them, keep its hash and are only read for the total
+// hash. Without them every chunk is hashed again.
+#[cfg(feature = "runtime")]
+pub fn replan(
+    path: &Path,
+    old: &FileConfig,
+    changed: Option<&[Range<u64>]>,
+) -> io::Result<FileConfig> {
+    let total_length = std::fs::metadata(path)?.len();
+    let boundaries: Vec<(u64, usize)> = if let Some(avg_size) = old.cdc {
+        let mut boundaries = vec![];
+        Cdc::new(avg_size).split(std::fs::File::open(path)?, |offset, data| {
+            boundaries.push((offset, data.len()))
+        })?;
+        boundaries
+    } else if total_length == old.total_length {
+        old.chunks
+            .iter()
+            .map(|chunk| (chunk.offset, chunk.length))
+            .collect()
+    } else {
+        let chunk_size = match old.chunks.as_slice() {
+            [first, _, ..] => first.length,
+            _ => CHUNK_SIZE,
+        };
+        PlanBuilder::default()
+            .with_chunk_size(chunk_size)
+            .with_min_tail(chunk_size)
+            .plan(total_length)
+    };
+
+    let unchanged: HashMap<(u64, usize), &String> = match changed {
+        Some(changed) => old
+            .chunks
+            .iter()
+            .filter(|chunk| {
+                !changed
+                    .iter()
+                    .any(|range| range.start < chunk.end() && chunk.offset < range.end)
+            })
+            .map(|chunk| ((chunk.offset, chunk.length), &chunk.hash))
+            .collect(),
+        None => HashMap::new(),
+    };
+    let (total_hash, chunks) = hash_chunks(path, boundaries, |offset, length| {
+        unchanged
+            .get(&(offset, length))
+            .map(|hash| hash.to_string())
+    })?;
+    Ok(FileConfig {
+        file_name: old.file_name.clone(),
+        total_length,
+        total_hash,
+        epoch: old.epoch + 1,
+        chunks,
+        metadata: Some(read_metadata(path)?),
+        link_target: None,
+        sealed: None,
+        hints: old.hints.clone(),
+        cdc: old.cdc,
+    })
+}
+
+// A relative path going down only, as plans name their files.
+pub fn check_file_name(file_name: &str) -> Result<(), String> {
+    let path = std::path::Path::new(file_name);
+    let normal = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    match normal && path.components().next().is_some() {
+        true => Ok(()),
+        false => Err(format!("{file_name:?} is not a relative file name")),
+    }
+}
+
+// Parses `ID` or `START-END`, as taken by --chunks.
+pub fn parse_chunk_ids(ids: &str) -> Result<RangeInclusive<ChunkId>, String> {
+    let (start, end) = ids.split_once('-').unwrap_or((ids, ids));
+    let start: u32 = start.trim().parse().map_err(|err| format!("{err}"))?;
+    let end: u32 = end.trim().parse().map_err(|err| format!("{err}"))?;
+    if start > end {
+        return Err(format!("Empty chunk range {ids}"));
+    }
+    Ok(ChunkId(start)..=ChunkId(end))
+}
+
+// Parses a byte count with an optional K, M, G or T (binary) suffix.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (digits, shift) = match size.char_indices().last() {
+        Some((at, 'K' | 'k')) => (&size[..at], 10),
+        Some((at, 'M' | 'm')) => (&size[..at], 20),
+        Some((at, 'G' | 'g')) => (&size[..at], 30),
+        Some((at, 'T' | 't')) => (&size[..at], 40),
+        _ => (size, 0),
+    };
+    let value: u64 = digits.parse().map_err(|err| format!("{err}"))?;
+    value
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("{size} is too large"))
+}
+
+// Parses `START-END` in bytes, END exclusive and left out for the end of the
+// file, as taken by --range. Both take K, M, G and T (binary) suffixes.
+pub fn parse_byte_range(range: &str) -> Result<Range<u64>, String> {
+    let (start, end) = range
+        .split_once('-')
+        .ok_or_else(|| format!("Expected START-END, got {range}"))?;
+    let start = parse_size(start)?;
+    let end = if end.trim().is_empty() {
+        u64::MAX
     } else {
-        0
+        parse_size(end)?
     };
+    if start >= end {
+        return Err(format!("Empty byte range {range}"));
+    }
+    Ok(start..end)
+}
+
+// Slices a file into chunks of `chunk_size` bytes, starting on multiples of
+// `alignment`. A last chunk shorter than `min_tail` is evened out with the one
+// before it, so no chunk is much smaller than the rest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlanBuilder {
+    chunk_size: usize,
+    alignment: usize,
+    // Chunks grow past `chunk_size` rather than there being more of them.
+    max_chunks: Option<usize>,
+    min_tail: usize,
+}
+
+impl Default for PlanBuilder {
+    fn default() -> Self {
+        Self {
+            chunk_size: CHUNK_SIZE,
+            alignment: DEFAULT_PAGE_SIZE,
+            max_chunks: None,
+            min_tail: CHUNK_SIZE,
+        }
+    }
+}
+
+impl PlanBuilder {
+    // Rounded up to a multiple of the alignment.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
 
-    let tail_2_offset = tail_1_len + tail_1_offset;
-    let tail_2_len = file_length - tail_2_offset;
+    pub fn with_alignment(mut self, alignment: usize) -> Self {
+        self.alignment = alignment.max(1);
+        self
+    }
+
+    pub fn with_max_chunks(mut self, max_chunks: usize) -> Self {
+        self.max_chunks = Some(max_chunks.max(1));
+        self
+    }
+
+    pub fn with_min_tail(mut self, min_tail: usize) -> Self {
+        self.min_tail = min_tail;
+        self
+    }
+
+    // Chunks are held in memory, so they stay within half of usize, more
+    // chunks than `max_chunks` if need be. Two of them then still add up
+    // within u64 when the tail is evened out.
+    fn chunk_size_for(&self, file_length: u64) -> u64 {
+        let alignment = self.alignment as u64;
+        let largest = ((usize::MAX / 2) as u64 / alignment * alignment).max(alignment);
+        let chunk_size = self
+            .max_chunks
+            .map_or(self.chunk_size as u64, |max_chunks| {
+                (self.chunk_size as u64).max(file_length.div_ceil(max_chunks as u64))
+            });
+        chunk_size
+            .div_ceil(alignment)
+            .saturating_mul(alignment)
+            .min(largest)
+    }
+
+    // (start_offset, length) of every chunk in order. An empty file still gets one, empty, chunk.
+    pub fn plan(&self, file_length: u64) -> Vec<(u64, usize)> {
+        let chunk_size = self.chunk_size_for(file_length);
+        let full_chunks = file_length / chunk_size;
+        let tail = file_length - full_chunks * chunk_size;
 
-    (0..full_chunks_used)
-        .map(|x| (x * CHUNK_SIZE as u64, CHUNK_SIZE))
-        .chain(std::iter::once((tail_1_offset, tail_1_len as usize)).filter(|(_, len)| *len > 0))
-        .chain(std::iter::once((tail_2_offset, tail_2_len as usize)))
+        let mut chunks: Vec<(u64, usize)> = (0..full_chunks)
+            .map(|x| (x * chunk_size, chunk_size as usize))
+            .collect();
+        if tail == 0 && !chunks.is_empty() {
+            return chunks;
+        }
+        if tail >= self.min_tail as u64 || chunks.is_empty() {
+            chunks.push((full_chunks * chunk_size, tail as usize));
+            return chunks;
+        }
+
+        // Split the last full chunk and the tail in two, the first half aligned.
+        let (offset, _) = chunks.pop().unwrap();
+        let remain_bytes = chunk_size + tail;
+        let alignment = self.alignment as u64;
+        let head_len = (remain_bytes / alignment).div_ceil(2) * alignment;
+        chunks.push((offset, head_len as usize));
+        chunks.push((offset + head_len, (remain_bytes - head_len) as usize));
+        chunks
+    }
+}
+
+//output an iterator over (start_offset, length)
+pub fn make_plan(file_length: u64) -> impl Iterator<Item = (u64, usize)> {
+    PlanBuilder::default().plan(file_length).into_iter()
 }
 
 // .map(|(offset, len)| (offset as usize, len))
 #[cfg(test)]
 mod test {
+    use crate::util::file::{create_sparse_file, write_at};
     use crate::util::plan::make_plan as make_plan_u64;
+    use crate::util::plan::{
+        DownloadHints, FileChunk, FileConfig, PlanBuilder, check_file_name, parse_byte_range,
+        parse_chunk_ids, plan_file, replan,
+    };
+    use crate::util::units::ChunkId;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+    use std::collections::HashSet;
     const M: usize = 1024 * 1024;
     const K: usize = 1024;
 
@@ -124,4 +572,310 @@ mod test {
                 .collect::<Vec<_>>()
         );
     }
+
+    #[test]
+    fn select_chunks_by_id_and_range() {
+        let config = FileConfig {
+            file_name: "disk.img".into(),
+            total_length: 128 * M as u64,
+            total_hash: String::new(),
+            epoch: 0,
+            metadata: None,
+            link_target: None,
+            sealed: None,
+            hints: None,
+            cdc: None,
+            chunks: make_plan_u64(128 * M as u64)
+                .zip(0..)
+                .map(|((offset, length), chunk_id)| FileChunk {
+                    chunk_id: ChunkId(chunk_id),
+                    hash: String::new(),
+                    offset,
+                    length,
+                })
+                .collect(),
+        };
+        let ids = |chunks: Vec<&FileChunk>| -> Vec<u32> {
+            chunks.iter().map(|chunk| chunk.chunk_id.0).collect()
+        };
+        let range = |start, end| ChunkId(start)..=ChunkId(end);
+
+        assert_eq!(parse_chunk_ids("3").unwrap(), range(3, 3));
+        assert_eq!(parse_chunk_ids("0-1").unwrap(), range(0, 1));
+        assert!(parse_chunk_ids("2-1").is_err());
+        assert_eq!(parse_byte_range("1M-2M").unwrap(), M as u64..2 * M as u64);
+        assert_eq!(parse_byte_range("96M-").unwrap().end, u64::MAX);
+        assert!(parse_byte_range("2M-1M").is_err());
+
+        // Ranges take every chunk they touch, ends are exclusive.
+        let ranges = [parse_byte_range("31M-32M").unwrap()];
+        assert_eq!(ids(config.select(&[], &ranges).unwrap()), vec![0]);
+        let ranges = [parse_byte_range("31M-33M").unwrap()];
+        assert_eq!(ids(config.select(&[], &ranges).unwrap()), vec![0, 1]);
+        let ranges = [parse_byte_range("100M-").unwrap()];
+        assert_eq!(
+            ids(config.select(&[range(3, 3)], &ranges).unwrap()),
+            vec![3]
+        );
+        assert_eq!(
+            ids(config.select(&[range(2, 2), range(0, 0)], &[]).unwrap()),
+            vec![0, 2],
+            "in plan order"
+        );
+
+        assert!(config.select(&[range(2, 4)], &[]).is_err());
+        assert!(
+            config
+                .select(&[], &[parse_byte_range("128M-").unwrap()])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn plans_cover_files_on_aligned_offsets() {
+        let mut rng = StdRng::seed_from_u64(3901);
+        for _ in 0..2000 {
+            let alignment = 1 << rng.random_range(0..14);
+            let chunk_size = rng.random_range(1..=256 * K);
+            let min_tail = rng.random_range(0..=2 * chunk_size);
+            let mut builder = PlanBuilder::default()
+                .with_alignment(alignment)
+                .with_chunk_size(chunk_size)
+                .with_min_tail(min_tail);
+            let max_chunks = rng.random_bool(0.5).then(|| rng.random_range(1..64));
+            if let Some(max_chunks) = max_chunks {
+                builder = builder.with_max_chunks(max_chunks);
+            }
+            let file_length = match rng.random_range(0..4) {
+                0 => rng.random_range(0..4) as u64,
+                1 => (rng.random_range(0..32) * chunk_size) as u64,
+                _ => rng.random_range(0..8 * M) as u64,
+            };
+
+            let plan = builder.plan(file_length);
+            let context = format!("{builder:?} for {file_length}: {plan:?}");
+            assert!(!plan.is_empty(), "{context}");
+            assert!(
+                plan.iter()
+                    .all(|(offset, _)| offset % alignment as u64 == 0),
+                "{context}"
+            );
+            assert!(
+                plan.iter()
+                    .all(|(_, length)| *length > 0 || file_length == 0),
+                "{context}"
+            );
+            let mut end = 0;
+            for (offset, length) in plan.iter() {
+                assert_eq!(*offset, end, "{context}");
+                end += *length as u64;
+            }
+            assert_eq!(end, file_length, "{context}");
+            if let Some(max_chunks) = max_chunks {
+                assert!(plan.len() <= max_chunks, "{context}");
+            }
+            if plan.len() > 2 {
+                let chunk_size = plan[0].1;
+                let (_, tail) = plan[plan.len() - 1];
+                assert!(chunk_size % alignment == 0, "{context}");
+                assert!(tail <= chunk_size, "{context}");
+                // Either long enough, or evened out with the chunk before.
+                let (_, before) = plan[plan.len() - 2];
+                assert!(tail >= min_tail || before + tail > chunk_size, "{context}");
+            }
+        }
+    }
+
+    #[test]
+    fn huge_files_plan_without_overflow() {
+        let plan = PlanBuilder::default().with_max_chunks(1).plan(u64::MAX);
+        assert!(plan.len() <= 3, "{plan:?}");
+        assert_eq!(plan[0].0, 0);
+        let end = plan.iter().try_fold(0u64, |end, (offset, length)| {
+            assert_eq!(*offset, end);
+            end.checked_add(*length as u64)
+        });
+        assert_eq!(end, Some(u64::MAX));
+    }
+
+    #[test]
+    fn chunks_past_the_file_are_rejected() {
+        let chunk = |chunk_id, offset, length| FileChunk {
+            chunk_id: ChunkId(chunk_id),
+            hash: String::new(),
+            offset,
+            length,
+        };
+        let mut config = FileConfig {
+            file_name: "file".to_string(),
+            total_length: 100,
+            total_hash: String::new(),
+            epoch: 0,
+            chunks: vec![chunk(0, 0, 60), chunk(1, 60, 40)],
+            metadata: None,
+            link_target: None,
+            sealed: None,
+            hints: None,
+            cdc: None,
+        };
+        assert!(config.check_chunks().is_ok());
+        config.chunks[1] = chunk(1, u64::MAX - 10, 40);
+        assert!(config.check_chunks().is_err());
+        assert_eq!(config.chunks[1].end(), u64::MAX);
+        config.chunks[1] = chunk(1, 60, 41);
+        assert!(config.check_chunks().is_err());
+
+        assert!(config.check_file_name().is_ok());
+        for name in ["dir/file", "a/./b"] {
+            assert!(check_file_name(name).is_ok(), "{name}");
+        }
+        for name in [
+            "",
+            ".",
+            "../file",
+            "dir/../../file",
+            "/etc/passwd",
+            "dir/..",
+        ] {
+            assert!(check_file_name(name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn replan_keeps_boundaries_of_unchanged_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("watched.bin");
+        create_sparse_file(&path, 3 * 64 * K as u64).unwrap();
+        let planned = FileConfig {
+            file_name: "watched.bin".into(),
+            total_length: 3 * 64 * K as u64,
+            total_hash: String::new(),
+            epoch: 0,
+            chunks: (0..3)
+                .map(|chunk_id| FileChunk {
+                    chunk_id: ChunkId(chunk_id),
+                    hash: String::new(),
+                    offset: chunk_id as u64 * 64 * K as u64,
+                    length: 64 * K,
+                })
+                .collect(),
+            metadata: None,
+            link_target: None,
+            sealed: None,
+            hints: None,
+            cdc: None,
+        };
+        let old = replan(&path, &planned, None).unwrap();
+        assert_eq!(old.epoch, 1);
+        let summary = |config: &FileConfig| -> Vec<(u64, usize, String)> {
+            config
+                .chunks
+                .iter()
+                .map(|chunk| (chunk.offset, chunk.length, chunk.hash.clone()))
+                .collect()
+        };
+
+        write_at(&path, 64 * K as u64 + 10, b"changed").unwrap();
+        let new = replan(&path, &old, None).unwrap();
+        assert_eq!(new.epoch, 2);
+        let changed: Vec<ChunkId> = old
+            .chunks
+            .iter()
+            .zip(new.chunks.iter())
+            .filter(|(old, new)| old.hash != new.hash)
+            .map(|(old, _)| old.chunk_id)
+            .collect();
+        assert_eq!(changed, vec![ChunkId(1)]);
+        assert_ne!(old.total_hash, new.total_hash);
+
+        // Grown files are cut anew, in chunks of the old size.
+        write_at(&path, 3 * 64 * K as u64, &[1; 100]).unwrap();
+        let grown = replan(&path, &new, None).unwrap();
+        assert_eq!(grown.total_length, 3 * 64 * K as u64 + 100);
+        assert_eq!(summary(&grown)[..2], summary(&new)[..2]);
+        assert_eq!(grown.chunks.len(), 4);
+    }
+
+    #[test]
+    fn replan_hashes_only_changed_ranges() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("watched.bin");
+        create_sparse_file(&path, 3 * 64 * K as u64).unwrap();
+        let old = plan_file(&path, &PlanBuilder::default().with_chunk_size(64 * K)).unwrap();
+
+        // Chunks clear of the given ranges are taken as they were, even where
+        // the file changed behind our back.
+        write_at(&path, 10, b"unannounced").unwrap();
+        write_at(&path, 64 * K as u64 + 10, b"changed").unwrap();
+        let changed = 64 * K as u64 + 10..64 * K as u64 + 17;
+        let new = replan(&path, &old, Some(std::slice::from_ref(&changed))).unwrap();
+        let hashes = |config: &FileConfig| -> Vec<String> {
+            config
+                .chunks
+                .iter()
+                .map(|chunk| chunk.hash.clone())
+                .collect()
+        };
+        assert_eq!(hashes(&new)[0], hashes(&old)[0]);
+        assert_ne!(hashes(&new)[1], hashes(&old)[1]);
+        assert_eq!(hashes(&new)[2], hashes(&old)[2]);
+        assert_ne!(new.total_hash, old.total_hash);
+        assert_ne!(
+            hashes(&replan(&path, &old, None).unwrap())[0],
+            hashes(&old)[0]
+        );
+    }
+
+    #[test]
+    fn replan_keeps_cutting_by_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("watched.bin");
+        let mut data = vec![0u8; 256 * K];
+        StdRng::seed_from_u64(7).fill(&mut data[..]);
+        std::fs::write(&path, &data).unwrap();
+        let planned = FileConfig {
+            cdc: Some(8 * K),
+            ..plan_file(&path, &PlanBuilder::default()).unwrap()
+        };
+        let old = replan(&path, &planned, None).unwrap();
+        assert_eq!(old.cdc, Some(8 * K));
+        assert!(old.chunks.len() > 8);
+
+        // Bytes inserted near the start shift the rest, whose chunks keep their hashes.
+        data.splice(100..100, [1u8; 77]);
+        std::fs::write(&path, &data).unwrap();
+        let new = replan(&path, &old, None).unwrap();
+        assert_eq!(new.cdc, Some(8 * K));
+        assert_eq!(new.total_length, old.total_length + 77);
+        let old_hashes: HashSet<&String> = old.chunks.iter().map(|chunk| &chunk.hash).collect();
+        let kept = new
+            .chunks
+            .iter()
+            .filter(|chunk| old_hashes.contains(&chunk.hash))
+            .count();
+        assert!(
+            kept + 2 >= new.chunks.len(),
+            "{kept} of {}",
+            new.chunks.len()
+        );
+    }
+
+    #[test]
+    fn hints_survive_the_plan_file() {
+        let hints = DownloadHints::new(
+            vec!["10.0.0.1:6000".parse().unwrap()],
+            Some(8000),
+            Some(crate::transmission::Transport::Tcp),
+        );
+        let text = toml::to_string(&hints).unwrap();
+        assert!(text.contains("transport = \"tcp\""), "{text}");
+        let parsed: DownloadHints = toml::from_str(&text).unwrap();
+        assert_eq!(parsed, hints);
+        assert!(parsed.check().is_ok());
+
+        let foreign: DownloadHints = toml::from_str("codec = \"reed-solomon\"").unwrap();
+        assert!(foreign.check().is_err());
+        let jumbo: DownloadHints = toml::from_str("mtu = 9000").unwrap();
+        assert!(jumbo.check().is_err());
+    }
 }
@@ -0,0 +1,109 @@
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::io::Result;
+use std::path::Path;
+
+use super::file::read_segment;
+use super::plan::FileChunk;
+
+pub const CIPHER: &str = "chacha20poly1305";
+pub const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+// A sealed chunk is the nonce, then the ciphertext and its tag.
+pub const SEAL_OVERHEAD: usize = NONCE_LENGTH + TAG_LENGTH;
+
+// Where the plaintext of a sealed chunk goes. Sealed files hold their chunks
+// back to back in plan order, so chunk n lies n overheads past its plaintext.
+pub fn plain_range(chunk: &FileChunk) -> (u64, usize) {
+    (
+        chunk
+            .offset
+            .saturating_sub(chunk.chunk_id.0 as u64 * SEAL_OVERHEAD as u64),
+        chunk.length.saturating_sub(SEAL_OVERHEAD),
+    )
+}
+
+// Encrypts the chunks of a file kept and served as ciphertext. The nonce of a
+// chunk is derived from its plaintext, so sealing the same bytes again gives
+// the same sealed chunk, and a client can check its plaintext against the
+// hashes of the plan.
+pub struct ContentKey {
+    cipher: ChaCha20Poly1305,
+    nonce_key: [u8; 32],
+}
+
+impl ContentKey {
+    pub fn new(key: &[u8; KEY_LENGTH]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+            nonce_key: blake3::derive_key("usync 2025 chunk nonce", key),
+        }
+    }
+
+    pub fn from_hex(key: &str) -> Option<Self> {
+        let mut bytes = [0u8; KEY_LENGTH];
+        hex::decode_to_slice(key.trim(), &mut bytes).ok()?;
+        Some(Self::new(&bytes))
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = blake3::keyed_hash(&self.nonce_key, plaintext);
+        let nonce = Nonce::from_slice(&nonce.as_bytes()[..NONCE_LENGTH]);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.cipher
+                .encrypt(nonce, plaintext)
+                .expect("Chunk too long to seal"),
+        );
+        sealed
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> std::result::Result<Vec<u8>, String> {
+        let (nonce, ciphertext) = sealed
+            .split_at_checked(NONCE_LENGTH)
+            .ok_or("too short to be sealed")?;
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "sealed with another key or altered".to_string())
+    }
+
+    // Hash of the plaintext of `chunk` in `path` once sealed, to compare with
+    // the planned one.
+    pub fn sealed_hash<P: AsRef<Path>>(&self, path: P, chunk: &FileChunk) -> Result<String> {
+        let (offset, length) = plain_range(chunk);
+        let plaintext = read_segment(path, offset, length)?;
+        Ok(hex::encode(blake3::hash(&self.seal(&plaintext)).as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::units::ChunkId;
+
+    #[test]
+    fn sealing_is_repeatable_and_tamper_evident() {
+        let key = ContentKey::new(&[3; KEY_LENGTH]);
+        let plaintext = b"the quick brown fox".to_vec();
+        let sealed = key.seal(&plaintext);
+        assert_eq!(sealed.len(), plaintext.len() + SEAL_OVERHEAD);
+        assert_eq!(sealed, key.seal(&plaintext));
+        assert_ne!(sealed, key.seal(b"the quick brown cat"));
+        assert_eq!(key.open(&sealed).unwrap(), plaintext);
+
+        let mut altered = sealed.clone();
+        altered[NONCE_LENGTH] ^= 1;
+        assert!(key.open(&altered).is_err());
+        assert!(ContentKey::new(&[4; KEY_LENGTH]).open(&sealed).is_err());
+        assert!(key.open(&sealed[..4]).is_err());
+
+        let chunk = FileChunk {
+            chunk_id: ChunkId(2),
+            hash: String::new(),
+            offset: 2 * (4096 + SEAL_OVERHEAD) as u64,
+            length: 4096 + SEAL_OVERHEAD,
+        };
+        assert_eq!(plain_range(&chunk), (2 * 4096, 4096));
+    }
+}
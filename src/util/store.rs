@@ -0,0 +1,169 @@
+use memmap2::Mmap;
+use std::fs::OpenOptions;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::Path;
+
+use super::file::{mmap_segment, prefetch_segment, read_segment};
+
+// Covers the logical block size of every common device.
+const DIRECT_IO_ALIGN: usize = 4096;
+
+// How chunk bytes are brought into memory before an encoder is built.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ChunkStore {
+    // Map the chunk through the page cache.
+    #[default]
+    Mmap,
+    // Read with O_DIRECT, bypassing the page cache. For servers pushing more
+    // data than they want cached.
+    Direct,
+}
+
+pub enum ChunkData {
+    Mapped(Mmap),
+    Direct(AlignedBuffer),
+    // Chunks off a page boundary cannot be mapped.
+    Buffered(Vec<u8>),
+}
+
+impl AsRef<[u8]> for ChunkData {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            ChunkData::Mapped(mmap) => mmap.as_ref(),
+            ChunkData::Direct(buffer) => buffer.as_ref(),
+            ChunkData::Buffered(data) => data,
+        }
+    }
+}
+
+// A heap buffer whose data starts at a DIRECT_IO_ALIGN boundary.
+pub struct AlignedBuffer {
+    raw: Vec<u8>,
+    start: usize,
+    length: usize,
+}
+
+impl AlignedBuffer {
+    fn new(capacity: usize) -> Self {
+        let raw = vec![0u8; capacity + DIRECT_IO_ALIGN];
+        let start = raw.as_ptr().align_offset(DIRECT_IO_ALIGN);
+        Self {
+            raw,
+            start,
+            length: capacity,
+        }
+    }
+
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.raw[self.start..self.start + self.length]
+    }
+}
+
+impl AsRef<[u8]> for AlignedBuffer {
+    fn as_ref(&self) -> &[u8] {
+        &self.raw[self.start..self.start + self.length]
+    }
+}
+
+// Blocking for the direct store, call it off the async workers. Reads start at
+// the block holding `offset`, the bytes before it are skipped afterwards.
+pub fn read_direct<P: AsRef<Path>>(path: P, offset: u64, length: usize) -> Result<AlignedBuffer> {
+    let skip = (offset % DIRECT_IO_ALIGN as u64) as usize;
+    let block_offset = offset - skip as u64;
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)?;
+
+    // Reads must cover whole blocks, the tail of the last one is cut off afterwards.
+    let mut buffer = AlignedBuffer::new((skip + length).next_multiple_of(DIRECT_IO_ALIGN));
+    let mut filled = 0;
+    while filled < skip + length {
+        let read = file.read_at(&mut buffer.as_mut()[filled..], block_offset + filled as u64)?;
+        if read == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!(
+                    "Chunk at {offset} ends after {} of {length} bytes",
+                    filled.saturating_sub(skip)
+                ),
+            ));
+        }
+        filled += read;
+    }
+    buffer.start += skip;
+    buffer.length = length;
+    Ok(buffer)
+}
+
+impl ChunkStore {
+    // `lock_pages` only applies to mapped chunks, direct reads never touch the page cache.
+    pub fn load<P: AsRef<Path>>(
+        &self,
+        path: P,
+        offset: u64,
+        length: usize,
+        lock_pages: bool,
+    ) -> Result<ChunkData> {
+        match self {
+            ChunkStore::Mmap if !offset.is_multiple_of(page_size::get() as u64) => {
+                read_segment(path, offset, length).map(ChunkData::Buffered)
+            }
+            ChunkStore::Mmap => {
+                let mmap = mmap_segment(path, offset, length)?;
+                if let Err(err) = prefetch_segment(&mmap, lock_pages) {
+                    eprintln!("Failed to prefetch chunk at {offset}: {err}");
+                }
+                Ok(ChunkData::Mapped(mmap))
+            }
+            ChunkStore::Direct => read_direct(path, offset, length).map(ChunkData::Direct),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::file::{create_sparse_file, write_at};
+    use tempfile::tempdir;
+
+    #[test]
+    fn stores_read_the_same_bytes() -> Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("direct.bin");
+        create_sparse_file(&file_path, 3 * 4096 + 100)?;
+        write_at(&file_path, 4096, &[0x5a; 8192])?;
+        write_at(&file_path, 3 * 4096, &[0xa5; 100])?;
+
+        let direct = match ChunkStore::Direct.load(&file_path, 4096, 2 * 4096 + 100, false) {
+            Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {
+                eprintln!("O_DIRECT unsupported here, skipped");
+                return Ok(());
+            }
+            result => result?,
+        };
+        let mapped = ChunkStore::Mmap.load(&file_path, 4096, 2 * 4096 + 100, false)?;
+        assert_eq!(direct.as_ref().len(), 2 * 4096 + 100);
+        assert_eq!(direct.as_ref(), mapped.as_ref());
+        assert!(direct.as_ref().as_ptr().align_offset(DIRECT_IO_ALIGN) == 0);
+
+        // Content-defined chunks start anywhere.
+        let unaligned = ChunkStore::Direct.load(&file_path, 4000, 200, false)?;
+        assert_eq!(
+            unaligned.as_ref(),
+            ChunkStore::Mmap
+                .load(&file_path, 4000, 200, false)?
+                .as_ref()
+        );
+        assert_eq!(&unaligned.as_ref()[96..], &[0x5a; 104]);
+
+        let past_end = ChunkStore::Direct.load(&file_path, 3 * 4096, 4096, false);
+        assert_eq!(
+            past_end.err().map(|err| err.kind()),
+            Some(ErrorKind::UnexpectedEof)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+use humansize::{BINARY, format_size};
+use owo_colors::OwoColorize;
+use serde::Serialize;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::engine::decoding::DecodeStats;
+use crate::util::units::ChunkId;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChunkSummary {
+    pub chunk_id: ChunkId,
+    pub length: usize,
+    pub bytes_received: u64,
+    pub frames_received: u64,
+    // The fewest frames that could have carried the chunk.
+    pub frames_needed: u64,
+    pub duplicate_frames: u64,
+    // Everything received beyond the chunk itself: FEC repair, retransmissions and padding.
+    pub wasted_bytes: u64,
+    pub duration_ms: u64,
+    pub verified: bool,
+}
+
+impl ChunkSummary {
+    pub fn new(chunk_id: ChunkId, length: usize, stats: &DecodeStats, verified: bool) -> Self {
+        let frames_needed = match stats.max_frame_length {
+            0 => 0,
+            frame_length => length.div_ceil(frame_length) as u64,
+        };
+        Self {
+            chunk_id,
+            length,
+            bytes_received: stats.bytes_received,
+            frames_received: stats.frames_received,
+            frames_needed,
+            duplicate_frames: stats.duplicate_frames,
+            wasted_bytes: match verified {
+                true => stats.bytes_received.saturating_sub(length as u64),
+                false => stats.bytes_received,
+            },
+            duration_ms: stats.elapsed.as_millis() as u64,
+            verified,
+        }
+    }
+
+    // Frames received per frame needed, 1.0 being a lossless transfer without repair.
+    pub fn overhead(&self) -> f64 {
+        self.frames_received as f64 / self.frames_needed.max(1) as f64
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TransferSummary {
+    pub chunks: Vec<ChunkSummary>,
+    pub verified_chunks: usize,
+    pub failed_chunks: usize,
+    pub bytes_received: u64,
+    pub wasted_bytes: u64,
+    pub duration_ms: u64,
+}
+
+impl TransferSummary {
+    pub fn new(mut chunks: Vec<ChunkSummary>, duration: Duration) -> Self {
+        chunks.sort_by_key(|chunk| chunk.chunk_id);
+        let verified_chunks = chunks.iter().filter(|chunk| chunk.verified).count();
+        Self {
+            verified_chunks,
+            failed_chunks: chunks.len() - verified_chunks,
+            bytes_received: chunks.iter().map(|chunk| chunk.bytes_received).sum(),
+            wasted_bytes: chunks.iter().map(|chunk| chunk.wasted_bytes).sum(),
+            duration_ms: duration.as_millis() as u64,
+            chunks,
+        }
+    }
+
+    pub fn print(&self) {
+        let print_config = BINARY.decimal_places(3).decimal_zeroes(3);
+        println!(
+            "{:>8} {:>12} {:>9} {:>8} {:>10}  Hash",
+            "Chunk", "Received", "Overhead", "Retrans", "Duration"
+        );
+        for chunk in self.chunks.iter() {
+            println!(
+                "{:>8} {:>12} {:>8.3}x {:>8} {:>8}ms  {}",
+                chunk.chunk_id,
+                format_size(chunk.bytes_received, print_config),
+                chunk.overhead(),
+                chunk.duplicate_frames,
+                chunk.duration_ms,
+                match chunk.verified {
+                    true => "OK".green().to_string(),
+                    false => "FAILED".red().to_string(),
+                }
+            );
+        }
+        println!(
+            "{} chunks verified, {} failed. Received {} in {:.3}s, {} of it wasted.",
+            self.verified_chunks.green(),
+            self.failed_chunks.red(),
+            format_size(self.bytes_received, print_config).yellow(),
+            self.duration_ms as f64 / 1000.0,
+            format_size(self.wasted_bytes, print_config).yellow(),
+        );
+    }
+
+    pub fn write_json<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        std::fs::write(
+            path,
+            serde_json::to_string_pretty(self).map_err(io::Error::other)?,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn summary_totals() -> io::Result<()> {
+        let stats = DecodeStats {
+            frames_received: 12,
+            bytes_received: 12 * 1000,
+            duplicate_frames: 1,
+            max_frame_length: 1000,
+            elapsed: Duration::from_millis(250),
+        };
+        let good = ChunkSummary::new(ChunkId(3), 9500, &stats, true);
+        assert_eq!(good.frames_needed, 10);
+        assert_eq!(good.wasted_bytes, 2500);
+        assert!((good.overhead() - 1.2).abs() < 1e-9);
+
+        let bad = ChunkSummary::new(ChunkId(1), 9500, &stats, false);
+        assert_eq!(bad.wasted_bytes, 12000);
+
+        let summary = TransferSummary::new(vec![good, bad], Duration::from_secs(1));
+        assert_eq!(summary.chunks[0].chunk_id, ChunkId(1));
+        assert_eq!((summary.verified_chunks, summary.failed_chunks), (1, 1));
+        assert_eq!(summary.wasted_bytes, 14500);
+
+        let dir = tempdir()?;
+        let path = dir.path().join("summary.json");
+        summary.write_json(&path)?;
+        let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        assert_eq!(json["chunks"][1]["bytes_received"], 12000);
+        assert_eq!(json["verified_chunks"], 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Error, Result};
+use std::path::{Path, PathBuf};
+
+use super::filter::PathFilter;
+use super::plan::FileConfig;
+
+// Which copy is kept when the client's and the server's differ.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ConflictPolicy {
+    // The copy modified last, by the mtimes in the plans.
+    #[default]
+    NewestWins,
+    ServerWins,
+    // Neither, the file is reported and left alone.
+    Fail,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncAction {
+    // Both copies hold the same bytes.
+    Skip,
+    Pull,
+    Push,
+    Conflict,
+}
+
+fn same_content(local: &FileConfig, remote: &FileConfig) -> bool {
+    local.total_length == remote.total_length
+        && local.total_hash == remote.total_hash
+        && local.link_target == remote.link_target
+}
+
+fn mtime(plan: &FileConfig) -> Option<(i64, u32)> {
+    plan.metadata
+        .map(|metadata| (metadata.mtime_secs, metadata.mtime_nanos))
+}
+
+// `local` and `remote` are the plans of a file's copy on the client and on the
+// server, None where there is no copy.
+pub fn decide(
+    local: Option<&FileConfig>,
+    remote: Option<&FileConfig>,
+    policy: ConflictPolicy,
+) -> SyncAction {
+    let (local, remote) = match (local, remote) {
+        (None, None) => return SyncAction::Skip,
+        (Some(_), None) => return SyncAction::Push,
+        (None, Some(_)) => return SyncAction::Pull,
+        (Some(local), Some(remote)) => (local, remote),
+    };
+    if same_content(local, remote) {
+        return SyncAction::Skip;
+    }
+    match policy {
+        ConflictPolicy::ServerWins => SyncAction::Pull,
+        ConflictPolicy::Fail => SyncAction::Conflict,
+        // Without mtimes, or with equal ones, neither is known to be newer.
+        ConflictPolicy::NewestWins => match (mtime(local), mtime(remote)) {
+            (Some(local), Some(remote)) if local > remote => SyncAction::Push,
+            (Some(local), Some(remote)) if local < remote => SyncAction::Pull,
+            _ => SyncAction::Conflict,
+        },
+    }
+}
+
+// A plan file, or a directory of them as `planner --out-dir` writes, by the
+// file name they plan.
+pub fn load_plans(path: &Path) -> Result<BTreeMap<String, (PathBuf, FileConfig)>> {
+    let files = match fs::metadata(path)?.is_dir() {
+        true => PathFilter::default()
+            .walk(path)?
+            .into_iter()
+            .filter(|file| {
+                file.extension()
+                    .is_some_and(|extension| extension == "toml")
+            })
+            .map(|file| path.join(file))
+            .collect(),
+        false => vec![path.to_path_buf()],
+    };
+    let mut plans = BTreeMap::new();
+    for file in files {
+        let plan: FileConfig = toml::from_str(&fs::read_to_string(&file)?)
+            .map_err(|err| Error::other(format!("{}: {err}", file.display())))?;
+        plan.check_chunks()
+            .and_then(|()| plan.check_file_name())
+            .map_err(|err| Error::other(format!("{}: {err}", file.display())))?;
+        plans.insert(plan.file_name.clone(), (file, plan));
+    }
+    Ok(plans)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::plan::FileMetadata;
+
+    fn plan(hash: &str, mtime_secs: Option<i64>) -> FileConfig {
+        FileConfig {
+            file_name: "notes.txt".to_string(),
+            total_length: 4,
+            total_hash: hash.to_string(),
+            epoch: 0,
+            chunks: vec![],
+            metadata: mtime_secs.map(|mtime_secs| FileMetadata {
+                mode: 0o644,
+                mtime_secs,
+                mtime_nanos: 0,
+            }),
+            link_target: None,
+            sealed: None,
+            hints: None,
+            cdc: None,
+        }
+    }
+
+    #[test]
+    fn newer_copy_wins_unless_told_otherwise() {
+        use ConflictPolicy::*;
+        use SyncAction::*;
+        let old = plan("aa", Some(100));
+        let new = plan("bb", Some(200));
+        let undated = plan("cc", None);
+
+        assert_eq!(decide(Some(&old), None, Fail), Push);
+        assert_eq!(decide(None, Some(&old), Fail), Pull);
+        // Touched without changing the bytes.
+        assert_eq!(decide(Some(&old), Some(&plan("aa", Some(300))), Fail), Skip);
+
+        assert_eq!(decide(Some(&new), Some(&old), NewestWins), Push);
+        assert_eq!(decide(Some(&old), Some(&new), NewestWins), Pull);
+        assert_eq!(
+            decide(Some(&plan("dd", Some(100))), Some(&old), NewestWins),
+            Conflict
+        );
+        assert_eq!(decide(Some(&undated), Some(&old), NewestWins), Conflict);
+
+        assert_eq!(decide(Some(&new), Some(&old), ServerWins), Pull);
+        assert_eq!(decide(Some(&new), Some(&old), Fail), Conflict);
+    }
+}
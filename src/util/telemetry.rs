@@ -0,0 +1,257 @@
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use tokio::time::Instant;
+
+use super::units::ChunkId;
+
+// Timelines of the chunks encoders sent, for performance analysis: queried with
+// `timings` on the control socket or dumped as JSON. Times are ms since the
+// recorder started, with the process.
+
+// Timelines kept after their encoder ended, the oldest go first.
+const MAX_ENDED: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChunkEnd {
+    // Closed at the peer's order, the chunk complete.
+    Finished,
+    OtherGeneration,
+    PeerDisconnected,
+    SendFailed,
+    // The chunk could not be loaded or encoded.
+    Failed,
+    // No order came to keep the encoder alive.
+    TimedOut,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkEvent {
+    Order,
+    InitStarted,
+    // `prepared` when the encoder was built ahead of the order.
+    InitDone { prepared: bool },
+    Frames(u64),
+    End(ChunkEnd),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChunkTimeline {
+    pub chunk_id: ChunkId,
+    pub peer: SocketAddr,
+    pub first_order_ms: Option<u64>,
+    pub last_order_ms: Option<u64>,
+    pub orders: u32,
+    pub init_started_ms: Option<u64>,
+    pub init_done_ms: Option<u64>,
+    pub prepared: bool,
+    pub first_frame_ms: Option<u64>,
+    pub last_frame_ms: Option<u64>,
+    pub frames: u64,
+    pub end_ms: Option<u64>,
+    pub end: Option<ChunkEnd>,
+}
+
+impl ChunkTimeline {
+    fn new(chunk_id: ChunkId, peer: SocketAddr) -> Self {
+        Self {
+            chunk_id,
+            peer,
+            first_order_ms: None,
+            last_order_ms: None,
+            orders: 0,
+            init_started_ms: None,
+            init_done_ms: None,
+            prepared: false,
+            first_frame_ms: None,
+            last_frame_ms: None,
+            frames: 0,
+            end_ms: None,
+            end: None,
+        }
+    }
+
+    fn started_ms(&self) -> Option<u64> {
+        [self.first_order_ms, self.init_started_ms, self.init_done_ms]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    fn apply(&mut self, event: ChunkEvent, at_ms: u64) {
+        match event {
+            ChunkEvent::Order => {
+                self.first_order_ms.get_or_insert(at_ms);
+                self.last_order_ms = Some(at_ms);
+                self.orders += 1;
+            }
+            ChunkEvent::InitStarted => self.init_started_ms = Some(at_ms),
+            ChunkEvent::InitDone { prepared } => {
+                self.init_done_ms = Some(at_ms);
+                self.prepared = prepared;
+            }
+            ChunkEvent::Frames(0) => {}
+            ChunkEvent::Frames(count) => {
+                self.first_frame_ms.get_or_insert(at_ms);
+                self.last_frame_ms = Some(at_ms);
+                self.frames += count;
+            }
+            ChunkEvent::End(end) => {
+                self.end_ms = Some(at_ms);
+                self.end = Some(end);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Timelines {
+    open: HashMap<(ChunkId, SocketAddr), ChunkTimeline>,
+    ended: VecDeque<ChunkTimeline>,
+}
+
+pub struct Telemetry {
+    origin: Instant,
+    timelines: Mutex<Timelines>,
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Self {
+            origin: Instant::now(),
+            timelines: Mutex::default(),
+        }
+    }
+}
+
+impl Telemetry {
+    pub fn record_at(&self, chunk_id: ChunkId, peer: SocketAddr, event: ChunkEvent, at: Instant) {
+        let at_ms = at.saturating_duration_since(self.origin).as_millis() as u64;
+        let mut timelines = self.timelines.lock().unwrap();
+        let timeline = timelines
+            .open
+            .entry((chunk_id, peer))
+            .or_insert_with(|| ChunkTimeline::new(chunk_id, peer));
+        timeline.apply(event, at_ms);
+        if let ChunkEvent::End(_) = event {
+            let ended = timelines.open.remove(&(chunk_id, peer)).unwrap();
+            if timelines.ended.len() == MAX_ENDED {
+                timelines.ended.pop_front();
+            }
+            timelines.ended.push_back(ended);
+        }
+    }
+
+    // Ended and running timelines of `chunk_id`, or of every chunk, in the
+    // order they started.
+    pub fn timelines(&self, chunk_id: Option<ChunkId>) -> Vec<ChunkTimeline> {
+        let timelines = self.timelines.lock().unwrap();
+        let mut found: Vec<ChunkTimeline> = timelines
+            .ended
+            .iter()
+            .chain(timelines.open.values())
+            .filter(|timeline| chunk_id.is_none_or(|chunk_id| timeline.chunk_id == chunk_id))
+            .cloned()
+            .collect();
+        found.sort_by_key(|timeline| (timeline.started_ms(), timeline.chunk_id, timeline.peer));
+        found
+    }
+
+    // Written to a temporary file first, so a reader never sees half a dump.
+    pub fn dump(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(&self.timelines(None)).map_err(io::Error::other)?;
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, json)?;
+        std::fs::rename(temporary, path)
+    }
+}
+
+static TELEMETRY: OnceLock<Telemetry> = OnceLock::new();
+
+pub fn telemetry() -> &'static Telemetry {
+    TELEMETRY.get_or_init(Telemetry::default)
+}
+
+pub fn record(chunk_id: ChunkId, peer: SocketAddr, event: ChunkEvent) {
+    telemetry().record_at(chunk_id, peer, event, Instant::now());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn timelines_follow_the_encoder() {
+        let telemetry = Telemetry::default();
+        let start = Instant::now();
+        let peer: SocketAddr = "10.0.0.2:7000".parse().unwrap();
+        let at = |ms| start + Duration::from_millis(ms);
+        for (ms, event) in [
+            (1, ChunkEvent::Order),
+            (2, ChunkEvent::InitStarted),
+            (40, ChunkEvent::InitDone { prepared: false }),
+            (60, ChunkEvent::Frames(3)),
+            (80, ChunkEvent::Order),
+            (90, ChunkEvent::Frames(0)),
+            (100, ChunkEvent::Frames(2)),
+            (120, ChunkEvent::End(ChunkEnd::Finished)),
+        ] {
+            telemetry.record_at(ChunkId(7), peer, event, at(ms));
+        }
+        // The same chunk sent again starts a timeline of its own.
+        telemetry.record_at(ChunkId(7), peer, ChunkEvent::Order, at(200));
+        telemetry.record_at(ChunkId(8), peer, ChunkEvent::Order, at(150));
+
+        let timelines = telemetry.timelines(Some(ChunkId(7)));
+        assert_eq!(timelines.len(), 2);
+        let first = &timelines[0];
+        assert_eq!(
+            (first.first_order_ms, first.last_order_ms),
+            (Some(1), Some(80))
+        );
+        assert_eq!(first.orders, 2);
+        assert_eq!(
+            (first.init_started_ms, first.init_done_ms),
+            (Some(2), Some(40))
+        );
+        assert_eq!(
+            (first.first_frame_ms, first.last_frame_ms),
+            (Some(60), Some(100))
+        );
+        assert_eq!(first.frames, 5);
+        assert_eq!(
+            (first.end_ms, first.end),
+            (Some(120), Some(ChunkEnd::Finished))
+        );
+        assert_eq!(timelines[1].end, None);
+        let chunks: Vec<u32> = telemetry
+            .timelines(None)
+            .iter()
+            .map(|timeline| timeline.chunk_id.0)
+            .collect();
+        assert_eq!(chunks, vec![7, 8, 7]);
+    }
+
+    #[tokio::test]
+    async fn dump_as_json() {
+        let telemetry = Telemetry::default();
+        let peer: SocketAddr = "10.0.0.2:7000".parse().unwrap();
+        telemetry.record_at(
+            ChunkId(3),
+            peer,
+            ChunkEvent::End(ChunkEnd::TimedOut),
+            Instant::now(),
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("timings.json");
+        telemetry.dump(&path).unwrap();
+        let json = std::fs::read_to_string(&path).unwrap();
+        assert!(json.contains(r#""end": "timed_out""#), "{json}");
+        assert!(json.contains(r#""peer": "10.0.0.2:7000""#), "{json}");
+    }
+}
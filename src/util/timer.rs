@@ -6,6 +6,8 @@ use std::{
 };
 use tokio::time::Instant;
 
+use super::clock::{SharedClock, system_clock};
+
 pub enum SenderTimerOutput {
     Send(usize),
     Close,
@@ -16,29 +18,41 @@ pub struct SenderTimer {
     sleep_after: Instant,
     exit_after: Instant,
     last_send: Instant,
+    // When the timer asked to be polled again at the latest.
+    due: Instant,
     waker: Option<Waker>,
+    clock: SharedClock,
 }
 
 const STOP_AFTER: Duration = Duration::from_secs(10);
 const EXIT_AFTER: Duration = Duration::from_secs(20);
 const MAX_BURST: usize = 8;
+// A poll this much later than asked for is not time the sender let pass but a
+// machine that was asleep or a runtime that stalled. The missed time neither
+// sends in a burst nor counts towards stopping.
+const MAX_LATENESS: Duration = Duration::from_secs(2);
 
 impl SenderTimer {
     pub fn new(interval: Duration) -> Self {
-        let now = Instant::now();
+        Self::with_clock(interval, system_clock())
+    }
+
+    pub fn with_clock(interval: Duration, clock: SharedClock) -> Self {
+        let now = clock.now();
         Self {
             interval,
             sleep_after: now + STOP_AFTER,
             exit_after: now + EXIT_AFTER,
             last_send: now,
+            due: now,
             waker: None,
+            clock,
         }
     }
 
     pub fn set_rate(&mut self, timestamp: Instant, new_interval: Option<Duration>) {
         if let Some(new_interval) = new_interval {
-            self.interval = new_interval;
-            self.last_send = self.last_send.max(timestamp - new_interval);
+            self.set_interval(timestamp, new_interval);
         }
 
         self.sleep_after = self.sleep_after.max(timestamp + STOP_AFTER);
@@ -48,6 +62,47 @@ impl SenderTimer {
             waker.wake();
         }
     }
+
+    // Changes the pace only, without keeping the timer alive any longer. The
+    // same interval again changes nothing, so repeated orders are harmless.
+    pub fn set_interval(&mut self, timestamp: Instant, new_interval: Duration) {
+        if new_interval == self.interval {
+            return;
+        }
+        self.interval = new_interval;
+        let earliest = timestamp.checked_sub(new_interval).unwrap_or(timestamp);
+        self.last_send = self.last_send.max(earliest);
+
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn absorb_jump(&mut self, now: Instant) {
+        // A clock that went back never holds sends for longer than an interval,
+        // nor keeps the timer alive for longer than a fresh one would be.
+        if now < self.last_send {
+            self.last_send = now;
+            self.sleep_after = self.sleep_after.min(now + STOP_AFTER);
+            self.exit_after = self.exit_after.min(now + EXIT_AFTER);
+        }
+        let late = now.saturating_duration_since(self.due);
+        if late > MAX_LATENESS {
+            self.sleep_after += late;
+            self.exit_after += late;
+            let earliest = now.checked_sub(self.interval).unwrap_or(now);
+            self.last_send = self.last_send.max(earliest);
+        }
+    }
+
+    fn wake_at(&mut self, at: Instant) {
+        self.due = at;
+        let waker = self.waker.as_ref().unwrap().clone();
+        tokio::spawn(async move {
+            tokio::time::sleep_until(at).await;
+            waker.wake();
+        });
+    }
 }
 
 impl Future for SenderTimer {
@@ -55,19 +110,16 @@ impl Future for SenderTimer {
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<SenderTimerOutput> {
         self.waker = Some(cx.waker().clone());
-        let now = Instant::now();
+        let now = self.clock.now();
+        self.absorb_jump(now);
 
         if now >= self.exit_after {
             return Poll::Ready(SenderTimerOutput::Close);
         }
 
         if now >= self.sleep_after {
-            let waker_clone = self.waker.as_ref().unwrap().clone();
-            let wake_time_clone = self.exit_after;
-            tokio::spawn(async move {
-                tokio::time::sleep_until(wake_time_clone).await;
-                waker_clone.wake();
-            });
+            let exit_after = self.exit_after;
+            self.wake_at(exit_after);
             return Poll::Pending;
         }
 
@@ -75,49 +127,92 @@ impl Future for SenderTimer {
 
         if now >= min_sendable_time {
             let can_send_num = (now.duration_since(self.last_send)).div_duration_f64(self.interval);
-            if can_send_num > 1.0 {
+            if can_send_num >= 1.0 {
                 let can_send_num = can_send_num.floor();
                 let advance = self.interval.mul_f64(can_send_num);
                 self.last_send += advance;
+                self.due = now;
                 return Poll::Ready(SenderTimerOutput::Send(
                     (can_send_num as usize).min(MAX_BURST),
                 ));
             }
         }
 
-        let waker_clone = self.waker.as_ref().unwrap().clone();
-        tokio::spawn(async move {
-            tokio::time::sleep_until(min_sendable_time).await;
-            waker_clone.wake();
-        });
+        self.wake_at(min_sendable_time);
         Poll::Pending
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::super::clock::AdjustableClock;
+    use super::*;
+    use std::sync::Arc;
+
+    const EPOCH_MS: u64 = 1_700_000_000_000;
+
+    #[tokio::test(start_paused = true)]
+    async fn sleeping_machine_neither_bursts_nor_closes() {
+        let clock = Arc::new(AdjustableClock::new(EPOCH_MS));
+        let mut timer = SenderTimer::with_clock(Duration::from_millis(10), clock.clone());
+        assert!(matches!((&mut timer).await, SenderTimerOutput::Send(1)));
+
+        // Asleep for longer than the timer lives, with the wall clock along.
+        tokio::time::advance(Duration::from_secs(60)).await;
+        clock.step_wall(60_000);
+        assert!(matches!((&mut timer).await, SenderTimerOutput::Send(1)));
+        assert!(matches!((&mut timer).await, SenderTimerOutput::Send(1)));
+
+        // Once awake, it still stops when nobody keeps it alive.
+        let start = Instant::now();
+        let mut sent = 0;
+        while let SenderTimerOutput::Send(count) = (&mut timer).await {
+            sent += count;
+        }
+        assert!(Instant::now() - start < EXIT_AFTER + MAX_LATENESS);
+        assert!(sent <= STOP_AFTER.as_millis() as usize / 10);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wall_clock_steps_change_nothing() {
+        let clock = Arc::new(AdjustableClock::new(EPOCH_MS));
+        let mut timer = SenderTimer::with_clock(Duration::from_millis(10), clock.clone());
+        let start = Instant::now();
+        for step_ms in [-3_600_000, 7_200_000, -(EPOCH_MS as i64) * 2] {
+            clock.step_wall(step_ms);
+            assert!(matches!((&mut timer).await, SenderTimerOutput::Send(1)));
+        }
+        assert_eq!(Instant::now() - start, Duration::from_millis(30));
+    }
+}
+
 #[cfg(feature = "slow-tests")]
 #[cfg(test)]
 mod test {
 
-    use super::super::timer_logger::{PROGRAM_START_TIME, print_relative_time};
     use super::*;
     use tokio::select;
 
+    fn elapsed_ms(start: Instant, at: Instant) -> f64 {
+        at.duration_since(start).as_secs_f64() * 1000.0
+    }
+
     #[tokio::test]
     async fn clock() {
         println!("start");
         let (tx, rx) = flume::bounded::<Duration>(16);
+        let start = Instant::now();
 
         let controller = tokio::spawn(async move {
-            tokio::time::sleep_until(*PROGRAM_START_TIME + Duration::from_secs(3)).await;
+            tokio::time::sleep_until(start + Duration::from_secs(3)).await;
             tx.send(Duration::from_millis(500)).unwrap();
 
-            tokio::time::sleep_until(*PROGRAM_START_TIME + Duration::from_secs(20)).await;
+            tokio::time::sleep_until(start + Duration::from_secs(20)).await;
             tx.send(Duration::from_millis(1500)).unwrap();
         });
 
         let sender = tokio::spawn(async move {
             let mut timer = SenderTimer::new(Duration::from_millis(900));
-            let mut cnt = 0;
             let mut sent_times = vec![];
 
             loop {
@@ -129,12 +224,11 @@ mod test {
                         match output {
                             SenderTimerOutput::Send(x) => {
                                 for _ in 0..x {
-                                    sent_times.push(print_relative_time(0, format!("send {}", cnt).as_str(), Instant::now()) / 100.0);
-                                    cnt += 1;
+                                    sent_times.push(elapsed_ms(start, Instant::now()) / 100.0);
                                 }
                             },
                             SenderTimerOutput::Close => {
-                                sent_times.push( print_relative_time(0, "CLOSE", Instant::now()) / 100.0);
+                                sent_times.push(elapsed_ms(start, Instant::now()) / 100.0);
                                 break;
                             }
                         };
@@ -0,0 +1,173 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use zerocopy::{BigEndian, FromBytes, Immutable, IntoBytes, KnownLayout, U32};
+
+// Integers easily taken for one another: chunk ids, byte offsets into a file,
+// frame offsets into a chunk and session ids. Byte arithmetic is checked, frame
+// arithmetic wraps on purpose, see `engine::window`. In memory they are native
+// u32 and u64, on the wire the big endian fields of the headers convert to them.
+
+#[derive(
+    Serialize,
+    Deserialize,
+    FromBytes,
+    IntoBytes,
+    Immutable,
+    KnownLayout,
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+)]
+#[serde(transparent)]
+#[repr(transparent)]
+pub struct ChunkId(pub u32);
+
+impl ChunkId {
+    // Plans count chunks in usize, the wire in u32.
+    pub fn from_index(index: usize) -> Option<Self> {
+        u32::try_from(index).ok().map(Self)
+    }
+
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl From<u32> for ChunkId {
+    fn from(chunk_id: u32) -> Self {
+        Self(chunk_id)
+    }
+}
+
+impl From<ChunkId> for u32 {
+    fn from(chunk_id: ChunkId) -> Self {
+        chunk_id.0
+    }
+}
+
+impl From<U32<BigEndian>> for ChunkId {
+    fn from(chunk_id: U32<BigEndian>) -> Self {
+        Self(chunk_id.get())
+    }
+}
+
+impl From<ChunkId> for U32<BigEndian> {
+    fn from(chunk_id: ChunkId) -> Self {
+        chunk_id.0.into()
+    }
+}
+
+impl fmt::Display for ChunkId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(transparent)]
+pub struct ByteOffset(pub u64);
+
+impl ByteOffset {
+    // Where `length` bytes from here end, None past u64::MAX.
+    pub fn checked_end(self, length: usize) -> Option<Self> {
+        self.0.checked_add(u64::try_from(length).ok()?).map(Self)
+    }
+
+    // For comparisons only, where a range ending at u64::MAX is as good as one past it.
+    pub fn saturating_end(self, length: usize) -> Self {
+        self.checked_end(length).unwrap_or(Self(u64::MAX))
+    }
+}
+
+impl From<u64> for ByteOffset {
+    fn from(offset: u64) -> Self {
+        Self(offset)
+    }
+}
+
+impl fmt::Display for ByteOffset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct FrameOffset(pub u32);
+
+impl FrameOffset {
+    // A window is kept under half the offset space, for serial comparisons to
+    // tell its two ends apart.
+    pub const MAX_WINDOW: u64 = (1 << 31) - 1;
+
+    // Wide offsets of the wire wrap into u32 like every other frame offset.
+    pub fn from_wide(offset: u64) -> Self {
+        Self(offset as u32)
+    }
+
+    // The end of a window of `frames` starting here, capped at `MAX_WINDOW`.
+    pub fn advance(self, frames: u64) -> Self {
+        Self(self.0.wrapping_add(frames.min(Self::MAX_WINDOW) as u32))
+    }
+}
+
+impl From<u32> for FrameOffset {
+    fn from(offset: u32) -> Self {
+        Self(offset)
+    }
+}
+
+impl From<U32<BigEndian>> for FrameOffset {
+    fn from(offset: U32<BigEndian>) -> Self {
+        Self(offset.get())
+    }
+}
+
+impl fmt::Display for FrameOffset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+// Of the packets exchanged with one peer, drawn at random, see `PacketIds`.
+#[derive(IntoBytes, Immutable, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[repr(transparent)]
+pub struct SessionId(pub u32);
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_ends_never_wrap() {
+        assert_eq!(ByteOffset(10).checked_end(5), Some(ByteOffset(15)));
+        assert_eq!(ByteOffset(u64::MAX - 1).checked_end(2), None);
+        assert_eq!(
+            ByteOffset(u64::MAX - 1).saturating_end(2),
+            ByteOffset(u64::MAX)
+        );
+        assert_eq!(ChunkId::from_index(u32::MAX as usize + 1), None);
+        assert_eq!(ChunkId::from_index(7), Some(ChunkId(7)));
+    }
+
+    #[test]
+    fn frame_windows_wrap_and_stay_under_half() {
+        assert_eq!(FrameOffset(10).advance(5), FrameOffset(15));
+        assert_eq!(FrameOffset(u32::MAX).advance(2), FrameOffset(1));
+        assert_eq!(FrameOffset(0).advance(u64::MAX), FrameOffset((1 << 31) - 1));
+        assert_eq!(FrameOffset::from_wide((1 << 32) + 3), FrameOffset(3));
+    }
+}
@@ -0,0 +1,115 @@
+use std::fmt;
+use std::io::{Error, Result};
+use std::net::{SocketAddr, ToSocketAddrs};
+
+const SCHEME: &str = "usync://";
+
+fn plan_hash(plan: &[u8]) -> String {
+    hex::encode(blake3::hash(plan).as_bytes())
+}
+
+// A file to fetch in one shareable string, `usync://host:port/<plan hash>?key=<key>`.
+// The plan comes from the server and is checked against the hash. The key is a
+// download token or a hex private key, and may be left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlanUri {
+    // Host and port of the server.
+    pub authority: String,
+    pub plan_hash: String,
+    pub key: Option<String>,
+}
+
+impl PlanUri {
+    pub fn new(authority: &str, plan: &[u8], key: Option<String>) -> Self {
+        Self {
+            authority: authority.to_string(),
+            plan_hash: plan_hash(plan),
+            key,
+        }
+    }
+
+    // Parameters other than `key` are ignored.
+    pub fn parse(uri: &str) -> std::result::Result<Self, String> {
+        let rest = uri
+            .trim()
+            .strip_prefix(SCHEME)
+            .ok_or("Not a usync:// URI")?;
+        let (rest, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let (authority, hash) = rest.split_once('/').ok_or("No plan hash in the URI")?;
+        if authority.is_empty() {
+            return Err("No server in the URI".to_string());
+        }
+        let hash = hash.trim_end_matches('/').to_ascii_lowercase();
+        if hash.len() != 64 || !hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(format!("{hash} is not a plan hash"));
+        }
+        let key = query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(name, value)| *name == "key" && !value.is_empty())
+            .map(|(_, value)| value.to_string());
+        Ok(Self {
+            authority: authority.to_string(),
+            plan_hash: hash,
+            key,
+        })
+    }
+
+    // The first address the host resolves to.
+    pub fn server(&self) -> Result<SocketAddr> {
+        self.authority
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::other(format!("{} resolves to no address", self.authority)))
+    }
+
+    pub fn check(&self, plan: &[u8]) -> std::result::Result<(), String> {
+        let hash = plan_hash(plan);
+        match hash == self.plan_hash {
+            true => Ok(()),
+            false => Err(format!(
+                "The server sent a plan hashed {hash} instead of {}",
+                self.plan_hash
+            )),
+        }
+    }
+}
+
+impl fmt::Display for PlanUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{SCHEME}{}/{}", self.authority, self.plan_hash)?;
+        match self.key.as_ref() {
+            Some(key) => write!(f, "?key={key}"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uris_name_the_server_and_the_plan() {
+        let plan = b"file_name = \"movie.mkv\"\n";
+        let uri = PlanUri::new("[::1]:7234", plan, Some("usync-token-abc".to_string()));
+        let parsed = PlanUri::parse(&uri.to_string()).unwrap();
+        assert_eq!(parsed, uri);
+        assert_eq!(parsed.server().unwrap(), "[::1]:7234".parse().unwrap());
+        assert!(parsed.check(plan).is_ok());
+        assert!(parsed.check(b"file_name = \"other.iso\"\n").is_err());
+
+        let keyless = format!("usync://10.0.0.1:7234/{}?via=relay", uri.plan_hash);
+        let keyless = PlanUri::parse(&keyless).unwrap();
+        assert_eq!(keyless.key, None);
+        assert_eq!(
+            keyless.to_string(),
+            format!("usync://10.0.0.1:7234/{}", uri.plan_hash)
+        );
+
+        assert!(PlanUri::parse("https://10.0.0.1:7234/abc").is_err());
+        assert!(PlanUri::parse("usync://10.0.0.1:7234").is_err());
+        assert!(PlanUri::parse("usync://10.0.0.1:7234/abc").is_err());
+        assert!(PlanUri::parse(&format!("usync:///{}", uri.plan_hash)).is_err());
+    }
+}
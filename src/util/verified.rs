@@ -0,0 +1,209 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io::{Error, Result};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use super::plan::FileChunk;
+
+// What the downloaded file looked like when the cache was saved. Any write to
+// the file, by us or anyone else, moves its mtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct FileStamp {
+    length: u64,
+    mtime_secs: i64,
+    mtime_nanos: i64,
+}
+
+impl FileStamp {
+    fn of(path: &Path) -> Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        Ok(Self {
+            length: metadata.len(),
+            mtime_secs: metadata.mtime(),
+            mtime_nanos: metadata.mtime_nsec(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheFile {
+    stamp: FileStamp,
+    chunks: Vec<FileChunk>,
+}
+
+// The chunks of a downloaded file known to hold their planned bytes, kept in
+// a TOML file beside it so a rerun skips rehashing them while the file stays
+// untouched.
+pub struct VerificationCache {
+    path: PathBuf,
+    file: PathBuf,
+    // Keyed by offset, verified chunks never overlap.
+    chunks: BTreeMap<u64, FileChunk>,
+}
+
+impl VerificationCache {
+    pub fn cache_path(file: &Path) -> PathBuf {
+        let mut name = file.file_name().map(OsString::from).unwrap_or_default();
+        name.push(".verified");
+        file.with_file_name(name)
+    }
+
+    // Vouches for nothing, the saved cache is replaced on the next save.
+    pub fn empty(file: &Path) -> Self {
+        Self {
+            path: Self::cache_path(file),
+            file: file.to_path_buf(),
+            chunks: BTreeMap::new(),
+        }
+    }
+
+    // Starts empty if there is no cache, it cannot be read, or the file
+    // changed since it was saved.
+    pub fn load(file: &Path) -> Self {
+        let mut cache = Self::empty(file);
+        if let Some(saved) = cache.read_fresh() {
+            cache.chunks = saved
+                .chunks
+                .into_iter()
+                .map(|chunk| (chunk.offset, chunk))
+                .collect();
+        }
+        cache
+    }
+
+    // The saved cache, if the file is as it was when it was saved.
+    fn read_fresh(&self) -> Option<CacheFile> {
+        std::fs::read_to_string(&self.path)
+            .ok()
+            .and_then(|cache| toml::from_str::<CacheFile>(&cache).ok())
+            .filter(|cache| FileStamp::of(&self.file).is_ok_and(|stamp| stamp == cache.stamp))
+    }
+
+    // The chunk was verified at the same offset, with the same length and hash.
+    pub fn is_verified(&self, chunk: &FileChunk) -> bool {
+        self.chunks.get(&chunk.offset).is_some_and(|verified| {
+            verified.chunk_id == chunk.chunk_id
+                && verified.length == chunk.length
+                && verified.hash == chunk.hash
+        })
+    }
+
+    // Chunks the written bytes overlap are no longer verified.
+    pub fn record(&mut self, chunk: &FileChunk) {
+        let end = chunk.end();
+        let before = self
+            .chunks
+            .range(..chunk.offset)
+            .next_back()
+            .filter(|(_, verified)| verified.end() > chunk.offset)
+            .map(|(offset, _)| *offset);
+        let overlapped: Vec<u64> = before
+            .into_iter()
+            .chain(
+                self.chunks
+                    .range(chunk.offset..end)
+                    .map(|(offset, _)| *offset),
+            )
+            .collect();
+        for offset in overlapped {
+            self.chunks.remove(&offset);
+        }
+        self.chunks.insert(chunk.offset, chunk.clone());
+    }
+
+    // Resizes the file without losing the cache, which only holds chunks the
+    // new length keeps, unless the file was changed by someone else meanwhile.
+    pub fn set_len(&mut self, length: u64) -> Result<()> {
+        let fresh = self.read_fresh().is_some();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&self.file)?
+            .set_len(length)?;
+        self.chunks.retain(|_, chunk| chunk.end() <= length);
+        if fresh {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    // Call after the last write to the file, including its metadata.
+    pub fn save(&self) -> Result<()> {
+        let cache = CacheFile {
+            stamp: FileStamp::of(&self.file)?,
+            chunks: self.chunks.values().cloned().collect(),
+        };
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, toml::to_string(&cache).map_err(Error::other)?)?;
+        std::fs::rename(tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::file::{chunk_hash, create_sparse_file, write_at};
+    use crate::util::units::ChunkId;
+
+    fn chunk(path: &Path, chunk_id: u32, offset: u64, length: usize) -> FileChunk {
+        FileChunk {
+            chunk_id: ChunkId(chunk_id),
+            hash: chunk_hash(path, offset, length).unwrap(),
+            offset,
+            length,
+        }
+    }
+
+    #[test]
+    fn cache_holds_while_the_file_is_untouched() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("download.bin");
+        create_sparse_file(&file, 3 * 4096)?;
+        let chunks = [
+            chunk(&file, 0, 0, 4096),
+            chunk(&file, 1, 4096, 4096),
+            chunk(&file, 2, 8192, 4096),
+        ];
+
+        let mut cache = VerificationCache::load(&file);
+        assert!(!cache.is_verified(&chunks[0]));
+        for chunk in &chunks {
+            cache.record(chunk);
+        }
+        // Written over the second half of chunk 1 and the start of chunk 2.
+        cache.record(&chunk(&file, 7, 6144, 4096));
+        cache.save()?;
+
+        let cache = VerificationCache::load(&file);
+        assert!(cache.is_verified(&chunks[0]));
+        assert!(!cache.is_verified(&chunks[1]));
+        assert!(!cache.is_verified(&chunks[2]));
+        let mut moved = chunks[0].clone();
+        moved.offset = 4096;
+        assert!(!cache.is_verified(&moved));
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        write_at(&file, 0, b"changed")?;
+        assert!(!VerificationCache::load(&file).is_verified(&chunks[0]));
+        Ok(())
+    }
+
+    #[test]
+    fn resizing_keeps_the_cache() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("download.bin");
+        create_sparse_file(&file, 2 * 4096)?;
+        let chunks = [chunk(&file, 0, 0, 4096), chunk(&file, 1, 4096, 4096)];
+        let mut cache = VerificationCache::load(&file);
+        chunks.iter().for_each(|chunk| cache.record(chunk));
+        cache.save()?;
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        cache.set_len(6000)?;
+        let cache = VerificationCache::load(&file);
+        assert!(cache.is_verified(&chunks[0]));
+        assert!(!cache.is_verified(&chunks[1]));
+        Ok(())
+    }
+}
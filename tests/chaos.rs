@@ -0,0 +1,69 @@
+mod common;
+
+use common::{FaultWindow, LinkProfile, SERVER_ADDR, TicketFault, run_transfer_with_faults};
+use tokio::time::Duration;
+
+const FOREVER_MS: u64 = 3_600_000;
+
+// The chunk decodes from the window of the first ticket, sent a second in, but
+// the tickets telling the server so never arrive. The encoder must stop on its
+// own, then exit.
+#[tokio::test(start_paused = true)]
+async fn encoders_sleep_and_exit_without_tickets() {
+    let faults = vec![FaultWindow::new(TicketFault::Drop, 1_500, FOREVER_MS)];
+    let outcome = run_transfer_with_faults(LinkProfile::default(), faults, &[0], 11).await;
+    outcome.assert_correct();
+
+    let last_send = outcome
+        .link
+        .last_send_from(SERVER_ADDR.parse().unwrap())
+        .unwrap();
+    assert!(last_send <= Duration::from_millis(11_500), "{last_send:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn transfer_resumes_after_ticket_blackout() {
+    // Longer than an idle encoder lives, so the transfer starts over from fresh encoders.
+    let faults = vec![FaultWindow::new(TicketFault::Drop, 0, 25_000)];
+    let outcome = run_transfer_with_faults(LinkProfile::default(), faults, &[1, 2], 12).await;
+    outcome.assert_correct();
+    assert!(outcome.elapsed >= Duration::from_secs(25));
+    assert!(
+        outcome.elapsed < Duration::from_secs(30),
+        "{:?}",
+        outcome.elapsed
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn transfer_resumes_after_corrupted_tickets() {
+    let faults = vec![FaultWindow::new(TicketFault::Corrupt, 0, 5_000)];
+    let outcome = run_transfer_with_faults(LinkProfile::default(), faults, &[3], 13).await;
+    outcome.assert_correct();
+    assert!(outcome.elapsed >= Duration::from_secs(5));
+    assert!(
+        outcome.elapsed < Duration::from_secs(10),
+        "{:?}",
+        outcome.elapsed
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn duplicated_and_reordered_tickets() {
+    let faults = vec![
+        FaultWindow::new(TicketFault::Duplicate, 0, 3_000),
+        FaultWindow::new(
+            TicketFault::Delay(Duration::from_millis(2_500)),
+            3_000,
+            10_000,
+        ),
+    ];
+    let outcome =
+        run_transfer_with_faults(LinkProfile::lossy(0.2, 30), faults, &[4, 5, 6, 7], 14).await;
+    outcome.assert_correct();
+    assert!(
+        outcome.elapsed < Duration::from_secs(15),
+        "{:?}",
+        outcome.elapsed
+    );
+}
@@ -0,0 +1,457 @@
+// In-process server and client over a simulated link. Tests run on a paused
+// current-thread runtime, so a transfer takes virtual time only. Loss and jitter
+// come from a seeded RNG, though tokio's random `select!` order still varies
+// the exact interleaving between runs.
+#![allow(dead_code)]
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use flume::{Receiver, Sender};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{Duration, Instant};
+
+use usync::constants::TRANSMISSION_INFO_LENGTH;
+use usync::engine::control::ControlState;
+use usync::engine::stats::SenderStats;
+use usync::engine::{Bus, BusAddress, BusMessage, decoding, receiving, sending};
+use usync::protocol::coding::raptorq_code::{RaptorqReceiver, RaptorqSender};
+use usync::protocol::mock_init;
+use usync::protocol::wire::packets::PacketType;
+use usync::protocol::wire::seed_packet_ids;
+use usync::transmission::UdpSocketLike;
+use usync::transmission::errors::SocketFailure;
+use usync::util::clock::{SharedClock, SimulatedClock};
+use usync::util::file::{CHUNK_INDEX, ChunkIndex, write_at};
+use usync::util::plan::FileChunk;
+use usync::util::units::ChunkId;
+
+pub const CHUNKS: u32 = 8;
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+pub const SERVER_ADDR: &str = "10.0.0.1:7000";
+pub const CLIENT_ADDR: &str = "10.0.0.2:7000";
+
+// Impairments applied to each direction of the link.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinkProfile {
+    pub loss: f64,
+    pub latency: Duration,
+    pub jitter: Duration,
+}
+
+impl LinkProfile {
+    pub fn lossy(loss: f64, latency_ms: u64) -> Self {
+        Self {
+            loss,
+            latency: Duration::from_millis(latency_ms),
+            jitter: Duration::ZERO,
+        }
+    }
+}
+
+// Targeted faults on the tickets the client sends.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TicketFault {
+    Drop,
+    // One byte of the signature flipped, so verification fails.
+    Corrupt,
+    Duplicate,
+    // Every other ticket is held back this long, arriving after newer ones.
+    Delay(Duration),
+}
+
+// A fault applied to tickets sent within [from, until) of the link's creation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FaultWindow {
+    pub fault: TicketFault,
+    pub from: Duration,
+    pub until: Duration,
+}
+
+impl FaultWindow {
+    pub fn new(fault: TicketFault, from_ms: u64, until_ms: u64) -> Self {
+        Self {
+            fault,
+            from: Duration::from_millis(from_ms),
+            until: Duration::from_millis(until_ms),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct LinkStats {
+    pub sent: usize,
+    pub dropped: usize,
+    // When each packet was sent, by its sender, since the link was created.
+    pub sends: Vec<(SocketAddr, Duration)>,
+}
+
+impl LinkStats {
+    pub fn last_send_from(&self, addr: SocketAddr) -> Option<Duration> {
+        self.sends
+            .iter()
+            .filter(|(from, _)| *from == addr)
+            .map(|(_, at)| *at)
+            .max()
+    }
+}
+
+// One end of the simulated link, delivering to the other end after the latency.
+pub struct SimSocket {
+    local_addr: SocketAddr,
+    peer: Sender<(Bytes, SocketAddr)>,
+    inbox: Receiver<(Bytes, SocketAddr)>,
+    profile: LinkProfile,
+    rng: Arc<Mutex<StdRng>>,
+    stats: Arc<Mutex<LinkStats>>,
+    faults: Vec<FaultWindow>,
+    start: Instant,
+    hold_next: AtomicBool,
+}
+
+pub fn sim_pair(
+    addr1: SocketAddr,
+    addr2: SocketAddr,
+    profile: LinkProfile,
+    faults: Vec<FaultWindow>,
+    seed: u64,
+) -> (SimSocket, SimSocket) {
+    let (tx1, rx1) = flume::unbounded();
+    let (tx2, rx2) = flume::unbounded();
+    let rng = Arc::new(Mutex::new(StdRng::seed_from_u64(seed)));
+    let stats = Arc::new(Mutex::new(LinkStats::default()));
+    (
+        SimSocket {
+            local_addr: addr1,
+            peer: tx2,
+            inbox: rx1,
+            profile,
+            rng: rng.clone(),
+            stats: stats.clone(),
+            faults: faults.clone(),
+            start: Instant::now(),
+            hold_next: AtomicBool::new(false),
+        },
+        SimSocket {
+            local_addr: addr2,
+            peer: tx1,
+            inbox: rx2,
+            profile,
+            rng,
+            stats,
+            faults,
+            start: Instant::now(),
+            hold_next: AtomicBool::new(false),
+        },
+    )
+}
+
+impl SimSocket {
+    pub fn stats(&self) -> Arc<Mutex<LinkStats>> {
+        self.stats.clone()
+    }
+
+    fn ticket_fault(&self, packet: &[u8]) -> Option<TicketFault> {
+        if packet.get(1) != Some(&u8::from(PacketType::Ticket)) {
+            return None;
+        }
+        let now = self.start.elapsed();
+        self.faults
+            .iter()
+            .find(|window| window.from <= now && now < window.until)
+            .map(|window| window.fault)
+    }
+
+    fn deliver(&self, packet: Bytes, delay: Duration) {
+        let peer = self.peer.clone();
+        let from = self.local_addr;
+        if delay.is_zero() {
+            peer.send((packet, from)).ok();
+        } else {
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                peer.send((packet, from)).ok();
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl UdpSocketLike for SimSocket {
+    async fn send_to(&self, bufs: &[Bytes], _target: SocketAddr) -> std::io::Result<usize> {
+        let mut packet = bufs.concat();
+        let length = packet.len();
+        let fault = self.ticket_fault(&packet);
+        let (lost, delay) = {
+            let mut rng = self.rng.lock().unwrap();
+            let jitter = match self.profile.jitter.is_zero() {
+                true => Duration::ZERO,
+                false => self.profile.jitter.mul_f64(rng.random::<f64>()),
+            };
+            (
+                rng.random::<f64>() < self.profile.loss,
+                self.profile.latency + jitter,
+            )
+        };
+        let lost = lost || fault == Some(TicketFault::Drop);
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.sent += 1;
+            stats.dropped += lost as usize;
+            stats.sends.push((self.local_addr, self.start.elapsed()));
+        }
+        if lost {
+            return Ok(length);
+        }
+
+        match fault {
+            Some(TicketFault::Corrupt) => packet[length - 1] ^= 0xff,
+            Some(TicketFault::Duplicate) => self.deliver(Bytes::from(packet.clone()), delay),
+            Some(TicketFault::Delay(hold))
+                if !self.hold_next.fetch_xor(true, Ordering::Relaxed) =>
+            {
+                self.deliver(Bytes::from(packet), delay + hold);
+                return Ok(length);
+            }
+            _ => {}
+        }
+        self.deliver(Bytes::from(packet), delay);
+        Ok(length)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        let (data, from) = self
+            .inbox
+            .recv_async()
+            .await
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::UnexpectedEof, err))?;
+        let length = data.len().min(buf.len());
+        buf[..length].copy_from_slice(&data[..length]);
+        Ok((length, from))
+    }
+}
+
+// Chunks served by every simulation in this test binary, since the chunk index is global.
+pub fn chunk_data() -> &'static [Vec<u8>] {
+    static DATA: OnceLock<Vec<Vec<u8>>> = OnceLock::new();
+    DATA.get_or_init(|| {
+        mock_init();
+        let mut rng = StdRng::seed_from_u64(0x05c);
+        let data: Vec<Vec<u8>> = (0..CHUNKS)
+            .map(|_| (0..CHUNK_SIZE).map(|_| rng.random()).collect())
+            .collect();
+
+        let path = tempfile::NamedTempFile::new()
+            .unwrap()
+            .into_temp_path()
+            .keep()
+            .unwrap();
+        for (chunk_id, chunk) in data.iter().enumerate() {
+            write_at(&path, (chunk_id * CHUNK_SIZE) as u64, chunk).unwrap();
+        }
+        CHUNK_INDEX
+            .set(ChunkIndex::new(
+                HashMap::from([(0, OsString::from(path))]),
+                (0..CHUNKS)
+                    .map(|chunk_id| {
+                        (
+                            ChunkId(chunk_id),
+                            (0, chunk_id as u64 * CHUNK_SIZE as u64, CHUNK_SIZE),
+                        )
+                    })
+                    .collect(),
+            ))
+            .map_err(|_| "Chunk index already set")
+            .unwrap();
+        data
+    })
+}
+
+// Plan entries for `chunk_ids`, laid out as on the server.
+pub fn plan_chunks(chunk_ids: &[u32]) -> Vec<FileChunk> {
+    chunk_ids
+        .iter()
+        .map(|chunk_id| FileChunk {
+            chunk_id: ChunkId(*chunk_id),
+            hash: hex::encode(blake3::hash(&chunk_data()[*chunk_id as usize]).as_bytes()),
+            offset: *chunk_id as u64 * CHUNK_SIZE as u64,
+            length: CHUNK_SIZE,
+        })
+        .collect()
+}
+
+pub struct TransferOutcome {
+    pub elapsed: Duration,
+    // None for chunks that failed to decode.
+    pub chunks: HashMap<u32, Option<Vec<u8>>>,
+    // Bus peers still registered once everything should have shut down.
+    pub leaked: Vec<BusAddress>,
+    pub link: LinkStats,
+}
+
+impl TransferOutcome {
+    pub fn assert_correct(&self) {
+        let data = chunk_data();
+        for (chunk_id, chunk) in self.chunks.iter() {
+            let chunk = chunk
+                .as_ref()
+                .unwrap_or_else(|| panic!("Chunk {chunk_id} failed to decode"));
+            assert!(
+                *chunk == data[*chunk_id as usize],
+                "Chunk {chunk_id} differs"
+            );
+        }
+        assert!(
+            self.leaked.is_empty(),
+            "Leaked bus peers: {:?}",
+            self.leaked
+        );
+    }
+}
+
+// Long enough for every encoder and decoder to time out and unregister.
+const SETTLE: Duration = Duration::from_secs(30);
+
+// Downloads `chunk_ids` from an in-process server. Call on a paused runtime.
+pub async fn run_transfer(profile: LinkProfile, chunk_ids: &[u32], seed: u64) -> TransferOutcome {
+    run_transfer_with_faults(profile, vec![], chunk_ids, seed).await
+}
+
+pub async fn run_transfer_with_faults(
+    profile: LinkProfile,
+    faults: Vec<FaultWindow>,
+    chunk_ids: &[u32],
+    seed: u64,
+) -> TransferOutcome {
+    simulate(
+        profile,
+        faults,
+        ControlState::new("client"),
+        chunk_ids,
+        seed,
+    )
+    .await
+}
+
+// The client reads its rate and pause state from `control`.
+pub async fn run_transfer_with_control(
+    profile: LinkProfile,
+    control: Arc<ControlState>,
+    chunk_ids: &[u32],
+    seed: u64,
+) -> TransferOutcome {
+    simulate(profile, vec![], control, chunk_ids, seed).await
+}
+
+// A server and client talking over the simulated link, sharing one bus.
+pub struct Simulation {
+    pub bus: Arc<Bus<BusAddress, BusMessage<TRANSMISSION_INFO_LENGTH>>>,
+    server: JoinHandle<Result<(), SocketFailure>>,
+    client: JoinHandle<Result<(), receiving::SessionError>>,
+    stats: JoinHandle<()>,
+    link: Arc<Mutex<LinkStats>>,
+}
+
+impl Simulation {
+    // `chunk_ids` are hinted to the server as upcoming.
+    pub fn start(
+        profile: LinkProfile,
+        faults: Vec<FaultWindow>,
+        control: Arc<ControlState>,
+        chunk_ids: &[u32],
+        seed: u64,
+    ) -> Self {
+        chunk_data();
+        seed_packet_ids(seed);
+        let clock: SharedClock = SimulatedClock::shared(1_700_000_000_000);
+        let server_addr: SocketAddr = SERVER_ADDR.parse().unwrap();
+        let client_addr: SocketAddr = CLIENT_ADDR.parse().unwrap();
+        let (server_socket, client_socket) =
+            sim_pair(server_addr, client_addr, profile, faults, seed);
+        let link = server_socket.stats();
+
+        let bus: Arc<Bus<BusAddress, BusMessage<TRANSMISSION_INFO_LENGTH>>> =
+            Arc::new(Bus::default());
+        let sender = sending::SendingSocket::new(
+            server_socket,
+            bus.clone().register(BusAddress::SenderSocket).unwrap(),
+        )
+        .with_clock(clock.clone());
+        let receiver = receiving::ReceivingSocket::new(
+            client_socket,
+            bus.clone().register(BusAddress::ReceiverSocket).unwrap(),
+        )
+        .with_upcoming(chunk_ids.iter().copied().map(ChunkId).collect())
+        .with_control(control)
+        .with_clock(clock);
+        let stats = SenderStats::new(bus.clone().register(BusAddress::SenderStats).unwrap());
+        Self {
+            stats: tokio::spawn(stats.run()),
+            bus,
+            server: tokio::spawn(sender.run::<RaptorqSender>()),
+            client: tokio::spawn(receiver.run(server_addr)),
+            link,
+        }
+    }
+
+    // Waits for every encoder and decoder to shut down, returning the bus peers
+    // still registered and what went over the link.
+    pub async fn finish(self) -> (Vec<BusAddress>, LinkStats) {
+        tokio::time::sleep(SETTLE).await;
+        let leaked = self
+            .bus
+            .addresses()
+            .into_iter()
+            .filter(|address| {
+                !matches!(
+                    address,
+                    BusAddress::SenderSocket | BusAddress::ReceiverSocket | BusAddress::SenderStats
+                )
+            })
+            .collect();
+        self.server.abort();
+        self.client.abort();
+        self.stats.abort();
+        let link = std::mem::take(&mut *self.link.lock().unwrap());
+        (leaked, link)
+    }
+}
+
+async fn simulate(
+    profile: LinkProfile,
+    faults: Vec<FaultWindow>,
+    control: Arc<ControlState>,
+    chunk_ids: &[u32],
+    seed: u64,
+) -> TransferOutcome {
+    let simulation = Simulation::start(profile, faults, control, chunk_ids, seed);
+
+    let start = Instant::now();
+    let mut decoders = JoinSet::new();
+    for chunk_id in chunk_ids.iter().copied() {
+        let handle = decoding::spawn::<RaptorqReceiver, TRANSMISSION_INFO_LENGTH>(
+            ChunkId(chunk_id),
+            simulation.bus.clone(),
+        );
+        decoders.spawn(async move { (chunk_id, handle.await.ok().flatten()) });
+    }
+    let mut chunks = HashMap::new();
+    while let Some(Ok((chunk_id, outcome))) = decoders.join_next().await {
+        chunks.insert(chunk_id, outcome.and_then(|outcome| outcome.data));
+    }
+    let elapsed = start.elapsed();
+
+    let (leaked, link) = simulation.finish().await;
+    TransferOutcome {
+        elapsed,
+        chunks,
+        leaked,
+        link,
+    }
+}
@@ -0,0 +1,63 @@
+// A whole transfer over real UDP sockets on 127.0.0.1, down to the file written
+// and checked. Chunks go with the plain codec, quick enough for debug builds,
+// and the kernel's loopback rarely drops what little a lost frame costs.
+mod common;
+
+use common::{CHUNK_SIZE, CHUNKS, chunk_data, plan_chunks};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::time::Duration;
+use usync::constants::TRANSMISSION_INFO_LENGTH;
+use usync::engine::download::DownloadManager;
+use usync::engine::receiving::ReceivingSocket;
+use usync::engine::sending::SendingSocket;
+use usync::engine::{Bus, BusAddress, BusMessage};
+use usync::protocol::coding::plain::{PlainReceiver, PlainSender};
+use usync::transmission::real::RealUdpSocket;
+use usync::util::units::ChunkId;
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+#[tokio::test]
+async fn transfer_over_loopback() {
+    chunk_data();
+    let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let server_socket = RealUdpSocket::bind(localhost).await.unwrap();
+    let server_addr = server_socket.local_addr().unwrap();
+    let client_socket = RealUdpSocket::bind(localhost).await.unwrap();
+
+    let bus: Arc<Bus<BusAddress, BusMessage<TRANSMISSION_INFO_LENGTH>>> = Arc::new(Bus::default());
+    let server = tokio::spawn(
+        SendingSocket::new(
+            server_socket,
+            bus.clone().register(BusAddress::SenderSocket).unwrap(),
+        )
+        .run::<PlainSender>(),
+    );
+    let chunk_ids: Vec<u32> = (0..CHUNKS).collect();
+    let client = tokio::spawn(
+        ReceivingSocket::new(
+            client_socket,
+            bus.clone().register(BusAddress::ReceiverSocket).unwrap(),
+        )
+        .with_upcoming(chunk_ids.iter().copied().map(ChunkId).collect())
+        .run(server_addr),
+    );
+
+    let destination = tempfile::NamedTempFile::new().unwrap();
+    let download = DownloadManager::new(bus.clone(), destination.path())
+        .with_retries(1)
+        .run::<PlainReceiver>(plan_chunks(&chunk_ids));
+    let mut report = tokio::time::timeout(TIMEOUT, download)
+        .await
+        .expect("Transfer timed out");
+    server.abort();
+    client.abort();
+
+    report.written.sort();
+    assert!(report.written.iter().map(|id| id.0).eq(chunk_ids));
+    assert!(report.failed.is_empty(), "{:?}", report.failed);
+    let written = std::fs::read(destination.path()).unwrap();
+    assert_eq!(written.len(), CHUNKS as usize * CHUNK_SIZE);
+    assert!(written == chunk_data().concat());
+}
@@ -0,0 +1,62 @@
+// The surface embedders rely on, see `usync::prelude`. A change that breaks
+// this test breaks them too, and waits for the next major version.
+use std::net::SocketAddr;
+use std::path::Path;
+use usync::constants::TRANSMISSION_INFO_LENGTH;
+use usync::prelude::*;
+
+#[test]
+fn signatures_hold() {
+    let _: fn(FileConfig, SocketAddr, &Path) -> Downloader = Downloader::new;
+    let _: fn(Downloader, u64) -> Downloader = Downloader::with_rate_kbps;
+    let _: fn(Downloader, usize) -> Downloader = Downloader::with_retries;
+    let _: fn(&Downloader) -> CancelHandle = Downloader::cancel_handle;
+    let _: fn(FileConfig, &Path, SocketAddr) -> Seeder = Seeder::new;
+    let _: fn(&Seeder) -> CancelHandle = Seeder::cancel_handle;
+    let _: fn(&CancelHandle) = CancelHandle::cancel;
+    let _: fn(&Path, &PlanBuilder) -> std::io::Result<FileConfig> = plan_file;
+    let _: fn(&str) -> Option<ContentKey> = ContentKey::from_hex;
+    let _: fn(usize) -> Option<ChunkId> = ChunkId::from_index;
+
+    let report = DownloadReport::default();
+    let _: (&Vec<ChunkId>, &Vec<ChunkId>) = (&report.written, &report.failed);
+    let progress = DownloadProgress::default();
+    let _: (usize, usize, u64) = (progress.total, progress.written, progress.bytes_written);
+}
+
+fn codec<S: FrameSender<N>, R: FrameReceiver<N>, const N: usize>() {}
+
+fn socket<S: UdpSocketLike>() {}
+
+#[test]
+fn bundled_implementations_fit_the_traits() {
+    codec::<RaptorqSender, RaptorqReceiver, TRANSMISSION_INFO_LENGTH>();
+    socket::<DynSocket>();
+}
+
+// Everything public without the `internals` feature, a declaration a line.
+// A change here changes the supported API, bless it with
+// `USYNC_BLESS=1 cargo test --test prelude` once that is intended.
+#[test]
+fn public_api_matches_the_snapshot() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut surface = String::new();
+    for file in ["src/lib.rs", "src/prelude.rs", "src/constants.rs"] {
+        let source = std::fs::read_to_string(root.join(file)).unwrap();
+        for line in source
+            .lines()
+            .filter(|line| line.starts_with("pub ") || line.starts_with("#[cfg("))
+        {
+            surface += &format!("{file}: {line}\n");
+        }
+    }
+    let snapshot = root.join("tests/public-api.txt");
+    if std::env::var_os("USYNC_BLESS").is_some() {
+        std::fs::write(&snapshot, &surface).unwrap();
+    }
+    assert_eq!(
+        surface,
+        std::fs::read_to_string(&snapshot).unwrap(),
+        "the public API changed"
+    );
+}
@@ -0,0 +1,251 @@
+mod common;
+
+use common::{
+    CHUNK_SIZE, CHUNKS, CLIENT_ADDR, LinkProfile, SERVER_ADDR, Simulation, chunk_data, plan_chunks,
+    run_transfer, run_transfer_with_control, sim_pair,
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::time::Duration;
+use usync::constants::TRANSMISSION_INFO_LENGTH;
+use usync::engine::control::ControlState;
+use usync::engine::download::{ChunkVerifier, DownloadManager};
+use usync::engine::receiving::{ReceivingSocket, await_offer};
+use usync::engine::sending::SendingSocket;
+use usync::engine::{Bus, BusAddress, BusMessage};
+use usync::protocol::coding::raptorq_code::{RaptorqReceiver, RaptorqSender};
+use usync::util::plan::FileChunk;
+use usync::util::units::ChunkId;
+
+#[tokio::test(start_paused = true)]
+async fn lossless_link() {
+    let outcome = run_transfer(LinkProfile::default(), &[0, 1, 2], 1).await;
+    outcome.assert_correct();
+    assert_eq!(outcome.link.dropped, 0);
+    assert!(
+        outcome.elapsed < Duration::from_secs(5),
+        "{:?}",
+        outcome.elapsed
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn lossy_link_with_latency() {
+    let chunk_ids: Vec<u32> = (0..CHUNKS).collect();
+    let outcome = run_transfer(LinkProfile::lossy(0.1, 20), &chunk_ids, 2).await;
+    outcome.assert_correct();
+    assert!(outcome.link.dropped > 0);
+    assert!(
+        outcome.elapsed < Duration::from_secs(15),
+        "{:?}",
+        outcome.elapsed
+    );
+}
+
+// Jitter beyond the packet spacing delivers frames out of order.
+#[tokio::test(start_paused = true)]
+async fn jittery_link() {
+    let profile = LinkProfile {
+        loss: 0.05,
+        latency: Duration::from_millis(10),
+        jitter: Duration::from_millis(30),
+    };
+    let outcome = run_transfer(profile, &[3, 4, 5], 3).await;
+    outcome.assert_correct();
+    assert!(
+        outcome.elapsed < Duration::from_secs(10),
+        "{:?}",
+        outcome.elapsed
+    );
+}
+
+// Encoders are closed while paused and restarted from the reported offsets.
+#[tokio::test(start_paused = true)]
+async fn pause_and_resume() {
+    let control = ControlState::new("client");
+    // Slow enough that the chunks are halfway through when paused.
+    control.set_rate_kbps(Some(256));
+    tokio::spawn({
+        let control = control.clone();
+        async move {
+            tokio::time::sleep(Duration::from_millis(1_500)).await;
+            control.set_paused(true);
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            control.set_paused(false);
+        }
+    });
+    let chunk_ids: Vec<u32> = (0..CHUNKS).collect();
+    let outcome =
+        run_transfer_with_control(LinkProfile::lossy(0.1, 20), control, &chunk_ids, 4).await;
+    outcome.assert_correct();
+    assert!(
+        outcome.elapsed >= Duration::from_millis(31_500),
+        "{:?}",
+        outcome.elapsed
+    );
+    assert!(
+        outcome.elapsed < Duration::from_secs(45),
+        "{:?}",
+        outcome.elapsed
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn download_manager_retries_and_writes_verified_chunks() {
+    let chunk_ids = [0, 1, 2, 3];
+    let simulation = Simulation::start(
+        LinkProfile::lossy(0.1, 20),
+        vec![],
+        ControlState::new("client"),
+        &chunk_ids,
+        5,
+    );
+    let mut chunks = plan_chunks(&chunk_ids);
+    // The server's chunk 3 no longer matches the plan.
+    chunks[3].hash = hex::encode([0u8; 32]);
+
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let manager = DownloadManager::new(simulation.bus.clone(), file.path()).with_retries(1);
+    let progress = manager.progress();
+    let mut report = manager.run::<RaptorqReceiver>(chunks).await;
+
+    report.written.sort();
+    assert_eq!(report.written, vec![ChunkId(0), ChunkId(1), ChunkId(2)]);
+    assert_eq!(report.failed, vec![ChunkId(3)]);
+    // Not decoding to the planned hash twice, it is not asked for again.
+    assert_eq!(report.blacklisted, vec![ChunkId(3)]);
+    assert!(!report.out_of_space);
+    // Chunk 3 was tried twice.
+    assert_eq!(report.summary.chunks.len(), 5);
+    assert_eq!(progress.borrow().bytes_written, 3 * CHUNK_SIZE as u64);
+
+    let written = std::fs::read(file.path()).unwrap();
+    for chunk_id in 0..3 {
+        assert!(written[chunk_id * CHUNK_SIZE..][..CHUNK_SIZE] == chunk_data()[chunk_id]);
+    }
+    let (leaked, _) = simulation.finish().await;
+    assert!(leaked.is_empty(), "{leaked:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn download_manager_stops_starting_chunks_over_budget() {
+    let chunk_ids = [0, 1, 2, 3];
+    let simulation = Simulation::start(
+        LinkProfile::lossy(0.0, 20),
+        vec![],
+        ControlState::new("client"),
+        &chunk_ids,
+        11,
+    );
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let manager = DownloadManager::new(simulation.bus.clone(), file.path())
+        .with_concurrency(1)
+        .with_max_bytes(5 * CHUNK_SIZE as u64 / 2);
+    let report = manager
+        .run::<RaptorqReceiver>(plan_chunks(&chunk_ids))
+        .await;
+
+    // A third chunk would not fit in what the first two left of the budget.
+    assert_eq!(report.written.len(), 2);
+    assert_eq!(report.over_budget.len(), 2);
+    assert!(report.failed.is_empty());
+    assert!(report.summary.bytes_received <= 5 * CHUNK_SIZE as u64 / 2);
+    let (leaked, _) = simulation.finish().await;
+    assert!(leaked.is_empty(), "{leaked:?}");
+}
+
+// Stands in for a check against an external manifest that does not list chunk 1.
+struct Manifest {
+    checked: AtomicUsize,
+}
+
+impl ChunkVerifier for Manifest {
+    fn verify(&self, chunk: &FileChunk, _data: &[u8]) -> Result<(), String> {
+        self.checked.fetch_add(1, Ordering::Relaxed);
+        match chunk.chunk_id {
+            ChunkId(1) => Err("not in the manifest".to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn download_manager_runs_added_verifiers() {
+    let chunk_ids = [0, 1, 2];
+    let simulation = Simulation::start(
+        LinkProfile::lossy(0.0, 20),
+        vec![],
+        ControlState::new("client"),
+        &chunk_ids,
+        12,
+    );
+    let mut chunks = plan_chunks(&chunk_ids);
+    // Never reaches the manifest, the plan hash check comes first.
+    chunks[2].hash = hex::encode([0u8; 32]);
+    let manifest = Arc::new(Manifest {
+        checked: AtomicUsize::new(0),
+    });
+
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let manager =
+        DownloadManager::new(simulation.bus.clone(), file.path()).with_verifier(manifest.clone());
+    let report = manager.run::<RaptorqReceiver>(chunks).await;
+
+    assert_eq!(report.written, vec![ChunkId(0)]);
+    let mut failed = report.failed.clone();
+    failed.sort();
+    assert_eq!(failed, vec![ChunkId(1), ChunkId(2)]);
+    assert_eq!(manifest.checked.load(Ordering::Relaxed), 2);
+    let (leaked, _) = simulation.finish().await;
+    assert!(leaked.is_empty(), "{leaked:?}");
+}
+
+// The client offers the file and serves it, the server asks for the chunks and writes them.
+#[tokio::test(start_paused = true)]
+async fn upload_reverses_the_roles() {
+    let chunk_ids = [2, 5, 6];
+    let chunks = plan_chunks(&chunk_ids);
+    let server_addr: SocketAddr = SERVER_ADDR.parse().unwrap();
+    let client_addr: SocketAddr = CLIENT_ADDR.parse().unwrap();
+    let (server_socket, client_socket) = sim_pair(
+        server_addr,
+        client_addr,
+        LinkProfile::lossy(0.05, 20),
+        vec![],
+        13,
+    );
+    let bus: Arc<Bus<BusAddress, BusMessage<TRANSMISSION_INFO_LENGTH>>> = Arc::new(Bus::default());
+    let uploader = SendingSocket::new(
+        client_socket,
+        bus.clone().register(BusAddress::SenderSocket).unwrap(),
+    );
+    let uploader =
+        tokio::spawn(uploader.push::<RaptorqSender>(server_addr, 42, Duration::from_secs(30)));
+
+    let from = await_offer::<_, TRANSMISSION_INFO_LENGTH>(&server_socket, 42)
+        .await
+        .unwrap();
+    assert_eq!(from, client_addr);
+    let receiver = ReceivingSocket::new(
+        server_socket,
+        bus.clone().register(BusAddress::ReceiverSocket).unwrap(),
+    )
+    .with_upcoming(chunk_ids.map(ChunkId).to_vec());
+    let receiver = tokio::spawn(receiver.run(from));
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let report = DownloadManager::new(bus.clone(), file.path())
+        .run::<RaptorqReceiver>(chunks.clone())
+        .await;
+    assert_eq!(report.written.len(), chunk_ids.len());
+    let written = std::fs::read(file.path()).unwrap();
+    for chunk in chunks.iter() {
+        assert!(
+            written[chunk.offset as usize..][..CHUNK_SIZE] == chunk_data()[chunk.chunk_id.index()]
+        );
+    }
+
+    // The uploader is done once the tickets stop.
+    receiver.abort();
+    assert!(uploader.await.unwrap().is_ok());
+}